@@ -0,0 +1,79 @@
+#![no_main]
+
+use std::collections::HashSet;
+
+use brainbug::common::*;
+use brainbug::compile::{self, AsmDialect, AsmOptions, CompileTimes, OptimizeOptions};
+use brainbug::interp::State;
+use libfuzzer_sys::fuzz_target;
+
+// Instructions the original program may run. Programs that run longer are taken not to halt, and are
+// only compiled.
+const STEP_LIMIT: u64 = 100_000;
+
+// What both the original and optimized programs read, followed by zeros.
+const INPUT: &[u8] = b"\x03\x01\x7f\xff";
+
+// Interpret program on INPUT, returning its output, or None if it ran more than step_limit
+// instructions.
+fn run(program: Vec<Instruction>, step_limit: u64) -> Option<Vec<u8>> {
+    let mut state = State::new(program);
+    state.set_eof_byte(0);
+    state.set_step_limit(step_limit);
+
+    let mut output = Vec::new();
+    state.interp(INPUT, &mut output).ok()?;
+    Some(output)
+}
+
+// Lex arbitrary bytes, optimize them and check the optimized program writes what the original does
+// in the interpreter, then generate asm, checking that nothing panics. The first byte picks the
+// passes and the second the backend options; the rest is the program.
+fuzz_target!(|data: &[u8]| {
+    let [flags, asm_flags, source @ ..] = data else {
+        return;
+    };
+
+    let mut program = lex(source);
+
+    // Like the CLI, reject unbalanced programs before compiling them.
+    if check_brackets(&program).is_err() {
+        return;
+    }
+
+    let expected = run(program.clone(), STEP_LIMIT);
+
+    let opts = OptimizeOptions {
+        simplify_loops: flags & 1 != 0,
+        vectorize_scans: flags & 2 != 0,
+        unroll_limit: if flags & 4 != 0 { 64 } else { 0 },
+        // Partial eval runs the program at compile time, so only programs that halt can use it.
+        partial_eval: flags & 8 != 0 && expected.is_some(),
+        partial_eval_past_loops: flags & 16 != 0,
+        eliminate_dead_loops: flags & 32 == 0,
+        ..OptimizeOptions::default()
+    };
+
+    if let Some(expected) = expected {
+        let mut optimized = program.clone();
+        compile::optimize(&mut optimized, opts);
+
+        // Partial eval may add an instruction for each cell it sets up, but no pass should make the
+        // program run much longer than that.
+        let output = run(optimized, 2 * STEP_LIMIT);
+        assert!(output.as_ref() == Some(&expected), "optimized program wrote {:?}, expected {:?}", output, expected);
+    }
+
+    let asm_opts = AsmOptions {
+        dialect: if asm_flags & 1 != 0 { AsmDialect::Nasm } else { AsmDialect::Att },
+        use_avx: asm_flags & 2 != 0,
+        checked: asm_flags & 4 != 0,
+        freestanding: asm_flags & 8 != 0,
+        ascii_out: asm_flags & 16 != 0,
+        ..AsmOptions::default()
+    };
+
+    if let Err(e) = compile::compile_to_asm(&mut program, opts, asm_opts, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()) {
+        panic!("failed to compile a balanced program: {}", e);
+    }
+});
@@ -1,9 +1,31 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error;
 use std::fmt;
 use regex::Regex;
 use std::path::{Path, PathBuf};
 use std::fs;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+// Debug commands some dialects add on top of the eight core commands. They print to stderr and
+// leave the tape untouched.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DebugCommand {
+    // Print the cells around the head
+    DumpTape,
+
+    // Print the value of the cell under the head
+    PrintCell,
+}
+
+impl fmt::Display for DebugCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugCommand::DumpTape => write!(f, "DUMPTAPE"),
+            DebugCommand::PrintCell => write!(f, "PRINTCELL"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone)]
 pub enum Instruction {
     MoveRight,
     MoveLeft,
@@ -15,13 +37,23 @@ pub enum Instruction {
     JumpUnlessZero,
     Zero,
 
+    // Zero the cell at the given offset from the head.
+    ZeroAt(i32),
+
     // Add or subtract the contents at the current cell to the cell at the given offset.
     Add(i32), 
     Sub(i32), 
 
-    // Scan until the head reaches a cell containing 0, moving the head by the specified number of cells each iteration
+    // Add a constant to the cell at the given offset.
+    AddConst(i32, u8),
+
+    // Scan until the head reaches a cell containing 0, moving the head by the specified number of cells each iteration.
+    // The stride may be negative but must be nonzero; the asm backends reject Scan(0).
     Scan(i32),
 
+    // Like Scan, but zero each cell the head passes over before moving on. The stride must be nonzero.
+    ClearScan(i32),
+
     // Set the head to the given position
     SetHeadPos(i32),
 
@@ -31,6 +63,19 @@ pub enum Instruction {
     // Output the given literal value
     Output(u8),
 
+    // Output the given literal bytes, merged from consecutive Outputs
+    OutputStr(Vec<u8>),
+
+    // Read into or write the cell at the given position, without moving the head
+    ReadAt(i32),
+    WriteAt(i32),
+
+    // Set the cell under the head to the given value
+    SetCurrent(u8),
+
+    // An extended debug command from LexOptions. Only the interpreter acts on these.
+    Debug(DebugCommand),
+
     Nop
 }
 
@@ -47,12 +92,20 @@ impl fmt::Display for Instruction {
             Instruction::JumpUnlessZero => write!(f, "]"),
             Instruction::Add(offset) => write!(f, "ADD({offset})"),
             Instruction::Sub(offset) => write!(f, "SUB({offset})"),
+            Instruction::AddConst(offset, val) => write!(f, "ADDCONST({offset}, {val})"),
             Instruction::Scan(x) => write!(f, "SCAN({x})"),
+            Instruction::ClearScan(x) => write!(f, "CLEARSCAN({x})"),
             Instruction::Nop => write!(f, "NOP"),
             Instruction::Zero => write!(f, "ZERO"),
+            Instruction::ZeroAt(offset) => write!(f, "ZEROAT({offset})"),
             Instruction::SetHeadPos(x) => write!(f, "SETHEADPOS({x})"),
             Instruction::SetCell(pos, val) => write!(f, "SETCELL({pos}, {val})"),
-            Instruction::Output(val) => write!(f, "OUTPUT({val})")
+            Instruction::Output(val) => write!(f, "OUTPUT({val})"),
+            Instruction::OutputStr(bytes) => write!(f, "OUTPUTSTR(\"{}\")", bytes.escape_ascii()),
+            Instruction::ReadAt(pos) => write!(f, "READAT({pos})"),
+            Instruction::WriteAt(pos) => write!(f, "WRITEAT({pos})"),
+            Instruction::SetCurrent(val) => write!(f, "SETCURRENT({val})"),
+            Instruction::Debug(cmd) => write!(f, "DEBUG({cmd})")
         }
     }
 }
@@ -63,26 +116,529 @@ impl fmt::Debug for Instruction {
     }
 }
 
-pub fn lex(program : &str) -> Vec<Instruction> {
+// Takes bytes as well as strings. Every command is a single ASCII byte, so programs don't need to be
+// valid UTF-8.
+pub fn lex(program : impl AsRef<[u8]>) -> Vec<Instruction> {
+    return lex_iter(program.as_ref()).collect();
+}
+
+// Like lex, but yields each instruction as it's reached instead of building the whole program up
+// front.
+pub fn lex_iter(program : &[u8]) -> impl Iterator<Item = Instruction> + '_ {
+    program.iter().filter_map(|c| lex_byte(*c))
+}
+
+// The instruction for a core command byte, or None for a comment.
+fn lex_byte(c : u8) -> Option<Instruction> {
+    match c {
+        b'>' => Some(Instruction::MoveRight),
+        b'<' => Some(Instruction::MoveLeft),
+        b'+' => Some(Instruction::Increment),
+        b'-' => Some(Instruction::Decrement),
+        b'.' => Some(Instruction::Write),
+        b',' => Some(Instruction::Read),
+        b'[' => Some(Instruction::JumpIfZero),
+        b']' => Some(Instruction::JumpUnlessZero),
+        _ => None
+    }
+}
+
+// Extra characters to lex as debug commands. The default has none, so it lexes like lex.
+#[derive(Clone, Default)]
+pub struct LexOptions {
+    pub debug_commands: HashMap<u8, DebugCommand>,
+
+    // Characters besides whitespace that check_strict accepts as comments.
+    pub comment_chars: HashSet<u8>,
+
+    // Skip cell directives like ";w32 5", so the sign of a negative offset isn't lexed as a '-'.
+    // wide_cells reads them.
+    pub cell_directives: bool,
+}
+
+impl LexOptions {
+    // The built in extensions: '#' dumps the tape and '$' prints the cell under the head.
+    pub fn with_builtin_debug_commands() -> LexOptions {
+        let mut opts = LexOptions::default();
+        opts.debug_commands.insert(b'#', DebugCommand::DumpTape);
+        opts.debug_commands.insert(b'$', DebugCommand::PrintCell);
+        return opts;
+    }
+}
+
+// Like lex, but also turns the characters in opts into debug commands. The core commands can't be
+// remapped.
+pub fn lex_with_options(program : impl AsRef<[u8]>, opts : &LexOptions) -> Vec<Instruction> {
+    if opts.debug_commands.is_empty() && !opts.cell_directives {
+        return lex(program);
+    }
+
     let mut insts = Vec::new();
 
-    for c in program.chars() {
-        match c {
-            '>' => insts.push(Instruction::MoveRight),
-            '<' => insts.push(Instruction::MoveLeft),
-            '+' => insts.push(Instruction::Increment),
-            '-' => insts.push(Instruction::Decrement),
-            '.' => insts.push(Instruction::Write),
-            ',' => insts.push(Instruction::Read),
-            '[' => insts.push(Instruction::JumpIfZero),
-            ']' => insts.push(Instruction::JumpUnlessZero),
-            _ => ()
+    for (_, c) in commented_bytes(program.as_ref(), opts) {
+        match lex_byte(*c) {
+            Some(inst) => insts.push(inst),
+            None => {
+                if let Some(cmd) = opts.debug_commands.get(c) {
+                    insts.push(Instruction::Debug(*cmd));
+                }
+            }
         }
     }
 
     return insts;
 }
 
+#[derive(Debug, Clone)]
+pub struct UnexpectedChar {
+    pub offset: usize,
+    pub byte: u8,
+}
+
+impl fmt::Display for UnexpectedChar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected character '{}' at byte {}", [self.byte].escape_ascii(), self.offset)
+    }
+}
+
+impl error::Error for UnexpectedChar {}
+
+// Check that every byte of the program is a command, a debug command from opts, whitespace or one of
+// opts' comment characters. lex skips anything else as a comment, which hides typos like a '{' for a
+// '[' in generated programs.
+pub fn check_strict(program : impl AsRef<[u8]>, opts : &LexOptions) -> Result<(), UnexpectedChar> {
+    for (offset, c) in commented_bytes(program.as_ref(), opts) {
+        if lex_byte(*c).is_none() && !c.is_ascii_whitespace() && !opts.debug_commands.contains_key(c) && !opts.comment_chars.contains(c) {
+            return Err(UnexpectedChar { offset, byte: *c });
+        }
+    }
+
+    return Ok(());
+}
+
+const WIDE_CELL_DIRECTIVE : &[u8] = b";w32";
+
+// The bytes of the program with their offsets, leaving out cell directives if opts has them on.
+fn commented_bytes<'a>(program : &'a [u8], opts : &LexOptions) -> impl Iterator<Item = (usize, &'a u8)> {
+    let cell_directives = opts.cell_directives;
+    let mut directive_end = 0;
+
+    program.iter().enumerate().filter(move |(offset, _)| {
+        if cell_directives && *offset >= directive_end && program[*offset..].starts_with(WIDE_CELL_DIRECTIVE) {
+            directive_end = program[*offset..].iter().position(|c| *c == b'\n').map_or(program.len(), |len| offset + len);
+        }
+        *offset >= directive_end
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct BadCellDirective {
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for BadCellDirective {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad cell directive at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+impl error::Error for BadCellDirective {}
+
+// The cells that ";w32 N" directives make 32 bit, each spanning the cells from N to N + 3 as a little
+// endian value. N is relative to where the head starts, and the rest of the directive's line is a
+// comment.
+pub fn wide_cells(program : impl AsRef<[u8]>) -> Result<BTreeSet<i32>, BadCellDirective> {
+    let program = program.as_ref();
+    let mut cells = BTreeSet::new();
+
+    for offset in 0..program.len() {
+        if !program[offset..].starts_with(WIDE_CELL_DIRECTIVE) {
+            continue;
+        }
+
+        let rest = &program[offset + WIDE_CELL_DIRECTIVE.len()..];
+        let line = &rest[..rest.iter().position(|c| *c == b'\n').unwrap_or(rest.len())];
+        let cell : i32 = match std::str::from_utf8(line).ok().and_then(|line| line.split_ascii_whitespace().next()?.parse().ok()) {
+            Some(cell) if line.first().is_some_and(|c| c.is_ascii_whitespace()) => cell,
+            _ => return Err(BadCellDirective { offset, reason: "expected a cell offset" }),
+        };
+
+        if cells.range(cell.saturating_sub(3)..=cell.saturating_add(3)).next().is_some() {
+            return Err(BadCellDirective { offset, reason: "overlaps another wide cell" });
+        }
+        cells.insert(cell);
+    }
+
+    return Ok(cells);
+}
+
+#[derive(Debug, Clone)]
+pub struct BadEscape {
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for BadEscape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad escape at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+impl error::Error for BadEscape {}
+
+// The bytes of a string with C style escapes: \n, \r, \t, \0, \\ and \xHH for any other byte.
+pub fn unescape(s : impl AsRef<[u8]>) -> Result<Vec<u8>, BadEscape> {
+    let s = s.as_ref();
+    let mut bytes = Vec::with_capacity(s.len());
+
+    let mut offset = 0;
+    while offset < s.len() {
+        if s[offset] != b'\\' {
+            bytes.push(s[offset]);
+            offset += 1;
+            continue;
+        }
+
+        let byte = match s.get(offset + 1) {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                // from_str_radix alone would take a sign, as in "\x+f".
+                let hex = s.get(offset + 2..offset + 4).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+                match hex.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).ok()) {
+                    Some(byte) => {
+                        bytes.push(byte);
+                        offset += 4;
+                        continue;
+                    },
+                    None => return Err(BadEscape { offset, reason: "expected two hex digits after \\x" }),
+                }
+            },
+            Some(_) => return Err(BadEscape { offset, reason: "unknown escape" }),
+            None => return Err(BadEscape { offset, reason: "trailing backslash" }),
+        };
+        bytes.push(byte);
+        offset += 2;
+    }
+
+    return Ok(bytes);
+}
+
+// Split a source file into the program and, if there's a '!', the input the program is fed in
+// place of stdin. Only the first '!' splits, so later ones are part of the input.
+pub fn split_program_input(source : &[u8]) -> (&[u8], Option<&[u8]>) {
+    match source.iter().position(|c| *c == b'!') {
+        Some(idx) => (&source[..idx], Some(&source[idx + 1..])),
+        None => (source, None),
+    }
+}
+
+// What can be told about a program without running it, for the stats mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramStats {
+    pub instructions: usize,
+
+    // How many times each core command appears, in the order "><+-.,[]".
+    pub command_counts: Vec<(Instruction, usize)>,
+
+    pub loops: usize,
+    pub max_loop_depth: usize,
+
+    // The command repeated most times in a row and how many times, or None for an empty program.
+    pub longest_run: Option<(Instruction, usize)>,
+}
+
+impl fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        for (inst, count) in &self.command_counts {
+            writeln!(f, "  {}: {}", inst, count)?;
+        }
+        writeln!(f, "loops: {}", self.loops)?;
+        writeln!(f, "max loop depth: {}", self.max_loop_depth)?;
+        match &self.longest_run {
+            Some((inst, len)) => write!(f, "longest run: {} x {}", len, inst),
+            None => write!(f, "longest run: 0"),
+        }
+    }
+}
+
+pub fn program_stats(insts : &[Instruction]) -> ProgramStats {
+    let command_counts = b"><+-.,[]".iter()
+        .map(|c| {
+            let inst = lex_byte(*c).unwrap();
+            let count = insts.iter().filter(|other| **other == inst).count();
+            (inst, count)
+        })
+        .collect();
+
+    let mut depth : usize = 0;
+    let mut max_loop_depth = 0;
+    for inst in insts {
+        match inst {
+            Instruction::JumpIfZero => {
+                depth += 1;
+                max_loop_depth = usize::max(max_loop_depth, depth);
+            },
+            Instruction::JumpUnlessZero => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    let mut longest_run : Option<(Instruction, usize)> = None;
+    let mut run_start = 0;
+    for pc in 0..insts.len() {
+        if insts[pc] != insts[run_start] {
+            run_start = pc;
+        }
+        let len = pc - run_start + 1;
+        if len > longest_run.as_ref().map_or(0, |(_, longest)| *longest) {
+            longest_run = Some((insts[pc].clone(), len));
+        }
+    }
+
+    return ProgramStats {
+        instructions: insts.len(),
+        command_counts,
+        loops: insts.iter().filter(|inst| **inst == Instruction::JumpIfZero).count(),
+        max_loop_depth,
+        longest_run,
+    };
+}
+
+// List the program one instruction per line with its pc, indenting loop bodies by their nesting
+// depth.
+pub fn pretty_print(insts : &[Instruction]) -> String {
+    let mut out = String::new();
+    let mut depth : usize = 0;
+
+    for (pc, inst) in insts.iter().enumerate() {
+        if *inst == Instruction::JumpUnlessZero {
+            depth = depth.saturating_sub(1);
+        }
+
+        out += &format!("{}\t{}{}\n", pc, "  ".repeat(depth), inst);
+
+        if *inst == Instruction::JumpIfZero {
+            depth += 1;
+        }
+    }
+
+    return out;
+}
+
+// Write the program back out as Brainfuck, for feeding an optimized program to other tools. Each
+// instruction with a Brainfuck equivalent is expanded into it, and a run of Adds and Subs ending in
+// a Zero becomes the loop it was simplified from. Instructions that name a cell by position are
+// expanded while the head's position is still known. Anything else, like a literal Output, is
+// written as a comment naming the instruction, so the result only matches the program if it has
+// none of those.
+pub fn emit_bf(insts : &[Instruction]) -> String {
+    let mut out = String::new();
+
+    // The head's offset from where it started, until a scan or a loop that moves it loses track.
+    let mut head : Option<i32> = Some(0);
+
+    let mut pc = 0;
+    while pc < insts.len() {
+        match &insts[pc] {
+            Instruction::MoveRight => {
+                out.push('>');
+                head = head.map(|h| h + 1);
+            },
+            Instruction::MoveLeft => {
+                out.push('<');
+                head = head.map(|h| h - 1);
+            },
+            Instruction::Increment => out.push('+'),
+            Instruction::Decrement => out.push('-'),
+            Instruction::Write => out.push('.'),
+            Instruction::Read => out.push(','),
+            Instruction::JumpIfZero => {
+                if !loop_keeps_head(insts, pc) {
+                    head = None;
+                }
+                out.push('[');
+            },
+            Instruction::JumpUnlessZero => out.push(']'),
+            Instruction::Zero => out.push_str("[-]"),
+            Instruction::ZeroAt(offset) => out += &format!("{}[-]{}", bf_moves(*offset), bf_moves(-offset)),
+            Instruction::Add(_) | Instruction::Sub(_) => {
+                match add_loop_end(insts, pc) {
+                    Some(zero_pc) => {
+                        // Walk from cell to cell rather than back to the index after each one, so the
+                        // repeated Adds of a simplified loop come out as a run of '+'s.
+                        let mut at = 0;
+                        out.push_str("[-");
+                        for inst in &insts[pc..zero_pc] {
+                            let (offset, command) = match inst {
+                                Instruction::Add(offset) => (*offset, '+'),
+                                Instruction::Sub(offset) => (*offset, '-'),
+                                _ => continue,
+                            };
+                            out += &bf_moves(offset - at);
+                            out.push(command);
+                            at = offset;
+                        }
+                        out += &bf_moves(-at);
+                        out.push(']');
+                        pc = zero_pc;
+                    },
+                    None => out += &bf_comment(&insts[pc]),
+                }
+            },
+            Instruction::AddConst(offset, val) => out += &format!("{}{}{}", bf_moves(*offset), bf_add(*val), bf_moves(-offset)),
+            Instruction::Scan(stride) => {
+                out += &format!("[{}]", bf_moves(*stride));
+                head = None;
+            },
+            Instruction::ClearScan(stride) => {
+                out += &format!("[[-]{}]", bf_moves(*stride));
+                head = None;
+            },
+            Instruction::SetHeadPos(pos) => match head {
+                Some(h) => {
+                    out += &bf_moves(pos - h);
+                    head = Some(*pos);
+                },
+                None => out += &bf_comment(&insts[pc]),
+            },
+            Instruction::SetCell(pos, val) => match head {
+                Some(h) => out += &format!("{}[-]{}{}", bf_moves(pos - h), bf_add(*val), bf_moves(h - pos)),
+                None => out += &bf_comment(&insts[pc]),
+            },
+            Instruction::ReadAt(pos) => match head {
+                Some(h) => out += &format!("{},{}", bf_moves(pos - h), bf_moves(h - pos)),
+                None => out += &bf_comment(&insts[pc]),
+            },
+            Instruction::WriteAt(pos) => match head {
+                Some(h) => out += &format!("{}.{}", bf_moves(pos - h), bf_moves(h - pos)),
+                None => out += &bf_comment(&insts[pc]),
+            },
+            Instruction::SetCurrent(val) => out += &format!("[-]{}", bf_add(*val)),
+            Instruction::Debug(DebugCommand::DumpTape) => out.push('#'),
+            Instruction::Debug(DebugCommand::PrintCell) => out.push('$'),
+            Instruction::Output(_) | Instruction::OutputStr(_) => out += &bf_comment(&insts[pc]),
+            Instruction::Nop => (),
+        }
+        pc += 1;
+    }
+
+    out.push('\n');
+    return out;
+}
+
+// Whether the loop starting at pc leaves the head where it found it on every iteration, so its
+// position is still known inside and after it.
+fn loop_keeps_head(insts : &[Instruction], start_pc : usize) -> bool {
+    // The head's movement within each loop still open
+    let mut deltas : Vec<i32> = Vec::new();
+
+    for inst in &insts[start_pc..] {
+        match inst {
+            Instruction::JumpIfZero => deltas.push(0),
+            Instruction::JumpUnlessZero => {
+                if deltas.pop() != Some(0) {
+                    return false;
+                }
+                if deltas.is_empty() {
+                    return true;
+                }
+            },
+            Instruction::MoveRight => *deltas.last_mut().unwrap() += 1,
+            Instruction::MoveLeft => *deltas.last_mut().unwrap() -= 1,
+            Instruction::Scan(_) | Instruction::ClearScan(_) | Instruction::SetHeadPos(_) => return false,
+            _ => (),
+        }
+    }
+
+    // Unmatched
+    return false;
+}
+
+// If the Adds and Subs starting at pc run up to a Zero of the cell under the head, the pc of that
+// Zero.
+fn add_loop_end(insts : &[Instruction], start_pc : usize) -> Option<usize> {
+    for pc in start_pc..insts.len() {
+        match insts[pc] {
+            Instruction::Add(offset) | Instruction::Sub(offset) if offset != 0 => (),
+            Instruction::Nop => (),
+            Instruction::Zero => return Some(pc),
+            _ => return None,
+        }
+    }
+
+    return None;
+}
+
+fn bf_moves(offset : i32) -> String {
+    if offset >= 0 {
+        return ">".repeat(offset as usize);
+    }
+    return "<".repeat(offset.unsigned_abs() as usize);
+}
+
+// Add val to the cell under the head, going whichever way round is shorter.
+fn bf_add(val : u8) -> String {
+    if val <= 128 {
+        return "+".repeat(val as usize);
+    }
+    return "-".repeat(256 - val as usize);
+}
+
+// A comment naming an instruction with no Brainfuck equivalent, spelled without any command
+// characters so other tools skip it.
+fn bf_comment(inst : &Instruction) -> String {
+    let text = match inst {
+        Instruction::Output(val) => format!("OUTPUT({val})"),
+        Instruction::OutputStr(bytes) => {
+            let vals : Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+            format!("OUTPUT({})", vals.join(" "))
+        },
+        _ => inst.to_string().replace(',', "").replace('-', "minus "),
+    };
+    return format!(" {text} ");
+}
+
+#[derive(Debug, Clone)]
+pub struct UnmatchedBracket {
+    pub pc: usize,
+}
+
+impl fmt::Display for UnmatchedBracket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unmatched bracket at instruction {}", self.pc)
+    }
+}
+
+impl error::Error for UnmatchedBracket {}
+
+// Check that every '[' in the program has a matching ']'.
+pub fn check_brackets(insts : &[Instruction]) -> Result<(), UnmatchedBracket> {
+    let mut open_pcs = Vec::new();
+
+    for (pc, inst) in insts.iter().enumerate() {
+        match inst {
+            Instruction::JumpIfZero => open_pcs.push(pc),
+            Instruction::JumpUnlessZero => {
+                if open_pcs.pop().is_none() {
+                    return Err(UnmatchedBracket { pc });
+                }
+            },
+            _ => (),
+        }
+    }
+
+    match open_pcs.pop() {
+        Some(pc) => Err(UnmatchedBracket { pc }),
+        None => Ok(()),
+    }
+}
+
 pub fn get_tests() -> (Vec<PathBuf>, Vec<PathBuf>, PathBuf) {
         let bfcheck_path_str = std::env::var("BFCHECK_PATH").expect("must set BFCHECK_PATH");
         let bfcheck_path = Path::new(&bfcheck_path_str);
@@ -111,4 +667,188 @@ pub fn get_tests() -> (Vec<PathBuf>, Vec<PathBuf>, PathBuf) {
         return (progs, outputs, bfcheck_path.join("input.dat"))
     }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{optimize, OptimizeOptions};
+    use crate::interp::interp_program;
+
+    #[test]
+    fn test_brackets_span_files() {
+        let mut prog = lex("++[>+");
+        prog.extend(lex("<-]>."));
+        assert!(check_brackets(&prog).is_ok());
+
+        let mut prog = lex("++[>+");
+        prog.extend(lex("<-"));
+        assert_eq!(check_brackets(&prog).unwrap_err().pc, 2);
+
+        let mut prog = lex("++");
+        prog.extend(lex("]"));
+        assert_eq!(check_brackets(&prog).unwrap_err().pc, 2);
+    }
+
+    #[test]
+    fn test_lex_non_utf8() {
+        // 0xff and a lone 0x80 continuation byte are never valid UTF-8.
+        let src = [b'+', 0xff, b'[', 0xc3, b'-', b']', 0x80, b'.'];
+        assert_eq!(lex(&src), lex("+[-]."));
+    }
+
+    #[test]
+    fn test_lex_iter() {
+        let src = b"+[->+<]x\xff.";
+        assert_eq!(lex_iter(src).collect::<Vec<_>>(), lex(src));
+
+        // Instructions come out one at a time, so a prefix doesn't need the rest of the program
+        let mut insts = lex_iter(b"a+b[");
+        assert_eq!(insts.next(), Some(Instruction::Increment));
+        assert_eq!(insts.next(), Some(Instruction::JumpIfZero));
+        assert_eq!(insts.next(), None);
+    }
+
+    #[test]
+    fn test_lex_with_options() {
+        let src = "+#[-$]#!";
+        assert_eq!(lex_with_options(src, &LexOptions::default()), lex(src));
+
+        let opts = LexOptions::with_builtin_debug_commands();
+        assert_eq!(lex_with_options(src, &opts), [
+            Instruction::Increment,
+            Instruction::Debug(DebugCommand::DumpTape),
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::Debug(DebugCommand::PrintCell),
+            Instruction::JumpUnlessZero,
+            Instruction::Debug(DebugCommand::DumpTape),
+        ]);
+
+        // The core commands can't be remapped
+        let mut opts = LexOptions::default();
+        opts.debug_commands.insert(b'+', DebugCommand::PrintCell);
+        assert_eq!(lex_with_options("+", &opts), [Instruction::Increment]);
+    }
+
+    #[test]
+    fn test_wide_cells() {
+        let src = ";w32 5\n+>;w32 -4 the accumulator\n-\n";
+        assert_eq!(wide_cells(src).unwrap().into_iter().collect::<Vec<_>>(), [-4, 5]);
+
+        // Without cell_directives, the directive's sign is a command like any other
+        let mut opts = LexOptions::default();
+        assert_eq!(lex_with_options(src, &opts), lex("+>--"));
+        opts.cell_directives = true;
+        assert_eq!(lex_with_options(src, &opts), lex("+>-"));
+        assert!(check_strict(src, &opts).is_ok());
+
+        assert_eq!(wide_cells("+;w32\n").unwrap_err().offset, 1);
+        assert!(wide_cells(";w32 x").is_err());
+        assert!(wide_cells(";w325").is_err());
+        assert_eq!(wide_cells(";w32 0\n;w32 3").unwrap_err().reason, "overlaps another wide cell");
+        assert!(wide_cells(";w32 0\n;w32 4").is_ok());
+    }
+
+    #[test]
+    fn test_split_program_input() {
+        assert_eq!(split_program_input(b"+[.,]"), (&b"+[.,]"[..], None));
+        assert_eq!(split_program_input(b",.!"), (&b",."[..], Some(&b""[..])));
+        assert_eq!(split_program_input(b",.!a!b"), (&b",."[..], Some(&b"a!b"[..])));
+        assert_eq!(split_program_input(b"!"), (&b""[..], Some(&b""[..])));
+    }
+
+    #[test]
+    fn test_program_stats() {
+        let stats = program_stats(&lex("++++[>+++[>++<-]<-]>>.<<,"));
+        assert_eq!(stats.instructions, 25);
+        assert_eq!(stats.command_counts, [
+            (Instruction::MoveRight, 4),
+            (Instruction::MoveLeft, 4),
+            (Instruction::Increment, 9),
+            (Instruction::Decrement, 2),
+            (Instruction::Write, 1),
+            (Instruction::Read, 1),
+            (Instruction::JumpIfZero, 2),
+            (Instruction::JumpUnlessZero, 2),
+        ]);
+        assert_eq!(stats.loops, 2);
+        assert_eq!(stats.max_loop_depth, 2);
+        assert_eq!(stats.longest_run, Some((Instruction::Increment, 4)));
+        assert!(stats.to_string().ends_with("max loop depth: 2\nlongest run: 4 x +"));
+
+        let stats = program_stats(&[]);
+        assert_eq!((stats.instructions, stats.loops, stats.max_loop_depth, stats.longest_run), (0, 0, 0, None));
+    }
+
+    #[test]
+    fn test_emit_bf() {
+        assert_eq!(emit_bf(&lex("+[->+<]>.")), "+[->+<]>.\n");
+        assert_eq!(emit_bf(&[Instruction::Add(2), Instruction::Sub(-1), Instruction::Nop, Instruction::Zero]), "[->>+<<<->]\n");
+        assert_eq!(emit_bf(&[Instruction::AddConst(-1, 255), Instruction::SetCurrent(3)]), "<->[-]+++\n");
+        assert_eq!(emit_bf(&[Instruction::SetHeadPos(2), Instruction::SetCell(0, 1), Instruction::WriteAt(3)]), ">><<[-]+>>>.<\n");
+
+        // Positions can't be reached once a scan loses track of the head
+        assert_eq!(emit_bf(&[Instruction::Scan(-1), Instruction::SetCell(-2, 4), Instruction::Output(b'a')]), "[<] SETCELL(minus 2 4)  OUTPUT(97) \n");
+        assert_eq!(emit_bf(&[Instruction::Add(1), Instruction::Write]), " ADD(1) .\n");
+
+        // Programs with nothing left as a comment run the same as the optimized program
+        for src in ["++++++[>++++++++<-]>+.+.+.", ">>+>+<<<,[>+>+<<-]>.>.<<[>>>[-]<<<-]+[>>]<.", "+>+>+>>,[<]>[[-]>].<<<<<<.", ">+++[<++>-]<[>>+<<-]>>."] {
+            let input = b"hi";
+            let expected = interp_program(lex(src), input);
+
+            let mut prog = lex(src);
+            optimize(&mut prog, OptimizeOptions::default());
+            let bf = emit_bf(&prog);
+            assert_eq!(interp_program(lex(&bf), input), expected, "{src} as {bf}");
+        }
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("ab").unwrap(), b"ab");
+        assert_eq!(unescape(r"a\nb\0\t\r\\\x7f\xFF").unwrap(), b"a\nb\0\t\r\\\x7f\xff");
+
+        for (s, offset) in [(r"a\q", 1), (r"\x4", 0), (r"\xzz", 0), ("ab\\", 2), (r"a\x+f", 1), (r"\x-1", 0)] {
+            assert_eq!(unescape(s).unwrap_err().offset, offset, "{s}");
+        }
+    }
+
+    #[test]
+    fn test_check_strict() {
+        let mut opts = LexOptions::default();
+        assert!(check_strict("+[->+<]\n\t.", &opts).is_ok());
+
+        // A '{' typed for a '['
+        let err = check_strict("+{->+<]", &opts).unwrap_err();
+        assert_eq!((err.offset, err.byte), (1, b'{'));
+        assert_eq!(err.to_string(), "unexpected character '{' at byte 1");
+
+        assert!(check_strict("+#", &opts).is_err());
+        assert!(check_strict("+#", &LexOptions::with_builtin_debug_commands()).is_ok());
+
+        opts.comment_chars.extend(b"ab");
+        assert!(check_strict("a+b", &opts).is_ok());
+        assert_eq!(check_strict("a+c", &opts).unwrap_err().offset, 2);
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        let mut prog = lex("+[>[-]>[<]<-]");
+        optimize(&mut prog, OptimizeOptions::default());
 
+        assert_eq!(pretty_print(&prog), concat!(
+            "0\t+\n",
+            "1\t[\n",
+            "2\t  >\n",
+            "3\t  ZERO\n",
+            "4\t  NOP\n",
+            "5\t  NOP\n",
+            "6\t  >\n",
+            "7\t  SCAN(-1)\n",
+            "8\t  NOP\n",
+            "9\t  NOP\n",
+            "10\t  <\n",
+            "11\t  -\n",
+            "12\t]\n",
+        ));
+    }
+}
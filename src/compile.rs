@@ -1,15 +1,18 @@
 use inkwell::basic_block::BasicBlock;
 use inkwell::passes::PassManager;
 use inkwell::types::BasicType;
-use tempfile::{tempfile, NamedTempFile};
+use inkwell::values::{CallSiteValue, IntValue};
+use tempfile::{tempfile, NamedTempFile, TempDir};
 use core::panic;
 use std::error;
-use std::io::{Write};
-use std::fs::{File};
-use std::process::{Command, Stdio, Output};
+use std::io::{self, ErrorKind, Write};
+use std::fs::{self, File};
+use std::process::{Child, Command, ExitStatus, Stdio, Output};
 use std::fmt;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use inkwell::module::{Linkage, Module};
 use inkwell::{targets::*, AddressSpace, IntPredicate, OptimizationLevel};
@@ -24,7 +27,78 @@ const TEST_RUNNER : &str = "
 #include <fcntl.h>
 #include <io.h>
 
-extern void bf_main( unsigned char* tape );
+// With BF_EXIT_FROM_CELL defined, the value of the cell under the head when the program finishes
+// becomes the exit code.
+// With BF_SELF_CENTER defined, bf_main takes the whole tape and starts the head in its middle.
+#ifdef BF_SELF_CENTER
+#define BF_MAIN_PARAMS unsigned char* base, size_t len
+#else
+#define BF_MAIN_PARAMS unsigned char* tape
+#endif
+
+#ifdef BF_EXIT_FROM_CELL
+extern unsigned char bf_main( BF_MAIN_PARAMS );
+#else
+extern void bf_main( BF_MAIN_PARAMS );
+#endif
+
+// Vectorized scans read cells past the one they stop on, so the tape has this many extra bytes on
+// each side. Must match TAPE_PADDING.
+#define BF_TAPE_PADDING 4096
+
+#define BF_TAPE_LEN (4000000 + 2 * BF_TAPE_PADDING)
+
+// With BF_GUARD_PAGES defined, the tape sits between two inaccessible pages, so a head that runs off
+// it faults at the first byte past the padding instead of corrupting the heap. The tape ends flush
+// against the upper guard page; below it, rounding up to whole pages may leave less than a page of
+// slack before the lower one.
+#ifdef BF_GUARD_PAGES
+#include <windows.h>
+
+static unsigned char* bf_alloc_tape(void)
+{
+    SYSTEM_INFO info;
+    GetSystemInfo(&info);
+    size_t page = info.dwPageSize;
+    size_t committed = (BF_TAPE_LEN + page - 1) / page * page;
+
+    unsigned char* region = VirtualAlloc(NULL, committed + 2 * page, MEM_RESERVE, PAGE_NOACCESS);
+    if (region == NULL || VirtualAlloc(region + page, committed, MEM_COMMIT, PAGE_READWRITE) == NULL) {
+        fprintf(stderr, \"error: unable to allocate the tape\\n\");
+        exit(1);
+    }
+    return region + page + committed - BF_TAPE_LEN;
+}
+
+// The process is about to exit, which releases the region.
+static void bf_free_tape(unsigned char* tape)
+{
+}
+#else
+static unsigned char* bf_alloc_tape(void)
+{
+    return calloc(BF_TAPE_LEN, sizeof(char));
+}
+
+static void bf_free_tape(unsigned char* tape)
+{
+    free(tape);
+}
+#endif
+
+// Called by programs compiled with bounds checks when the head leaves the tape.
+void bf_head_escaped(void)
+{
+    fflush(stdout);
+    fprintf(stderr, \"error: head moved off the tape\\n\");
+    abort();
+}
+
+// Called by programs to write a run of literal bytes. Returns EOF like putchar if it can't.
+int bf_write(const unsigned char* buf, size_t len)
+{
+    return fwrite(buf, 1, len, stdout) == len ? 0 : EOF;
+}
 
 int main(int argc, char** argv)
 {
@@ -32,13 +106,92 @@ int main(int argc, char** argv)
     _setmode(0,_O_BINARY);
     _setmode(1,_O_BINARY);
 
-    unsigned char* tape = calloc(4000000, sizeof(char));
-    bf_main( tape + 2000000 );
-    free(tape);
+    unsigned char* tape = bf_alloc_tape();
+#ifdef BF_SELF_CENTER
+#define BF_MAIN_ARGS tape + BF_TAPE_PADDING, 4000000
+#else
+#define BF_MAIN_ARGS tape + BF_TAPE_PADDING + 2000000
+#endif
+#ifdef BF_EXIT_FROM_CELL
+    int status = bf_main( BF_MAIN_ARGS );
+#else
+    int status = 0;
+    bf_main( BF_MAIN_ARGS );
+#endif
+    bf_free_tape(tape);
+
+    // Output may still be buffered, so a closed stdout can go unnoticed until now.
+    if (fflush(stdout) == EOF) {
+        fprintf(stderr, \"error: unable to write output\\n\");
+        return 1;
+    }
+
+    // The tests check for this marker. Other builds leave stderr to the program.
+#ifdef BF_EXIT_MARKER
     fprintf(stderr, \"Exited successfully\\n\");
+#endif
+    return status;
 }
 ";
 
+// Runner for freestanding programs, which run on Linux without libc. It's preprocessed like the C
+// runner, so BF_EXIT_FROM_CELL and BF_SELF_CENTER work the same way.
+const FREESTANDING_RUNNER : &str = "
+#define BF_TAPE_PADDING 4096
+#define SYS_WRITE 1
+#define SYS_EXIT 60
+
+	.bss
+	.p2align 12
+bf_tape:
+	.zero 4000000 + 2 * BF_TAPE_PADDING
+
+	.section .rodata
+bf_head_escaped_msg:
+	.ascii \"error: head moved off the tape\\n\"
+bf_head_escaped_msg_end:
+
+	.text
+	.globl _start
+_start:
+#ifdef BF_SELF_CENTER
+	leaq bf_tape + BF_TAPE_PADDING(%rip), %rdi
+	movl $4000000, %esi
+#else
+	leaq bf_tape + BF_TAPE_PADDING + 2000000(%rip), %rdi
+#endif
+	callq bf_main
+#ifdef BF_EXIT_FROM_CELL
+	movzbl %al, %edi
+#else
+	xorl %edi, %edi
+#endif
+	movl $SYS_EXIT, %eax
+	syscall
+
+	.globl bf_head_escaped
+bf_head_escaped:
+	movl $2, %edi
+	leaq bf_head_escaped_msg(%rip), %rsi
+	movl $(bf_head_escaped_msg_end - bf_head_escaped_msg), %edx
+	movl $SYS_WRITE, %eax
+	syscall
+	movl $1, %edi
+	movl $SYS_EXIT, %eax
+	syscall
+";
+
+// Bytes the runner allocates on either side of the tape.
+const TAPE_PADDING : i32 = 4096;
+
+// Cells on either side of where the runner starts the head.
+const TAPE_HALF_SIZE : i32 = 2000000;
+
+// Each gather in a vectorized scan reads up to 8 dwords spanning 7 strides plus 3 bytes in the scan's
+// direction, starting from a cell on the tape. Larger strides would read past the padding when the
+// head is near either end of the tape, so they're scanned one cell at a time instead.
+const MAX_VECTOR_SCAN_STRIDE : i32 = (TAPE_PADDING - 3) / 7;
+
 const FUNC_BEGIN : &str = "
 	.text
 	.def	@feat.00;
@@ -116,7 +269,9 @@ bf_main:
 
 ";
 
+// Returns the cell under the head, which the runner uses as the exit code when asked to.
 const FUNC_END : &str = "
+	movzbl	(%r12), %eax
 	addq	$32, %rsp
 	popq	%r12
 	retq
@@ -125,14 +280,34 @@ const FUNC_END : &str = "
 	.addrsig
 ";
 
+// Flush output before reading so a prompt shows up before the program waits for input. fflush(NULL)
+// flushes every stream, which avoids needing the address of stdout.
 const READ_CHAR : &str = "
+	xorl %ecx, %ecx
+	callq fflush
 	callq getchar
     movb %al, (%r12)
 ";
 
+// Like READ_CHAR, but store eof_byte rather than 255 when getchar returns EOF.
+fn read_char_asm( addr : &str, eof_byte : u8 ) -> String {
+    let mut asm = "".to_owned();
+    asm += "\txorl %ecx, %ecx\n";
+    asm += "\tcallq fflush\n";
+    asm += "\tcallq getchar\n";
+    asm += &format!("\tmovl ${eof_byte}, %edx\n");
+    asm += "\tcmpl $-1, %eax\n";
+    asm += "\tcmovel %edx, %eax\n";
+    asm += &format!("\tmovb %al, {addr}\n");
+    return asm;
+}
+
+// putchar returns EOF once output can't be written, e.g. when stdout is a closed pipe.
 const WRITE_CHAR : &str = "
     movzbl (%r12), %ecx
 	callq putchar
+	cmpl $-1, %eax
+	je .WRITE_FAILED
 ";
 
 const INCREMENT : &str = "
@@ -155,47 +330,198 @@ const ZERO : &str = "
     movb $0, (%r12)
 ";
 
+// Freestanding bf_main takes the head in rdi, as the System V ABI passes it. The 8 bytes below the
+// saved registers are scratch space for writing Output literals.
+const FREESTANDING_FUNC_PROLOGUE : &str = "
+	.text
+	.globl	bf_main
+	.p2align	4, 0x90
+bf_main:
+	pushq	%r12
+	pushq	%r13
+	subq	$8, %rsp
+
+	movq %rdi, %r12
+	movq %rdi, %r13
+
+";
+
+const FREESTANDING_FUNC_END : &str = "
+	movzbl	(%r12), %eax
+	addq	$8, %rsp
+	popq	%r13
+	popq	%r12
+	retq
+";
+
+// With self_center, bf_main is passed the tape's base and length instead of the head, and starts
+// the head in the middle of the tape. Runs after the prologue has copied the base into r12.
+fn self_center_asm( len_reg : &str ) -> String {
+    let mut asm = "".to_owned();
+    asm += &format!("\tshrq $1, {len_reg}\n");
+    asm += &format!("\taddq {len_reg}, %r12\n");
+    asm += "\tmovq %r12, %r13\n";
+    return asm;
+}
+
+// NASM (win64 object format) equivalents of the templates above, for assembling without clang.
+const NASM_FUNC_BEGIN : &str = "
+	default rel
+
+	extern getchar
+	extern fflush
+	extern putchar
+	extern exit
+
+	section .text
+	global bf_main
+
+bf_main:
+	push r12
+	sub rsp, 32
+
+	mov r12, rcx
+	mov r13, rcx
+
+";
+
+const NASM_FUNC_END : &str = "
+	movzx eax, byte [r12]
+	add rsp, 32
+	pop r12
+	ret
+";
+
 struct LoopState {
     start_pc : usize,
     
     head_delta : i32,
-    ptr_changes : HashMap<i32, i32>
+    ptr_changes : HashMap<i32, i32>,
+
+    // Why the loop stopped being a candidate before reaching its end, if it did
+    declined : Option<LoopVerdict>,
+}
+
+// What simplify_loops did with a loop.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoopVerdict {
+    Simplified,
+    ContainsLoop,
+    ContainsIo,
+    UnsupportedInstruction(Instruction),
+
+    // Net head movement per iteration
+    NonzeroHeadDelta(i32),
+
+    // Net change to the index cell per iteration
+    IndexNotUnit(i32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopReport {
+    pub start_pc: usize,
+    pub end_pc: usize,
+    pub verdict: LoopVerdict,
 }
 
-fn simplify_loops( program : &mut Vec<Instruction>) {
+impl fmt::Display for LoopReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "loop at pc {}-{}: ", self.start_pc, self.end_pc)?;
+        match &self.verdict {
+            LoopVerdict::Simplified => write!(f, "simplified"),
+            LoopVerdict::ContainsLoop => write!(f, "not simplified, contains another loop"),
+            LoopVerdict::ContainsIo => write!(f, "not simplified, contains I/O"),
+            LoopVerdict::UnsupportedInstruction(inst) => write!(f, "not simplified, contains {}", inst),
+            LoopVerdict::NonzeroHeadDelta(delta) => write!(f, "not simplified, head moves {} cells per iteration", delta),
+            LoopVerdict::IndexNotUnit(delta) => write!(f, "not simplified, index cell changes by {} per iteration instead of 1 or -1", delta),
+        }
+    }
+}
+
+// Report what simplify_loops would do with each loop in the program, in order of the loops' ends.
+// The program itself is left alone.
+pub fn explain_loops( program : &[Instruction] ) -> Vec<LoopReport> {
+    let mut reports = Vec::new();
+    simplify_loops_explained(&mut program.to_vec(), &mut reports);
+    return reports;
+}
+
+// Report what simplify_loops would do with the loop starting at start_pc, or None if no loop starts
+// there.
+pub fn explain_loop( program : &[Instruction], start_pc : usize ) -> Option<LoopReport> {
+    return explain_loops(program).into_iter().find(|report| report.start_pc == start_pc);
+}
+
+// Report what simplify_loops does with each loop when compiling with opts. The passes before it run
+// first, as they do when compiling, so loops they remove aren't reported and pcs are those of the
+// program dump prints.
+pub fn explain_optimized_loops( program : &[Instruction], opts : OptimizeOptions ) -> Vec<LoopReport> {
+    let mut program = program.to_vec();
+    optimize(&mut program, OptimizeOptions { simplify_loops: false, vectorize_scans: false, ..opts });
+    return explain_loops(&program);
+}
+
+pub(crate) fn simplify_loops( program : &mut Vec<Instruction>) {
+    simplify_loops_explained(program, &mut Vec::new());
+}
+
+fn simplify_loops_explained( program : &mut Vec<Instruction>, reports : &mut Vec<LoopReport> ) {
     let mut in_loop = false;
     let mut curr_loop = LoopState {
         start_pc: 0,
         head_delta: 0,
-        ptr_changes: HashMap::new()
+        ptr_changes: HashMap::new(),
+        declined: None,
     };
+    let mut open_pcs : Vec<usize> = Vec::new();
 
     for pc in 0..program.len() {
-        let inst = program[pc];
+        let inst = program[pc].clone();
 
         match inst {
             Instruction::JumpIfZero => {
                 curr_loop = LoopState {
                     start_pc: pc,
                     head_delta: 0,
-                    ptr_changes: HashMap::new()
+                    ptr_changes: HashMap::new(),
+                    declined: None,
                 };
+                open_pcs.push(pc);
 
                 in_loop = true;
             },
 
             Instruction::JumpUnlessZero => {
+                let start_pc = match open_pcs.pop() {
+                    Some(start_pc) => start_pc,
+                    None => continue,
+                };
+                let mut report = LoopReport { start_pc, end_pc: pc, verdict: LoopVerdict::Simplified };
+
+                if start_pc != curr_loop.start_pc {
+                    report.verdict = LoopVerdict::ContainsLoop;
+                    reports.push(report.clone());
+                } else if let Some(verdict) = curr_loop.declined.clone() {
+                    report.verdict = verdict;
+                    reports.push(report.clone());
+                }
+
                 if in_loop {
                     in_loop = false;
 
                     if curr_loop.head_delta != 0 {
+                        report.verdict = LoopVerdict::NonzeroHeadDelta(curr_loop.head_delta);
+                        reports.push(report);
                         continue;
                     }
 
-                    if !curr_loop.ptr_changes.contains_key(&0) 
-                        || (curr_loop.ptr_changes[&0] != 1  && curr_loop.ptr_changes[&0] != -1) {
+                    let index_delta = curr_loop.ptr_changes.get(&0).copied().unwrap_or(0);
+                    if index_delta != 1 && index_delta != -1 {
+                        report.verdict = LoopVerdict::IndexNotUnit(index_delta);
+                        reports.push(report);
                         continue;
                     }
+                    reports.push(report);
 
                     let decrement_loop = curr_loop.ptr_changes[&0] == -1;
 
@@ -205,6 +531,9 @@ fn simplify_loops( program : &mut Vec<Instruction>) {
 
                     let mut write_pc = curr_loop.start_pc;
 
+                    // Emit the adds in order of offset rather than the order the loop touched the
+                    // cells, so loops with the same effect become the same instructions however
+                    // they were written.
                     let mut head_deltas : Vec<&i32> = curr_loop.ptr_changes.keys().collect();
                     head_deltas.sort();
 
@@ -238,7 +567,12 @@ fn simplify_loops( program : &mut Vec<Instruction>) {
                 }
             }
 
-            Instruction::Read | Instruction::Write => in_loop = false,
+            Instruction::Read | Instruction::Write => {
+                if in_loop {
+                    curr_loop.declined = Some(LoopVerdict::ContainsIo);
+                }
+                in_loop = false;
+            },
 
             Instruction::MoveLeft => {
                 if in_loop {
@@ -266,24 +600,332 @@ fn simplify_loops( program : &mut Vec<Instruction>) {
                 }
             }
 
+            _ => {
+                if in_loop {
+                    curr_loop.declined = Some(LoopVerdict::UnsupportedInstruction(inst));
+                }
+                in_loop = false;
+            },
+            }
+        }
+
+    simplify_clear_loops(program);
+    fuse_clear_and_set(program);
+    fold_constant_adds(program);
+}
+
+// Once inner clear loops have become Zero, a loop like "[>[-]>[-]<<-]" only clears the cells at
+// fixed offsets from the head. Lower it to a block that runs at most once, since the loop must leave
+// the cells untouched when the index cell starts at zero.
+fn simplify_clear_loops( program : &mut Vec<Instruction>) {
+    let mut in_loop = false;
+    let mut start_pc = 0;
+    let mut head_delta : i32 = 0;
+    let mut index_delta : i32 = 0;
+    let mut zeroed : Vec<i32> = Vec::new();
+
+    for pc in 0..program.len() {
+        match program[pc] {
+            Instruction::JumpIfZero => {
+                in_loop = true;
+                start_pc = pc;
+                head_delta = 0;
+                index_delta = 0;
+                zeroed.clear();
+            },
+
+            Instruction::JumpUnlessZero => {
+                if !in_loop {
+                    continue;
+                }
+                in_loop = false;
+
+                if head_delta != 0 || index_delta.abs() != 1 || zeroed.is_empty() {
+                    continue;
+                }
+
+                zeroed.sort();
+                zeroed.dedup();
+
+                let mut write_pc = start_pc + 1;
+                for i in write_pc..pc {
+                    program[i] = Instruction::Nop;
+                }
+
+                for offset in &zeroed {
+                    program[write_pc] = Instruction::ZeroAt(*offset);
+                    write_pc += 1;
+                }
+                program[write_pc] = Instruction::Zero;
+            },
+
+            Instruction::MoveLeft => head_delta -= 1,
+            Instruction::MoveRight => head_delta += 1,
+            Instruction::Nop => (),
+
+            Instruction::Zero if head_delta != 0 => zeroed.push(head_delta),
+
+            Instruction::Increment if head_delta == 0 => index_delta += 1,
+            Instruction::Decrement if head_delta == 0 => index_delta -= 1,
+
             _ => in_loop = false,
+        }
+    }
+}
+
+// Fuse a Zero followed by a run of increments/decrements on the same cell (e.g. "[-]+++") into a
+// single store of the resulting constant.
+fn fuse_clear_and_set( program : &mut Vec<Instruction>) {
+    for pc in 0..program.len() {
+        if program[pc] != Instruction::Zero {
+            continue;
+        }
+
+        let mut value : u8 = 0;
+        let mut fused = false;
+
+        for i in (pc + 1)..program.len() {
+            match program[i] {
+                Instruction::Nop => (),
+                Instruction::Increment => value = value.wrapping_add(1),
+                Instruction::Decrement => value = value.wrapping_sub(1),
+                _ => break,
+            }
+
+            if program[i] != Instruction::Nop {
+                program[i] = Instruction::Nop;
+                fused = true;
+            }
+        }
+
+        if fused {
+            program[pc] = Instruction::SetCurrent(value);
+        }
+    }
+}
+
+// Fold runs of increments/decrements into a single constant add to the current cell.
+fn fold_constant_adds( program : &mut Vec<Instruction>) {
+    let mut pc = 0;
+
+    while pc < program.len() {
+        let mut run_end = pc;
+        let mut value : u8 = 0;
+
+        while run_end < program.len() {
+            match program[run_end] {
+                Instruction::Increment => value = value.wrapping_add(1),
+                Instruction::Decrement => value = value.wrapping_sub(1),
+                _ => break,
+            }
+            run_end += 1;
+        }
+
+        if run_end - pc < 2 {
+            pc += 1;
+            continue;
+        }
+
+        for i in pc..run_end {
+            program[i] = Instruction::Nop;
+        }
+
+        if value != 0 {
+            program[pc] = Instruction::AddConst(0, value);
+        }
+
+        pc = run_end;
+    }
+}
+
+// Fully unroll loops whose trip count is known at compile time, e.g. "+++[>+<-]", as long as the
+// unrolled body is at most `limit` instructions. Only flat loops without I/O whose index cell steps
+// by one each iteration are unrolled.
+pub(crate) fn unroll_loops( program : &mut Vec<Instruction>, limit : usize ) {
+    let mut unrolled = Vec::with_capacity(program.len());
+
+    // Known cell values by offset from where tracking started. Cells missing from the map are zero
+    // while rest_zero is set, and unknown otherwise.
+    let mut known : HashMap<i32, Option<u8>> = HashMap::new();
+    let mut rest_zero = true;
+    let mut head : i32 = 0;
+
+    let mut pc = 0;
+    while pc < program.len() {
+        let inst = program[pc].clone();
+
+        if inst == Instruction::JumpIfZero {
+            let index_value = match known.get(&head) {
+                Some(value) => *value,
+                None if rest_zero => Some(0),
+                None => None,
+            };
+
+            if let (Some(value), Some((end_pc, index_step))) = (index_value, unrollable_loop(program, pc)) {
+                let trip_count = if index_step < 0 { value as usize } else { (256 - value as usize) % 256 };
+                let body = &program[(pc + 1)..end_pc];
+
+                if trip_count * body.len() <= limit {
+                    for _ in 0..trip_count {
+                        for body_inst in body {
+                            track_known_cells(body_inst.clone(), &mut known, &mut rest_zero, &mut head);
+                            unrolled.push(body_inst.clone());
+                        }
+                    }
+
+                    pc = end_pc + 1;
+                    continue;
+                }
+            }
+
+            // The body may run any number of times, so nothing is known inside it.
+            known.clear();
+            rest_zero = false;
+        } else if inst == Instruction::JumpUnlessZero {
+            // The loop only exits once the index cell is zero.
+            known.clear();
+            rest_zero = false;
+            head = 0;
+            known.insert(head, Some(0));
+        } else {
+            track_known_cells(inst.clone(), &mut known, &mut rest_zero, &mut head);
+        }
+
+        unrolled.push(inst);
+        pc += 1;
+    }
+
+    *program = unrolled;
+}
+
+// If the loop starting at start_pc is flat, does no I/O, returns the head to where it started and
+// steps the index cell by one, return the pc of its closing bracket and the index cell's step.
+fn unrollable_loop( program : &[Instruction], start_pc : usize ) -> Option<(usize, i32)> {
+    let mut head_delta : i32 = 0;
+    let mut index_step : i32 = 0;
+
+    for pc in (start_pc + 1)..program.len() {
+        match program[pc] {
+            Instruction::MoveLeft => head_delta -= 1,
+            Instruction::MoveRight => head_delta += 1,
+            Instruction::Increment if head_delta == 0 => index_step += 1,
+            Instruction::Decrement if head_delta == 0 => index_step -= 1,
+            Instruction::Increment | Instruction::Decrement | Instruction::Nop => (),
+
+            Instruction::JumpUnlessZero => {
+                if head_delta == 0 && index_step.abs() == 1 {
+                    return Some((pc, index_step));
+                }
+                return None;
+            },
+
+            _ => return None,
+        }
+    }
+
+    return None;
+}
+
+fn track_known_cells( inst : Instruction, known : &mut HashMap<i32, Option<u8>>, rest_zero : &mut bool, head : &mut i32 ) {
+    let curr = match known.get(head) {
+        Some(value) => *value,
+        None if *rest_zero => Some(0),
+        None => None,
+    };
+
+    match inst {
+        Instruction::MoveLeft => *head -= 1,
+        Instruction::MoveRight => *head += 1,
+        Instruction::Increment => { known.insert(*head, curr.map(|v| v.wrapping_add(1))); },
+        Instruction::Decrement => { known.insert(*head, curr.map(|v| v.wrapping_sub(1))); },
+        Instruction::Read => { known.insert(*head, None); },
+        Instruction::Write | Instruction::Nop => (),
+
+        // Anything else has already been optimized; stop tracking rather than model it.
+        _ => {
+            known.clear();
+            *rest_zero = false;
+        },
+    }
+}
+
+// Remove loops that can never run because the cell under the head is known to be zero when they're
+// reached, e.g. the loop in "[-][.]" or a comment loop at the start of the program.
+pub(crate) fn eliminate_dead_loops( program : &mut Vec<Instruction>) {
+    let mut open_pcs = Vec::new();
+    let mut matching_pcs = HashMap::new();
+
+    for pc in 0..program.len() {
+        match program[pc] {
+            Instruction::JumpIfZero => open_pcs.push(pc),
+            Instruction::JumpUnlessZero => {
+                if let Some(start_pc) = open_pcs.pop() {
+                    matching_pcs.insert(start_pc, pc);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    for pc in 0..program.len() {
+        if program[pc] != Instruction::JumpIfZero || !current_cell_known_zero(program, pc) {
+            continue;
+        }
+
+        if let Some(end_pc) = matching_pcs.get(&pc) {
+            for i in pc..(end_pc + 1) {
+                program[i] = Instruction::Nop;
             }
         }
+    }
+}
+
+// Whether the cell under the head is known to be zero just before pc.
+fn current_cell_known_zero( program : &[Instruction], pc : usize ) -> bool {
+    // Absolute cells set by a run of SetCells, latest value first.
+    let mut set_cells : HashMap<i32, u8> = HashMap::new();
+
+    for i in (0..pc).rev() {
+        match program[i] {
+            Instruction::Nop => (),
+
+            Instruction::SetCell(pos, val) => {
+                set_cells.entry(pos).or_insert(val);
+            },
+
+            // SetCells are only useful if we know where the head is.
+            Instruction::SetHeadPos(pos) => return set_cells.get(&pos) == Some(&0),
+            _ if !set_cells.is_empty() => return false,
+
+            // Loops and scans only finish on a zero cell.
+            Instruction::Zero | Instruction::SetCurrent(0) | Instruction::JumpUnlessZero
+                | Instruction::Scan(_) | Instruction::ClearScan(_) => return true,
+
+            _ => return false,
+        }
+    }
+
+    // The tape starts zeroed with the head at 0.
+    return set_cells.get(&0).map_or(true, |val| *val == 0);
 }
 
-fn vectorize_scans( program : &mut Vec<Instruction>) {
+pub(crate) fn vectorize_scans( program : &mut Vec<Instruction>) {
     let mut in_loop = false;
     let mut head_delta : i32  = 0;
     let mut start_pc : usize = 0;
 
+    // Whether the loop zeroes the cell under the head before moving, as in "[[-]>]".
+    let mut clears = false;
+
     for pc in 0..program.len() {
-        let inst = program[pc];
+        let inst = program[pc].clone();
 
         match inst {
             Instruction::JumpIfZero => {
                 in_loop = true;
                 head_delta = 0;
                 start_pc = pc;
+                clears = false;
             },
 
             Instruction::JumpUnlessZero => {
@@ -303,7 +945,11 @@ fn vectorize_scans( program : &mut Vec<Instruction>) {
                         program[i] = Instruction::Nop;
                     }
 
-                    program[start_pc] = Instruction::Scan(head_delta);
+                    if clears {
+                        program[start_pc] = Instruction::ClearScan(head_delta);
+                    } else {
+                        program[start_pc] = Instruction::Scan(head_delta);
+                    }
                 }
             }
 
@@ -319,1620 +965,5200 @@ fn vectorize_scans( program : &mut Vec<Instruction>) {
                 }
             },
 
+            Instruction::Zero if in_loop && head_delta == 0 && !clears => clears = true,
+
+            Instruction::Nop => (),
+
             _ => in_loop = false,
         }
     }
 }
 
-fn partial_eval( program : &mut Vec<Instruction>) {
+pub(crate) fn partial_eval( program : &mut Vec<Instruction>, past_unknown_loops : bool, output_limit : Option<usize>, exit_from_cell : bool ) {
     let mut state = State::new(program.clone());
-    let insts = state.partial_eval();
-    *program = insts.clone();
+    state.set_partial_eval_past_unknown_loops(past_unknown_loops);
+    if let Some(limit) = output_limit {
+        state.set_partial_eval_output_limit(limit);
+    }
+    state.set_partial_eval_final_cell_live(exit_from_cell);
+    *program = state.partial_eval();
+    merge_outputs(program);
 }
 
-pub fn compile_to_asm( input : &mut Vec<Instruction>, do_simplify_loops : bool, do_simplify_scans : bool, do_partial_eval : bool ) -> String {
-    if do_partial_eval {
-        partial_eval(input);
+// Clear the high bit of every literal byte the program writes. Bytes written from cells are masked
+// when they're written.
+fn mask_outputs_to_ascii( program : &mut [Instruction]) {
+    for inst in program.iter_mut() {
+        match inst {
+            Instruction::Output(x) => *x &= 0x7f,
+            Instruction::OutputStr(bytes) => bytes.iter_mut().for_each(|b| *b &= 0x7f),
+            _ => (),
+        }
     }
+}
+
+// Merge each run of Outputs into an OutputStr, so the backends can write it with one call.
+fn merge_outputs( program : &mut Vec<Instruction>) {
+    let mut merged = Vec::with_capacity(program.len());
+    let mut run : Vec<u8> = Vec::new();
+
+    for inst in program.drain(..) {
+        if let Instruction::Output(x) = inst {
+            run.push(x);
+            continue;
+        }
 
-    if do_simplify_loops {
-        simplify_loops(input);
+        push_output_run(&mut merged, &mut run);
+        merged.push(inst);
     }
+    push_output_run(&mut merged, &mut run);
 
-    if do_simplify_scans {
-        vectorize_scans(input);
+    *program = merged;
+}
+
+fn push_output_run( program : &mut Vec<Instruction>, run : &mut Vec<u8> ) {
+    match run.len() {
+        0 => (),
+        1 => program.push(Instruction::Output(run[0])),
+        _ => program.push(Instruction::OutputStr(std::mem::take(run))),
     }
+    run.clear();
+}
 
-    let mut globals : String = "".to_owned();
-    let mut instructions = "".to_owned();
+// How long each phase of compiling to an exe took, in seconds.
+#[derive(Default)]
+pub struct CompileTimes {
+    pub partial_eval: f64,
+    pub unroll_loops: f64,
+    pub simplify_loops: f64,
+    pub vectorize_scans: f64,
+    pub assemble: f64,
+    pub link: f64,
+}
 
-    let mut curr_label_num = 0;
-    let mut label_stack = vec![0;0];
+impl CompileTimes {
+    pub fn print(&self) {
+        println!("\nCompile times:");
+        println!("  partial eval:    {}", self.partial_eval);
+        println!("  unroll loops:    {}", self.unroll_loops);
+        println!("  simplify loops:  {}", self.simplify_loops);
+        println!("  vectorize scans: {}", self.vectorize_scans);
+        println!("  assemble:        {}", self.assemble);
+        println!("  link:            {}", self.link);
+    }
 
-    let mut generated_indices : HashSet<i32> = HashSet::new();
+    // Record time spent in a pass. Passes without a field of their own aren't recorded.
+    fn record(&mut self, pass : PassKind, secs : f64) {
+        match pass {
+            PassKind::PartialEval => self.partial_eval = secs,
+            PassKind::UnrollLoops => self.unroll_loops = secs,
+            PassKind::SimplifyLoops => self.simplify_loops = secs,
+            PassKind::VectorizeScans => self.vectorize_scans = secs,
+            PassKind::EliminateDeadLoops | PassKind::Other => (),
+        }
+    }
+}
 
-    for inst in input {
-        match inst {
-            Instruction::MoveRight => instructions += MOVE_RIGHT,
-            Instruction::MoveLeft => instructions += MOVE_LEFT,
-            Instruction::Increment => instructions += INCREMENT,
-            Instruction::Decrement => instructions += DECREMENT,
-            Instruction::Read => instructions += READ_CHAR,
-            Instruction::Write => instructions += WRITE_CHAR,
+// Which optimization passes to run. Mirrors the CLI's optimization flags.
+#[derive(Clone, Copy, Debug)]
+pub struct OptimizeOptions {
+    pub simplify_loops: bool,
+    pub vectorize_scans: bool,
+    pub partial_eval: bool,
+    // Let partial eval carry on past loops whose condition depends on input. See
+    // State::set_partial_eval_past_unknown_loops.
+    pub partial_eval_past_loops: bool,
+    // Most Outputs partial eval may fold writes into, or None for no limit. See
+    // State::set_partial_eval_output_limit.
+    pub partial_eval_output_limit: Option<usize>,
+    // Remove loops that can't be entered, like comment loops at the start of the program.
+    pub eliminate_dead_loops: bool,
+    // Largest number of instructions a loop may unroll into. 0 disables unrolling.
+    pub unroll_limit: usize,
+    // The program exits with the cell under the head, so partial eval must leave that cell's final
+    // value in place even if the program never reads it.
+    pub exit_from_cell: bool,
+}
 
-            Instruction::JumpIfZero => {
-                let new_label_num = curr_label_num;
-                curr_label_num += 1;
-                label_stack.push(new_label_num);
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        OptimizeOptions {
+            simplify_loops: true,
+            vectorize_scans: true,
+            partial_eval: false,
+            partial_eval_past_loops: false,
+            partial_eval_output_limit: None,
+            eliminate_dead_loops: true,
+            unroll_limit: 0,
+            exit_from_cell: false,
+        }
+    }
+}
 
-                instructions += "\n";
+// Which of the compiler's own passes an InstructionPass is. Passes defined elsewhere are Other.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PassKind {
+    PartialEval,
+    UnrollLoops,
+    EliminateDeadLoops,
+    SimplifyLoops,
+    VectorizeScans,
+    Other,
+}
 
-                // Generate a jump to the end label.
-                instructions += "\tcmpb $0, (%r12)\n";
-                instructions += &("\tje .UZ".to_owned() + &new_label_num.to_string() + "\n");
+// An optimization that rewrites a program in place. Passes may leave Nops behind.
+pub trait InstructionPass {
+    fn name(&self) -> &'static str;
+    fn kind(&self) -> PassKind { PassKind::Other }
+    // Rewrite the program, adding what the pass did to the counters in stats.
+    fn run(&mut self, program : &mut Vec<Instruction>, stats : &mut OptStats);
+}
 
-                // Generate a label so corresponding jump unless zero can jump back.
-                instructions += &(".IZ".to_owned() + &new_label_num.to_string() + ":\n");
-            },
+// Folds everything up to the first instruction that depends on input. It may run after other passes,
+// but stops at the first instruction they synthesize, so it folds the most when it runs first.
+pub struct PartialEval {
+    pub past_unknown_loops: bool,
+    pub output_limit: Option<usize>,
+    pub exit_from_cell: bool,
+}
 
-            Instruction::JumpUnlessZero => {
-                instructions += "\n";
+impl InstructionPass for PartialEval {
+    fn name(&self) -> &'static str { "partial eval" }
+    fn kind(&self) -> PassKind { PassKind::PartialEval }
+    fn run(&mut self, program : &mut Vec<Instruction>, stats : &mut OptStats) {
+        let before = count_instructions(program, |inst| *inst != Instruction::Nop);
+        partial_eval(program, self.past_unknown_loops, self.output_limit, self.exit_from_cell);
+        stats.partial_eval_eliminated += before.saturating_sub(count_instructions(program, |inst| *inst != Instruction::Nop));
+    }
+}
 
-                // Get the current brace from the stack.
-                let label_num = label_stack.pop().unwrap();
+pub struct UnrollLoops {
+    pub limit: usize,
+}
 
-                // Generate a jump to the start label.
-                instructions += "\tcmpb $0, (%r12)\n";
-                instructions += &("\tjne .IZ".to_owned() + &label_num.to_string() + "\n");
-
-                // Generate a label so corresponding jump if zero can jump back.
-                instructions += &(".UZ".to_owned() + &label_num.to_string() + ":\n");
-            },
+impl InstructionPass for UnrollLoops {
+    fn name(&self) -> &'static str { "unroll loops" }
+    fn kind(&self) -> PassKind { PassKind::UnrollLoops }
+    fn run(&mut self, program : &mut Vec<Instruction>, _stats : &mut OptStats) { unroll_loops(program, self.limit); }
+}
 
-            Instruction::Zero => instructions += ZERO,
+pub struct SimplifyLoops;
 
-            Instruction::Add(offset) => {
-                instructions += "\tmovzbl (%r12), %eax\n";
-                instructions += &format!("\taddb %al, {offset}(%r12)\n");
-            },
+impl InstructionPass for SimplifyLoops {
+    fn name(&self) -> &'static str { "simplify loops" }
+    fn kind(&self) -> PassKind { PassKind::SimplifyLoops }
+    fn run(&mut self, program : &mut Vec<Instruction>, stats : &mut OptStats) {
+        let loops_before = count_instructions(program, |inst| *inst == Instruction::JumpIfZero);
+        simplify_loops(program);
+        stats.loops_simplified += loops_before - count_instructions(program, |inst| *inst == Instruction::JumpIfZero);
+    }
+}
 
-            Instruction::Sub(offset) => {
-                instructions += "\tmovzbl (%r12), %eax\n";
-                instructions += &format!("\tsubb %al, {offset}(%r12)\n");
-            },
+// Removes comment loops even when loops aren't simplified. It runs after partial eval, which
+// evaluates such loops away itself.
+pub struct EliminateDeadLoops;
 
-            Instruction::Scan(x) => {
-                // Generate label names.
-                let label_num = curr_label_num;
-                curr_label_num += 1;
+impl InstructionPass for EliminateDeadLoops {
+    fn name(&self) -> &'static str { "eliminate dead loops" }
+    fn kind(&self) -> PassKind { PassKind::EliminateDeadLoops }
+    fn run(&mut self, program : &mut Vec<Instruction>, _stats : &mut OptStats) { eliminate_dead_loops(program); }
+}
 
-                let loop_label = ".SCAN".to_owned() + &label_num.to_string();
+pub struct VectorizeScans;
 
-                // Generate indices for scan.
+impl InstructionPass for VectorizeScans {
+    fn name(&self) -> &'static str { "vectorize scans" }
+    fn kind(&self) -> PassKind { PassKind::VectorizeScans }
+    fn run(&mut self, program : &mut Vec<Instruction>, stats : &mut OptStats) {
+        let is_scan = |inst : &Instruction| matches!(inst, Instruction::Scan(_) | Instruction::ClearScan(_));
+        let scans_before = count_instructions(program, is_scan);
+        vectorize_scans(program);
+        stats.scans_vectorized += count_instructions(program, is_scan) - scans_before;
+    }
+}
 
-                let is_neg = *x < 0;
-                let abs_scan = x.abs();
+// Passes run in the order they were added.
+#[derive(Default)]
+pub struct PassPipeline {
+    passes: Vec<Box<dyn InstructionPass>>,
+}
 
-                let head_delta_str = if !is_neg {
-                    &x.to_string()
-                } else {
-                    &("neg".to_owned() + &abs_scan.to_string())
-                };
-                let global_name = "_ymm@indices".to_owned() + &head_delta_str;
+impl PassPipeline {
+    pub fn new() -> Self {
+        PassPipeline::default()
+    }
 
-                if !generated_indices.contains(x) {
-                    globals += &("\t.globl\t".to_owned() + &global_name + "\n");
-                    globals += &("\t.section	.rdata,\"dr\",discard,".to_owned() + &global_name + "\n");
-                    globals += &("\t.p2align	5, 0x0\n");
-                    globals += &(global_name.to_owned() + ":\n");
+    // The passes selected by opts, in the order the compiler runs them.
+    pub fn from_options(opts : OptimizeOptions) -> Self {
+        let mut pipeline = PassPipeline::new();
 
-                    if !is_neg {
-                        for i in 0..8 {
-                            globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
-                        }
-                    } else {
-                        for i in (0..8).rev() {
-                            globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
-                        }
-                    }
+        if opts.partial_eval {
+            pipeline.add(Box::new(PartialEval { past_unknown_loops: opts.partial_eval_past_loops, output_limit: opts.partial_eval_output_limit, exit_from_cell: opts.exit_from_cell }));
+        }
+        if opts.unroll_limit > 0 {
+            pipeline.add(Box::new(UnrollLoops { limit: opts.unroll_limit }));
+        }
+        if opts.eliminate_dead_loops {
+            pipeline.add(Box::new(EliminateDeadLoops));
+        }
+        if opts.simplify_loops {
+            pipeline.add(Box::new(SimplifyLoops));
+        }
+        if opts.vectorize_scans {
+            pipeline.add(Box::new(VectorizeScans));
+        }
 
-                    generated_indices.insert(*x);
-                }
+        return pipeline;
+    }
 
-                let bytes_per_iter = 8 * abs_scan;
-
-                if !is_neg {
-                    instructions += "	movq	%r12, %rax\n";
-                    instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
-                    instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
-                    instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
-                    instructions += &("	movl	$".to_owned() + &bytes_per_iter.to_string() + ", %edx\n");
-                    instructions += "	.p2align	4, 0x90\n";
-                    instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
-                    instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
-                    instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
-                    instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
-                    instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
-                    instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
-                    instructions += "	vpmovmskb	%ymm3, %r8d\n";
-                    instructions += "	tzcntl	%r8d, %r9d\n";
-                    instructions += "	shrl	$2, %r9d\n";
-                    instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r9d, %r9d\n");
-                    instructions += "	addq	%rax, %r9\n";
-                    instructions += &("	addq	$".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
-                    instructions += "	movq	%r9, %r12\n";
-                    instructions += "	testl	%r8d, %r8d\n";
-                    instructions += &("	je	".to_owned() + &loop_label + "\n");
-                    instructions += "# %bb.2:\n";
-                    instructions += "	vzeroupper\n";
-                } else {
-                    let start_offset = 7 * abs_scan;
-
-                    instructions += "	movq	%r12, %rax\n";
-                    instructions += &("	addq	$-".to_owned() + &start_offset.to_string() + ", %rax\n");
-                    instructions += "	movq	%rax, %r12\n";
-                    instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
-                    instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
-                    instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
-                    instructions += "	.p2align	4, 0x90\n";
-                    instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
-                    instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
-                    instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
-                    instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
-                    instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
-                    instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
-                    instructions += "	vpmovmskb	%ymm3, %edx\n";
-                    instructions += "	tzcntl	%edx, %r8d\n";
-                    instructions += "	shrl	$2, %r8d\n";
-                    instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r8d, %r8d\n");
-                    instructions += "	movq	%rax, %r9\n";
-                    instructions += "	subq	%r8, %r9\n";
-                    instructions += &("	addq	$".to_owned() + &start_offset.to_string() + ", %r9\n");
-                    instructions += &("	addq	$-".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
-                    instructions += "	testl	%edx, %edx\n";
-                    instructions += "	cmovneq	%r9, %rax\n";
-                    instructions += "	movq	%rax, %r12\n";
-                    instructions += &("	je	".to_owned() + &loop_label + "\n");
-                    instructions += "# %bb.2:\n";
-                    instructions += "	vzeroupper\n";
-                }
-            }
+    pub fn add(&mut self, pass : Box<dyn InstructionPass>) {
+        self.passes.push(pass);
+    }
 
-            Instruction::Output(x) => {
-                instructions += &format!("    movl ${x}, %ecx\n");
-                instructions += "	callq putchar\n";
-            },
+    // Run each pass in turn and return what they did to the program.
+    pub fn run(&mut self, program : &mut Vec<Instruction>) -> OptStats {
+        return self.run_timed(program, &mut CompileTimes::default());
+    }
 
-            Instruction::SetHeadPos(x) => {
-                instructions += "   movq %r13, %r12\n";
-                instructions += &format!("   addq ${x}, %r12\n");
-            },
+    fn run_timed(&mut self, program : &mut Vec<Instruction>, times : &mut CompileTimes) -> OptStats {
+        let mut stats = OptStats::default();
 
-            Instruction::SetCell(pos, val) => {
-                instructions += &format!("   movb ${val}, {pos}(%r13)\n");
-            }
+        for pass in &mut self.passes {
+            let instructions_before = count_instructions(program, |inst| *inst != Instruction::Nop);
 
-            Instruction::Nop => (),
+            let start_time = SystemTime::now();
+            pass.run(program, &mut stats);
+            times.record(pass.kind(), start_time.elapsed().unwrap().as_secs_f64());
 
-            _ => panic!("unhandled instruction: {}", inst)
+            let instructions_after = count_instructions(program, |inst| *inst != Instruction::Nop);
+            stats.passes.push(PassStats { name: pass.name(), instructions_before, instructions_after });
         }
+
+        return stats;
     }
+}
 
-    let program = FUNC_BEGIN.to_owned() + &globals + FUNC_PROLOGUE + &instructions + FUNC_END;
-    return program;
+fn count_instructions( program : &[Instruction], pred : impl Fn(&Instruction) -> bool ) -> usize {
+    return program.iter().filter(|inst| pred(inst)).count();
+}
+
+// How many instructions, not counting Nops, the program had before and after one pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassStats {
+    pub name: &'static str,
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+}
+
+// What the optimization passes did to a program.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptStats {
+    pub passes: Vec<PassStats>,
+
+    // Loops the simplify loops pass removed, either by lowering them to straight-line code or
+    // because they could never run. Clear loops keep their brackets, so they aren't counted.
+    pub loops_simplified: usize,
+    pub scans_vectorized: usize,
+    pub partial_eval_eliminated: usize,
 }
 
-pub fn compile_to_llvm<'a>( context : &'a Context, input : &mut Vec<Instruction>, do_simplify_loops : bool ) -> Module<'a> {
-    if do_simplify_loops {
-        simplify_loops(input);
+impl fmt::Display for OptStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pass in &self.passes {
+            writeln!(f, "{}: {} -> {} instructions", pass.name, pass.instructions_before, pass.instructions_after)?;
+        }
+        writeln!(f, "loops simplified: {}", self.loops_simplified)?;
+        writeln!(f, "scans vectorized: {}", self.scans_vectorized)?;
+        write!(f, "instructions eliminated by partial eval: {}", self.partial_eval_eliminated)
     }
+}
 
-    let module = context.create_module("bf_main");
+// Interpret the program before and after optimizing it, with no input, and report the first way the
+// runs differ. Partial eval only writes out the cells the rest of the program reads, so with it
+// only the output is compared; otherwise the final head position and tape must match too. Returns
+// Ok(false) without comparing if the unoptimized program runs more than step_limit instructions.
+pub fn verify_optimization( program : &[Instruction], opts : OptimizeOptions, eof_byte : u8, step_limit : u64 ) -> Result<bool> {
+    let run = |program : Vec<Instruction>| {
+        let mut state = State::new(program);
+        state.set_eof_byte(eof_byte);
+        state.set_step_limit(step_limit);
+
+        let mut output = Vec::new();
+        let finished = state.interp_with_max_output(io::empty(), &mut output, usize::MAX).is_ok();
+        return (finished, output, state);
+    };
 
-    // Add declarations for getchar and putchar
+    let (finished, expected_output, expected) = run(program.to_vec());
+    if !finished {
+        return Ok(false);
+    }
 
-    let getchar_fn_ty = context.i32_type().fn_type(&[], false);
-    let putchar_fn_ty = context.i32_type().fn_type(&[context.i32_type().into()], false);
+    let mut optimized = program.to_vec();
+    optimize(&mut optimized, opts);
+    let (finished, output, actual) = run(optimized);
 
-    let getchar_fn = module.add_function("getchar", getchar_fn_ty, None);
-    let putchar_fn = module.add_function("putchar", putchar_fn_ty, None);
+    if !finished {
+        return Err(Box::new(OptMismatch { detail: format!("the optimized program ran more than {} instructions", step_limit) }));
+    }
+    if output != expected_output {
+        let at = output.iter().zip(&expected_output).position(|(a, b)| a != b).unwrap_or(usize::min(output.len(), expected_output.len()));
+        return Err(Box::new(OptMismatch { detail: format!("output differs from byte {}", at) }));
+    }
+    if opts.partial_eval {
+        return Ok(true);
+    }
+    if actual.relative_head_pos() != expected.relative_head_pos() {
+        return Err(Box::new(OptMismatch { detail: format!("the head ends at {} instead of {}", actual.relative_head_pos(), expected.relative_head_pos()) }));
+    }
 
-    // Add a bf_main function.
+    let (actual_cells, expected_cells) = (actual.nonzero_cells(), expected.nonzero_cells());
+    if actual_cells != expected_cells {
+        let value_at = |cells : &[(isize, u8)], pos| cells.iter().find(|(p, _)| *p == pos).map_or(0, |(_, x)| *x);
+        let pos = actual_cells.iter().chain(&expected_cells)
+            .map(|(pos, _)| *pos)
+            .filter(|pos| value_at(&actual_cells, *pos) != value_at(&expected_cells, *pos))
+            .min()
+            .unwrap();
+        return Err(Box::new(OptMismatch { detail: format!("cell {} ends as {} instead of {}", pos, value_at(&actual_cells, pos), value_at(&expected_cells, pos)) }));
+    }
 
-    let void_ptr_ty = context.ptr_type(AddressSpace::default());
-    let func_ty = context.void_type().fn_type(&[void_ptr_ty.into()], false);
+    return Ok(true);
+}
 
-    let bf_main_func = module.add_function("bf_main", func_ty, None);
+// Run the selected optimization passes over the program in place, returning what they did.
+pub fn optimize( program : &mut Vec<Instruction>, opts : OptimizeOptions ) -> OptStats {
+    return PassPipeline::from_options(opts).run(program);
+}
 
-    // Populate function.
+fn optimize_timed( program : &mut Vec<Instruction>, opts : OptimizeOptions, times : &mut CompileTimes ) -> OptStats {
+    return PassPipeline::from_options(opts).run_timed(program, times);
+}
 
-    let builder = context.create_builder();
+// Syntax of the assembly compile_to_asm generates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsmDialect {
+    // AT&T syntax for clang's integrated assembler
+    Att,
 
-    // Add basic blocks. Create a basic block whenever a branch is possible (jump if zero or jump
-    // unless zero).
+    // Intel syntax with NASM directives. Scans are always scalar.
+    Nasm,
+}
 
-    let mut basic_blocks : Vec<BasicBlock> = Vec::new();
+// How compile_to_asm generates code. The defaults are what a plain compile uses.
+#[derive(Clone, Copy, Debug)]
+pub struct AsmOptions {
+    pub dialect: AsmDialect,
 
-    let mut curr_block = context.append_basic_block(bf_main_func, "entry");
+    // Comment each instruction's asm with its pc and instruction.
+    pub annotate: bool,
 
-    // We need a new basic block for every possible branch instruction.
-    let mut curr_bb = 0;
-    for inst in &mut *input {
-        match inst {
-            Instruction::JumpIfZero | Instruction::JumpUnlessZero => {
-                basic_blocks.push(context.append_basic_block(bf_main_func, &curr_bb.to_string()));
-                curr_bb = curr_bb + 1;
-            },
+    // Use AVX2 for scans.
+    pub use_avx: bool,
 
-            _ => ()
-        }
-    }
-    builder.position_at_end(curr_block);
+    // Cells each vectorized scan iteration checks: 8, 4 or 1. See scan_asm.
+    pub scan_lanes: u32,
 
-    // Allocate a single pointer alloca to track the head position.
-    let head_pos_ty = bf_main_func.get_first_param().unwrap().get_type();
-    let head_pos = builder.build_alloca(head_pos_ty, "head_pos").unwrap();
-    builder.build_store(head_pos, bf_main_func.get_first_param().unwrap()).unwrap();
+    // Bounds check every cell access and head movement, aborting through the runner's
+    // bf_head_escaped if the head leaves the tape.
+    pub checked: bool,
 
-    // Visit BF insts
+    // Target the freestanding runner: read and write with Linux syscalls instead of libc, and keep
+    // scans scalar since the vector scans' constants live in COFF sections.
+    pub freestanding: bool,
 
-    let mut bb_jump_back_stack : Vec<BasicBlock> = Vec::new();
-    let mut bb_next_stack : Vec<BasicBlock> = Vec::new();
+    // Clear the high bit of every byte written.
+    pub ascii_out: bool,
 
-    for inst in input {
-        match inst {
-            Instruction::Read => {
-                // Call getchar
-                let read_value_i32 = builder.build_call(getchar_fn, &[], "read_value_i32").unwrap();
-                let read_value_i8 = builder.build_int_truncate(read_value_i32.try_as_basic_value().unwrap_left().into_int_value(), context.i8_type(), "read_value_i8").unwrap();
+    // What a read stores once input runs out.
+    pub eof_byte: u8,
 
-                // Store read value
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                builder.build_store(curr_head_pos.into_pointer_value(), read_value_i8).unwrap();
-            },
+    // Make bf_main take the tape's base and length and start the head at base + len/2, instead of
+    // taking the head itself.
+    pub self_center: bool,
+}
 
-            Instruction::Write => {
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
-                let curr_head_val_i32 = builder.build_int_z_extend(curr_head_val_i8.into_int_value(), context.i32_type(), "curr_head_val_i32").unwrap();
+impl Default for AsmOptions {
+    fn default() -> Self {
+        AsmOptions {
+            dialect: AsmDialect::Att,
+            annotate: false,
+            use_avx: true,
+            scan_lanes: 8,
+            checked: false,
+            freestanding: false,
+            ascii_out: false,
+            eof_byte: 255,
+            self_center: false,
+        }
+    }
+}
 
-                // Call putchar on value.
-                builder.build_call(putchar_fn, &[curr_head_val_i32.into()], "putchar_head").unwrap();
-            },
+// A label generated by compile_to_asm and the instruction it was generated for. Loops share one
+// number between their .IZ and .UZ labels; scans use .SCAN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmLabel {
+    pub label_num: usize,
+    pub pc: usize,
+    pub inst: Instruction,
+}
 
+impl fmt::Display for AsmLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{}\t{}", self.label_num, self.pc, self.inst)
+    }
+}
 
-            Instruction::Increment => {
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+// Coalesce the run of Zero, ZeroAt, Nop and head moves starting at pc into the widest stores that cover
+// the cells it clears, followed by one move of the head. Returns the pc after the run and its asm, or
+// None if the run doesn't start with a zero or merging wouldn't save any stores.
+fn zero_run_asm( program : &[Instruction], start : usize ) -> Option<(usize, String)> {
+    if !matches!(program[start], Instruction::Zero | Instruction::ZeroAt(_)) {
+        return None;
+    }
 
-                // Add 1 to value
-                let added_val_i8 = builder.build_int_add(curr_head_val_i8.into_int_value(), context.i8_type().const_int(1, false), "incremented").unwrap();
+    let mut zeroed = BTreeSet::new();
+    let mut num_zeros = 0;
+    let mut head_delta : i32 = 0;
+    let mut end = start;
 
-                // Store value.
-                builder.build_store(curr_head_pos.into_pointer_value(), added_val_i8).unwrap();
+    while end < program.len() {
+        match program[end] {
+            Instruction::Zero => zeroed.insert(head_delta),
+            Instruction::ZeroAt(offset) => zeroed.insert(head_delta + offset),
+            Instruction::MoveRight => {
+                head_delta += 1;
+                end += 1;
+                continue;
+            },
+            Instruction::MoveLeft => {
+                head_delta -= 1;
+                end += 1;
+                continue;
+            },
+            Instruction::Nop => {
+                end += 1;
+                continue;
             },
+            _ => break,
+        };
+        num_zeros += 1;
+        end += 1;
+    }
 
-            Instruction::Add(x) => {
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+    // Split the cleared cells into contiguous runs, then each run into 16, 8, 4, 2 and 1 byte stores.
+    let mut stores : Vec<(i32, usize)> = Vec::new();
+    let mut offsets = zeroed.into_iter().peekable();
+    while let Some(first) = offsets.next() {
+        let mut len = 1;
+        while offsets.next_if_eq(&(first + len)).is_some() {
+            len += 1;
+        }
 
-                let x_i64 = i64::from(*x);
-                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+        let mut offset = first;
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let size = [16, 8, 4, 2, 1].into_iter().find(|size| *size <= remaining).unwrap();
+            stores.push((offset, size));
+            offset += size as i32;
+            remaining -= size;
+        }
+    }
 
-                // Read value at offset.
-                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
-                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
-                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
-                let offset_head_val_i8 = builder.build_load(context.i8_type(), offset_head_pos.try_into().unwrap(), "offset_head_val_i8").unwrap();
+    if stores.len() >= num_zeros {
+        return None;
+    }
 
-                // Add values
-                let sum = builder.build_int_add(curr_head_val_i8.into_int_value(), offset_head_val_i8.into_int_value(), "sum").unwrap();
+    let mut asm = "".to_owned();
+    if stores.iter().any(|(_, size)| *size == 16) {
+        asm += "\txorps %xmm0, %xmm0\n";
+    }
+    for (offset, size) in stores {
+        asm += &match size {
+            16 => format!("\tmovups %xmm0, {offset}(%r12)\n"),
+            8 => format!("\tmovq $0, {offset}(%r12)\n"),
+            4 => format!("\tmovl $0, {offset}(%r12)\n"),
+            2 => format!("\tmovw $0, {offset}(%r12)\n"),
+            _ => format!("\tmovb $0, {offset}(%r12)\n"),
+        };
+    }
+    if head_delta != 0 {
+        asm += &format!("\taddq ${head_delta}, %r12\n");
+    }
 
-                // Store value.
-                builder.build_store(offset_head_pos, sum).unwrap();
-            }
+    return Some((end, asm));
+}
 
-            Instruction::Sub(x) => {
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+// Emit a scan moving the head by x cells at a time until it reaches a zero cell. With AVX2, each
+// iteration gathers lanes cells, which must be 4 (xmm) or 8 (ymm); 1 scans a cell at a time. Without
+// AVX2, 4 lanes checks 4 cells per iteration with SSE2 and 8 falls back to 1. Strides above
+// MAX_VECTOR_SCAN_STRIDE always go a cell at a time. Index tables used by the AVX2 version are added
+// to globals the first time each stride is seen.
+fn scan_asm( x : i32, label_num : usize, use_avx : bool, lanes : u32, globals : &mut String, generated_indices : &mut HashSet<i32> ) -> String {
+    let mut asm = "".to_owned();
+
+    let loop_label = ".SCAN".to_owned() + &label_num.to_string();
+
+    if (!use_avx && lanes != 4) || lanes == 1 || x.abs() > MAX_VECTOR_SCAN_STRIDE {
+        // Step the head one stride at a time until it lands on a zero.
+        let end_label = loop_label.to_owned() + "_END";
+
+        asm += "\tcmpb $0, (%r12)\n";
+        asm += &format!("\tje {end_label}\n");
+        asm += &format!("{loop_label}:\n");
+        asm += &format!("\taddq ${x}, %r12\n");
+        asm += "\tcmpb $0, (%r12)\n";
+        asm += &format!("\tjne {loop_label}\n");
+        asm += &format!("{end_label}:\n");
+        return asm;
+    }
 
-                let x_i64 = i64::from(*x);
-                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+    if !use_avx {
+        return sse_scan_asm(x, &loop_label);
+    }
 
-                // Read value at offset.
-                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
-                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
-                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
-                let offset_head_val_i8 = builder.build_load(context.i8_type(), offset_head_pos.try_into().unwrap(), "offset_head_val_i8").unwrap();
+    // Generate indices for scan. Each table lives in its own discard (select any)
+    // COMDAT section and its contents only depend on the stride, so when several
+    // programs are linked together the linker folds same-named tables into one instead
+    // of reporting a duplicate symbol.
 
-                // Sub values
-                let sum = builder.build_int_sub(offset_head_val_i8.into_int_value(), curr_head_val_i8.into_int_value(), "sum").unwrap();
+    let is_neg = x < 0;
+    let abs_scan = x.abs();
 
-                // Store value.
-                builder.build_store(offset_head_pos, sum).unwrap();
+    let head_delta_str = if !is_neg {
+        &x.to_string()
+    } else {
+        &("neg".to_owned() + &abs_scan.to_string())
+    };
+    let lanes = lanes as i32;
+    let vreg = if lanes == 8 { "ymm" } else { "xmm" };
+    let global_name = format!("_{vreg}@indices") + &head_delta_str;
+
+    if !generated_indices.contains(&x) {
+        *globals += &("\t.globl\t".to_owned() + &global_name + "\n");
+        *globals += &("\t.section	.rdata,\"dr\",discard,".to_owned() + &global_name + "\n");
+        *globals += &format!("\t.p2align	{}, 0x0\n", if lanes == 8 { 5 } else { 4 });
+        *globals += &(global_name.to_owned() + ":\n");
+
+        if !is_neg {
+            for i in 0..lanes {
+                *globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
             }
+        } else {
+            for i in (0..lanes).rev() {
+                *globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
+            }
+        }
 
-            Instruction::Zero => {
-                // Get head ptr
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+        generated_indices.insert(x);
+    }
 
-                // Store 0 to head.
-                builder.build_store(curr_head_pos.into_pointer_value(), context.i8_type().const_zero()).unwrap();
-            }
+    let bytes_per_iter = lanes * abs_scan;
+
+    if !is_neg {
+        asm += "	movq	%r12, %rax\n";
+        asm += &format!("	vmovdqa	{global_name}(%rip), %{vreg}0\n");
+        asm += "	vpxor	%xmm1, %xmm1, %xmm1\n";
+        asm += &format!("	vpbroadcastd	__real@ffffff00(%rip), %{vreg}2\n");
+        asm += &("	movl	$".to_owned() + &bytes_per_iter.to_string() + ", %edx\n");
+        asm += "	.p2align	4, 0x90\n";
+        asm += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
+        asm += &format!("	vpcmpeqd	%{vreg}3, %{vreg}3, %{vreg}3\n");
+        asm += "	vpxor	%xmm4, %xmm4, %xmm4\n";
+        asm += &format!("	vpgatherdd	%{vreg}3, (%rax,%{vreg}0), %{vreg}4\n");
+        asm += &format!("	vpor	%{vreg}2, %{vreg}4, %{vreg}3\n");
+        asm += &format!("	vpcmpeqb	%{vreg}1, %{vreg}3, %{vreg}3\n");
+        asm += &format!("	vpmovmskb	%{vreg}3, %r8d\n");
+        asm += "	tzcntl	%r8d, %r9d\n";
+        asm += "	shrl	$2, %r9d\n";
+        asm += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r9d, %r9d\n");
+        asm += "	addq	%rax, %r9\n";
+        asm += &("	addq	$".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
+        asm += "	movq	%r9, %r12\n";
+        asm += "	testl	%r8d, %r8d\n";
+        asm += &("	je	".to_owned() + &loop_label + "\n");
+        asm += "# %bb.2:\n";
+        asm += "	vzeroupper\n";
+    } else {
+        let start_offset = (lanes - 1) * abs_scan;
+
+        asm += "	movq	%r12, %rax\n";
+        asm += &("	addq	$-".to_owned() + &start_offset.to_string() + ", %rax\n");
+        asm += "	movq	%rax, %r12\n";
+        asm += &format!("	vmovdqa	{global_name}(%rip), %{vreg}0\n");
+        asm += "	vpxor	%xmm1, %xmm1, %xmm1\n";
+        asm += &format!("	vpbroadcastd	__real@ffffff00(%rip), %{vreg}2\n");
+        asm += "	.p2align	4, 0x90\n";
+        asm += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
+        asm += &format!("	vpcmpeqd	%{vreg}3, %{vreg}3, %{vreg}3\n");
+        asm += "	vpxor	%xmm4, %xmm4, %xmm4\n";
+        asm += &format!("	vpgatherdd	%{vreg}3, (%rax,%{vreg}0), %{vreg}4\n");
+        asm += &format!("	vpor	%{vreg}2, %{vreg}4, %{vreg}3\n");
+        asm += &format!("	vpcmpeqb	%{vreg}1, %{vreg}3, %{vreg}3\n");
+        asm += &format!("	vpmovmskb	%{vreg}3, %edx\n");
+        asm += "	tzcntl	%edx, %r8d\n";
+        asm += "	shrl	$2, %r8d\n";
+        asm += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r8d, %r8d\n");
+        asm += "	movq	%rax, %r9\n";
+        asm += "	subq	%r8, %r9\n";
+        asm += &("	addq	$".to_owned() + &start_offset.to_string() + ", %r9\n");
+        asm += &("	addq	$-".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
+        asm += "	testl	%edx, %edx\n";
+        asm += "	cmovneq	%r9, %rax\n";
+        asm += "	movq	%rax, %r12\n";
+        asm += &("	je	".to_owned() + &loop_label + "\n");
+        asm += "# %bb.2:\n";
+        asm += "	vzeroupper\n";
+    }
 
-            Instruction::Decrement => {
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+    return asm;
+}
 
-                // Sub 1 from value
-                let subbed_val_i8 = builder.build_int_sub(curr_head_val_i8.into_int_value(), context.i8_type().const_int(1, false), "decremented").unwrap();
+// Scan 4 cells per iteration without AVX2. The cells are loaded a byte at a time and packed into one
+// dword, which pcmpeqb compares against zero all at once.
+fn sse_scan_asm( x : i32, loop_label : &str ) -> String {
+    let mut asm = "".to_owned();
+
+    asm += "\tmovq %r12, %rax\n";
+    asm += "\tpxor %xmm1, %xmm1\n";
+    asm += "\t.p2align 4, 0x90\n";
+    asm += &format!("{loop_label}:\n");
+    asm += "\tmovzbl (%rax), %ecx\n";
+    for lane in 1..4 {
+        asm += &format!("\tmovzbl {}(%rax), %edx\n", lane * x);
+        asm += &format!("\tshll ${}, %edx\n", lane * 8);
+        asm += "\torl %edx, %ecx\n";
+    }
+    asm += "\tmovd %ecx, %xmm3\n";
+    asm += "\tpcmpeqb %xmm1, %xmm3\n";
+    asm += "\tpmovmskb %xmm3, %r8d\n";
+    // Only the low 4 bytes were loaded; the rest of xmm3 is zero and always matches.
+    asm += "\tandl $15, %r8d\n";
+    asm += &format!("\taddq ${}, %rax\n", 4 * x);
+    asm += "\ttestl %r8d, %r8d\n";
+    asm += &format!("\tje {loop_label}\n");
+
+    // The head lands on the first zero lane of the last iteration.
+    asm += "\tbsfl %r8d, %r9d\n";
+    asm += &format!("\timulq ${x}, %r9, %r9\n");
+    asm += &format!("\tleaq {}(%rax,%r9), %r12\n", -4 * x);
+    return asm;
+}
 
-                // Store value.
-                builder.build_store(curr_head_pos.into_pointer_value(), subbed_val_i8).unwrap();
-            },
+// Emit a clear scan: zero each cell from the head, stepping x cells at a time, until reaching a zero
+// cell. The scan finds the end first so unit strides can be cleared 32 bytes at a time like a memset.
+fn clear_scan_asm( x : i32, label_num : usize, use_avx : bool, lanes : u32, globals : &mut String, generated_indices : &mut HashSet<i32> ) -> String {
+    let mut asm = "".to_owned();
+
+    let loop_label = ".CLEAR".to_owned() + &label_num.to_string();
+    let end_label = loop_label.to_owned() + "_END";
+
+    if !use_avx && lanes != 4 {
+        asm += &format!("{loop_label}:\n");
+        asm += "\tcmpb $0, (%r12)\n";
+        asm += &format!("\tje {end_label}\n");
+        asm += "\tmovb $0, (%r12)\n";
+        asm += &format!("\taddq ${x}, %r12\n");
+        asm += &format!("\tjmp {loop_label}\n");
+        asm += &format!("{end_label}:\n");
+        return asm;
+    }
 
-            Instruction::MoveRight => {
-                // Read curr head ptr
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+    // Remember where the clear starts, then find where it ends.
+    asm += "\tmovq %r12, %r10\n";
+    asm += &scan_asm(x, label_num, use_avx, lanes, globals, generated_indices);
+
+    if x.abs() != 1 || !use_avx {
+        // Strided cells aren't contiguous, so clear them one at a time. Without AVX there's no 32
+        // byte store, so unit strides are cleared this way too.
+        asm += "\tcmpq %r12, %r10\n";
+        asm += &format!("\tje {end_label}\n");
+        asm += &format!("{loop_label}:\n");
+        asm += "\tmovb $0, (%r10)\n";
+        asm += &format!("\taddq ${x}, %r10\n");
+        asm += "\tcmpq %r12, %r10\n";
+        asm += &format!("\tjne {loop_label}\n");
+        asm += &format!("{end_label}:\n");
+        return asm;
+    }
 
-                // Add 1 to ptr
-                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
-                let new_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(1, false), "new_head_pos_int").unwrap();
-                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+    // Put the first cell to clear in r10 and the number of cells in rcx.
+    if x > 0 {
+        asm += "\tmovq %r12, %rcx\n";
+        asm += "\tsubq %r10, %rcx\n";
+    } else {
+        asm += "\tmovq %r10, %rcx\n";
+        asm += "\tsubq %r12, %rcx\n";
+        asm += "\tleaq 1(%r12), %r10\n";
+    }
 
-                // Store result
-                builder.build_store(head_pos, new_head_pos).unwrap();
-            },
+    let tail_label = loop_label.to_owned() + "_TAIL";
+    let byte_label = loop_label.to_owned() + "_BYTE";
+
+    asm += "\tvpxor %xmm0, %xmm0, %xmm0\n";
+    asm += &format!("{loop_label}:\n");
+    asm += "\tcmpq $32, %rcx\n";
+    asm += &format!("\tjb {tail_label}\n");
+    asm += "\tvmovdqu %ymm0, (%r10)\n";
+    asm += "\taddq $32, %r10\n";
+    asm += "\tsubq $32, %rcx\n";
+    asm += &format!("\tjmp {loop_label}\n");
+    asm += &format!("{tail_label}:\n");
+    asm += "\ttestq %rcx, %rcx\n";
+    asm += &format!("\tje {end_label}\n");
+    asm += &format!("{byte_label}:\n");
+    asm += "\tmovb $0, (%r10)\n";
+    asm += "\taddq $1, %r10\n";
+    asm += "\tsubq $1, %rcx\n";
+    asm += &format!("\tjne {byte_label}\n");
+    asm += &format!("{end_label}:\n");
+    asm += "\tvzeroupper\n";
+
+    return asm;
+}
 
-            Instruction::MoveLeft => {
-                // Read curr head ptr
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+// Jump to .HEAD_ESCAPED unless base + offset is on the tape. Clobbers rax.
+fn bounds_check_asm( base : &str, offset : i32 ) -> String {
+    let mut asm = "".to_owned();
+    asm += &format!("\tleaq {offset}({base}), %rax\n");
+    asm += "\tsubq %r13, %rax\n";
+    asm += &format!("\taddq ${TAPE_HALF_SIZE}, %rax\n");
+    asm += &format!("\tcmpq ${}, %rax\n", 2 * TAPE_HALF_SIZE);
+    asm += "\tjae .HEAD_ESCAPED\n";
+    return asm;
+}
 
-                // Sub 1 from ptr
-                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
-                let new_head_pos_int = builder.build_int_sub(curr_head_pos_int, context.i64_type().const_int(1, false), "new_head_pos_int").unwrap();
-                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+// Read a byte from stdin into addr with a read syscall, storing eof_byte at EOF.
+fn syscall_read_asm( addr : &str, eof_byte : u8 ) -> String {
+    let mut asm = "".to_owned();
+    asm += &format!("\tleaq {addr}, %rsi\n");
+    asm += "\txorl %edi, %edi\n";
+    asm += "\tmovl $1, %edx\n";
+    asm += "\txorl %eax, %eax\n";
+    asm += "\tsyscall\n";
+    asm += "\tcmpq $1, %rax\n";
+    asm += "\tje 1f\n";
+    asm += &format!("\tmovb ${eof_byte}, (%rsi)\n");
+    asm += "1:\n";
+    return asm;
+}
 
-                // Store result
-                builder.build_store(head_pos, new_head_pos).unwrap();
-            },
+// Write len bytes from addr to stdout with a write syscall. Output isn't buffered, so every write
+// costs a syscall.
+fn syscall_write_asm( addr : &str, len : usize ) -> String {
+    let mut asm = "".to_owned();
+    asm += &format!("\tleaq {addr}, %rsi\n");
+    asm += "\tmovl $1, %edi\n";
+    asm += &format!("\tmovl ${len}, %edx\n");
+    asm += "\tmovl $1, %eax\n";
+    asm += "\tsyscall\n";
+    asm += &format!("\tcmpq ${len}, %rax\n");
+    asm += "\tjne .WRITE_FAILED\n";
+    return asm;
+}
 
-            Instruction::JumpIfZero => {
-                // Get basic blocks that we'll jump to.
-                let if_zero = basic_blocks.pop().unwrap();
-                let not_zero = basic_blocks.pop().unwrap();
+// Write the cell at addr with its high bit cleared, for programs compiled with ascii_out. Freestanding
+// programs copy it to the scratch byte at (%rsp) to write it from there.
+fn ascii_write_asm( addr : &str, freestanding : bool ) -> String {
+    let mut asm = format!("\tmovzbl {addr}, %ecx\n");
+    asm += "\tandl $0x7f, %ecx\n";
+    if freestanding {
+        asm += "\tmovb %cl, (%rsp)\n";
+        asm += &syscall_write_asm("(%rsp)", 1);
+    } else {
+        asm += "\tcallq putchar\n";
+        asm += "\tcmpl $-1, %eax\n";
+        asm += "\tje .WRITE_FAILED\n";
+    }
+    return asm;
+}
 
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+// A labelled run of bytes for OutputStr to write.
+fn string_data_asm( label : &str, bytes : &[u8] ) -> String {
+    let mut asm = format!("{label}:\n");
+    for chunk in bytes.chunks(16) {
+        let vals : Vec<String> = chunk.iter().map(|b| b.to_string()).collect();
+        asm += &format!("\t.byte {}\n", vals.join(", "));
+    }
+    return asm;
+}
 
-                // Compare val to 0.
-                let is_zero = builder.build_int_compare(IntPredicate::EQ, curr_head_val_i8.into_int_value(), context.i8_type().const_zero().into(), "is_zero").unwrap();
+// A scalar scan or clear scan that checks the head after every step, so it can't run off the tape
+// looking for a zero.
+fn checked_scan_asm( x : i32, label_num : usize, clears : bool ) -> String {
+    let mut asm = "".to_owned();
 
-                // Create a branch instruction.
-                builder.build_conditional_branch(is_zero, if_zero, not_zero).unwrap();
+    let loop_label = if clears { ".CLEAR" } else { ".SCAN" }.to_owned() + &label_num.to_string();
+    let end_label = loop_label.to_owned() + "_END";
 
-                // Push the if and not zero BBs to the jump back stack. We'll target them when we
-                // hit the corresponding jump unless zero.
-                bb_jump_back_stack.push(not_zero);
-                bb_next_stack.push(if_zero);
+    asm += &format!("{loop_label}:\n");
+    asm += "\tcmpb $0, (%r12)\n";
+    asm += &format!("\tje {end_label}\n");
+    if clears {
+        asm += "\tmovb $0, (%r12)\n";
+    }
+    asm += &format!("\taddq ${x}, %r12\n");
+    asm += &bounds_check_asm("%r12", 0);
+    asm += &format!("\tjmp {loop_label}\n");
+    asm += &format!("{end_label}:\n");
+    return asm;
+}
 
-                // Set curr block to the loop body.
-                builder.position_at_end(not_zero);
-            },
+// Optimize the program and lower it to assembly, returning the assembly and what the optimization
+// passes did.
+pub fn compile_to_asm( input : &mut Vec<Instruction>, opts : OptimizeOptions, asm_opts : AsmOptions, hot_loops : &HashSet<usize>, labels : &mut Vec<AsmLabel>, times : &mut CompileTimes ) -> Result<(String, OptStats)> {
+    let stats = optimize_timed(input, opts, times);
+    if asm_opts.ascii_out {
+        mask_outputs_to_ascii(input);
+    }
 
-            Instruction::JumpUnlessZero => {
-                // Get basic blocks that we'll jump to.
-                let not_zero_bb = bb_jump_back_stack.pop().unwrap();
-                let if_zero_bb = bb_next_stack.pop().unwrap();
+    let asm = match asm_opts.dialect {
+        AsmDialect::Att => att_program(input, asm_opts, hot_loops, labels)?,
+        AsmDialect::Nasm => nasm_program(input, asm_opts.annotate, asm_opts.ascii_out, asm_opts.eof_byte, asm_opts.self_center, hot_loops, labels)?,
+    };
+    return Ok((asm, stats));
+}
 
-                // Read value at head.
-                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
-                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+fn att_program( input : &[Instruction], asm_opts : AsmOptions, hot_loops : &HashSet<usize>, labels : &mut Vec<AsmLabel> ) -> Result<String> {
+    let AsmOptions { dialect: _, annotate, use_avx, scan_lanes, checked, freestanding, ascii_out, eof_byte, self_center } = asm_opts;
 
-                // Compare val to 0.
-                let not_zero = builder.build_int_compare(IntPredicate::NE, curr_head_val_i8.into_int_value(), context.i8_type().const_zero().into(), "not_zero").unwrap();
+    let use_avx = use_avx && !freestanding;
 
-                // Create a branch instruction.
-                builder.build_conditional_branch(not_zero, not_zero_bb, if_zero_bb).unwrap();
+    let mut globals : String = "".to_owned();
+    let mut instructions = "".to_owned();
 
-                // Set curr block to the next block.
-                builder.position_at_end(if_zero_bb);
-            }
+    // Bytes written by OutputStr, kept in a read only section.
+    let mut strings = "".to_owned();
+    let mut string_count = 0;
 
-            Instruction::Nop => (),
+    let mut curr_label_num = 0;
+    // Label number and pc of each open loop.
+    let mut label_stack : Vec<(usize, usize)> = Vec::new();
 
-            _ => panic!("unhandled instruction: {}", inst)
-        }
-    }
+    let mut generated_indices : HashSet<i32> = HashSet::new();
 
-    builder.build_return(None).unwrap();
+    // Instructions before this pc were already compiled as part of a zero run.
+    let mut skip_until = 0;
 
-    // module.write_bitcode_to_path(Path::new("bf_program_bleh.bc"));
+    for (pc, inst) in input.iter().enumerate() {
+        if pc < skip_until {
+            continue;
+        }
 
-    module.verify().unwrap();
+        // Mark where each instruction's assembly begins so the output can be mapped back to the
+        // program.
+        if annotate && *inst != Instruction::Nop {
+            instructions += &format!("# pc={pc} op={inst}\n");
+        }
 
-    return module;
+        // Checked mode bounds checks every store and move, so it keeps them separate.
+        if !checked {
+            if let Some((end, asm)) = zero_run_asm(input, pc) {
+                instructions += &asm;
+                skip_until = end;
+                continue;
+            }
+        }
 
-    // let mut curr_label_num = 0;
-    // let mut label_stack = vec![0;0];
+        if checked {
+            match inst {
+                Instruction::ZeroAt(offset) | Instruction::Add(offset) | Instruction::Sub(offset) | Instruction::AddConst(offset, _) => {
+                    instructions += &bounds_check_asm("%r12", *offset);
+                },
+                Instruction::SetCell(pos, _) | Instruction::ReadAt(pos) | Instruction::WriteAt(pos) => {
+                    instructions += &bounds_check_asm("%r13", *pos);
+                },
+                _ => (),
+            }
+        }
 
-    // let mut generated_indices : HashSet<i32> = HashSet::new();
+        match inst {
+            Instruction::MoveRight => instructions += MOVE_RIGHT,
+            Instruction::MoveLeft => instructions += MOVE_LEFT,
+            Instruction::Increment => instructions += INCREMENT,
+            Instruction::Decrement => instructions += DECREMENT,
+            Instruction::Write if ascii_out => instructions += &ascii_write_asm("(%r12)", freestanding),
+            Instruction::Read if freestanding => instructions += &syscall_read_asm("(%r12)", eof_byte),
+            Instruction::Read if eof_byte != 255 => instructions += &read_char_asm("(%r12)", eof_byte),
+            Instruction::Write if freestanding => instructions += &syscall_write_asm("(%r12)", 1),
+            Instruction::Read => instructions += READ_CHAR,
+            Instruction::Write => instructions += WRITE_CHAR,
 
-    // for inst in input {
-    //     match inst {
-    //         Instruction::MoveRight => instructions += MOVE_RIGHT,
-    //         Instruction::MoveLeft => instructions += MOVE_LEFT,
-    //         Instruction::Increment => instructions += INCREMENT,
-    //         Instruction::Decrement => instructions += DECREMENT,
-    //         Instruction::Read => instructions += READ_CHAR,
-    //         Instruction::Write => instructions += WRITE_CHAR,
+            Instruction::JumpIfZero => {
+                let new_label_num = curr_label_num;
+                curr_label_num += 1;
+                label_stack.push((new_label_num, pc));
+                labels.push(AsmLabel { label_num: new_label_num, pc, inst: inst.clone() });
 
-    //         Instruction::JumpIfZero => {
-    //             let new_label_num = curr_label_num;
-    //             curr_label_num += 1;
-    //             label_stack.push(new_label_num);
+                instructions += "\n";
 
-    //             instructions += "\n";
+                // Generate a jump to the end label.
+                instructions += "\tcmpb $0, (%r12)\n";
+                instructions += &("\tje .UZ".to_owned() + &new_label_num.to_string() + "\n");
 
-    //             // Generate a jump to the end label.
-    //             instructions += "\tcmpb $0, (%r12)\n";
-    //             instructions += &("\tje .UZ".to_owned() + &new_label_num.to_string() + "\n");
+                // Start hot loop bodies on a fetch boundary.
+                if hot_loops.contains(&pc) {
+                    instructions += "\t.p2align 4, 0x90\n";
+                }
 
-    //             // Generate a label so corresponding jump unless zero can jump back.
-    //             instructions += &(".IZ".to_owned() + &new_label_num.to_string() + ":\n");
-    //         },
+                // Generate a label so corresponding jump unless zero can jump back.
+                instructions += &(".IZ".to_owned() + &new_label_num.to_string() + ":\n");
+            },
 
-    //         Instruction::JumpUnlessZero => {
-    //             instructions += "\n";
+            Instruction::JumpUnlessZero => {
+                instructions += "\n";
 
-    //             // Get the current brace from the stack.
-    //             let label_num = label_stack.pop().unwrap();
+                // Get the current brace from the stack.
+                let label_num = match label_stack.pop() {
+                    Some((label_num, _)) => label_num,
+                    None => return Err(Box::new(UnmatchedLoopLabel { pc })),
+                };
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
 
-    //             // Generate a jump to the start label.
-    //             instructions += "\tcmpb $0, (%r12)\n";
-    //             instructions += &("\tjne .IZ".to_owned() + &label_num.to_string() + "\n");
+                // Generate a jump to the start label.
+                instructions += "\tcmpb $0, (%r12)\n";
+                instructions += &("\tjne .IZ".to_owned() + &label_num.to_string() + "\n");
 
-    //             // Generate a label so corresponding jump if zero can jump back.
-    //             instructions += &(".UZ".to_owned() + &label_num.to_string() + ":\n");
-    //         },
+                // Generate a label so corresponding jump if zero can jump back.
+                instructions += &(".UZ".to_owned() + &label_num.to_string() + ":\n");
+            },
 
-    //         Instruction::Zero => instructions += ZERO,
+            Instruction::Zero => instructions += ZERO,
 
-    //         Instruction::Add(offset) => {
-    //             instructions += "\tmovzbl (%r12), %eax\n";
-    //             instructions += &format!("\taddb %al, {offset}(%r12)\n");
-    //         },
+            Instruction::ZeroAt(offset) => instructions += &format!("\tmovb $0, {offset}(%r12)\n"),
 
-    //         Instruction::Sub(offset) => {
-    //             instructions += "\tmovzbl (%r12), %eax\n";
-    //             instructions += &format!("\tsubb %al, {offset}(%r12)\n");
-    //         },
+            Instruction::SetCurrent(val) => instructions += &format!("\tmovb ${val}, (%r12)\n"),
 
-    //         Instruction::Scan(x) => {
-    //             // Generate label names.
-    //             let label_num = curr_label_num;
-    //             curr_label_num += 1;
+            Instruction::Add(offset) => {
+                instructions += "\tmovzbl (%r12), %eax\n";
+                instructions += &format!("\taddb %al, {offset}(%r12)\n");
+            },
 
-    //             let loop_label = ".SCAN".to_owned() + &label_num.to_string();
+            Instruction::Sub(offset) => {
+                instructions += "\tmovzbl (%r12), %eax\n";
+                instructions += &format!("\tsubb %al, {offset}(%r12)\n");
+            },
 
-    //             // Generate indices for scan.
+            Instruction::AddConst(offset, val) => {
+                instructions += &format!("\taddb ${val}, {offset}(%r12)\n");
+            },
 
-    //             let is_neg = *x < 0;
-    //             let abs_scan = x.abs();
+            Instruction::Scan(0) | Instruction::ClearScan(0) => {
+                return Err(Box::new(ZeroScanStride { pc }));
+            }
 
-    //             let head_delta_str = if !is_neg {
-    //                 &x.to_string()
-    //             } else {
-    //                 &("neg".to_owned() + &abs_scan.to_string())
-    //             };
-    //             let global_name = "_ymm@indices".to_owned() + &head_delta_str;
+            Instruction::Scan(x) => {
+                let label_num = curr_label_num;
+                curr_label_num += 1;
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
 
-    //             if !generated_indices.contains(x) {
-    //                 globals += &("\t.globl\t".to_owned() + &global_name + "\n");
-    //                 globals += &("\t.section	.rdata,\"dr\",discard,".to_owned() + &global_name + "\n");
-    //                 globals += &("\t.p2align	5, 0x0\n");
-    //                 globals += &(global_name.to_owned() + ":\n");
+                if checked {
+                    instructions += &checked_scan_asm(*x, label_num, false);
+                } else {
+                    instructions += &scan_asm(*x, label_num, use_avx, scan_lanes, &mut globals, &mut generated_indices);
+                }
+            }
 
-    //                 if !is_neg {
-    //                     for i in 0..8 {
-    //                         globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
-    //                     }
-    //                 } else {
-    //                     for i in (0..8).rev() {
-    //                         globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
-    //                     }
-    //                 }
+            Instruction::ClearScan(x) => {
+                let label_num = curr_label_num;
+                curr_label_num += 1;
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
 
-    //                 generated_indices.insert(*x);
-    //             }
+                if checked {
+                    instructions += &checked_scan_asm(*x, label_num, true);
+                } else {
+                    instructions += &clear_scan_asm(*x, label_num, use_avx, scan_lanes, &mut globals, &mut generated_indices);
+                }
+            }
 
-    //             let bytes_per_iter = 8 * abs_scan;
+            Instruction::Output(x) if freestanding => {
+                instructions += &format!("\tmovb ${x}, (%rsp)\n");
+                instructions += &syscall_write_asm("(%rsp)", 1);
+            },
 
-    //             if !is_neg {
-    //                 instructions += "	movq	%r12, %rax\n";
-    //                 instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
-    //                 instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
-    //                 instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
-    //                 instructions += &("	movl	$".to_owned() + &bytes_per_iter.to_string() + ", %edx\n");
-    //                 instructions += "	.p2align	4, 0x90\n";
-    //                 instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
-    //                 instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
-    //                 instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
-    //                 instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
-    //                 instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
-    //                 instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
-    //                 instructions += "	vpmovmskb	%ymm3, %r8d\n";
-    //                 instructions += "	tzcntl	%r8d, %r9d\n";
-    //                 instructions += "	shrl	$2, %r9d\n";
-    //                 instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r9d, %r9d\n");
-    //                 instructions += "	addq	%rax, %r9\n";
-    //                 instructions += &("	addq	$".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
-    //                 instructions += "	movq	%r9, %r12\n";
-    //                 instructions += "	testl	%r8d, %r8d\n";
-    //                 instructions += &("	je	".to_owned() + &loop_label + "\n");
-    //                 instructions += "# %bb.2:\n";
-    //                 instructions += "	vzeroupper\n";
-    //             } else {
-    //                 let start_offset = 7 * abs_scan;
+            Instruction::Output(x) => {
+                instructions += &format!("    movl ${x}, %ecx\n");
+                instructions += "	callq putchar\n";
+                instructions += "\tcmpl $-1, %eax\n";
+                instructions += "\tje .WRITE_FAILED\n";
+            },
 
-    //                 instructions += "	movq	%r12, %rax\n";
-    //                 instructions += &("	addq	$-".to_owned() + &start_offset.to_string() + ", %rax\n");
-    //                 instructions += "	movq	%rax, %r12\n";
-    //                 instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
-    //                 instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
-    //                 instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
-    //                 instructions += "	.p2align	4, 0x90\n";
-    //                 instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
-    //                 instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
-    //                 instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
-    //                 instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
-    //                 instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
-    //                 instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
-    //                 instructions += "	vpmovmskb	%ymm3, %edx\n";
-    //                 instructions += "	tzcntl	%edx, %r8d\n";
-    //                 instructions += "	shrl	$2, %r8d\n";
-    //                 instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r8d, %r8d\n");
-    //                 instructions += "	movq	%rax, %r9\n";
-    //                 instructions += "	subq	%r8, %r9\n";
-    //                 instructions += &("	addq	$".to_owned() + &start_offset.to_string() + ", %r9\n");
-    //                 instructions += &("	addq	$-".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
-    //                 instructions += "	testl	%edx, %edx\n";
-    //                 instructions += "	cmovneq	%r9, %rax\n";
-    //                 instructions += "	movq	%rax, %r12\n";
-    //                 instructions += &("	je	".to_owned() + &loop_label + "\n");
-    //                 instructions += "# %bb.2:\n";
-    //                 instructions += "	vzeroupper\n";
-    //             }
-    //         }
+            Instruction::OutputStr(bytes) => {
+                let label = format!(".STR{}", string_count);
+                string_count += 1;
+                strings += &string_data_asm(&label, bytes);
 
-    //         Instruction::Output(x) => {
-    //             instructions += &format!("    movl ${x}, %ecx\n");
-    //             instructions += "	callq putchar\n";
-    //         },
+                if freestanding {
+                    instructions += &syscall_write_asm(&format!("{label}(%rip)"), bytes.len());
+                } else {
+                    instructions += &format!("\tleaq {label}(%rip), %rcx\n");
+                    instructions += &format!("\tmovq ${}, %rdx\n", bytes.len());
+                    instructions += "\tcallq bf_write\n";
+                    instructions += "\tcmpl $-1, %eax\n";
+                    instructions += "\tje .WRITE_FAILED\n";
+                }
+            },
 
-    //         Instruction::SetHeadPos(x) => {
-    //             instructions += "   movq %r13, %r12\n";
-    //             instructions += &format!("   addq ${x}, %r12\n");
-    //         },
+            Instruction::SetHeadPos(x) => {
+                instructions += "   movq %r13, %r12\n";
+                instructions += &format!("   addq ${x}, %r12\n");
+            },
 
-    //         Instruction::SetCell(pos, val) => {
-    //             instructions += &format!("   movb ${val}, {pos}(%r13)\n");
-    //         }
+            Instruction::SetCell(pos, val) => {
+                instructions += &format!("   movb ${val}, {pos}(%r13)\n");
+            }
 
-    //         Instruction::Nop => (),
+            Instruction::WriteAt(pos) if ascii_out => instructions += &ascii_write_asm(&format!("{pos}(%r13)"), freestanding),
+            Instruction::ReadAt(pos) if freestanding => instructions += &syscall_read_asm(&format!("{pos}(%r13)"), eof_byte),
+            Instruction::ReadAt(pos) if eof_byte != 255 => instructions += &read_char_asm(&format!("{pos}(%r13)"), eof_byte),
+            Instruction::WriteAt(pos) if freestanding => instructions += &syscall_write_asm(&format!("{pos}(%r13)"), 1),
 
-    //         _ => panic!("unhandled instruction: {}", inst)
-    //     }
-    // }
-}
+            Instruction::ReadAt(pos) => {
+                instructions += "\txorl %ecx, %ecx\n";
+                instructions += "\tcallq fflush\n";
+                instructions += "\tcallq getchar\n";
+                instructions += &format!("\tmovb %al, {pos}(%r13)\n");
+            },
 
-pub fn compile_asm_to_exe( asm : &str, output_path : &str) -> Result<()> {
-    let output_dir = tempfile::Builder::new()
-        .keep(false)
-        .tempdir_in(".").map_err(|e| Box::new(e))?;
+            Instruction::WriteAt(pos) => {
+                instructions += &format!("\tmovzbl {pos}(%r13), %ecx\n");
+                instructions += "\tcallq putchar\n";
+                instructions += "\tcmpl $-1, %eax\n";
+                instructions += "\tje .WRITE_FAILED\n";
+            },
 
-     let runner_path = output_dir.path().join("bf_main.c");
-    let mut runner_file = File::create(runner_path.clone()).map_err(|e| Box::new(e))?;
-    write!(runner_file, "{}", TEST_RUNNER).unwrap();
+            // Debug commands only mean something to the interpreter
+            Instruction::Nop | Instruction::Debug(_) => (),
 
-     let bf_asm_path = output_dir.path().join("bf_program.S");
-    let mut bf_asm_file = File::create(bf_asm_path.clone()).map_err(|e| Box::new(e))?;
-    write!(bf_asm_file, "{}", asm).unwrap();
+            _ => panic!("unhandled instruction: {}", inst)
+        }
 
-    Command::new("clang")
-        .arg(runner_path)
-        .arg(bf_asm_path)
-        .arg("-march=native")
-        .arg("-o")
-        .arg(output_path.clone())
-        .status().expect("Error compiling BF program.");
+        if checked {
+            match inst {
+                Instruction::MoveRight | Instruction::MoveLeft | Instruction::SetHeadPos(_) => {
+                    instructions += &bounds_check_asm("%r12", 0);
+                },
+                _ => (),
+            }
+        }
+    }
 
-    return Ok(());
+    // Error paths, out of line so the program falls through to the end past them.
+    instructions += "\tjmp .BF_DONE\n";
+    instructions += ".WRITE_FAILED:\n";
+    if freestanding {
+        instructions += "\tmovl $1, %edi\n";
+        instructions += "\tmovl $60, %eax\n";
+        instructions += "\tsyscall\n";
+    } else {
+        instructions += "\tmovl $1, %ecx\n";
+        instructions += "\tcallq exit\n";
+    }
+    if checked {
+        instructions += ".HEAD_ESCAPED:\n";
+        instructions += "\tcallq bf_head_escaped\n";
+    }
+    instructions += ".BF_DONE:\n";
+
+    // Any loop left open would jump to a .UZ label that was never defined.
+    if let Some((_, pc)) = label_stack.pop() {
+        return Err(Box::new(UnmatchedLoopLabel { pc }));
+    }
+
+    if freestanding {
+        if !strings.is_empty() {
+            strings = "\n\t.section\t.rodata\n".to_owned() + &strings;
+        }
+        if self_center {
+            instructions = self_center_asm("%rsi") + &instructions;
+        }
+        return Ok(FREESTANDING_FUNC_PROLOGUE.to_owned() + &instructions + FREESTANDING_FUNC_END + &strings);
+    }
+
+    if self_center {
+        instructions = self_center_asm("%rdx") + &instructions;
+    }
+
+    if !strings.is_empty() {
+        globals += &("\n\t.section\t.rdata,\"dr\"\n".to_owned() + &strings);
+    }
+
+    let program = FUNC_BEGIN.to_owned() + &globals + FUNC_PROLOGUE + &instructions + FUNC_END;
+    return Ok(program);
 }
 
-pub fn compile_llvm_to_exe( module : &Module, output_path : &str, dump_llvm : bool) -> Result<()> {
-    let output_dir = tempfile::Builder::new()
-        .keep(false)
-        .tempdir_in(".").map_err(|e| Box::new(e))?;
+// Replace the -1 getchar returns at EOF with eof_byte. Truncating it already gives 255.
+fn nasm_eof_asm( eof_byte : u8 ) -> String {
+    if eof_byte == 255 {
+        return "".to_owned();
+    }
+    return format!("\tmov edx, {eof_byte}\n\tcmp eax, -1\n\tcmove eax, edx\n");
+}
 
-    let runner_path = output_dir.path().join("bf_main.c");
-    let mut runner_file = File::create(runner_path.clone()).map_err(|e| Box::new(e))?;
-    write!(runner_file, "{}", TEST_RUNNER).unwrap();
+// Generate NASM for an already optimized program. Labels are numbered the same way as the AT&T
+// output, so a .map file describes either.
+fn nasm_program( input : &[Instruction], annotate : bool, ascii_out : bool, eof_byte : u8, self_center : bool, hot_loops : &HashSet<usize>, labels : &mut Vec<AsmLabel> ) -> Result<String> {
+    let mut instructions = "".to_owned();
 
-    // Write the module to an object file
+    let mut curr_label_num = 0;
+    // Label number and pc of each open loop.
+    let mut label_stack : Vec<(usize, usize)> = Vec::new();
 
-    Target::initialize_all(&InitializationConfig::default());
+    for (pc, inst) in input.iter().enumerate() {
+        if annotate && *inst != Instruction::Nop {
+            instructions += &format!("; pc={pc} op={inst}\n");
+        }
 
-    let target_triple = TargetMachine::get_default_triple();
-    let target = Target::from_triple(&target_triple).unwrap();
-    let target_machine = target
-        .create_target_machine(
-            &target_triple,
-            "generic",
-            "",
-            OptimizationLevel::Default,
-            RelocMode::PIC,
-            CodeModel::Default)
-        .unwrap();
+        match inst {
+            Instruction::MoveRight => instructions += "\tinc r12\n",
+            Instruction::MoveLeft => instructions += "\tdec r12\n",
+            Instruction::Increment => instructions += "\tinc byte [r12]\n",
+            Instruction::Decrement => instructions += "\tdec byte [r12]\n",
 
-    module.set_triple(&target_triple);
-    module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+            Instruction::Read => {
+                instructions += "\txor ecx, ecx\n";
+                instructions += "\tcall fflush\n";
+                instructions += "\tcall getchar\n";
+                instructions += &nasm_eof_asm(eof_byte);
+                instructions += "\tmov [r12], al\n";
+            },
+
+            Instruction::Write => {
+                instructions += "\tmovzx ecx, byte [r12]\n";
+                if ascii_out {
+                    instructions += "\tand ecx, 0x7f\n";
+                }
+                instructions += "\tcall putchar\n";
+                instructions += "\tcmp eax, -1\n";
+                instructions += "\tje .WRITE_FAILED\n";
+            },
+
+            Instruction::JumpIfZero => {
+                let label_num = curr_label_num;
+                curr_label_num += 1;
+                label_stack.push((label_num, pc));
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
+
+                instructions += "\n\tcmp byte [r12], 0\n";
+                instructions += &format!("\tje .UZ{label_num}\n");
+                if hot_loops.contains(&pc) {
+                    instructions += "\talign 16\n";
+                }
+                instructions += &format!(".IZ{label_num}:\n");
+            },
+
+            Instruction::JumpUnlessZero => {
+                let label_num = match label_stack.pop() {
+                    Some((label_num, _)) => label_num,
+                    None => return Err(Box::new(UnmatchedLoopLabel { pc })),
+                };
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
+
+                instructions += "\n\tcmp byte [r12], 0\n";
+                instructions += &format!("\tjne .IZ{label_num}\n");
+                instructions += &format!(".UZ{label_num}:\n");
+            },
+
+            Instruction::Zero => instructions += "\tmov byte [r12], 0\n",
+
+            Instruction::ZeroAt(offset) => instructions += &format!("\tmov byte [r12{offset:+}], 0\n"),
+
+            Instruction::SetCurrent(val) => instructions += &format!("\tmov byte [r12], {val}\n"),
+
+            Instruction::Add(offset) => {
+                instructions += "\tmovzx eax, byte [r12]\n";
+                instructions += &format!("\tadd [r12{offset:+}], al\n");
+            },
+
+            Instruction::Sub(offset) => {
+                instructions += "\tmovzx eax, byte [r12]\n";
+                instructions += &format!("\tsub [r12{offset:+}], al\n");
+            },
+
+            Instruction::AddConst(offset, val) => {
+                instructions += &format!("\tadd byte [r12{offset:+}], {val}\n");
+            },
+
+            Instruction::Scan(0) | Instruction::ClearScan(0) => {
+                return Err(Box::new(ZeroScanStride { pc }));
+            }
+
+            Instruction::Scan(x) => {
+                let label_num = curr_label_num;
+                curr_label_num += 1;
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
+
+                instructions += "\tcmp byte [r12], 0\n";
+                instructions += &format!("\tje .SCAN{label_num}_END\n");
+                instructions += &format!(".SCAN{label_num}:\n");
+                instructions += &format!("\tadd r12, {x}\n");
+                instructions += "\tcmp byte [r12], 0\n";
+                instructions += &format!("\tjne .SCAN{label_num}\n");
+                instructions += &format!(".SCAN{label_num}_END:\n");
+            }
+
+            Instruction::ClearScan(x) => {
+                let label_num = curr_label_num;
+                curr_label_num += 1;
+                labels.push(AsmLabel { label_num, pc, inst: inst.clone() });
+
+                instructions += &format!(".CLEAR{label_num}:\n");
+                instructions += "\tcmp byte [r12], 0\n";
+                instructions += &format!("\tje .CLEAR{label_num}_END\n");
+                instructions += "\tmov byte [r12], 0\n";
+                instructions += &format!("\tadd r12, {x}\n");
+                instructions += &format!("\tjmp .CLEAR{label_num}\n");
+                instructions += &format!(".CLEAR{label_num}_END:\n");
+            }
+
+            Instruction::Output(x) => {
+                instructions += &format!("\tmov ecx, {x}\n");
+                instructions += "\tcall putchar\n";
+                instructions += "\tcmp eax, -1\n";
+                instructions += "\tje .WRITE_FAILED\n";
+            },
+
+            // NASM output is linked against the user's own runner, which only has to provide the C
+            // library, so there's no bf_write to call.
+            Instruction::OutputStr(bytes) => {
+                for x in bytes {
+                    instructions += &format!("\tmov ecx, {x}\n");
+                    instructions += "\tcall putchar\n";
+                    instructions += "\tcmp eax, -1\n";
+                    instructions += "\tje .WRITE_FAILED\n";
+                }
+            },
+
+            Instruction::SetHeadPos(x) => instructions += &format!("\tlea r12, [r13{x:+}]\n"),
+
+            Instruction::SetCell(pos, val) => instructions += &format!("\tmov byte [r13{pos:+}], {val}\n"),
+
+            Instruction::ReadAt(pos) => {
+                instructions += "\txor ecx, ecx\n";
+                instructions += "\tcall fflush\n";
+                instructions += "\tcall getchar\n";
+                instructions += &nasm_eof_asm(eof_byte);
+                instructions += &format!("\tmov [r13{pos:+}], al\n");
+            },
+
+            Instruction::WriteAt(pos) => {
+                instructions += &format!("\tmovzx ecx, byte [r13{pos:+}]\n");
+                if ascii_out {
+                    instructions += "\tand ecx, 0x7f\n";
+                }
+                instructions += "\tcall putchar\n";
+                instructions += "\tcmp eax, -1\n";
+                instructions += "\tje .WRITE_FAILED\n";
+            },
+
+            Instruction::Nop | Instruction::Debug(_) => (),
+        }
+    }
+
+    if let Some((_, pc)) = label_stack.pop() {
+        return Err(Box::new(UnmatchedLoopLabel { pc }));
+    }
+
+    instructions += "\tjmp .BF_DONE\n";
+    instructions += ".WRITE_FAILED:\n";
+    instructions += "\tmov ecx, 1\n";
+    instructions += "\tcall exit\n";
+    instructions += ".BF_DONE:\n";
+
+    if self_center {
+        instructions = "\tshr rdx, 1\n\tadd r12, rdx\n\tmov r13, r12\n".to_owned() + &instructions;
+    }
+
+    return Ok(NASM_FUNC_BEGIN.to_owned() + &instructions + NASM_FUNC_END);
+}
+
+// Start of the Rust source compile_to_rust generates, up to the body of main. Like the compiled
+// runner, reads flush pending output first and give 255 at EOF, and a failed write exits with 1.
+const RUST_PRELUDE : &str = "// Generated by brainbug
+#![allow(dead_code, unused_mut, unused_variables)]
+
+use std::io::{self, Read, Write};
+
+const TAPE_HALF_SIZE: usize = {TAPE_HALF_SIZE};
+
+fn read_byte(stdin: &mut impl Read, stdout: &mut impl Write) -> u8 {
+    if stdout.flush().is_err() {
+        std::process::exit(1);
+    }
+    let mut buf = [0u8; 1];
+    match stdin.read(&mut buf) {
+        Ok(1) => buf[0],
+        _ => 255,
+    }
+}
+
+fn write_bytes(stdout: &mut impl Write, bytes: &[u8]) {
+    if stdout.write_all(bytes).is_err() {
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let mut tape = vec![0u8; 2 * TAPE_HALF_SIZE];
+    let start = TAPE_HALF_SIZE;
+    let mut head = start;
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+
+";
+
+const RUST_MAIN_END : &str = "
+    if stdout.flush().is_err() {
+        std::process::exit(1);
+    }
+}
+";
+
+// Index expression for the cell offset cells from base.
+fn rust_index( base : &str, offset : i32 ) -> String {
+    match offset {
+        0 => base.to_owned(),
+        x if x > 0 => format!("{base} + {x}"),
+        x => format!("{base} - {}", -x),
+    }
+}
+
+// Optimize the program and lower it to a standalone Rust program, with loops as while loops over a
+// Vec<u8> tape. Indexing off either end of the tape panics. Also returns what the optimization passes did.
+pub fn compile_to_rust( input : &mut Vec<Instruction>, opts : OptimizeOptions ) -> Result<(String, OptStats)> {
+    let stats = optimize(input, opts);
+
+    let mut body = "".to_owned();
+    // Pc of each open loop.
+    let mut loop_stack : Vec<usize> = Vec::new();
+
+    for (pc, inst) in input.iter().enumerate() {
+        let indent = "    ".repeat(loop_stack.len() + 1);
+        let line = match inst {
+            Instruction::MoveRight => "head += 1;".to_owned(),
+            Instruction::MoveLeft => "head -= 1;".to_owned(),
+            Instruction::Increment => "tape[head] = tape[head].wrapping_add(1);".to_owned(),
+            Instruction::Decrement => "tape[head] = tape[head].wrapping_sub(1);".to_owned(),
+            Instruction::Read => "tape[head] = read_byte(&mut stdin, &mut stdout);".to_owned(),
+            Instruction::Write => "write_bytes(&mut stdout, &[tape[head]]);".to_owned(),
+
+            Instruction::JumpIfZero => {
+                loop_stack.push(pc);
+                "while tape[head] != 0 {".to_owned()
+            },
+            Instruction::JumpUnlessZero => {
+                if loop_stack.pop().is_none() {
+                    return Err(Box::new(UnmatchedBracket { pc }));
+                }
+                body += &format!("{}}}\n", "    ".repeat(loop_stack.len() + 1));
+                continue;
+            },
+
+            Instruction::Zero => "tape[head] = 0;".to_owned(),
+            Instruction::SetCurrent(val) => format!("tape[head] = {val};"),
+            Instruction::ZeroAt(offset) => format!("tape[{}] = 0;", rust_index("head", *offset)),
+            Instruction::Add(offset) => {
+                let idx = rust_index("head", *offset);
+                format!("tape[{idx}] = tape[{idx}].wrapping_add(tape[head]);")
+            },
+            Instruction::Sub(offset) => {
+                let idx = rust_index("head", *offset);
+                format!("tape[{idx}] = tape[{idx}].wrapping_sub(tape[head]);")
+            },
+            Instruction::AddConst(offset, val) => {
+                let idx = rust_index("head", *offset);
+                format!("tape[{idx}] = tape[{idx}].wrapping_add({val});")
+            },
+
+            Instruction::Scan(0) | Instruction::ClearScan(0) => return Err(Box::new(ZeroScanStride { pc })),
+            Instruction::Scan(1) => "head += tape[head..].iter().position(|&c| c == 0).expect(\"scan ran off the tape\");".to_owned(),
+            Instruction::Scan(-1) => "head = tape[..=head].iter().rposition(|&c| c == 0).expect(\"scan ran off the tape\");".to_owned(),
+            Instruction::Scan(x) if *x > 0 => format!("head += {x} * tape[head..].iter().step_by({x}).position(|&c| c == 0).expect(\"scan ran off the tape\");"),
+            Instruction::Scan(x) => format!("head -= {} * tape[..=head].iter().rev().step_by({}).position(|&c| c == 0).expect(\"scan ran off the tape\");", -x, -x),
+            Instruction::ClearScan(x) => {
+                let step = if *x > 0 { format!("head += {x};") } else { format!("head -= {};", -x) };
+                format!("while tape[head] != 0 {{ tape[head] = 0; {step} }}")
+            },
+
+            Instruction::SetHeadPos(pos) => format!("head = {};", rust_index("start", *pos)),
+            Instruction::SetCell(pos, val) => format!("tape[{}] = {val};", rust_index("start", *pos)),
+            Instruction::ReadAt(pos) => format!("tape[{}] = read_byte(&mut stdin, &mut stdout);", rust_index("start", *pos)),
+            Instruction::WriteAt(pos) => format!("write_bytes(&mut stdout, &[tape[{}]]);", rust_index("start", *pos)),
+            Instruction::Output(val) => format!("write_bytes(&mut stdout, &[{val}]);"),
+            Instruction::OutputStr(bytes) => format!("write_bytes(&mut stdout, b\"{}\");", bytes.escape_ascii()),
+
+            // Debug commands only affect the interpreter.
+            Instruction::Debug(_) | Instruction::Nop => continue,
+        };
+
+        body += &format!("{indent}{line}\n");
+    }
+
+    if let Some(pc) = loop_stack.pop() {
+        return Err(Box::new(UnmatchedBracket { pc }));
+    }
+
+    return Ok((RUST_PRELUDE.replace("{TAPE_HALF_SIZE}", &TAPE_HALF_SIZE.to_string()) + &body + RUST_MAIN_END, stats));
+}
+
+pub fn compile_to_llvm<'a>( context : &'a Context, input : &mut Vec<Instruction>, opts : OptimizeOptions, ascii_out : bool, eof_byte : u8 ) -> (Module<'a>, OptStats) {
+    let stats = optimize(input, opts);
+    if ascii_out {
+        mask_outputs_to_ascii(input);
+    }
+
+    return (llvm_module(context, input, "bf_main", ascii_out, eof_byte), stats);
+}
+
+// Compile each program into its own module, sharing one context. Program N's entry point is named
+// bf_main_N so the modules can be linked into one binary. ascii_out and eof_byte apply to every
+// program, as in compile_to_llvm.
+pub fn compile_many_to_llvm<'a>( context : &'a Context, programs : &[Vec<Instruction>], opts : OptimizeOptions, ascii_out : bool, eof_byte : u8 ) -> Vec<Module<'a>> {
+    let mut modules = Vec::new();
+
+    for (i, program) in programs.iter().enumerate() {
+        let mut program = program.clone();
+        optimize(&mut program, opts);
+        if ascii_out {
+            mask_outputs_to_ascii(&mut program);
+        }
+
+        modules.push(llvm_module(context, &program, &format!("bf_main_{i}"), ascii_out, eof_byte));
+    }
+
+    return modules;
+}
+
+// Build a module defining func_name, which runs an already optimized program on the tape it's
+// passed. With ascii_out, bytes written from cells have their high bit cleared. Reads store
+// eof_byte once input runs out.
+fn llvm_module<'a>( context : &'a Context, input : &[Instruction], func_name : &str, ascii_out : bool, eof_byte : u8 ) -> Module<'a> {
+    let module = context.create_module(func_name);
+
+    // Add declarations for getchar and putchar
+
+    let getchar_fn_ty = context.i32_type().fn_type(&[], false);
+    let putchar_fn_ty = context.i32_type().fn_type(&[context.i32_type().into()], false);
+
+    let getchar_fn = module.add_function("getchar", getchar_fn_ty, None);
+
+    // Output is flushed before each read so prompts show up before the program waits for input.
+    let fflush_fn_ty = context.i32_type().fn_type(&[context.ptr_type(AddressSpace::default()).into()], false);
+    let fflush_fn = module.add_function("fflush", fflush_fn_ty, None);
+    let putchar_fn = module.add_function("putchar", putchar_fn_ty, None);
+
+    let exit_fn_ty = context.void_type().fn_type(&[context.i32_type().into()], false);
+    let exit_fn = module.add_function("exit", exit_fn_ty, None);
+
+    // Add a bf_main function.
+
+    let void_ptr_ty = context.ptr_type(AddressSpace::default());
+    let func_ty = context.void_type().fn_type(&[void_ptr_ty.into()], false);
+
+    let bf_main_func = module.add_function(func_name, func_ty, None);
+
+    // Populate function.
+
+    let builder = context.create_builder();
+
+    // Add basic blocks. Create a basic block whenever a branch is possible (jump if zero or jump
+    // unless zero).
+
+    let mut basic_blocks : Vec<BasicBlock> = Vec::new();
+
+    let mut curr_block = context.append_basic_block(bf_main_func, "entry");
+
+    // We need a new basic block for every possible branch instruction.
+    let mut curr_bb = 0;
+    for inst in input {
+        match inst {
+            Instruction::JumpIfZero | Instruction::JumpUnlessZero => {
+                basic_blocks.push(context.append_basic_block(bf_main_func, &curr_bb.to_string()));
+                curr_bb = curr_bb + 1;
+            },
+
+            _ => ()
+        }
+    }
+    // Exit with status 1 when putchar returns EOF, meaning output can't be written any more.
+    let write_failed_block = context.append_basic_block(bf_main_func, "write_failed");
+    builder.position_at_end(write_failed_block);
+    builder.build_call(exit_fn, &[context.i32_type().const_int(1, false).into()], "exit").unwrap();
+    builder.build_unreachable().unwrap();
+
+    let check_write = |put_result : CallSiteValue| {
+        let put_result = put_result.try_as_basic_value().unwrap_left().into_int_value();
+        let failed = builder.build_int_compare(IntPredicate::EQ, put_result, context.i32_type().const_all_ones(), "write_failed").unwrap();
+
+        let wrote_block = context.append_basic_block(bf_main_func, "wrote");
+        builder.build_conditional_branch(failed, write_failed_block, wrote_block).unwrap();
+        builder.position_at_end(wrote_block);
+    };
+
+    let mask_to_ascii = |val : IntValue<'a>| {
+        if ascii_out {
+            builder.build_and(val, context.i32_type().const_int(0x7f, false), "ascii_val").unwrap()
+        } else {
+            val
+        }
+    };
+
+    // Call getchar. Truncating its result stores EOF (-1) as 255, matching the interpreter and the
+    // asm backend, so other sentinels are selected before truncating.
+    let read_byte = || {
+        builder.build_call(fflush_fn, &[context.ptr_type(AddressSpace::default()).const_null().into()], "flush").unwrap();
+        let read_value_i32 = builder.build_call(getchar_fn, &[], "read_value_i32").unwrap().try_as_basic_value().unwrap_left().into_int_value();
+        let read_value_i32 = if eof_byte == 255 {
+            read_value_i32
+        } else {
+            let at_eof = builder.build_int_compare(IntPredicate::EQ, read_value_i32, context.i32_type().const_all_ones(), "at_eof").unwrap();
+            let eof_value = context.i32_type().const_int(u64::from(eof_byte), false);
+            builder.build_select(at_eof, eof_value, read_value_i32, "read_value_or_eof").unwrap().into_int_value()
+        };
+        builder.build_int_truncate(read_value_i32, context.i8_type(), "read_value_i8").unwrap()
+    };
+
+    builder.position_at_end(curr_block);
+
+    // Allocate a single pointer alloca to track the head position.
+    let head_pos_ty = bf_main_func.get_first_param().unwrap().get_type();
+    let head_pos = builder.build_alloca(head_pos_ty, "head_pos").unwrap();
+    builder.build_store(head_pos, bf_main_func.get_first_param().unwrap()).unwrap();
+
+    // Partial evaluation addresses cells relative to where the head started, which is the tape
+    // pointer bf_main was called with.
+    let tape_base_int = builder.build_ptr_to_int(bf_main_func.get_first_param().unwrap().into_pointer_value(), context.i64_type(), "tape_base_int").unwrap();
+
+    // Visit BF insts
+
+    let mut bb_jump_back_stack : Vec<BasicBlock> = Vec::new();
+    let mut bb_next_stack : Vec<BasicBlock> = Vec::new();
+
+    for inst in input {
+        match inst {
+            Instruction::Read => {
+                let read_value_i8 = read_byte();
+
+                // Store read value
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                builder.build_store(curr_head_pos.into_pointer_value(), read_value_i8).unwrap();
+            },
+
+            Instruction::Write => {
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+                let curr_head_val_i32 = builder.build_int_z_extend(curr_head_val_i8.into_int_value(), context.i32_type(), "curr_head_val_i32").unwrap();
+                let curr_head_val_i32 = mask_to_ascii(curr_head_val_i32);
+
+                // Call putchar on value.
+                let put_result = builder.build_call(putchar_fn, &[curr_head_val_i32.into()], "putchar_head").unwrap();
+                check_write(put_result);
+            },
+
+
+            Instruction::Increment => {
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                // Add 1 to value
+                let added_val_i8 = builder.build_int_add(curr_head_val_i8.into_int_value(), context.i8_type().const_int(1, false), "incremented").unwrap();
+
+                // Store value.
+                builder.build_store(curr_head_pos.into_pointer_value(), added_val_i8).unwrap();
+            },
+
+            Instruction::Add(x) => {
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                // Read value at offset.
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
+                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
+                let offset_head_val_i8 = builder.build_load(context.i8_type(), offset_head_pos.try_into().unwrap(), "offset_head_val_i8").unwrap();
+
+                // Add values
+                let sum = builder.build_int_add(curr_head_val_i8.into_int_value(), offset_head_val_i8.into_int_value(), "sum").unwrap();
+
+                // Store value at offset.
+                builder.build_store(offset_head_pos, sum).unwrap();
+            }
+
+            Instruction::Sub(x) => {
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                // Read value at offset.
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
+                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
+                let offset_head_val_i8 = builder.build_load(context.i8_type(), offset_head_pos.try_into().unwrap(), "offset_head_val_i8").unwrap();
+
+                // Subtract the value at head from the value at offset, like subb in the asm backend.
+                let difference = builder.build_int_sub(offset_head_val_i8.into_int_value(), curr_head_val_i8.into_int_value(), "difference").unwrap();
+
+                // Store value at offset.
+                builder.build_store(offset_head_pos, difference).unwrap();
+            }
+
+            Instruction::AddConst(x, val) => {
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                // Read value at offset.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
+                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
+                let offset_head_val_i8 = builder.build_load(context.i8_type(), offset_head_pos.try_into().unwrap(), "offset_head_val_i8").unwrap();
+
+                // Add constant
+                let sum = builder.build_int_add(offset_head_val_i8.into_int_value(), context.i8_type().const_int(u64::from(*val), false), "sum").unwrap();
+
+                // Store value.
+                builder.build_store(offset_head_pos, sum).unwrap();
+            }
+
+            Instruction::Zero => {
+                // Get head ptr
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+
+                // Store 0 to head.
+                builder.build_store(curr_head_pos.into_pointer_value(), context.i8_type().const_zero()).unwrap();
+            }
+
+            Instruction::ZeroAt(x) => {
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                // Get offset ptr
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let offset_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "offset_head_pos_int").unwrap();
+                let offset_head_pos = builder.build_int_to_ptr(offset_head_pos_int, head_pos_ty.into_pointer_type(), "offset_head_pos").unwrap();
+
+                // Store 0 to offset.
+                builder.build_store(offset_head_pos, context.i8_type().const_zero()).unwrap();
+            }
+
+            Instruction::SetCurrent(val) => {
+                // Get head ptr
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+
+                // Store constant to head.
+                builder.build_store(curr_head_pos.into_pointer_value(), context.i8_type().const_int(u64::from(*val), false)).unwrap();
+            }
+
+            Instruction::Decrement => {
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                // Sub 1 from value
+                let subbed_val_i8 = builder.build_int_sub(curr_head_val_i8.into_int_value(), context.i8_type().const_int(1, false), "decremented").unwrap();
+
+                // Store value.
+                builder.build_store(curr_head_pos.into_pointer_value(), subbed_val_i8).unwrap();
+            },
+
+            Instruction::MoveRight => {
+                // Read curr head ptr
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+
+                // Add 1 to ptr
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let new_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(1, false), "new_head_pos_int").unwrap();
+                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+
+                // Store result
+                builder.build_store(head_pos, new_head_pos).unwrap();
+            },
+
+            Instruction::MoveLeft => {
+                // Read curr head ptr
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+
+                // Sub 1 from ptr
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let new_head_pos_int = builder.build_int_sub(curr_head_pos_int, context.i64_type().const_int(1, false), "new_head_pos_int").unwrap();
+                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+
+                // Store result
+                builder.build_store(head_pos, new_head_pos).unwrap();
+            },
+
+            Instruction::JumpIfZero => {
+                // Get basic blocks that we'll jump to.
+                let if_zero = basic_blocks.pop().unwrap();
+                let not_zero = basic_blocks.pop().unwrap();
+
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                // Compare val to 0.
+                let is_zero = builder.build_int_compare(IntPredicate::EQ, curr_head_val_i8.into_int_value(), context.i8_type().const_zero().into(), "is_zero").unwrap();
+
+                // Create a branch instruction.
+                builder.build_conditional_branch(is_zero, if_zero, not_zero).unwrap();
+
+                // Push the if and not zero BBs to the jump back stack. We'll target them when we
+                // hit the corresponding jump unless zero.
+                bb_jump_back_stack.push(not_zero);
+                bb_next_stack.push(if_zero);
+
+                // Set curr block to the loop body.
+                builder.position_at_end(not_zero);
+            },
+
+            Instruction::JumpUnlessZero => {
+                // Get basic blocks that we'll jump to.
+                let not_zero_bb = bb_jump_back_stack.pop().unwrap();
+                let if_zero_bb = bb_next_stack.pop().unwrap();
+
+                // Read value at head.
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+
+                // Compare val to 0.
+                let not_zero = builder.build_int_compare(IntPredicate::NE, curr_head_val_i8.into_int_value(), context.i8_type().const_zero().into(), "not_zero").unwrap();
+
+                // Create a branch instruction.
+                builder.build_conditional_branch(not_zero, not_zero_bb, if_zero_bb).unwrap();
+
+                // Set curr block to the next block.
+                builder.position_at_end(if_zero_bb);
+            }
+
+            Instruction::Scan(x) | Instruction::ClearScan(x) => {
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                let clears = matches!(inst, Instruction::ClearScan(_));
+
+                let scan_check = context.append_basic_block(bf_main_func, "scan_check");
+                let scan_step = context.append_basic_block(bf_main_func, "scan_step");
+                let scan_done = context.append_basic_block(bf_main_func, "scan_done");
+                builder.build_unconditional_branch(scan_check).unwrap();
+
+                // Read value at head and stop once it's zero.
+                builder.position_at_end(scan_check);
+                let curr_head_pos = builder.build_load(head_pos_ty, head_pos, "curr_head_pos").unwrap();
+                let curr_head_val_i8 = builder.build_load(context.i8_type(), curr_head_pos.try_into().unwrap(), "curr_head_val_i8").unwrap();
+                let is_zero = builder.build_int_compare(IntPredicate::EQ, curr_head_val_i8.into_int_value(), context.i8_type().const_zero(), "is_zero").unwrap();
+                builder.build_conditional_branch(is_zero, scan_done, scan_step).unwrap();
+
+                // Otherwise move the head by the stride, clearing the cell first for a clear scan.
+                builder.position_at_end(scan_step);
+                if clears {
+                    builder.build_store(curr_head_pos.into_pointer_value(), context.i8_type().const_zero()).unwrap();
+                }
+                let curr_head_pos_int = builder.build_ptr_to_int(curr_head_pos.into_pointer_value(), context.i64_type(), "head_pos_int").unwrap();
+                let new_head_pos_int = builder.build_int_add(curr_head_pos_int, context.i64_type().const_int(x_u64, false), "new_head_pos_int").unwrap();
+                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+                builder.build_store(head_pos, new_head_pos).unwrap();
+                builder.build_unconditional_branch(scan_check).unwrap();
+
+                builder.position_at_end(scan_done);
+            }
+
+            Instruction::Output(val) => {
+                let put_result = builder.build_call(putchar_fn, &[context.i32_type().const_int(u64::from(*val), false).into()], "putchar_const").unwrap();
+                check_write(put_result);
+            }
+
+            Instruction::OutputStr(bytes) => {
+                // Keep the bytes in a constant and loop over them, rather than a putchar per byte.
+                let str_val = context.const_string(bytes, false);
+                let str_global = module.add_global(str_val.get_type(), None, "output_str");
+                str_global.set_initializer(&str_val);
+                str_global.set_constant(true);
+                str_global.set_linkage(Linkage::Private);
+                let str_base_int = builder.build_ptr_to_int(str_global.as_pointer_value(), context.i64_type(), "str_base_int").unwrap();
+
+                let entry_block = builder.get_insert_block().unwrap();
+                let output_str_step = context.append_basic_block(bf_main_func, "output_str_step");
+                let output_str_done = context.append_basic_block(bf_main_func, "output_str_done");
+                builder.build_unconditional_branch(output_str_step).unwrap();
+
+                // Write the byte at idx.
+                builder.position_at_end(output_str_step);
+                let idx = builder.build_phi(context.i64_type(), "idx").unwrap();
+                let idx_val = idx.as_basic_value().into_int_value();
+                let byte_pos_int = builder.build_int_add(str_base_int, idx_val, "byte_pos_int").unwrap();
+                let byte_pos = builder.build_int_to_ptr(byte_pos_int, head_pos_ty.into_pointer_type(), "byte_pos").unwrap();
+                let byte_i8 = builder.build_load(context.i8_type(), byte_pos, "byte_i8").unwrap();
+                let byte_i32 = builder.build_int_z_extend(byte_i8.into_int_value(), context.i32_type(), "byte_i32").unwrap();
+                let put_result = builder.build_call(putchar_fn, &[byte_i32.into()], "putchar_str").unwrap();
+                check_write(put_result);
+
+                // Move on to the next byte until they've all been written.
+                let next_idx = builder.build_int_add(idx_val, context.i64_type().const_int(1, false), "next_idx").unwrap();
+                let more = builder.build_int_compare(IntPredicate::ULT, next_idx, context.i64_type().const_int(bytes.len() as u64, false), "more").unwrap();
+                builder.build_conditional_branch(more, output_str_step, output_str_done).unwrap();
+                idx.add_incoming(&[(&context.i64_type().const_zero(), entry_block), (&next_idx, builder.get_insert_block().unwrap())]);
+
+                builder.position_at_end(output_str_done);
+            }
+
+            Instruction::SetHeadPos(x) => {
+                let x_i64 = i64::from(*x);
+                let x_u64 = u64::from_ne_bytes(x_i64.to_ne_bytes());
+
+                let new_head_pos_int = builder.build_int_add(tape_base_int, context.i64_type().const_int(x_u64, false), "new_head_pos_int").unwrap();
+                let new_head_pos = builder.build_int_to_ptr(new_head_pos_int, head_pos_ty.into_pointer_type(), "new_head_pos").unwrap();
+                builder.build_store(head_pos, new_head_pos).unwrap();
+            }
+
+            Instruction::SetCell(pos, val) => {
+                let pos_i64 = i64::from(*pos);
+                let pos_u64 = u64::from_ne_bytes(pos_i64.to_ne_bytes());
+
+                let cell_pos_int = builder.build_int_add(tape_base_int, context.i64_type().const_int(pos_u64, false), "cell_pos_int").unwrap();
+                let cell_pos = builder.build_int_to_ptr(cell_pos_int, head_pos_ty.into_pointer_type(), "cell_pos").unwrap();
+                builder.build_store(cell_pos, context.i8_type().const_int(u64::from(*val), false)).unwrap();
+            }
+
+            Instruction::ReadAt(pos) => {
+                let pos_i64 = i64::from(*pos);
+                let pos_u64 = u64::from_ne_bytes(pos_i64.to_ne_bytes());
+
+                let read_value_i8 = read_byte();
+
+                let cell_pos_int = builder.build_int_add(tape_base_int, context.i64_type().const_int(pos_u64, false), "cell_pos_int").unwrap();
+                let cell_pos = builder.build_int_to_ptr(cell_pos_int, head_pos_ty.into_pointer_type(), "cell_pos").unwrap();
+                builder.build_store(cell_pos, read_value_i8).unwrap();
+            }
+
+            Instruction::WriteAt(pos) => {
+                let pos_i64 = i64::from(*pos);
+                let pos_u64 = u64::from_ne_bytes(pos_i64.to_ne_bytes());
+
+                let cell_pos_int = builder.build_int_add(tape_base_int, context.i64_type().const_int(pos_u64, false), "cell_pos_int").unwrap();
+                let cell_pos = builder.build_int_to_ptr(cell_pos_int, head_pos_ty.into_pointer_type(), "cell_pos").unwrap();
+                let cell_val_i8 = builder.build_load(context.i8_type(), cell_pos, "cell_val_i8").unwrap();
+                let cell_val_i32 = builder.build_int_z_extend(cell_val_i8.into_int_value(), context.i32_type(), "cell_val_i32").unwrap();
+                let cell_val_i32 = mask_to_ascii(cell_val_i32);
+
+                let put_result = builder.build_call(putchar_fn, &[cell_val_i32.into()], "putchar_cell").unwrap();
+                check_write(put_result);
+            }
+
+            Instruction::Nop | Instruction::Debug(_) => (),
+        }
+    }
+
+    builder.build_return(None).unwrap();
+
+    // module.write_bitcode_to_path(Path::new("bf_program_bleh.bc"));
+
+    module.verify().unwrap();
+
+    return module;
+
+    // let mut curr_label_num = 0;
+    // let mut label_stack = vec![0;0];
+
+    // let mut generated_indices : HashSet<i32> = HashSet::new();
+
+    // for inst in input {
+    //     match inst {
+    //         Instruction::MoveRight => instructions += MOVE_RIGHT,
+    //         Instruction::MoveLeft => instructions += MOVE_LEFT,
+    //         Instruction::Increment => instructions += INCREMENT,
+    //         Instruction::Decrement => instructions += DECREMENT,
+    //         Instruction::Read => instructions += READ_CHAR,
+    //         Instruction::Write => instructions += WRITE_CHAR,
+
+    //         Instruction::JumpIfZero => {
+    //             let new_label_num = curr_label_num;
+    //             curr_label_num += 1;
+    //             label_stack.push(new_label_num);
+
+    //             instructions += "\n";
+
+    //             // Generate a jump to the end label.
+    //             instructions += "\tcmpb $0, (%r12)\n";
+    //             instructions += &("\tje .UZ".to_owned() + &new_label_num.to_string() + "\n");
+
+    //             // Generate a label so corresponding jump unless zero can jump back.
+    //             instructions += &(".IZ".to_owned() + &new_label_num.to_string() + ":\n");
+    //         },
+
+    //         Instruction::JumpUnlessZero => {
+    //             instructions += "\n";
+
+    //             // Get the current brace from the stack.
+    //             let label_num = label_stack.pop().unwrap();
+
+    //             // Generate a jump to the start label.
+    //             instructions += "\tcmpb $0, (%r12)\n";
+    //             instructions += &("\tjne .IZ".to_owned() + &label_num.to_string() + "\n");
+
+    //             // Generate a label so corresponding jump if zero can jump back.
+    //             instructions += &(".UZ".to_owned() + &label_num.to_string() + ":\n");
+    //         },
+
+    //         Instruction::Zero => instructions += ZERO,
+
+    //         Instruction::Add(offset) => {
+    //             instructions += "\tmovzbl (%r12), %eax\n";
+    //             instructions += &format!("\taddb %al, {offset}(%r12)\n");
+    //         },
+
+    //         Instruction::Sub(offset) => {
+    //             instructions += "\tmovzbl (%r12), %eax\n";
+    //             instructions += &format!("\tsubb %al, {offset}(%r12)\n");
+    //         },
+
+    //         Instruction::Scan(x) => {
+    //             // Generate label names.
+    //             let label_num = curr_label_num;
+    //             curr_label_num += 1;
+
+    //             let loop_label = ".SCAN".to_owned() + &label_num.to_string();
+
+    //             // Generate indices for scan.
+
+    //             let is_neg = *x < 0;
+    //             let abs_scan = x.abs();
+
+    //             let head_delta_str = if !is_neg {
+    //                 &x.to_string()
+    //             } else {
+    //                 &("neg".to_owned() + &abs_scan.to_string())
+    //             };
+    //             let global_name = "_ymm@indices".to_owned() + &head_delta_str;
+
+    //             if !generated_indices.contains(x) {
+    //                 globals += &("\t.globl\t".to_owned() + &global_name + "\n");
+    //                 globals += &("\t.section	.rdata,\"dr\",discard,".to_owned() + &global_name + "\n");
+    //                 globals += &("\t.p2align	5, 0x0\n");
+    //                 globals += &(global_name.to_owned() + ":\n");
+
+    //                 if !is_neg {
+    //                     for i in 0..8 {
+    //                         globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
+    //                     }
+    //                 } else {
+    //                     for i in (0..8).rev() {
+    //                         globals += &("\t.long\t".to_owned() + &((i * abs_scan).to_string()) + "\n");
+    //                     }
+    //                 }
+
+    //                 generated_indices.insert(*x);
+    //             }
+
+    //             let bytes_per_iter = 8 * abs_scan;
+
+    //             if !is_neg {
+    //                 instructions += "	movq	%r12, %rax\n";
+    //                 instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
+    //                 instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
+    //                 instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
+    //                 instructions += &("	movl	$".to_owned() + &bytes_per_iter.to_string() + ", %edx\n");
+    //                 instructions += "	.p2align	4, 0x90\n";
+    //                 instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
+    //                 instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
+    //                 instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
+    //                 instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
+    //                 instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
+    //                 instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
+    //                 instructions += "	vpmovmskb	%ymm3, %r8d\n";
+    //                 instructions += "	tzcntl	%r8d, %r9d\n";
+    //                 instructions += "	shrl	$2, %r9d\n";
+    //                 instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r9d, %r9d\n");
+    //                 instructions += "	addq	%rax, %r9\n";
+    //                 instructions += &("	addq	$".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
+    //                 instructions += "	movq	%r9, %r12\n";
+    //                 instructions += "	testl	%r8d, %r8d\n";
+    //                 instructions += &("	je	".to_owned() + &loop_label + "\n");
+    //                 instructions += "# %bb.2:\n";
+    //                 instructions += "	vzeroupper\n";
+    //             } else {
+    //                 let start_offset = 7 * abs_scan;
+
+    //                 instructions += "	movq	%r12, %rax\n";
+    //                 instructions += &("	addq	$-".to_owned() + &start_offset.to_string() + ", %rax\n");
+    //                 instructions += "	movq	%rax, %r12\n";
+    //                 instructions += &("	vmovdqa	".to_owned() + &global_name + "(%rip), %ymm0\n");
+    //                 instructions += "	vpxor	%xmm1, %xmm1, %xmm1\n";
+    //                 instructions += "	vpbroadcastd	__real@ffffff00(%rip), %ymm2\n";
+    //                 instructions += "	.p2align	4, 0x90\n";
+    //                 instructions += &(loop_label.to_owned() + ":                                # =>This Inner Loop Header: Depth=1\n");
+    //                 instructions += "	vpcmpeqd	%ymm3, %ymm3, %ymm3\n";
+    //                 instructions += "	vpxor	%xmm4, %xmm4, %xmm4\n";
+    //                 instructions += "	vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4\n";
+    //                 instructions += "	vpor	%ymm2, %ymm4, %ymm3\n";
+    //                 instructions += "	vpcmpeqb	%ymm1, %ymm3, %ymm3\n";
+    //                 instructions += "	vpmovmskb	%ymm3, %edx\n";
+    //                 instructions += "	tzcntl	%edx, %r8d\n";
+    //                 instructions += "	shrl	$2, %r8d\n";
+    //                 instructions += &("	imull	$".to_owned() + &abs_scan.to_string() + ", %r8d, %r8d\n");
+    //                 instructions += "	movq	%rax, %r9\n";
+    //                 instructions += "	subq	%r8, %r9\n";
+    //                 instructions += &("	addq	$".to_owned() + &start_offset.to_string() + ", %r9\n");
+    //                 instructions += &("	addq	$-".to_owned() + &bytes_per_iter.to_string() + ", %rax\n");
+    //                 instructions += "	testl	%edx, %edx\n";
+    //                 instructions += "	cmovneq	%r9, %rax\n";
+    //                 instructions += "	movq	%rax, %r12\n";
+    //                 instructions += &("	je	".to_owned() + &loop_label + "\n");
+    //                 instructions += "# %bb.2:\n";
+    //                 instructions += "	vzeroupper\n";
+    //             }
+    //         }
+
+    //         Instruction::Output(x) => {
+    //             instructions += &format!("    movl ${x}, %ecx\n");
+    //             instructions += "	callq putchar\n";
+    //         },
+
+    //         Instruction::SetHeadPos(x) => {
+    //             instructions += "   movq %r13, %r12\n";
+    //             instructions += &format!("   addq ${x}, %r12\n");
+    //         },
+
+    //         Instruction::SetCell(pos, val) => {
+    //             instructions += &format!("   movb ${val}, {pos}(%r13)\n");
+    //         }
+
+    //         Instruction::Nop => (),
+
+    //         _ => panic!("unhandled instruction: {}", inst)
+    //     }
+    // }
+}
+
+// How compile_asm_to_exe builds the runner and links the program. The defaults are what a plain
+// compile uses.
+#[derive(Clone, Copy, Debug)]
+pub struct ExeOptions {
+    // Tune the runner for the host, for programs compiled with AVX2 scans.
+    pub use_avx: bool,
+
+    // Exit with the value of the cell under the head when the program ends.
+    pub exit_from_cell: bool,
+
+    // Match a program compiled with AsmOptions::self_center.
+    pub self_center: bool,
+
+    // Put guard pages either side of the tape.
+    pub guard_pages: bool,
+
+    // Link against the freestanding runner instead of libc.
+    pub freestanding: bool,
+
+    // Keep the intermediate files instead of deleting them.
+    pub keep_temps: bool,
+}
+
+impl Default for ExeOptions {
+    fn default() -> Self {
+        ExeOptions {
+            use_avx: true,
+            exit_from_cell: false,
+            self_center: false,
+            guard_pages: false,
+            freestanding: false,
+            keep_temps: false,
+        }
+    }
+}
+
+pub fn compile_asm_to_exe( asm : &str, output_path : &str, opts : ExeOptions, times : &mut CompileTimes ) -> Result<()> {
+    let ExeOptions { use_avx, freestanding, keep_temps, .. } = opts;
+
+    let output_dir = clang_temp_dir(keep_temps)?;
+
+    let (runner_name, runner) = if freestanding { ("bf_main.S", FREESTANDING_RUNNER) } else { ("bf_main.c", TEST_RUNNER) };
+    let runner_path = output_dir.path().join(runner_name);
+    let mut runner_file = File::create(runner_path.clone()).map_err(|e| Box::new(e))?;
+    write!(runner_file, "{}", runner).map_err(|e| Box::new(e))?;
+
+    // Freestanding programs are built for Linux whatever the host is, and the host's default
+    // linker may not handle ELF, so they're linked with lld.
+    let target_args = clang_target_args(freestanding);
+    let link_args : &[&str] = if freestanding { &["-nostdlib", "-static", "-fuse-ld=lld"] } else { &[] };
+
+    // Assemble the program and compile the runner separately from linking so each can be timed.
+    let start_time = SystemTime::now();
+
+    let runner_obj_path = output_dir.path().join("bf_main.o");
+    let mut clang = Command::new("clang");
+    clang.args(target_args)
+        .arg("-c")
+        .arg(runner_path)
+        .arg("-o")
+        .arg(runner_obj_path.clone());
+
+    // Only tune for the host when the program is allowed to use AVX2, so that scalar builds stay
+    // portable to older CPUs.
+    if use_avx {
+        clang.arg("-march=native");
+    }
+
+    clang.args(runner_defines(opts));
+
+    run_clang(&mut clang)?;
+
+    let bf_obj_path = output_dir.path().join("bf_program.o");
+    assemble(asm, output_dir.path(), &bf_obj_path, freestanding)?;
+
+    times.assemble = start_time.elapsed().unwrap().as_secs_f64();
+
+    let start_time = SystemTime::now();
+
+    run_clang(Command::new("clang")
+        .args(target_args)
+        .args(link_args)
+        .arg(runner_obj_path)
+        .arg(bf_obj_path)
+        .arg("-o")
+        .arg(output_path))?;
+
+    times.link = start_time.elapsed().unwrap().as_secs_f64();
+
+    return Ok(());
+}
+
+// The -D flags the runner is compiled with for these options.
+fn runner_defines( opts : ExeOptions ) -> Vec<&'static str> {
+    let mut defines = Vec::new();
+
+    if opts.exit_from_cell {
+        defines.push("-DBF_EXIT_FROM_CELL");
+    }
+    if opts.self_center {
+        defines.push("-DBF_SELF_CENTER");
+    }
+    if opts.guard_pages {
+        defines.push("-DBF_GUARD_PAGES");
+    }
+    if cfg!(test) {
+        defines.push("-DBF_EXIT_MARKER");
+    }
+
+    return defines;
+}
+
+// Like compile_asm_to_exe, but keep each exe in cache_dir under a hash of its asm and build options,
+// and copy it from there instead of running clang when it's already been built. Returns whether the
+// exe came from the cache.
+pub fn compile_asm_to_exe_cached( asm : &str, output_path : &str, opts : ExeOptions, cache_dir : &Path, times : &mut CompileTimes ) -> Result<bool> {
+    let extension = if opts.freestanding { "" } else { ".exe" };
+    let cached_path = cache_dir.join(format!("{:016x}{extension}", fnv1a_hash(exe_cache_key(asm, opts).as_bytes())));
+
+    if cached_path.exists() {
+        copy_atomically(&cached_path, Path::new(output_path))?;
+        return Ok(true);
+    }
+
+    compile_asm_to_exe(asm, output_path, opts, times)?;
+
+    fs::create_dir_all(cache_dir).map_err(|e| Box::new(e))?;
+    copy_atomically(Path::new(output_path), &cached_path)?;
+    return Ok(false);
+}
+
+// Everything that goes into the exe: the runner's source and defines, and the asm. The version is
+// there too in case the runner's build changes in ways the rest of the key can't see.
+// -march=native makes AVX2 exes specific to the machine that built them, but a cache directory
+// belongs to one machine.
+fn exe_cache_key( asm : &str, opts : ExeOptions ) -> String {
+    let runner = if opts.freestanding { FREESTANDING_RUNNER } else { TEST_RUNNER };
+    return format!("{}\n{} {} {}\n{runner}\n{asm}", env!("CARGO_PKG_VERSION"), opts.use_avx, opts.freestanding, runner_defines(opts).join(" "));
+}
+
+// 64 bit FNV-1a. Unlike std's hashers, its output is the same across Rust versions, so cache keys
+// stay valid.
+fn fnv1a_hash( bytes : &[u8] ) -> u64 {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+// Copy from to a temporary file next to to, then rename it into place, so that nothing ever sees a
+// partly written to, even when several builds share a cache.
+fn copy_atomically( from : &Path, to : &Path ) -> Result<()> {
+    let dir = match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let temp_file = NamedTempFile::new_in(dir).map_err(|e| Box::new(e))?;
+    fs::copy(from, temp_file.path()).map_err(|e| Box::new(e))?;
+    temp_file.persist(to).map_err(|e| Box::new(e))?;
+    return Ok(());
+}
+
+// Assemble the program into an object file exporting bf_main, without compiling the runner or
+// linking, which is enough to check that the generated asm assembles.
+pub fn compile_asm_to_object( asm : &str, output_path : &Path, freestanding : bool, keep_temps : bool, times : &mut CompileTimes ) -> Result<()> {
+    let output_dir = clang_temp_dir(keep_temps)?;
+
+    let start_time = SystemTime::now();
+    assemble(asm, output_dir.path(), output_path, freestanding)?;
+    times.assemble = start_time.elapsed().unwrap().as_secs_f64();
+
+    return Ok(());
+}
+
+// A directory in the working directory for the files handed to clang. With keep_temps it's left
+// behind when dropped, and its path is printed.
+fn clang_temp_dir( keep_temps : bool ) -> Result<TempDir> {
+    let output_dir = tempfile::Builder::new()
+        .keep(keep_temps)
+        .tempdir_in(".").map_err(|e| Box::new(e))?;
+
+    if keep_temps {
+        println!("Intermediate files kept in {}", output_dir.path().display());
+    }
+
+    return Ok(output_dir);
+}
+
+// Flags that make clang build for the target freestanding programs run on.
+fn clang_target_args( freestanding : bool ) -> &'static [&'static str] {
+    if freestanding { &["--target=x86_64-unknown-linux-gnu"] } else { &[] }
+}
+
+// Write the asm to a file in dir and assemble it into an object file at output_path.
+fn assemble( asm : &str, dir : &Path, output_path : &Path, freestanding : bool ) -> Result<()> {
+    let bf_asm_path = dir.join("bf_program.S");
+    let mut bf_asm_file = File::create(bf_asm_path.clone()).map_err(|e| Box::new(e))?;
+    write!(bf_asm_file, "{}", asm).map_err(|e| Box::new(e))?;
+
+    return run_clang(Command::new("clang")
+        .args(clang_target_args(freestanding))
+        .arg("-c")
+        .arg(bf_asm_path)
+        .arg("-o")
+        .arg(output_path));
+}
+
+// Write the module to an object file exporting bf_main, without linking it against the runner.
+pub fn compile_llvm_to_object( module : &Module, output_path : &Path ) -> Result<()> {
+    Target::initialize_all(&InitializationConfig::default());
+
+    let target_triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&target_triple).unwrap();
+    let target_machine = target
+        .create_target_machine(
+            &target_triple,
+            "generic",
+            "",
+            OptimizationLevel::Default,
+            RelocMode::PIC,
+            CodeModel::Default)
+        .unwrap();
+
+    module.set_triple(&target_triple);
+    module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+
+    target_machine.write_to_file(module, FileType::Object, output_path).map_err(|e| Box::new(e))?;
+
+    return Ok(());
+}
+
+pub fn compile_llvm_to_exe( module : &Module, output_path : &str, dump_llvm : bool, keep_temps : bool ) -> Result<()> {
+    let output_dir = clang_temp_dir(keep_temps)?;
+
+    let runner_path = output_dir.path().join("bf_main.c");
+    let mut runner_file = File::create(runner_path.clone()).map_err(|e| Box::new(e))?;
+    write!(runner_file, "{}", TEST_RUNNER).map_err(|e| Box::new(e))?;
+
+    let bf_obj_path = output_dir.path().join("bf_program.o");
+    compile_llvm_to_object(module, &bf_obj_path)?;
+
+    if dump_llvm {
+        module.write_bitcode_to_path(Path::new("bf_program.bc"));
+    }
+
+    let mut clang = Command::new("clang");
+    clang.arg(runner_path)
+        .arg(bf_obj_path)
+        .arg("-march=native")
+        .arg("-o")
+        .arg(output_path);
+
+    if cfg!(test) {
+        clang.arg("-DBF_EXIT_MARKER");
+    }
+
+    return run_clang(&mut clang);
+}
+
+// Load a module written by compile-llvm -S, so it can be linked without compiling the BF source again.
+pub fn load_llvm_bitcode<'a>( context : &'a Context, path : &Path ) -> Result<Module<'a>> {
+    let module = Module::parse_bitcode_from_path(path, context).map_err(|e| Box::new(e))?;
+
+    // The runner calls bf_main, so catch bitcode from elsewhere before clang fails to link it.
+    if module.get_function("bf_main").is_none() {
+        return Err(Box::new(MissingBfMain));
+    }
+
+    return Ok(module);
+}
+
+// What this build of brainbug can do on this machine, for front ends deciding which options to offer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub version: &'static str,
+
+    // Output formats of the compile and compile-llvm modes
+    pub targets: Vec<&'static str>,
+
+    pub llvm_version: (u32, u32, u32),
+    pub clang_found: bool,
+
+    // Whether the CPU running brainbug can execute the AVX2 scans compile generates by default
+    pub host_avx2: bool,
+}
+
+impl Capabilities {
+    pub fn to_json(&self) -> String {
+        let targets : Vec<String> = self.targets.iter().map(|t| format!("\"{t}\"")).collect();
+        let (major, minor, patch) = self.llvm_version;
+
+        let mut json = "{\n".to_owned();
+        json += &format!("  \"version\": \"{}\",\n", self.version);
+        json += &format!("  \"targets\": [{}],\n", targets.join(", "));
+        json += &format!("  \"llvm_version\": \"{major}.{minor}.{patch}\",\n");
+        json += &format!("  \"clang_found\": {},\n", self.clang_found);
+        json += &format!("  \"host_avx2\": {}\n", self.host_avx2);
+        json += "}";
+        return json;
+    }
+}
+
+pub fn capabilities() -> Capabilities {
+    // Anything but a missing executable means clang is there, even if it didn't like the arguments.
+    let clang_found = match Command::new("clang").arg("--version").output() {
+        Ok(_) => true,
+        Err(e) => e.kind() != ErrorKind::NotFound,
+    };
+
+    #[cfg(target_arch = "x86_64")]
+    let host_avx2 = is_x86_feature_detected!("avx2");
+    #[cfg(not(target_arch = "x86_64"))]
+    let host_avx2 = false;
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        targets: vec!["exe", "asm", "nasm", "llvm-bitcode", "llvm-object", "rust"],
+        llvm_version: inkwell::support::get_llvm_version(),
+        clang_found,
+        host_avx2,
+    }
+}
+
+// Run a clang invocation, turning a failed assemble or link into an error carrying clang's
+// diagnostics.
+fn run_clang( clang : &mut Command ) -> Result<()> {
+    let output = match clang.output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(Box::new(ClangNotFound)),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    if output.status.success() {
+        return Ok(());
+    } else {
+        return Err(Box::new(ClangError {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }));
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+#[derive(Debug, Clone)]
+struct ClangError {
+    status: ExitStatus,
+    stderr: String,
+}
+
+impl fmt::Display for ClangError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "clang failed to assemble and link BF program ({}):\n{}", self.status, self.stderr)
+    }
+}
+
+impl error::Error for ClangError {}
+
+#[derive(Debug, Clone)]
+struct ClangNotFound;
+
+impl fmt::Display for ClangNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "clang not found; install LLVM or use the interpreter.")
+    }
+}
+
+impl error::Error for ClangNotFound {}
+
+// Codegen reached a loop whose .IZ/.UZ labels can't both be defined. Brackets are checked before
+// compiling, so this is a compiler bug rather than a problem with the program.
+#[derive(Debug, Clone)]
+struct UnmatchedLoopLabel {
+    pc: usize,
+}
+
+impl fmt::Display for UnmatchedLoopLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "internal compiler error: loop at pc {} has no matching bracket in the generated asm", self.pc)
+    }
+}
+
+impl error::Error for UnmatchedLoopLabel {}
+
+// A Scan or ClearScan with a stride of 0 would never move the head, so it can't be compiled.
+// vectorize_scans never produces one; this catches hand-built or custom-pass instruction streams.
+#[derive(Debug, Clone)]
+struct ZeroScanStride {
+    pc: usize,
+}
+
+impl fmt::Display for ZeroScanStride {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "scan at pc {} has a stride of 0; scan strides must be nonzero", self.pc)
+    }
+}
+
+impl error::Error for ZeroScanStride {}
+
+#[derive(Debug, Clone)]
+struct BadExitCode;
+
+impl fmt::Display for BadExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "exited wit hbad exit code")
+    }
+}
+
+impl error::Error for BadExitCode {}
+
+#[derive(Debug, Clone)]
+struct MissingBfMain;
+
+impl fmt::Display for MissingBfMain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bitcode doesn't define bf_main; was it written by compile-llvm -S?")
+    }
+}
+
+impl error::Error for MissingBfMain {}
+
+// Returned by verify_optimization when the optimized program behaves differently from the original.
+#[derive(Debug, Clone)]
+pub struct OptMismatch {
+    pub detail: String,
+}
+
+impl fmt::Display for OptMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "optimization changed the program's behavior: {}", self.detail)
+    }
+}
+
+impl error::Error for OptMismatch {}
+
+// Returned when a compiled program runs past its time limit and is killed.
+#[derive(Debug, Clone)]
+pub struct Timeout {
+    pub limit: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "program didn't finish within {:?} and was killed", self.limit)
+    }
+}
+
+impl error::Error for Timeout {}
+
+// How often wait_with_timeout checks whether the program has exited.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Run a compiled program and return its exit code. Unless the exit code comes from the program's
+// final cell, anything but 0 is an error. With a timeout, a program still running after it is killed.
+pub fn run( exe_path : &str, exit_from_cell : bool, timeout : Option<Duration> ) -> Result<u8> {
+    let exe_path = "./".to_owned() + exe_path;
+    let status = match timeout {
+        Some(timeout) => run_with_timeout(&exe_path, timeout)?,
+        None => run_interactive(&exe_path)?,
+    };
+    match status.code() {
+        Some(0) => return Ok(0),
+        Some(code) if exit_from_cell => return Ok(code as u8),
+        _ => return Err(Box::new(BadExitCode)),
+    }
+}
+
+// Run a compiled program attached to this process's stdin, stdout and stderr, so it can interact with
+// the terminal. compile_run_capture runs one with fixed input and captures its output instead.
+pub fn run_interactive( exe_path : &str ) -> Result<ExitStatus> {
+    let status = Command::new(exe_path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status().map_err(|e| Box::new(e))?;
+    return Ok(status);
+}
+
+// Like run_interactive, but kill the program and return Timeout if it runs for longer than timeout.
+pub fn run_with_timeout( exe_path : &str, timeout : Duration ) -> Result<ExitStatus> {
+    let mut child = Command::new(exe_path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn().map_err(|e| Box::new(e))?;
+    return wait_with_timeout(&mut child, timeout);
+}
+
+fn wait_with_timeout( child : &mut Child, timeout : Duration ) -> Result<ExitStatus> {
+    let start_time = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| Box::new(e))? {
+            return Ok(status);
+        }
+
+        if start_time.elapsed() >= timeout {
+            child.kill().map_err(|e| Box::new(e))?;
+            child.wait().map_err(|e| Box::new(e))?;
+            return Err(Box::new(Timeout { limit: timeout }));
+        }
+
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+}
+
+// Which compiler turns the program into machine code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    // brainbug's own assembly, assembled by clang
+    Asm,
+    Llvm,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompileOptions {
+    pub backend: Backend,
+    pub optimize: OptimizeOptions,
+
+    // Use AVX2 for scans. Only affects the asm backend.
+    pub use_avx: bool,
+
+    // Kill the program and fail with Timeout if it runs for longer than this.
+    pub timeout: Option<Duration>,
+
+    // What a read stores once input runs out.
+    pub eof_byte: u8,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            backend: Backend::Asm,
+            optimize: OptimizeOptions::default(),
+            use_avx: true,
+            timeout: None,
+            eof_byte: 255,
+        }
+    }
+}
+
+// Compile a program to a temporary exe, run it with input as its stdin, and capture its stdout and
+// stderr. Compile errors come back as io::Error with clang's diagnostics in the message.
+pub fn compile_run_capture( src : &str, input : &[u8], opts : CompileOptions ) -> io::Result<Output> {
+    return run_capture(&mut lex(src), input, opts).map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()));
+}
+
+fn run_capture( program : &mut Vec<Instruction>, input : &[u8], opts : CompileOptions ) -> Result<Output> {
+    let output_dir = tempfile::Builder::new()
+        .keep(false)
+        .tempdir().map_err(|e| Box::new(e))?;
+    let exe_path = output_dir.path().join("bf.exe");
+
+    match opts.backend {
+        Backend::Asm => {
+            let mut times = CompileTimes::default();
+            let asm = compile_to_asm(program, opts.optimize, AsmOptions { use_avx: opts.use_avx, eof_byte: opts.eof_byte, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut times)?.0;
+            compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { use_avx: opts.use_avx, ..ExeOptions::default() }, &mut times)?;
+        },
+
+        Backend::Llvm => {
+            let context = Context::create();
+            let module = compile_to_llvm(&context, program, opts.optimize, false, opts.eof_byte).0;
+            compile_llvm_to_exe(&module, exe_path.to_str().unwrap(), false, false)?;
+        },
+    }
+
+    return capture_output(&mut Command::new(exe_path), input, opts.timeout);
+}
+
+// Run cmd with input on its stdin, collecting its output. With a timeout, the command is killed and
+// Timeout returned if it runs for longer. Input is written and output drained on their own threads, so
+// a command that doesn't read all its input, or writes a lot before reading, can't block this.
+fn capture_output( cmd : &mut Command, input : &[u8], timeout : Option<Duration> ) -> Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn().map_err(|e| Box::new(e))?;
+
+    let stdout = read_pipe_in_background(child.stdout.take().unwrap());
+    let stderr = read_pipe_in_background(child.stderr.take().unwrap());
+    let stdin = write_pipe_in_background(child.stdin.take().unwrap(), input.to_vec());
+
+    let status = match timeout {
+        Some(timeout) => wait_with_timeout(&mut child, timeout)?,
+        None => child.wait().map_err(|e| Box::new(e))?,
+    };
+
+    // A command may exit without reading all of its input.
+    match stdin.join().unwrap() {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(Box::new(e)),
+        _ => (),
+    }
+
+    return Ok(Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    });
+}
+
+fn read_pipe_in_background( mut pipe : impl io::Read + Send + 'static ) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+// Write bytes to the pipe, closing it once they're written.
+fn write_pipe_in_background( mut pipe : impl io::Write + Send + 'static, bytes : Vec<u8> ) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || pipe.write_all(&bytes))
+}
+
+// Long enough for the slowest test program, so a miscompiled one that never exits fails instead of
+// hanging the test run.
+#[cfg(test)]
+const TEST_RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+fn compile_and_run_asm_with_input( program : &mut Vec<Instruction>, program_input : &Vec<u8>, do_simplify_loops : bool, do_simplify_scans : bool, do_partial_eval : bool ) -> Result<Output> {
+    let opts = CompileOptions {
+        backend: Backend::Asm,
+        optimize: OptimizeOptions {
+            simplify_loops: do_simplify_loops,
+            vectorize_scans: do_simplify_scans,
+            partial_eval: do_partial_eval,
+            partial_eval_past_loops: false,
+            partial_eval_output_limit: None,
+            eliminate_dead_loops: do_simplify_loops,
+            unroll_limit: 0,
+            exit_from_cell: false,
+        },
+        use_avx: true,
+        timeout: Some(TEST_RUN_TIMEOUT),
+        eof_byte: 255,
+    };
+    return run_capture(program, program_input, opts);
+}
+
+#[cfg(test)]
+fn compile_and_run_llvm_with_input( program : &mut Vec<Instruction>, program_input : &Vec<u8>, do_simplify_loops : bool, do_simplify_scans : bool, do_partial_eval : bool ) -> Result<Output> {
+    let opts = CompileOptions {
+        backend: Backend::Llvm,
+        optimize: OptimizeOptions {
+            simplify_loops: do_simplify_loops,
+            vectorize_scans: do_simplify_scans,
+            partial_eval: do_partial_eval,
+            partial_eval_past_loops: false,
+            partial_eval_output_limit: None,
+            eliminate_dead_loops: do_simplify_loops,
+            unroll_limit: 0,
+            exit_from_cell: false,
+        },
+        use_avx: true,
+        timeout: Some(TEST_RUN_TIMEOUT),
+        eof_byte: 255,
+    };
+    return run_capture(program, program_input, opts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::*;
+    use std::io::Read;
+    use rand::*;
+
+    #[test]
+    fn test_execute_empty_asm() {
+        let mut input = Vec::new();
+        input.write("".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(""), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_empty_llvm() {
+        let mut input = Vec::new();
+        input.write("".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(""), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_read_write_char() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_read_write_char_llvm() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_increment() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",+."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("1").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_increment_llvm() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",+."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("1").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_decrement() {
+        let mut input = Vec::new();
+        input.write("1".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",-."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("0").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_decrement_llvm() {
+        let mut input = Vec::new();
+        input.write("1".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",-."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("0").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_move_right() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_move_right_llvm() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_move_left() {
+        let mut input = Vec::new();
+        input.write("AB".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>,<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        assert!(output.find("B").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_move_left_llvm() {
+        let mut input = Vec::new();
+        input.write("AB".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>,<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        assert!(output.find("B").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_jump_if_zero_take_jump() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>[<.>]"), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_jump_if_zero_take_jump_llvm() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>[<.>]"), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_none());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_jump_if_zero_dont_take_jump() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>+[<.>-]"), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_jump_if_zero_dont_take_jump_llvm() {
+        let mut input = Vec::new();
+        input.write("A".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+[<.>-]"), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("A").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_loop() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++++[<+>-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("5").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_loop_llvm() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++++[<+>-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("5").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_inner_loop() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++[>++[<<+>>-]<-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+
+        assert!(output.find("6").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_inner_loop_llvm() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++[>++[<<+>>-]<-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+
+        assert!(output.find("6").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_multiple_loops() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++[<+>-]++[<+>-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("5").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_multiple_loops_llvm() {
+        let mut input = Vec::new();
+        input.write("0".as_bytes());
+
+        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++[<+>-]++[<+>-]<."), &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = String::from_utf8(run_res.stdout).unwrap();
+        assert!(output.find("5").is_some());
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_decrement_loop_to_zero() {
+        let mut prog = lex("[-]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [Instruction::Zero, Instruction::Nop, Instruction::Nop]);
+    }
+
+    #[test]
+    fn test_simplified_loops_canonical() {
+        let optimized = |src : &str| {
+            let mut prog = lex(src);
+            optimize(&mut prog, OptimizeOptions::default());
+            prog
+        };
+
+        // Same effect, cells visited in different orders and the index changed first or last
+        let copies = ["+[->+>>-<<<]", "+[->>>-<<+<]", "+[>>>-<<+<-]", "+[>+>>-<<<-]"];
+        for src in copies {
+            assert_eq!(optimized(src), optimized(copies[0]), "{src}");
+        }
+        assert_eq!(optimized(copies[0])[..4], [Instruction::Increment, Instruction::Add(1), Instruction::Sub(3), Instruction::Zero]);
+
+        let clears = ["+[>[-]<<<[-]>>-]", "+[<<[-]>>>[-]<-]"];
+        assert_eq!(optimized(clears[0]), optimized(clears[1]));
+    }
+
+
+    #[test]
+    fn test_execute_decrement_loop_to_zero() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[-].");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 0);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+#[test]
+    fn test_execute_decrement_loop_to_zero_llvm() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[-].");
+
+        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 0);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_clear_and_set() {
+        let mut prog = lex("[-]+++");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::SetCurrent(3),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_clear_and_set_wraps() {
+        let mut prog = lex("[-]-->.");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::SetCurrent(254),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveRight,
+            Instruction::Write,
+        ]);
+    }
+
+    #[test]
+    fn test_execute_clear_and_set() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[-]+++.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 3);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_multi_clear_loop() {
+        let mut prog = lex("[>[-]>>[-]<<<-]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::JumpIfZero,
+            Instruction::ZeroAt(1),
+            Instruction::ZeroAt(3),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::JumpUnlessZero,
+        ]);
+    }
+
+    #[test]
+    fn test_multi_decrement_loop_is_not_a_clear() {
+        // The other cells only drop by the index cell's value, so this is a pair of subtractions
+        // rather than a clear.
+        let mut prog = lex("[->->-<<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Sub(1),
+            Instruction::Sub(2),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_execute_multi_clear_loop() {
+        let mut input = Vec::new();
+
+        // The first clear loop is skipped since its index is zero, the second clears both cells.
+        let mut prog = lex(">+++>++++<<[>[-]>[-]<<-]>.>.<<+[>[-]>[-]<<-]>.>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output, [3, 4, 0, 0]);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_eliminate_dead_loops() {
+        // The clear collapses to Zero, so the loop after it can't run.
+        let mut prog = lex("+[-][.>]>.");
+        simplify_loops(&mut prog);
+        eliminate_dead_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Increment,
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveRight,
+            Instruction::Write,
+        ]);
+    }
+
+    #[test]
+    fn test_eliminate_dead_loops_after_loop_and_at_start() {
+        let mut prog = lex("[comment, loop.]+[,.][.]");
+        eliminate_dead_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Increment,
+            Instruction::JumpIfZero,
+            Instruction::Read,
+            Instruction::Write,
+            Instruction::JumpUnlessZero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_dead_loops_removed_without_simplifying() {
+        let opts = OptimizeOptions { simplify_loops: false, vectorize_scans: false, ..OptimizeOptions::default() };
+        let mut prog = lex("[a comment, with punctuation.]+[->+<]");
+        optimize(&mut prog, opts);
+        assert_eq!(prog[..4], [Instruction::Nop, Instruction::Nop, Instruction::Nop, Instruction::Nop]);
+        assert_eq!(prog[4..], lex("+[->+<]"));
+
+        let mut prog = lex("[a comment, with punctuation.]+[->+<]");
+        optimize(&mut prog, OptimizeOptions { eliminate_dead_loops: false, ..opts });
+        assert_eq!(prog, lex("[a comment, with punctuation.]+[->+<]"));
+    }
+
+    #[test]
+    fn test_dead_loops_kept_when_simplifying() {
+        let opts = OptimizeOptions { simplify_loops: true, eliminate_dead_loops: false, ..OptimizeOptions::default() };
+        let mut prog = lex("[a comment, with punctuation.]+[->+<]");
+        optimize(&mut prog, opts);
+        assert_eq!(prog[..4], lex("[,.]"));
+        assert!(prog[4..].contains(&Instruction::Add(1)));
+    }
+
+    #[test]
+    fn test_eliminate_dead_loops_after_set_cell() {
+        let mut prog = vec![
+            Instruction::SetHeadPos(1),
+            Instruction::SetCell(0, 3),
+            Instruction::SetCell(1, 0),
+            Instruction::JumpIfZero,
+            Instruction::Write,
+            Instruction::JumpUnlessZero,
+            Instruction::SetHeadPos(0),
+            Instruction::JumpIfZero,
+            Instruction::Write,
+            Instruction::JumpUnlessZero,
+        ];
+        eliminate_dead_loops(&mut prog);
+
+        // Only the loop at the zeroed cell is removed; the head moves back to a nonzero cell for the
+        // second one.
+        assert_eq!(prog, [
+            Instruction::SetHeadPos(1),
+            Instruction::SetCell(0, 3),
+            Instruction::SetCell(1, 0),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::SetHeadPos(0),
+            Instruction::JumpIfZero,
+            Instruction::Write,
+            Instruction::JumpUnlessZero,
+        ]);
+    }
+
+    #[test]
+    fn test_fold_constant_adds() {
+        let mut prog = lex("+++>+<++--<---+");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::AddConst(0, 3),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveRight,
+            Instruction::Increment,
+            Instruction::MoveLeft,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveLeft,
+            Instruction::AddConst(0, 254),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_execute_fold_constant_adds() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("+++++>---<-.>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0], 4);
+        assert_eq!(output[1], 253);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_increment_loop_to_zero() {
+        let mut prog = lex("[+]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [Instruction::Zero, Instruction::Nop, Instruction::Nop]);
+    }
+
+    #[test]
+    fn test_execute_increment_loop_to_zero() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[+].");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 0);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_increment_loop_to_zero_llvm() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[+].");
+
+        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 0);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_decrement_loop_add_1() {
+        let mut prog = lex("[->+<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Add(1),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_execute_decrement_loop_add_1() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[->+<]>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 6);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_decrement_loop_sub_1() {
+        let mut prog = lex("[->-<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Sub(1),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_execute_decrement_loop_sub_1() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[->-<]>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 250);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_decrement_loop_sub_1_llvm() {
+        let mut input = Vec::new();
+
+        let mut prog = lex("++++++[->-<]>.");
+
+        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
+
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 250);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
+    }
+
+    #[test]
+    fn test_execute_sub_loops_llvm() {
+        // Loops that subtract their index from cells on both sides, some more than once, leaving
+        // results that wrapped below zero.
+        let programs = [
+            ("+++++++[->-<]>.", vec![Instruction::Sub(1)]),
+            ("++>>+++++<<<+++++[->>>-<--<-<]>.>.>.>.", vec![Instruction::Sub(1), Instruction::Sub(2), Instruction::Sub(2), Instruction::Sub(3)]),
+            ("+++[-<-<<--->>>>>>-<<<]<.<<.>>>>>>.", vec![Instruction::Sub(-3), Instruction::Sub(-3), Instruction::Sub(-3), Instruction::Sub(-1), Instruction::Sub(3)]),
+        ];
+
+        for (src, subs) in programs {
+            let mut prog = lex(src);
+            simplify_loops(&mut prog);
+            let prog_subs : Vec<Instruction> = prog.into_iter().filter(|inst| matches!(inst, Instruction::Sub(_))).collect();
+            assert_eq!(prog_subs, subs);
+
+            let expected = crate::interp::interp_program(lex(src), b"");
+            let llvm_res = compile_and_run_llvm_with_input(&mut lex(src), &Vec::new(), true, true, false).unwrap();
+            let asm_res = compile_and_run_asm_with_input(&mut lex(src), &Vec::new(), true, true, false).unwrap();
+            assert!(llvm_res.status.success());
+            assert_eq!(llvm_res.stdout, expected);
+            assert_eq!(asm_res.stdout, expected);
+        }
+    }
+
+    #[test]
+    fn test_increment_loop_add_1() {
+        let mut prog = lex("[+>+<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Sub(1),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_increment_loop_add_1_write() {
+        let mut prog = lex("[+>+>.<<]");
+        let prog_orig = prog.clone();
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, prog_orig);
+    }
+
+    #[test]
+    fn test_increment_loop_add_1_read() {
+        let mut prog = lex("[+>+>,<<]");
+        let prog_orig = prog.clone();
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, prog_orig);
+    }
+
+    #[test]
+    fn test_increment_loop_sub_1() {
+        let mut prog = lex("[+>-<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Add(1),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_decrement_loop_add_sub() {
+        let mut prog = lex("[->>+++>>>>>-+---<++<<<<<<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Add(2),
+            Instruction::Add(2),
+            Instruction::Add(2),
+            Instruction::Add(6),
+            Instruction::Add(6),
+            Instruction::Sub(7),
+            Instruction::Sub(7),
+            Instruction::Sub(7),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_increment_loop_add_sub() {
+        let mut prog = lex("[+>>+++>>>>>-+---<++<<<<<<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::Sub(2),
+            Instruction::Sub(2),
+            Instruction::Sub(2),
+            Instruction::Sub(6),
+            Instruction::Sub(6),
+            Instruction::Add(7),
+            Instruction::Add(7),
+            Instruction::Add(7),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
+
+    #[test]
+    fn test_decrement_loop_nested_add_1() {
+        let mut prog = lex("++[->+++[->+<]<]");
+        simplify_loops(&mut prog);
+
+        assert_eq!(prog, [
+            Instruction::AddConst(0, 2),
+            Instruction::Nop,
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::MoveRight,
+            Instruction::AddConst(0, 3),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Add(1),
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveLeft,
+            Instruction::JumpUnlessZero
+        ]);
+    }
+
+    #[test]
+    fn test_execute_decrement_loop_nested_add_1() {
+        let mut input = Vec::new();
 
-    let bf_obj_path = output_dir.path().join("bf_program.o");
+        let mut prog = lex("++[->+++[->+<]<]>>.");
 
-    target_machine.write_to_file(module, FileType::Object, &bf_obj_path).unwrap();
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
 
-    if dump_llvm {
-        module.write_bitcode_to_path(Path::new("bf_program.bc"));
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 6);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
     }
 
-    Command::new("clang")
-        .arg(runner_path)
-        .arg(bf_obj_path)
-        .arg("-march=native")
-        .arg("-o")
-        .arg(output_path.clone())
-        .status().expect("Error compiling BF program.");
-
-    return Ok(());
-}
+    #[test]
+    fn test_execute_decrement_loop_nested_add_1_llvm() {
+        let mut input = Vec::new();
 
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+        let mut prog = lex("++[->+++[->+<]<]>>.");
 
-#[derive(Debug, Clone)]
-struct BadExitCode;
+        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, true, false).unwrap();
+        assert!(run_res.status.success());
 
-impl fmt::Display for BadExitCode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "exited wit hbad exit code")
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 6);
+        let err_output = String::from_utf8(run_res.stderr).unwrap();
+        assert!(err_output.find("Exited successfully").is_some());
     }
-}
 
-impl error::Error for BadExitCode {}
+    #[test]
+    fn test_explain_loops() {
+        let prog = lex("[->+<]+[>]+[--]+[.-]+[[-]-]");
+        let reports = explain_loops(&prog);
+
+        assert_eq!(reports, [
+            LoopReport { start_pc: 0, end_pc: 5, verdict: LoopVerdict::Simplified },
+            LoopReport { start_pc: 7, end_pc: 9, verdict: LoopVerdict::NonzeroHeadDelta(1) },
+            LoopReport { start_pc: 11, end_pc: 14, verdict: LoopVerdict::IndexNotUnit(-2) },
+            LoopReport { start_pc: 16, end_pc: 19, verdict: LoopVerdict::ContainsIo },
+            LoopReport { start_pc: 22, end_pc: 24, verdict: LoopVerdict::Simplified },
+            LoopReport { start_pc: 21, end_pc: 26, verdict: LoopVerdict::ContainsLoop },
+        ]);
+        assert_eq!(reports[1].to_string(), "loop at pc 7-9: not simplified, head moves 1 cells per iteration");
 
-pub fn run( exe_path : &str ) -> Result<()> {
-    let status = Command::new("./".to_owned() + exe_path).status().expect("Error executing BF program.");
-    if status.success() {
-        return Ok(());
-    } else {
-        return Err(Box::new(BadExitCode));
+        // Explaining doesn't touch the program.
+        assert_eq!(prog, lex("[->+<]+[>]+[--]+[.-]+[[-]-]"));
+
+        assert_eq!(explain_loop(&prog, 11), Some(reports[2].clone()));
+        assert_eq!(explain_loop(&prog, 22).unwrap().verdict, LoopVerdict::Simplified);
+        assert_eq!(explain_loop(&prog, 12), None);
     }
-}
 
-fn compile_and_run_asm_with_input( program : &mut Vec<Instruction>, program_input : &Vec<u8>, do_simplify_loops : bool, do_simplify_scans : bool, do_partial_eval : bool ) -> Result<Output> {
-    let output_dir = tempfile::Builder::new()
-        .keep(false)
-        .tempdir().map_err(|e| Box::new(e))?;
+    #[test]
+    fn test_explain_optimized_loops() {
+        let prog = lex("++[>+<-]>,[>]");
+        let opts = OptimizeOptions { partial_eval: false, ..OptimizeOptions::default() };
+        assert_eq!(explain_optimized_loops(&prog, opts), explain_loops(&prog));
 
-    let asm = compile_to_asm(program, do_simplify_loops, do_simplify_scans, do_partial_eval);
+        assert_eq!(explain_loops(&prog)[0], LoopReport { start_pc: 2, end_pc: 7, verdict: LoopVerdict::Simplified });
 
-    let exe_path = output_dir.path().join("bf.exe");
-    compile_asm_to_exe(&asm, exe_path.to_str().unwrap()).expect("failed to compile program");
+        // Partial eval and unrolling remove the first loop and renumber the scan.
+        let scan = |start_pc| vec![LoopReport { start_pc, end_pc: start_pc + 2, verdict: LoopVerdict::NonzeroHeadDelta(1) }];
+        assert_eq!(explain_optimized_loops(&prog, OptimizeOptions { partial_eval: true, ..opts }), scan(3));
+        assert_eq!(explain_optimized_loops(&prog, OptimizeOptions { unroll_limit: 20, ..opts }), scan(12));
+    }
 
-    let cmd = Command::new(exe_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn().expect("Error starting BF program.");
-    cmd.stdin.as_ref().unwrap().write_all(program_input).unwrap();
-    // write!(cmd.stdin.as_ref().unwrap(), "{}", program_input).unwrap();
-    
-    let output = cmd.wait_with_output().expect("Error running BF program.");
-    return Ok(output);
-}
+    #[test]
+    fn test_scan_loop() {
+        let mut prog = lex("[>]");
+        vectorize_scans(&mut prog);
 
-fn compile_and_run_llvm_with_input( program : &mut Vec<Instruction>, program_input : &Vec<u8>, do_simplify_loops : bool, dump_llvm : bool ) -> Result<Output> {
-    let output_dir = tempfile::Builder::new()
-        .keep(false)
-        .tempdir().map_err(|e| Box::new(e))?;
+        assert_eq!(prog, [
+            Instruction::Scan(1),
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
 
-    let context = Context::create();
-    let module = compile_to_llvm(&context, program, do_simplify_loops );
+    #[test]
+    fn test_scan_loop_left() {
+        let mut prog = lex("[<]");
+        vectorize_scans(&mut prog);
 
-    let exe_path = output_dir.path().join("bf.exe");
-    compile_llvm_to_exe(&module, exe_path.to_str().unwrap(), dump_llvm).expect("failed to compile program");
+        assert_eq!(prog, [
+            Instruction::Scan(-1),
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
 
-    let cmd = Command::new(exe_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn().expect("Error starting BF program.");
-    cmd.stdin.as_ref().unwrap().write_all(program_input).unwrap();
-    // write!(cmd.stdin.as_ref().unwrap(), "{}", program_input).unwrap();
-    
-    let output = cmd.wait_with_output().expect("Error running BF program.");
-    return Ok(output);
-}
+    #[test]
+    fn test_scan_loop_2() {
+        let mut prog = lex("[>>]");
+        vectorize_scans(&mut prog);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::*;
-    use std::io::Read;
-    use rand::*;
+        assert_eq!(prog, [
+            Instruction::Scan(2),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
+    }
 
     #[test]
-    fn test_execute_empty_asm() {
+    fn test_execute_scan_loop() {
         let mut input = Vec::new();
-        input.write("".as_bytes());
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(""), &input, true, true, false).unwrap();
+        let mut prog = lex("+>++>+++>++++>+++++>>++++++<<<<<<[>]>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
         assert!(run_res.status.success());
 
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 6);
         let err_output = String::from_utf8(run_res.stderr).unwrap();
         assert!(err_output.find("Exited successfully").is_some());
     }
 
     #[test]
-    fn test_execute_empty_llvm() {
+    fn test_execute_scan_loop_left() {
         let mut input = Vec::new();
-        input.write("".as_bytes());
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(""), &input, true, false ).unwrap();
+        let mut prog = lex("+<++<+++<++++<+++++<<++++++>>>>>>[<]<.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
         assert!(run_res.status.success());
 
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 6);
         let err_output = String::from_utf8(run_res.stderr).unwrap();
         assert!(err_output.find("Exited successfully").is_some());
     }
 
+
     #[test]
-    fn test_execute_read_write_char() {
+    fn test_execute_scan_loop_2() {
         let mut input = Vec::new();
-        input.write("A".as_bytes());
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",."), &input, true, true, false).unwrap();
+        let mut prog = lex(">+>>++>>+++>>>++++>+++++<<<<<<<<[>>]>.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
         assert!(run_res.status.success());
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 4);
         let err_output = String::from_utf8(run_res.stderr).unwrap();
         assert!(err_output.find("Exited successfully").is_some());
     }
 
     #[test]
-    fn test_execute_read_write_char_llvm() {
+    fn test_execute_scan_loop_2_left() {
         let mut input = Vec::new();
-        input.write("A".as_bytes());
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",."), &input, true, false).unwrap();
+        let mut prog = lex("+<<++<<+++<<<++++<+++++>>>>>>>>[<<]<.");
+
+        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
         assert!(run_res.status.success());
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
+        let output = run_res.stdout;
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0], 4);
         let err_output = String::from_utf8(run_res.stderr).unwrap();
         assert!(err_output.find("Exited successfully").is_some());
     }
 
+
     #[test]
-    fn test_execute_increment() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_execute_random_programs_match_interp() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",+."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let num_tests = 100;
+        let max_len = 40;
+        let step_limit = 100000;
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("1").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let mut num_compared = 0;
+        for _ in 0..num_tests {
+            let len = rng.gen_range(1..=max_len);
+            let mut src = String::new();
+            gen_random_program(&mut rng, &mut src, &mut 0, &mut Vec::new(), 0, len, false);
+            let input : Vec<u8> = (0..rng.gen_range(0..8)).map(|_| rng.gen()).collect();
+
+            // Programs that don't halt within the step limit are skipped.
+            let mut state = State::new(lex(&src));
+            state.set_step_limit(step_limit);
+            let mut expected = Vec::new();
+            if state.interp_with_max_output(&input[..], &mut expected, usize::MAX).is_err() {
+                continue;
+            }
+
+            let (simplify, scans, partial_eval) = (rng.gen(), rng.gen(), rng.gen());
+            let run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, simplify, scans, partial_eval).unwrap();
+            assert!(run_res.status.success(), "{} exited with {}", src, run_res.status);
+            assert_eq!(run_res.stdout, expected, "{} with input {:?}", src, input);
+            num_compared += 1;
+        }
+
+        // Most programs should halt, or this isn't testing much.
+        assert!(num_compared > num_tests / 2);
     }
 
     #[test]
-    fn test_execute_increment_llvm() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_execute_scan_loop_random() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",+."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+        let num_tests = 10;
+        let num_cells = 50;
+        let max_scan = 20;
+
+        for _ in 0..num_tests {
+            let mut input_prog = Vec::new();
+
+            let scan_dist = rand::distributions::Uniform::new(-max_scan, max_scan);
+            let mut scan_num_skipped : i32 = rng.sample(scan_dist);
+            if scan_num_skipped == 0 {
+                scan_num_skipped += 1;
+            }
+
+            // Select a random cell to hold cell
+            let rand_cell_dist = rand::distributions::Uniform::new(0, num_cells);
+            let cell_idx = rng.sample(rand_cell_dist);
+
+            // Generate a random input number for each cell.
+            let scan_dist_cells = rand::distributions::Uniform::new(1, 255);
+            for cell in 0..num_cells {
+                if cell != cell_idx {
+                    let cell_value = rng.sample(scan_dist_cells);
+                    for j in 0..cell_value {
+                        input_prog.push("+");
+                    }
+                }
+
+                if scan_num_skipped > 0 {
+                    input_prog.push(">");
+                } else {
+                    input_prog.push("<");
+                }
+            }
+
+            // Reset head
+            for i in 0..num_cells {
+                if scan_num_skipped > 0 {
+                    input_prog.push("<");
+                } else {
+                    input_prog.push(">");
+                }
+            }
+
+            // Generate scan loop
+            // TODO(Kincaid): Add support for negative head deltas
+            input_prog.push("[");
+            for _ in 0..(scan_num_skipped.abs()) {
+                if scan_num_skipped > 0 {
+                    input_prog.push(">");
+                } else {
+                    input_prog.push("<");
+                }
+            }
+            input_prog.push("]");
+            if scan_num_skipped > 0 {
+                input_prog.push(">");
+            } else {
+                input_prog.push("<");
+            }
+            input_prog.push(".");
+
+            let mut prog = lex(&(input_prog.join("")));
+            //println!("{}", input.join(""));
+            //assert!(false);
+
+            let mut input = Vec::new();
+            
+            // Execute without vectorizing scans
+            let no_vec_run_res = compile_and_run_asm_with_input(&mut prog, &input, false, false, false).unwrap();
+            assert!(no_vec_run_res.status.success());
+
+            let no_vec_output = no_vec_run_res.stdout;
+            assert_eq!(no_vec_output.len(), 1);
+            let no_vec_res = no_vec_output[0];
+
+            let no_vec_err_output = String::from_utf8(no_vec_run_res.stderr).unwrap();
+            assert!(no_vec_err_output.find("Exited successfully").is_some());
+
+            // Execute with vectorized scans
+            let with_vec_run_res = compile_and_run_asm_with_input(&mut prog, &input, false, true, false).unwrap();
+            assert!(with_vec_run_res.status.success());
+
+            let with_vec_output = with_vec_run_res.stdout;
+            assert_eq!(with_vec_output.len(), 1);
+            let with_vec_res = with_vec_output[0];
+
+            let with_vec_err_output = String::from_utf8(with_vec_run_res.stderr).unwrap();
+            assert!(with_vec_err_output.find("Exited successfully").is_some());
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("1").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+            assert_eq!(with_vec_res, no_vec_res);
+        }
     }
 
-    #[test]
-    fn test_execute_decrement() {
-        let mut input = Vec::new();
-        input.write("1".as_bytes());
-
-        let run_res = compile_and_run_asm_with_input(&mut lex(",-."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    // A tape of distinct nonzero cells except for a zero stride * steps cells from where the head
+    // starts, then a scan loop, then writes of the cells either side of where the head ends up.
+    fn scan_landing_program( stride : i32, steps : i32 ) -> String {
+        let num_cells = 80;
+        let start = if stride > 0 { 1 } else { num_cells - 2 };
+        let zero = start + stride * steps;
+
+        let mut src = String::new();
+        for i in 0..num_cells {
+            let val = if i == zero { 0 } else { i as usize % 250 + 1 };
+            src += &"+".repeat(val);
+            src += ">";
+        }
+        src += &"<".repeat((num_cells - start) as usize);
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("0").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let step = if stride > 0 { ">" } else { "<" };
+        src += &format!("[{}]<.>>.", step.repeat(stride.unsigned_abs() as usize));
+        return src;
     }
 
     #[test]
-    fn test_execute_decrement_llvm() {
-        let mut input = Vec::new();
-        input.write("1".as_bytes());
+    fn test_scan_landing_matches_loop() {
+        for stride in [1, 2, 3, -1, -2, -3] {
+            for steps in [0, 1, 7, 8, 9, 16] {
+                let src = scan_landing_program(stride, steps);
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",-."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+                let mut expected = Vec::new();
+                State::new(lex(&src)).interp(&b""[..], &mut expected).unwrap();
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("0").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+                let mut prog = lex(&src);
+                vectorize_scans(&mut prog);
+                assert!(prog.contains(&Instruction::Scan(stride)));
+
+                let mut output = Vec::new();
+                State::new(prog).interp(&b""[..], &mut output).unwrap();
+                assert_eq!(output, expected, "stride {stride}, {steps} steps");
+            }
+        }
     }
 
     #[test]
-    fn test_execute_move_right() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
-
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
-
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+    fn test_execute_scan_landing_matches_loop() {
+        // Steps either side of the 8 cells the AVX2 scan checks at a time
+        for stride in [1, 2, 3, -1, -2, -3] {
+            for steps in [0, 1, 7, 8, 9, 16] {
+                let src = scan_landing_program(stride, steps);
+
+                let mut expected = Vec::new();
+                State::new(lex(&src)).interp(&b""[..], &mut expected).unwrap();
+
+                for (backend, use_avx) in [(Backend::Asm, true), (Backend::Asm, false), (Backend::Llvm, true)] {
+                    let opts = CompileOptions { backend, use_avx, timeout: Some(TEST_RUN_TIMEOUT), ..CompileOptions::default() };
+                    let output = compile_run_capture(&src, b"", opts).unwrap();
+                    assert_eq!(output.stdout, expected, "stride {stride}, {steps} steps on {backend:?}, use_avx {use_avx}");
+                }
+            }
+        }
     }
 
     #[test]
-    fn test_execute_move_right_llvm() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
-
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
-
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+    fn test_scan_lanes_asm() {
+        let compile_avx = |use_avx, lanes| compile_to_asm(&mut lex(",[>>>]<[<<]."), OptimizeOptions::default(), AsmOptions { use_avx, scan_lanes: lanes, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        let compile = |lanes| compile_avx(true, lanes);
+
+        let asm = compile(8);
+        assert!(asm.contains("vpgatherdd	%ymm3, (%rax,%ymm0), %ymm4"));
+        assert!(asm.contains("_ymm@indices3:\n\t.long\t0\n\t.long\t3\n\t.long\t6\n\t.long\t9\n\t.long\t12\n"));
+        assert!(asm.contains("movl	$24, %edx"));
+
+        let asm = compile(4);
+        assert!(asm.contains("vpgatherdd	%xmm3, (%rax,%xmm0), %xmm4"));
+        assert!(!asm.contains("%ymm3"));
+        assert!(asm.contains("_xmm@indices3:\n\t.long\t0\n\t.long\t3\n\t.long\t6\n\t.long\t9\n\t.globl"));
+        assert!(asm.contains("_xmm@indicesneg2:\n\t.long\t6\n"));
+        assert!(asm.contains("movl	$12, %edx"));
+        assert!(asm.contains("addq	$-6, %rax"));
+
+        let asm = compile(1);
+        assert!(!asm.contains("vpgatherdd"));
+
+        // SSE2 only
+        let asm = compile_avx(false, 4);
+        assert!(!asm.contains("vp"));
+        assert!(asm.contains("\tmovzbl 9(%rax), %edx\n\tshll $24, %edx\n"));
+        assert!(asm.contains("\tmovzbl -6(%rax), %edx\n"));
+        assert!(asm.contains("\tpcmpeqb %xmm1, %xmm3\n"));
+        assert!(asm.contains("\taddq $-8, %rax\n"));
     }
 
     #[test]
-    fn test_execute_move_left() {
-        let mut input = Vec::new();
-        input.write("AB".as_bytes());
+    fn test_execute_scan_lanes() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>,<."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        // Steps either side of the 4 cells an xmm scan checks at a time
+        for stride in [1, 2, 3, -1, -2, -3] {
+            for steps in [0, 1, 3, 4, 5, 8] {
+                let src = scan_landing_program(stride, steps);
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
-        assert!(output.find("B").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+                let mut expected = Vec::new();
+                State::new(lex(&src)).interp(&b""[..], &mut expected).unwrap();
+
+                for (use_avx, lanes) in [(true, 1), (true, 4), (false, 4)] {
+                    let mut times = CompileTimes::default();
+                    let asm = compile_to_asm(&mut lex(&src), OptimizeOptions::default(), AsmOptions { use_avx, scan_lanes: lanes, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+                    compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { use_avx, ..ExeOptions::default() }, &mut times).unwrap();
+
+                    let output = Command::new(&exe_path).output().unwrap();
+                    assert_eq!(output.stdout, expected, "stride {stride}, {steps} steps, {lanes} lanes, avx {use_avx}");
+                }
+            }
+        }
     }
 
     #[test]
-    fn test_execute_move_left_llvm() {
-        let mut input = Vec::new();
-        input.write("AB".as_bytes());
-
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>,<."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+    fn test_scan_loop_non_power_2() {
+        let mut prog = lex("[>>>]");
+        vectorize_scans(&mut prog);
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
-        assert!(output.find("B").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert_eq!(prog, [
+            Instruction::Scan(3),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
     }
 
     #[test]
-    fn test_execute_jump_if_zero_take_jump() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
-
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>[<.>]"), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    fn test_clear_scan_loop() {
+        let mut prog = lex("+[[-]>]");
+        simplify_loops(&mut prog);
+        vectorize_scans(&mut prog);
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert_eq!(prog, [
+            Instruction::Increment,
+            Instruction::ClearScan(1),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
     }
 
     #[test]
-    fn test_execute_jump_if_zero_take_jump_llvm() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
-
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>[<.>]"), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+    fn test_clear_scan_loop_clears_after_moving_is_not_a_clear_scan() {
+        let mut prog = lex("+[>[-]]");
+        simplify_loops(&mut prog);
+        vectorize_scans(&mut prog);
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_none());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert!(!prog.iter().any(|inst| matches!(inst, Instruction::ClearScan(_) | Instruction::Scan(_))));
     }
 
     #[test]
-    fn test_execute_jump_if_zero_dont_take_jump() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
+    fn test_execute_clear_scan_random() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>+[<.>-]"), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let num_cells = 200;
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
-    }
+        for stride in [1i32, -1, 3, -2] {
+            let (fwd, back) = if stride > 0 { (">", "<") } else { ("<", ">") };
 
-    #[test]
-    fn test_execute_jump_if_zero_dont_take_jump_llvm() {
-        let mut input = Vec::new();
-        input.write("A".as_bytes());
+            // Fill every cell with a nonzero value except one sentinel on the stride, far enough
+            // out that the unit strides take the 32 byte path.
+            let sentinel = rng.gen_range(40..60) * stride.abs() as usize;
+            let mut src = String::new();
+            for cell in 0..num_cells {
+                if cell != sentinel {
+                    src += &"+".repeat(rng.gen_range(1..255));
+                }
+                src += fwd;
+            }
+            src += &back.repeat(num_cells);
+
+            // Clear up to the sentinel, then go back to the start and print every cell.
+            src += "[[-]";
+            src += &fwd.repeat(stride.abs() as usize);
+            src += "]";
+            src += &back.repeat(sentinel);
+            for _ in 0..num_cells {
+                src += ".";
+                src += fwd;
+            }
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+[<.>-]"), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+            let input = Vec::new();
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("A").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+            let mut interp_output = Vec::new();
+            let mut state = State::new(lex(&src));
+            state.interp(&input[..], interp_output.by_ref()).unwrap();
+
+            let scalar_run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, true, false, false).unwrap();
+            assert!(scalar_run_res.status.success());
+            assert_eq!(scalar_run_res.stdout, interp_output);
+
+            let vec_run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, true, true, false).unwrap();
+            assert!(vec_run_res.status.success());
+            assert_eq!(vec_run_res.stdout, interp_output);
+        }
     }
 
     #[test]
-    fn test_execute_loop() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_compile_llvm_to_object() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let obj_path = output_dir.path().join("bf_program.o");
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++++[<+>-]<."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let context = Context::create();
+        let mut prog = lex("+[>+<-].");
+        let module = compile_to_llvm(&context, &mut prog, OptimizeOptions::default(), false, 255).0;
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("5").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        compile_llvm_to_object(&module, &obj_path).unwrap();
+
+        assert!(metadata(&obj_path).unwrap().len() > 0);
+        assert!(module.get_function("bf_main").is_some());
     }
 
     #[test]
-    fn test_execute_loop_llvm() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_compile_many_to_llvm() {
+        let context = Context::create();
+        let programs = vec![lex("+[>+<-]."), lex(",[.,]"), lex("")];
+        let modules = compile_many_to_llvm(&context, &programs, OptimizeOptions::default(), false, 255);
+
+        assert_eq!(modules.len(), 3);
+        for (i, module) in modules.iter().enumerate() {
+            assert!(module.get_function(&format!("bf_main_{i}")).is_some());
+            assert!(module.get_function("bf_main").is_none());
+        }
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++++[<+>-]<."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+        // The programs themselves aren't optimized in place.
+        assert_eq!(programs[0], lex("+[>+<-]."));
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("5").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        // Output masking and the EOF byte reach every module.
+        let ir = compile_many_to_llvm(&context, &programs, OptimizeOptions::default(), false, 255)[1].print_to_string().to_string();
+        assert!(!ir.contains("ascii_val") && !ir.contains("read_value_or_eof"));
+        for module in compile_many_to_llvm(&context, &programs[..2], OptimizeOptions::default(), true, 0) {
+            let ir = module.print_to_string().to_string();
+            assert!(ir.contains("ascii_val"));
+            assert_eq!(ir.contains("read_value_or_eof"), module.get_function("bf_main_1").is_some());
+        }
     }
 
     #[test]
-    fn test_execute_inner_loop() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
-
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++[>++[<<+>>-]<-]<."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    fn test_debug_loops_not_simplified() {
+        let mut prog = lex_with_options("+[-$>+<]", &LexOptions::with_builtin_debug_commands());
+        let expected = prog.clone();
+        optimize(&mut prog, OptimizeOptions::default());
+        assert_eq!(prog, expected);
+    }
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
+    #[test]
+    fn test_optimize() {
+        let mut prog = lex("+++.[-]>[>]");
+        optimize(&mut prog, OptimizeOptions { simplify_loops: true, vectorize_scans: true, partial_eval: false, partial_eval_past_loops: false, partial_eval_output_limit: None, eliminate_dead_loops: true, unroll_limit: 0, exit_from_cell: false });
 
-        assert!(output.find("6").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert_eq!(prog, [
+            Instruction::AddConst(0, 3),
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Write,
+            Instruction::Zero,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::MoveRight,
+            Instruction::Scan(1),
+            Instruction::Nop,
+            Instruction::Nop,
+        ]);
     }
 
     #[test]
-    fn test_execute_inner_loop_llvm() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_pass_pipeline() {
+        let pass_names = |opts| -> Vec<&str> {
+            PassPipeline::from_options(opts).passes.iter().map(|pass| pass.name()).collect()
+        };
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++[>++[<<+>>-]<-]<."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
-
-        let output = String::from_utf8(run_res.stdout).unwrap();
+        let opts = OptimizeOptions { partial_eval: true, unroll_limit: 10, ..OptimizeOptions::default() };
+        assert_eq!(pass_names(opts), ["partial eval", "unroll loops", "eliminate dead loops", "simplify loops", "vectorize scans"]);
+        assert_eq!(pass_names(OptimizeOptions { simplify_loops: false, vectorize_scans: false, eliminate_dead_loops: false, ..opts }), ["partial eval", "unroll loops"]);
 
-        assert!(output.find("6").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
-    }
+        // A custom pass can be mixed in with the built in ones.
+        struct StripNops;
 
-    #[test]
-    fn test_execute_multiple_loops() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+        impl InstructionPass for StripNops {
+            fn name(&self) -> &'static str { "strip nops" }
+            fn run(&mut self, program : &mut Vec<Instruction>, _stats : &mut OptStats) { program.retain(|inst| *inst != Instruction::Nop); }
+        }
 
-        let run_res = compile_and_run_asm_with_input(&mut lex(",>+++[<+>-]++[<+>-]<."), &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let mut pipeline = PassPipeline::new();
+        pipeline.add(Box::new(SimplifyLoops));
+        pipeline.add(Box::new(StripNops));
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("5").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let mut prog = lex(",[-]>,[->+<]");
+        pipeline.run(&mut prog);
+        assert_eq!(prog, [Instruction::Read, Instruction::Zero, Instruction::MoveRight, Instruction::Read, Instruction::Add(1), Instruction::Zero]);
     }
 
     #[test]
-    fn test_execute_multiple_loops_llvm() {
-        let mut input = Vec::new();
-        input.write("0".as_bytes());
+    fn test_partial_eval_after_other_passes() {
+        // Partial eval folds up to the first instruction the earlier passes made, including Nops.
+        let src = "++>+++[-]<[->+++<]>.[-]++++++.>+++[>+>+<<-]>>.";
+        for passes in 0..4 {
+            let mut pipeline = PassPipeline::new();
+            if passes & 1 != 0 {
+                pipeline.add(Box::new(SimplifyLoops));
+            }
+            if passes & 2 != 0 {
+                pipeline.add(Box::new(UnrollLoops { limit: 30 }));
+            }
+            pipeline.add(Box::new(PartialEval { past_unknown_loops: false, output_limit: None, exit_from_cell: false }));
+            pipeline.add(Box::new(VectorizeScans));
 
-        let run_res = compile_and_run_llvm_with_input(&mut lex(",>+++[<+>-]++[<+>-]<."), &input, true, false).unwrap();
-        assert!(run_res.status.success());
+            let mut prog = lex(src);
+            pipeline.run(&mut prog);
+            assert_eq!(crate::interp::interp_program(prog, b""), crate::interp::interp_program(lex(src), b""), "{passes}");
+        }
 
-        let output = String::from_utf8(run_res.stdout).unwrap();
-        assert!(output.find("5").is_some());
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let mut prog = lex("++");
+        prog.extend([Instruction::Nop, Instruction::Zero, Instruction::Increment, Instruction::Write]);
+        partial_eval(&mut prog, false, None, false);
+        assert_eq!(prog, [Instruction::SetCell(0, 2), Instruction::Zero, Instruction::Increment, Instruction::Write]);
     }
 
     #[test]
-    fn test_decrement_loop_to_zero() {
-        let mut prog = lex("[-]");
-        simplify_loops(&mut prog);
-
-        assert_eq!(prog, [Instruction::Zero, Instruction::Nop, Instruction::Nop]);
+    fn test_optimization_stats() {
+        let stats = optimize(&mut lex(",[->+<]>[>]"), OptimizeOptions::default());
+        assert_eq!(stats.to_string(), "eliminate dead loops: 11 -> 11 instructions\n\
+                                       simplify loops: 11 -> 7 instructions\n\
+                                       vectorize scans: 7 -> 5 instructions\n\
+                                       loops simplified: 1\n\
+                                       scans vectorized: 1\n\
+                                       instructions eliminated by partial eval: 0");
+
+        // Compiling reports what the optimization it ran did, and records time against each pass.
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
+        let mut times = CompileTimes::default();
+        let (_, stats) = compile_to_asm(&mut lex("+++[>++<-]>."), opts, AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap();
+        assert_eq!(stats.passes[0], PassStats { name: "partial eval", instructions_before: 12, instructions_after: 1 });
+        assert_eq!(stats.partial_eval_eliminated, 11);
+        assert!(times.partial_eval > 0.0);
+
+        let (_, stats) = compile_to_rust(&mut lex(",[->+<]>[>]"), OptimizeOptions::default()).unwrap();
+        assert_eq!(stats.loops_simplified, 1);
+        assert_eq!(stats.scans_vectorized, 1);
     }
 
+    // Running a pass a second time, alone or after the passes before it in the pipeline, must leave
+    // the program as it is.
+    fn assert_idempotent( program : &[Instruction] ) {
+        let mut earlier_passes : Vec<Box<dyn InstructionPass>> = Vec::new();
+        let passes : Vec<Box<dyn InstructionPass>> = vec![Box::new(SimplifyLoops), Box::new(VectorizeScans)];
+
+        for mut pass in passes {
+            let mut inputs = vec![program.to_vec()];
+            if !earlier_passes.is_empty() {
+                let mut prog = program.to_vec();
+                for earlier in earlier_passes.iter_mut() {
+                    earlier.run(&mut prog, &mut OptStats::default());
+                }
+                inputs.push(prog);
+            }
 
-    #[test]
-    fn test_execute_decrement_loop_to_zero() {
-        let mut input = Vec::new();
+            for input in inputs {
+                let mut once = input.clone();
+                pass.run(&mut once, &mut OptStats::default());
 
-        let mut prog = lex("++++++[-].");
+                let mut twice = once.clone();
+                pass.run(&mut twice, &mut OptStats::default());
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+                assert_eq!(once, twice, "{} changed {:?} on its second run", pass.name(), input);
+            }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 0);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+            earlier_passes.push(pass);
+        }
     }
 
-#[test]
-    fn test_execute_decrement_loop_to_zero_llvm() {
-        let mut input = Vec::new();
-
-        let mut prog = lex("++++++[-].");
-
-        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, false).unwrap();
-        assert!(run_res.status.success());
+    #[test]
+    fn test_passes_idempotent() {
+        let programs = [
+            "",
+            "+[-]",
+            "+++[>++<-]>.",
+            "++[>+++[>++<-]<-]>>.",
+            ",[>+>+<<-]>[-<+>]",
+            ",[->+>-<<]>>[-<<+>>]",
+            "+[>]<[<]",
+            "+>+>+<<[>>]",
+            ",[[-]>]",
+            "+[>[-]>[-]<<-]",
+            "+[-[>+<-]>]",
+            ",[.,]",
+            "++>+++[<[->+<]>-]",
+            "+[>+>+<<-]>>[<<+>>-]<<[[-]>]",
+            "+[>>>]<[<<]",
+            "-[[-]+>[<-->]<]",
+        ];
+
+        for src in programs {
+            assert_idempotent(&lex(src));
+        }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 0);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        for entry in std::fs::read_dir("test-cases").unwrap() {
+            assert_idempotent(&lex(std::fs::read(entry.unwrap().path()).unwrap()));
+        }
     }
 
     #[test]
-    fn test_increment_loop_to_zero() {
-        let mut prog = lex("[+]");
-        simplify_loops(&mut prog);
+    fn test_verify_optimization() {
+        let opts = OptimizeOptions { unroll_limit: 16, ..OptimizeOptions::default() };
+        for src in ["++++++[>++++++++<-]>+.+.+.", "<<+>>+[<[-<+>>+<]>-]<<[>]>.", "+>+>+>>+<<<<[>]+[<]>.", ",[>+<-]>."] {
+            assert!(verify_optimization(&lex(src), opts, 255, 1_000_000).unwrap(), "{src}");
+        }
 
-        assert_eq!(prog, [Instruction::Zero, Instruction::Nop, Instruction::Nop]);
+        // Partial eval leaves the cell the loop cleared out of the folded program, which is fine as
+        // the output is the same.
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
+        assert!(verify_optimization(&lex("+++[>++<-]>."), opts, 0, 1_000_000).unwrap());
+
+        // Never halts
+        assert!(!verify_optimization(&lex("+[]"), OptimizeOptions::default(), 255, 1_000_000).unwrap());
     }
 
     #[test]
-    fn test_execute_increment_loop_to_zero() {
-        let mut input = Vec::new();
-
-        let mut prog = lex("++++++[+].");
-
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    fn test_optimize_partial_eval_only() {
+        let mut prog = lex("+++.[-]>[>]");
+        optimize(&mut prog, OptimizeOptions { simplify_loops: false, vectorize_scans: false, partial_eval: true, partial_eval_past_loops: false, partial_eval_output_limit: None, eliminate_dead_loops: false, unroll_limit: 0, exit_from_cell: false });
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 0);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert_eq!(prog, [Instruction::Output(3)]);
     }
 
     #[test]
-    fn test_execute_increment_loop_to_zero_llvm() {
-        let mut input = Vec::new();
-
-        let mut prog = lex("++++++[+].");
+    fn test_interp_optimized_program() {
+        let opts = OptimizeOptions { partial_eval: true, unroll_limit: 16, ..OptimizeOptions::default() };
 
-        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, false).unwrap();
-        assert!(run_res.status.success());
+        for src in ["++++++[>++++++++<-]>+.+.+.", ">>+>+<<<,[>+>+<<-]>.>.<<[>>>[-]<<<-]+[>>]<.", "+++[>+++[>+<-]<-]>>[<<+>>-]<<.>>>+[<]>."] {
+            let input = b"hi";
+            let expected = crate::interp::interp_program(lex(src), input);
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 0);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+            let mut prog = lex(src);
+            optimize(&mut prog, opts);
+            assert_eq!(crate::interp::interp_program(prog, input), expected, "{src}");
+        }
     }
 
     #[test]
-    fn test_decrement_loop_add_1() {
-        let mut prog = lex("[->+<]");
-        simplify_loops(&mut prog);
+    fn test_partial_eval_merges_outputs() {
+        let mut prog = lex("++++++++[>++++++++<-]>+.+.+.,.+.+.");
+        partial_eval(&mut prog, false, None, false);
 
         assert_eq!(prog, [
-            Instruction::Add(1),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
+            Instruction::OutputStr(b"ABC".to_vec()),
+            Instruction::ReadAt(1),
+            Instruction::WriteAt(1),
+            Instruction::SetHeadPos(1),
+            Instruction::Increment,
+            Instruction::Write,
+            Instruction::Increment,
+            Instruction::Write,
         ]);
+
+        let mut prog = lex("+.>,<+.");
+        partial_eval(&mut prog, false, None, false);
+        assert_eq!(prog, [Instruction::Output(1), Instruction::ReadAt(1), Instruction::Output(2)]);
     }
 
     #[test]
-    fn test_execute_decrement_loop_add_1() {
-        let mut input = Vec::new();
+    fn test_output_str_asm() {
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
 
-        let mut prog = lex("++++++[->+<]>.");
+        let asm = compile_to_asm(&mut lex("+.+.+."), opts, AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("callq bf_write").count(), 1);
+        assert!(asm.find("putchar").is_none());
+        assert!(asm.find(".STR0:\n\t.byte 1, 2, 3\n").is_some());
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let asm = compile_to_asm(&mut lex("+.+.+."), opts, AsmOptions { freestanding: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("cmpq $3, %rax").count(), 1);
+        assert!(asm.find("cmpq $1, %rax").is_none());
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 6);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        // NASM keeps writing a byte at a time
+        let asm = compile_to_asm(&mut lex("+.+.+."), opts, AsmOptions { dialect: AsmDialect::Nasm, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("call putchar").count(), 3);
     }
 
     #[test]
-    fn test_decrement_loop_sub_1() {
-        let mut prog = lex("[->-<]");
-        simplify_loops(&mut prog);
+    fn test_zero_scan_stride() {
+        let opts = OptimizeOptions { simplify_loops: false, vectorize_scans: false, partial_eval: false, partial_eval_past_loops: false, partial_eval_output_limit: None, eliminate_dead_loops: false, unroll_limit: 0, exit_from_cell: false };
+
+        for dialect in [AsmDialect::Att, AsmDialect::Nasm] {
+            for inst in [Instruction::Scan(0), Instruction::ClearScan(0)] {
+                let mut prog = vec![Instruction::Add(0), inst];
+                let err = compile_to_asm(&mut prog, opts, AsmOptions { dialect, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap_err();
+                assert!(err.to_string().contains("pc 1"));
+            }
+        }
+    }
 
-        assert_eq!(prog, [
-            Instruction::Sub(1),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+    #[test]
+    fn test_zero_run_asm() {
+        let compile = |src : &str, checked : bool| compile_to_asm(&mut lex(src), OptimizeOptions::default(), AsmOptions { checked, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+
+        let asm = compile("+>+<[-]>[-]<.", false);
+        assert!(asm.contains("\tmovw $0, 0(%r12)\n"));
+        assert!(!asm.contains("movb $0"));
+
+        // Cells -1 through 2 clear with one 4 byte store, and the head ends up 2 to the right
+        let asm = compile("+[-]>[-]>[-]<<<[-]>>>.", false);
+        assert!(asm.contains("\tmovl $0, -1(%r12)\n\taddq $2, %r12\n"));
+
+        // A gap splits the run, but the pair still merges
+        let asm = compile("+[-]>>[-]>[-].", false);
+        assert!(asm.contains("\tmovb $0, 0(%r12)\n\tmovw $0, 2(%r12)\n\taddq $3, %r12\n"));
+
+        // The first cell is set so its clear isn't removed as a dead loop
+        let asm = compile(&("+".to_owned() + &"[-]>".repeat(17)), false);
+        assert!(asm.contains("\tmovups %xmm0, 0(%r12)\n\tmovb $0, 16(%r12)\n\taddq $17, %r12\n"));
+
+        // Nothing to merge
+        let asm = compile("+[-]>>[-].", false);
+        assert!(!asm.contains("movw"));
+        assert_eq!(asm.matches("movb $0").count(), 2);
+
+        let asm = compile("+>+<[-]>[-]<.", true);
+        assert!(!asm.contains("movw"));
     }
 
     #[test]
-    fn test_execute_decrement_loop_sub_1() {
-        let mut input = Vec::new();
+    fn test_compile_to_rust() {
+        let rust = compile_to_rust(&mut lex("+[>]<[[-]<<]++[->+++<]>."), OptimizeOptions::default()).unwrap().0;
+        assert!(rust.contains("fn main() {"));
+        assert!(rust.contains("\n    head += tape[head..].iter().position(|&c| c == 0)"));
+        assert!(rust.contains("\n    while tape[head] != 0 { tape[head] = 0; head -= 2; }\n"));
+        assert!(rust.contains("\n    tape[head + 1] = tape[head + 1].wrapping_add(tape[head]);\n"));
+        assert!(rust.contains("\n    write_bytes(&mut stdout, &[tape[head]]);\n"));
+
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
+        let rust = compile_to_rust(&mut lex("++++++++++.+++."), opts).unwrap().0;
+        assert!(rust.contains("write_bytes(&mut stdout, b\"\\n\\r\");"));
+
+        let opts = OptimizeOptions { simplify_loops: false, vectorize_scans: false, partial_eval: false, partial_eval_past_loops: false, partial_eval_output_limit: None, eliminate_dead_loops: false, unroll_limit: 0, exit_from_cell: false };
+        let rust = compile_to_rust(&mut lex(",[[-]]"), opts).unwrap().0;
+        assert!(rust.contains("\n    while tape[head] != 0 {\n        while tape[head] != 0 {\n            tape[head] = tape[head].wrapping_sub(1);\n        }\n    }\n"));
+
+        let err = compile_to_rust(&mut lex("[[]"), opts).unwrap_err();
+        assert_eq!(err.to_string(), "unmatched bracket at instruction 0");
+        assert!(compile_to_rust(&mut vec![Instruction::Scan(0)], opts).is_err());
+    }
 
-        let mut prog = lex("++++++[->-<]>.");
+    #[test]
+    fn test_ascii_out_asm() {
+        let program = "+".repeat(200) + ".>" + &"+".repeat(200) + "[.,]";
+        let compile = |dialect, freestanding, ascii_out| compile_to_asm(&mut lex(&program), OptimizeOptions::default(), AsmOptions { dialect, freestanding, ascii_out, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        let asm = compile(AsmDialect::Att, false, true);
+        assert_eq!(asm.matches("\tandl $0x7f, %ecx\n\tcallq putchar\n").count(), 2);
+        assert!(!compile(AsmDialect::Att, false, false).contains("$0x7f"));
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 250);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let asm = compile(AsmDialect::Att, true, true);
+        assert_eq!(asm.matches("\tandl $0x7f, %ecx\n\tmovb %cl, (%rsp)\n").count(), 2);
+
+        let asm = compile(AsmDialect::Nasm, false, true);
+        assert_eq!(asm.matches("\tand ecx, 0x7f\n").count(), 2);
+
+        // Literal output is masked at compile time
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
+        let asm = compile_to_asm(&mut lex(&program), opts, AsmOptions { ascii_out: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.contains("movl $72, %ecx"));
     }
 
     #[test]
-    fn test_execute_decrement_loop_sub_1_llvm() {
-        let mut input = Vec::new();
+    fn test_hot_loop_alignment() {
+        let compile = |dialect, hot_loops : &HashSet<usize>| compile_to_asm(&mut lex("+[,.[-<]]"), OptimizeOptions::default(), AsmOptions { dialect, ..AsmOptions::default() }, hot_loops, &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
 
-        let mut prog = lex("++++++[->-<]>.");
+        let asm = compile(AsmDialect::Att, &HashSet::from([4]));
+        assert!(asm.contains("\tje .UZ1\n\t.p2align 4, 0x90\n.IZ1:\n"));
+        assert!(asm.contains("\tje .UZ0\n.IZ0:\n"));
 
-        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, false).unwrap();
-        assert!(run_res.status.success());
+        let asm = compile(AsmDialect::Nasm, &HashSet::from([4]));
+        assert!(asm.contains("\tje .UZ1\n\talign 16\n.IZ1:\n"));
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 250);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        assert!(!compile(AsmDialect::Att, &HashSet::new()).contains(".p2align 4, 0x90\n.IZ"));
     }
 
     #[test]
-    fn test_increment_loop_add_1() {
-        let mut prog = lex("[+>+<]");
-        simplify_loops(&mut prog);
+    fn test_unroll_loops() {
+        let mut prog = lex("+++[>+<-]");
+        unroll_loops(&mut prog, 12);
 
-        assert_eq!(prog, [
-            Instruction::Sub(1),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+        assert_eq!(prog, lex("+++>+<->+<->+<-"));
     }
 
     #[test]
-    fn test_increment_loop_add_1_write() {
-        let mut prog = lex("[+>+>.<<]");
-        let prog_orig = prog.clone();
-        simplify_loops(&mut prog);
+    fn test_unroll_loops_over_limit() {
+        let mut prog = lex("+++[>+<-]");
+        unroll_loops(&mut prog, 11);
 
-        assert_eq!(prog, prog_orig);
+        assert_eq!(prog, lex("+++[>+<-]"));
     }
 
     #[test]
-    fn test_increment_loop_add_1_read() {
-        let mut prog = lex("[+>+>,<<]");
-        let prog_orig = prog.clone();
-        simplify_loops(&mut prog);
+    fn test_unroll_loops_after_unknown_input() {
+        // The read makes the first cell unknown, but the clear loop leaves it at zero.
+        let mut prog = lex(",[-]++[>+<-],[>+<-]");
+        unroll_loops(&mut prog, 100);
 
-        assert_eq!(prog, prog_orig);
+        assert_eq!(prog, lex(",[-]++>+<->+<-,[>+<-]"));
     }
 
     #[test]
-    fn test_increment_loop_sub_1() {
-        let mut prog = lex("[+>-<]");
-        simplify_loops(&mut prog);
+    fn test_unroll_loops_increment_index() {
+        // Counting up from 254 wraps to zero after two iterations.
+        let mut prog = lex("--[>+<+]");
+        unroll_loops(&mut prog, 100);
 
-        assert_eq!(prog, [
-            Instruction::Add(1),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+        assert_eq!(prog, lex("-->+<+>+<+"));
     }
 
     #[test]
-    fn test_decrement_loop_add_sub() {
-        let mut prog = lex("[->>+++>>>>>-+---<++<<<<<<]");
-        simplify_loops(&mut prog);
+    fn test_execute_unroll_loops() {
+        let src = "++++++[>++++++++<-]>+.,[-]+++[>++<-]>.";
+        let input = b"x".to_vec();
+
+        let mut interp_output = Vec::new();
+        let mut state = State::new(lex(src));
+        state.interp(&input[..], interp_output.by_ref()).unwrap();
+
+        let mut times = CompileTimes::default();
+        let opts = OptimizeOptions { unroll_limit: 100, ..OptimizeOptions::default() };
+        let asm = compile_to_asm(&mut lex(src), opts, AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions::default(), &mut times).unwrap();
+
+        let cmd = Command::new(exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn().unwrap();
+        cmd.stdin.as_ref().unwrap().write_all(&input).unwrap();
+        let run_res = cmd.wait_with_output().unwrap();
 
-        assert_eq!(prog, [
-            Instruction::Add(2),
-            Instruction::Add(2),
-            Instruction::Add(2),
-            Instruction::Add(6),
-            Instruction::Add(6),
-            Instruction::Sub(7),
-            Instruction::Sub(7),
-            Instruction::Sub(7),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
+        assert!(run_res.status.success());
+        assert_eq!(run_res.stdout, interp_output);
+    }
+
+    #[test]
+    fn test_asm_labels() {
+        let mut prog = lex("+[>[<-]>]");
+        let mut labels = Vec::new();
+        let asm = compile_to_asm(&mut prog, OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut labels, &mut CompileTimes::default()).unwrap().0;
+
+        assert_eq!(labels, [
+            AsmLabel { label_num: 0, pc: 1, inst: Instruction::JumpIfZero },
+            AsmLabel { label_num: 1, pc: 3, inst: Instruction::JumpIfZero },
+            AsmLabel { label_num: 1, pc: 6, inst: Instruction::JumpUnlessZero },
+            AsmLabel { label_num: 0, pc: 8, inst: Instruction::JumpUnlessZero },
         ]);
+        for label in &labels {
+            assert!(asm.find(&format!(".IZ{}:", label.label_num)).is_some());
+        }
+
+        let mut prog = lex(",[>]");
+        let mut labels = Vec::new();
+        compile_to_asm(&mut prog, OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut labels, &mut CompileTimes::default()).unwrap();
+
+        assert_eq!(labels, [AsmLabel { label_num: 0, pc: 1, inst: Instruction::Scan(1) }]);
+        assert_eq!(labels[0].to_string(), "0\t1\tSCAN(1)");
     }
 
     #[test]
-    fn test_increment_loop_add_sub() {
-        let mut prog = lex("[+>>+++>>>>>-+---<++<<<<<<]");
-        simplify_loops(&mut prog);
+    fn test_nasm_dialect() {
+        let src = ",[>[<-]>]>[>>]<<+.";
 
-        assert_eq!(prog, [
-            Instruction::Sub(2),
-            Instruction::Sub(2),
-            Instruction::Sub(2),
-            Instruction::Sub(6),
-            Instruction::Sub(6),
-            Instruction::Add(7),
-            Instruction::Add(7),
-            Instruction::Add(7),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+        let mut att_labels = Vec::new();
+        compile_to_asm(&mut lex(src), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut att_labels, &mut CompileTimes::default()).unwrap();
+
+        let mut nasm_labels = Vec::new();
+        let asm = compile_to_asm(&mut lex(src), OptimizeOptions::default(), AsmOptions { dialect: AsmDialect::Nasm, ..AsmOptions::default() }, &HashSet::new(), &mut nasm_labels, &mut CompileTimes::default()).unwrap().0;
+
+        assert!(asm.find("global bf_main").is_some());
+        assert!(asm.find("section .text").is_some());
+        assert!(asm.find("%r12").is_none());
+
+        // Scans are scalar, and labels match the AT&T output so the same map describes both.
+        assert!(asm.find("add r12, 2").is_some());
+        assert!(asm.find("vpgatherdd").is_none());
+        assert_eq!(nasm_labels, att_labels);
+
+        let err = compile_to_asm(&mut lex("+[>+"), OptimizeOptions::default(), AsmOptions { dialect: AsmDialect::Nasm, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap_err();
+        assert!(err.to_string().find("loop at pc 1").is_some());
     }
 
     #[test]
-    fn test_decrement_loop_nested_add_1() {
-        let mut prog = lex("++[->+++[->+<]<]");
-        simplify_loops(&mut prog);
+    fn test_capabilities_json() {
+        let caps = Capabilities {
+            version: "1.2.3",
+            targets: vec!["exe", "asm"],
+            llvm_version: (18, 1, 8),
+            clang_found: false,
+            host_avx2: true,
+        };
+
+        assert_eq!(caps.to_json(), "{\n  \"version\": \"1.2.3\",\n  \"targets\": [\"exe\", \"asm\"],\n  \"llvm_version\": \"18.1.8\",\n  \"clang_found\": false,\n  \"host_avx2\": true\n}");
+        assert_eq!(capabilities().version, env!("CARGO_PKG_VERSION"));
+    }
 
-        assert_eq!(prog, [
-            Instruction::Increment,
-            Instruction::Increment,
-            Instruction::JumpIfZero,
-            Instruction::Decrement,
-            Instruction::MoveRight,
-            Instruction::Increment,
-            Instruction::Increment,
-            Instruction::Increment,
-            Instruction::Add(1),
-            Instruction::Zero,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::MoveLeft,
-            Instruction::JumpUnlessZero
-        ]);
+    #[test]
+    fn test_asm_unmatched_loop_label() {
+        let err = compile_to_asm(&mut lex("+[>+"), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap_err();
+        assert!(err.to_string().find("loop at pc 1").is_some());
+
+        let err = compile_to_asm(&mut lex("+]"), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap_err();
+        assert!(err.to_string().find("loop at pc 1").is_some());
     }
 
     #[test]
-    fn test_execute_decrement_loop_nested_add_1() {
-        let mut input = Vec::new();
+    fn test_annotate_asm() {
+        let mut prog = lex("+[>]");
 
-        let mut prog = lex("++[->+++[->+<]<]>>.");
+        let asm = compile_to_asm(&mut prog, OptimizeOptions::default(), AsmOptions { annotate: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        assert!(asm.find("# pc=0 op=+\n").is_some());
+        assert!(asm.find("# pc=1 op=SCAN(1)\n").is_some());
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 6);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        // Nops left behind by the scan vectorizer aren't annotated.
+        assert!(asm.find("# pc=2").is_none());
     }
 
     #[test]
-    fn test_execute_decrement_loop_nested_add_1_llvm() {
-        let mut input = Vec::new();
+    fn test_scalar_scan() {
+        let mut prog = lex(",[>>]");
 
-        let mut prog = lex("++[->+++[->+<]<]>>.");
+        let asm = compile_to_asm(&mut prog, OptimizeOptions::default(), AsmOptions { use_avx: false, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
 
-        let run_res = compile_and_run_llvm_with_input(&mut prog, &input, true, false).unwrap();
-        assert!(run_res.status.success());
+        assert!(asm.find("addq $2, %r12").is_some());
+        assert!(asm.find("vpgatherdd").is_none());
+    }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 6);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+    #[test]
+    fn test_execute_compile_run_capture() {
+        for backend in [Backend::Asm, Backend::Llvm] {
+            let opts = CompileOptions { backend, ..CompileOptions::default() };
+            let output = compile_run_capture(",[.,]", b"abc", opts).unwrap();
+
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"abc");
+            assert!(String::from_utf8_lossy(&output.stderr).find("Exited successfully").is_some());
+        }
+    }
+
+    #[test]
+    fn test_execute_compile_asm_to_object() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let obj_path = output_dir.path().join("bf_program.o");
+
+        let asm = compile_to_asm(&mut lex(",[>+<-]>."), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        compile_asm_to_object(&asm, &obj_path, false, false, &mut CompileTimes::default()).unwrap();
+        assert!(obj_path.exists());
+
+        // Assembler diagnostics are reported without linking
+        let err = compile_asm_to_object(&(asm + "\tbogusop %r12\n"), &obj_path, false, false, &mut CompileTimes::default()).unwrap_err();
+        assert!(err.to_string().contains("bogusop"));
+    }
+
+    #[test]
+    fn test_execute_run_timeout() {
+        for backend in [Backend::Asm, Backend::Llvm] {
+            let opts = CompileOptions { backend, timeout: Some(Duration::from_secs(1)), ..CompileOptions::default() };
+            let err = compile_run_capture("+[]", b"", opts).unwrap_err();
+            assert!(err.to_string().contains("didn't finish within 1s"));
+
+            let output = compile_run_capture(",[.,]", b"abc", opts).unwrap();
+            assert_eq!(output.stdout, b"abc");
+        }
     }
 
     #[test]
-    fn test_scan_loop() {
-        let mut prog = lex("[>]");
-        vectorize_scans(&mut prog);
-
-        assert_eq!(prog, [
-            Instruction::Scan(1),
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+    fn test_execute_eof_byte() {
+        // Each program branches on what EOF reads as. The first two never finish if it reads as 255.
+        let cases : [(&str, &[u8], u8); 3] = [
+            (",[.,]", b"abc", 0),
+            (",----------[++++++++++.,----------]", b"ab", b'\n'),
+            ("+.,>,<[->-<]>[++++++++++++++++++++++++++++++++++++++++++++++++.]", b"x", b'x'),
+        ];
+
+        for (src, input, eof_byte) in cases {
+            let mut state = State::new(lex(src));
+            state.set_eof_byte(eof_byte);
+            let mut expected = Vec::new();
+            state.interp(input, &mut expected).unwrap();
+
+            for backend in [Backend::Asm, Backend::Llvm] {
+                for partial_eval in [false, true] {
+                    let optimize = OptimizeOptions { partial_eval, ..OptimizeOptions::default() };
+                    let opts = CompileOptions { backend, optimize, eof_byte, timeout: Some(TEST_RUN_TIMEOUT), ..CompileOptions::default() };
+                    let output = compile_run_capture(src, input, opts).unwrap();
+                    assert_eq!(output.stdout, expected, "{src} on {backend:?}, partial_eval {partial_eval}");
+                }
+            }
+        }
     }
 
     #[test]
-    fn test_scan_loop_left() {
-        let mut prog = lex("[<]");
-        vectorize_scans(&mut prog);
-
-        assert_eq!(prog, [
-            Instruction::Scan(-1),
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+    fn test_wait_with_timeout() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start_time = Instant::now();
+        let err = wait_with_timeout(&mut child, Duration::from_millis(100)).unwrap_err();
+        assert!(err.downcast_ref::<Timeout>().is_some());
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+
+        let mut child = Command::new("sleep").arg("0").spawn().unwrap();
+        assert!(wait_with_timeout(&mut child, Duration::from_secs(10)).unwrap().success());
     }
 
     #[test]
-    fn test_scan_loop_2() {
-        let mut prog = lex("[>>]");
-        vectorize_scans(&mut prog);
+    fn test_run_clang_error() {
+        // sh stands in for a clang that rejects its input.
+        assert!(run_clang(Command::new("sh").args(["-c", "exit 0"])).is_ok());
+
+        let err = run_clang(Command::new("sh").args(["-c", "echo 'bf_program.S:3: error: invalid instruction' >&2; exit 1"])).unwrap_err();
+        let err = err.downcast::<ClangError>().unwrap();
+        assert_eq!(err.status.code(), Some(1));
+        assert_eq!(err.stderr, "bf_program.S:3: error: invalid instruction\n");
+        assert!(err.to_string().ends_with(":\nbf_program.S:3: error: invalid instruction\n"));
+    }
 
-        assert_eq!(prog, [
-            Instruction::Scan(2),
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+    #[test]
+    fn test_capture_output() {
+        // More input than a pipe holds, echoed back before all of it has been written.
+        let input : Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let output = capture_output(Command::new("sh").args(["-c", "cat"]), &input, Some(TEST_RUN_TIMEOUT)).unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout == input);
+
+        // A command that never reads its input still times out.
+        let err = capture_output(Command::new("sh").args(["-c", "sleep 10"]), &input, Some(Duration::from_millis(200))).unwrap_err();
+        assert!(err.downcast_ref::<Timeout>().is_some());
+
+        // One that exits without reading it succeeds.
+        let output = capture_output(Command::new("sh").args(["-c", "echo done"]), &input, None).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
     }
 
     #[test]
-    fn test_execute_scan_loop() {
-        let mut input = Vec::new();
+    fn test_run_clang_not_found() {
+        let err = run_clang(&mut Command::new("brainbug-test-no-such-clang")).unwrap_err();
+        assert!(err.downcast_ref::<ClangNotFound>().is_some());
+        assert_eq!(err.to_string(), "clang not found; install LLVM or use the interpreter.");
+    }
 
-        let mut prog = lex("+>++>+++>++++>+++++>>++++++<<<<<<[>]>.");
+    #[test]
+    fn test_large_stride_scan_is_scalar() {
+        let src = ",[".to_owned() + &">".repeat(MAX_VECTOR_SCAN_STRIDE as usize + 1) + "]";
+        let asm = compile_to_asm(&mut lex(&src), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.find("vpgatherdd").is_none());
+
+        let src = ",[".to_owned() + &">".repeat(MAX_VECTOR_SCAN_STRIDE as usize) + "]";
+        let asm = compile_to_asm(&mut lex(&src), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.find("vpgatherdd").is_some());
+    }
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    #[test]
+    fn test_large_stride_sse_scan_is_scalar() {
+        // The SSE2 scan loads 3 strides ahead, so it has the same limit as the gathers.
+        let sse = AsmOptions { use_avx: false, scan_lanes: 4, ..AsmOptions::default() };
+
+        // A scan, then a clear scan.
+        for prefix in [",[", ",[[-]"] {
+            let src = prefix.to_owned() + &">".repeat(MAX_VECTOR_SCAN_STRIDE as usize + 1) + "]";
+            let asm = compile_to_asm(&mut lex(&src), OptimizeOptions::default(), sse, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+            assert!(!asm.contains("pcmpeqb"), "{prefix}");
+        }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 6);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let src = ",[".to_owned() + &">".repeat(MAX_VECTOR_SCAN_STRIDE as usize) + "]";
+        let asm = compile_to_asm(&mut lex(&src), OptimizeOptions::default(), sse, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.contains("pcmpeqb"));
     }
 
     #[test]
-    fn test_execute_scan_loop_left() {
-        let mut input = Vec::new();
-
-        let mut prog = lex("+<++<+++<++++<+++++<<++++++>>>>>>[<]<.");
+    fn test_execute_left_scan_near_tape_start() {
+        // Put the head 5 cells from the start of the tape, then scan left by 3. The first gather
+        // reads 7 strides to the left, well before the tape's first cell.
+        let src = "<".repeat(2000000 - 5) + ",[<<<]+.";
+        let input = vec![7];
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
+        let run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, true, true, false).unwrap();
         assert!(run_res.status.success());
+        assert_eq!(run_res.stdout, [1]);
+    }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 6);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+    #[test]
+    fn test_execute_run_interactive() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+
+        let asm = compile_to_asm(&mut lex("++[>+<-]"), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions::default(), &mut CompileTimes::default()).unwrap();
+
+        // Nothing is piped, so the program shares the test's stdio.
+        let status = run_interactive(exe_path.to_str().unwrap()).unwrap();
+        assert!(status.success());
+
+        // The same program with its output captured instead.
+        let output = compile_run_capture("++[>+<-]", b"", CompileOptions::default()).unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).find("Exited successfully").is_some());
     }
 
+    #[test]
+    fn test_checked_asm() {
+        let asm = compile_to_asm(&mut lex(",[>]>[-]<,[->+<]"), OptimizeOptions::default(), AsmOptions { checked: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.find(".HEAD_ESCAPED:").is_some());
+        assert!(asm.find("leaq 1(%r12), %rax").is_some());
+        assert!(asm.find("vpgatherdd").is_none());
+
+        let asm = compile_to_asm(&mut lex(",[>]"), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.find("HEAD_ESCAPED").is_none());
+    }
 
     #[test]
-    fn test_execute_scan_loop_2() {
-        let mut input = Vec::new();
+    fn test_write_failed_asm() {
+        // Every putchar is followed by a check of its result, including writes of partially
+        // evaluated output.
+        let asm = compile_to_asm(&mut lex("+.,.,>[-]<[->+<]>."), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("callq putchar").count(), asm.matches("je .WRITE_FAILED").count());
+        assert!(asm.find(".WRITE_FAILED:").is_some());
+
+        let asm = compile_to_asm(&mut lex("+.,."), OptimizeOptions::default(), AsmOptions { dialect: AsmDialect::Nasm, use_avx: false, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("call putchar").count(), asm.matches("je .WRITE_FAILED").count());
+        assert!(asm.find(".WRITE_FAILED:").is_some());
+    }
 
-        let mut prog = lex(">+>>++>>+++>>>++++>+++++<<<<<<<<[>>]>.");
+    #[test]
+    fn test_flush_before_read_asm() {
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+        // Partial eval leaves the first read a Read and turns the second into a ReadAt
+        let asm = compile_to_asm(&mut lex("+.,>,."), opts, AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("\tcallq fflush\n\tcallq getchar\n").count(), 2);
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 4);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+        let asm = compile_to_asm(&mut lex("+.,>,."), opts, AsmOptions { dialect: AsmDialect::Nasm, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert_eq!(asm.matches("\tcall fflush\n\tcall getchar\n").count(), 2);
     }
 
     #[test]
-    fn test_execute_scan_loop_2_left() {
-        let mut input = Vec::new();
-
-        let mut prog = lex("+<<++<<+++<<<++++<+++++>>>>>>>>[<<]<.");
+    fn test_eof_byte_asm() {
+        let opts = OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() };
+        let compile = |dialect, freestanding, eof_byte| compile_to_asm(&mut lex("+.,>,."), opts, AsmOptions { dialect, freestanding, eof_byte, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+
+        // Truncating getchar's -1 already stores 255, so the default adds nothing
+        assert!(compile(AsmDialect::Att, false, 255).find("cmove").is_none());
+        assert!(compile(AsmDialect::Nasm, false, 255).find("cmove").is_none());
+
+        // Both the Read and the ReadAt swap in the sentinel
+        let asm = compile(AsmDialect::Att, false, 0);
+        assert_eq!(asm.matches("\tmovl $0, %edx\n\tcmpl $-1, %eax\n\tcmovel %edx, %eax\n").count(), 2);
+        let asm = compile(AsmDialect::Nasm, false, 0);
+        assert_eq!(asm.matches("\tmov edx, 0\n\tcmp eax, -1\n\tcmove eax, edx\n").count(), 2);
+
+        let asm = compile(AsmDialect::Att, true, 10);
+        assert_eq!(asm.matches("\tmovb $10, (%rsi)\n").count(), 2);
+        assert!(compile(AsmDialect::Att, true, 255).find("\tmovb $255, (%rsi)\n").is_some());
+    }
 
-        let run_res = compile_and_run_asm_with_input(&mut prog, &input, true, true, false).unwrap();
-        assert!(run_res.status.success());
+    #[test]
+    fn test_freestanding_asm() {
+        let asm = compile_to_asm(&mut lex("+.,[>]>,.<[-]"), OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() }, AsmOptions { freestanding: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        assert!(asm.find("_start").is_none());
+        assert!(asm.find("putchar").is_none());
+        assert!(asm.find("getchar").is_none());
+        assert!(asm.find("callq exit").is_none());
+        assert!(asm.find(".seh_").is_none());
+        assert!(asm.find("vpgatherdd").is_none());
+
+        // The Output literal, then the Read, ReadAt and WriteAt
+        assert_eq!(asm.matches("\tsyscall\n").count(), 5);
+    }
 
-        let output = run_res.stdout;
-        assert_eq!(output.len(), 1);
-        assert_eq!(output[0], 4);
-        let err_output = String::from_utf8(run_res.stderr).unwrap();
-        assert!(err_output.find("Exited successfully").is_some());
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_execute_freestanding() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf");
+
+        let asm = compile_to_asm(&mut lex(",.,.,.,.>+++++[>++++++++<-]>++"), OptimizeOptions::default(), AsmOptions { freestanding: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { exit_from_cell: true, freestanding: true, ..ExeOptions::default() }, &mut CompileTimes::default()).unwrap();
+
+        let mut child = Command::new(&exe_path).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(b"echo").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.stdout, b"echo");
+        assert_eq!(output.status.code(), Some(42));
     }
 
+    #[test]
+    fn test_execute_checked_head_escape() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+
+        // Walks left forever, leaving a trail of nonzero cells behind it.
+        let asm = compile_to_asm(&mut lex("+[<+]"), OptimizeOptions::default(), AsmOptions { checked: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions::default(), &mut CompileTimes::default()).unwrap();
+
+        let output = Command::new(&exe_path).stderr(Stdio::piped()).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).find("head moved off the tape").is_some());
+    }
 
     #[test]
-    fn test_execute_scan_loop_random() {
-        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    fn test_execute_exit_from_cell() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
 
-        let num_tests = 10;
-        let num_cells = 50;
-        let max_scan = 20;
+        let mut times = CompileTimes::default();
+        let asm = compile_to_asm(&mut lex("+++++[>++++++++<-]>++"), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { exit_from_cell: true, ..ExeOptions::default() }, &mut times).unwrap();
 
-        for _ in 0..num_tests {
-            let mut input_prog = Vec::new();
+        let status = Command::new(&exe_path).status().unwrap();
+        assert_eq!(status.code(), Some(42));
 
-            let scan_dist = rand::distributions::Uniform::new(-max_scan, max_scan);
-            let mut scan_num_skipped : i32 = rng.sample(scan_dist);
-            if scan_num_skipped == 0 {
-                scan_num_skipped += 1;
-            }
+        // Without the option the exit code stays 0.
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions::default(), &mut times).unwrap();
 
-            // Select a random cell to hold cell
-            let rand_cell_dist = rand::distributions::Uniform::new(0, num_cells);
-            let cell_idx = rng.sample(rand_cell_dist);
+        let status = Command::new(&exe_path).status().unwrap();
+        assert_eq!(status.code(), Some(0));
 
-            // Generate a random input number for each cell.
-            let scan_dist_cells = rand::distributions::Uniform::new(1, 255);
-            for cell in 0..num_cells {
-                if cell != cell_idx {
-                    let cell_value = rng.sample(scan_dist_cells);
-                    for j in 0..cell_value {
-                        input_prog.push("+");
-                    }
-                }
+        // Partial eval folds the whole program, but leaves the head and its cell where they end.
+        let opts = OptimizeOptions { partial_eval: true, exit_from_cell: true, ..OptimizeOptions::default() };
+        let asm = compile_to_asm(&mut lex("+++++[>++++++++<-]>++"), opts, AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+        compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { exit_from_cell: true, ..ExeOptions::default() }, &mut times).unwrap();
 
-                if scan_num_skipped > 0 {
-                    input_prog.push(">");
-                } else {
-                    input_prog.push("<");
-                }
-            }
+        let status = Command::new(&exe_path).status().unwrap();
+        assert_eq!(status.code(), Some(42));
+    }
 
-            // Reset head
-            for i in 0..num_cells {
-                if scan_num_skipped > 0 {
-                    input_prog.push("<");
-                } else {
-                    input_prog.push(">");
-                }
-            }
+    #[test]
+    fn test_exit_from_cell_partial_eval() {
+        let mut prog = lex("+++++");
+        optimize(&mut prog, OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() });
+        assert_eq!(prog, []);
+
+        let mut prog = lex("+++++[>++++++++<-]>++");
+        optimize(&mut prog, OptimizeOptions { partial_eval: true, exit_from_cell: true, ..OptimizeOptions::default() });
+        assert_eq!(prog, [Instruction::SetHeadPos(1), Instruction::SetCell(1, 42)]);
+    }
 
-            // Generate scan loop
-            // TODO(Kincaid): Add support for negative head deltas
-            input_prog.push("[");
-            for _ in 0..(scan_num_skipped.abs()) {
-                if scan_num_skipped > 0 {
-                    input_prog.push(">");
-                } else {
-                    input_prog.push("<");
-                }
-            }
-            input_prog.push("]");
-            if scan_num_skipped > 0 {
-                input_prog.push(">");
-            } else {
-                input_prog.push("<");
-            }
-            input_prog.push(".");
+    #[test]
+    fn test_self_center_asm() {
+        let compile = |dialect, freestanding, self_center| compile_to_asm(&mut lex("+[>+<-]>."), OptimizeOptions::default(), AsmOptions { dialect, freestanding, self_center, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+
+        // The head is centered before any instruction runs, and partial eval's base follows it
+        let asm = compile(AsmDialect::Att, false, true);
+        assert!(asm.find("\tmovq %rcx, %r13\n\n\tshrq $1, %rdx\n\taddq %rdx, %r12\n\tmovq %r12, %r13\n").is_some());
+        let asm = compile(AsmDialect::Att, true, true);
+        assert!(asm.find("\tmovq %rdi, %r13\n\n\tshrq $1, %rsi\n\taddq %rsi, %r12\n\tmovq %r12, %r13\n").is_some());
+        let asm = compile(AsmDialect::Nasm, false, true);
+        assert!(asm.find("\tmov r13, rcx\n\n\tshr rdx, 1\n\tadd r12, rdx\n\tmov r13, r12\n").is_some());
+
+        assert!(compile(AsmDialect::Att, false, false).find("shrq").is_none());
+        assert!(compile(AsmDialect::Nasm, false, false).find("shr rdx").is_none());
+    }
 
-            let mut prog = lex(&(input_prog.join("")));
-            //println!("{}", input.join(""));
-            //assert!(false);
+    #[test]
+    fn test_execute_self_center() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+
+        // Starts far enough left that the head would leave a tape passed as its base
+        let src = "<".repeat(1000) + "+++++[>++++++++<-]>++.[>+<-]>.";
+        for partial_eval in [false, true] {
+            let opts = OptimizeOptions { partial_eval, ..OptimizeOptions::default() };
+            let mut times = CompileTimes::default();
+            let asm = compile_to_asm(&mut lex(&src), opts, AsmOptions { self_center: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+            compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { exit_from_cell: true, self_center: true, ..ExeOptions::default() }, &mut times).unwrap();
+
+            let output = Command::new(&exe_path).output().unwrap();
+            assert_eq!(output.stdout, b"**");
+            assert_eq!(output.status.code(), Some(42));
+        }
+    }
 
-            let mut input = Vec::new();
-            
-            // Execute without vectorizing scans
-            let no_vec_run_res = compile_and_run_asm_with_input(&mut prog, &input, false, false, false).unwrap();
-            assert!(no_vec_run_res.status.success());
+    #[test]
+    fn test_exe_cache_hit() {
+        assert_eq!(fnv1a_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_hash(b"a"), 0xaf63dc4c8601ec8c);
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("bf.exe");
+
+        // A hit copies the cached exe without building anything
+        let asm = "# not valid asm, so a miss would fail to assemble\n";
+        let key = exe_cache_key(asm, ExeOptions::default());
+        fs::write(cache_dir.path().join(format!("{:016x}.exe", fnv1a_hash(key.as_bytes()))), b"cached exe").unwrap();
+
+        let hit = compile_asm_to_exe_cached(asm, output_path.to_str().unwrap(), ExeOptions::default(), cache_dir.path(), &mut CompileTimes::default()).unwrap();
+        assert!(hit);
+        assert_eq!(fs::read(&output_path).unwrap(), b"cached exe");
+
+        // The runner, the version and the runner's defines are part of the key
+        assert!(key.contains(TEST_RUNNER));
+        assert!(key.starts_with(env!("CARGO_PKG_VERSION")));
+        assert!(key.contains("-DBF_EXIT_MARKER"));
+        assert!(exe_cache_key(asm, ExeOptions { freestanding: true, ..ExeOptions::default() }).contains(FREESTANDING_RUNNER));
+        assert_ne!(exe_cache_key(asm, ExeOptions { guard_pages: true, ..ExeOptions::default() }), key);
+    }
 
-            let no_vec_output = no_vec_run_res.stdout;
-            assert_eq!(no_vec_output.len(), 1);
-            let no_vec_res = no_vec_output[0];
+    #[test]
+    fn test_execute_exe_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+
+        let mut times = CompileTimes::default();
+        let asm = compile_to_asm(&mut lex("+++++[>++++++++<-]>++."), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+
+        // Build options are part of the key, so exit_from_cell misses even with the same asm
+        for (exit_from_cell, expect_hit) in [(false, false), (false, true), (true, false), (true, true)] {
+            let hit = compile_asm_to_exe_cached(&asm, exe_path.to_str().unwrap(), ExeOptions { exit_from_cell, ..ExeOptions::default() }, cache_dir.path(), &mut times).unwrap();
+            assert_eq!(hit, expect_hit);
+
+            let output = Command::new(&exe_path).output().unwrap();
+            assert_eq!(output.stdout, b"*");
+            assert_eq!(output.status.code(), Some(if exit_from_cell { 42 } else { 0 }));
+        }
+    }
 
-            let no_vec_err_output = String::from_utf8(no_vec_run_res.stderr).unwrap();
-            assert!(no_vec_err_output.find("Exited successfully").is_some());
+    #[test]
+    fn test_execute_llvm_bitcode() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let bc_path = output_dir.path().join("bf_program.bc");
+        let exe_path = output_dir.path().join("bf.exe");
+
+        let context = Context::create();
+        let module = compile_to_llvm(&context, &mut lex("+++++[>++++++++<-]>++."), OptimizeOptions::default(), false, 255).0;
+        assert!(module.write_bitcode_to_path(&bc_path));
+
+        let loaded = load_llvm_bitcode(&context, &bc_path).unwrap();
+        compile_llvm_to_exe(&loaded, exe_path.to_str().unwrap(), false, false).unwrap();
+        let output = Command::new(&exe_path).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"*");
+
+        // Modules from compile_many_to_llvm name their entry points bf_main_N, which the runner
+        // can't call.
+        let modules = compile_many_to_llvm(&context, &[lex("+.")], OptimizeOptions::default(), false, 255);
+        assert!(modules[0].write_bitcode_to_path(&bc_path));
+        assert!(load_llvm_bitcode(&context, &bc_path).is_err());
+    }
 
-            // Execute with vectorized scans
-            let with_vec_run_res = compile_and_run_asm_with_input(&mut prog, &input, false, true, false).unwrap();
-            assert!(with_vec_run_res.status.success());
+    #[test]
+    fn test_execute_guard_pages() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+        let build = |src : &str| {
+            let mut times = CompileTimes::default();
+            let asm = compile_to_asm(&mut lex(src), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+            compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions { guard_pages: true, ..ExeOptions::default() }, &mut times).unwrap();
+        };
+
+        build("+++++[>++++++++<-]>++.");
+        let output = Command::new(&exe_path).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"*");
+
+        // Walks right forever, so only the guard page stops it
+        build("+[>+]");
+        let output = Command::new(&exe_path).output().unwrap();
+        assert!(!output.status.success());
+    }
 
-            let with_vec_output = with_vec_run_res.stdout;
-            assert_eq!(with_vec_output.len(), 1);
-            let with_vec_res = with_vec_output[0];
+    #[test]
+    fn test_execute_ascii_out() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+        let exe_path = output_dir.path().join("bf.exe");
+
+        // Byte 200 from a cell, then as a literal once partial eval has folded the program
+        for opts in [OptimizeOptions::default(), OptimizeOptions { partial_eval: true, ..OptimizeOptions::default() }] {
+            let mut times = CompileTimes::default();
+            let asm = compile_to_asm(&mut lex(&("+".repeat(200) + ".")), opts, AsmOptions { ascii_out: true, ..AsmOptions::default() }, &HashSet::new(), &mut Vec::new(), &mut times).unwrap().0;
+            compile_asm_to_exe(&asm, exe_path.to_str().unwrap(), ExeOptions::default(), &mut times).unwrap();
+
+            let output = Command::new(&exe_path).output().unwrap();
+            assert_eq!(output.stdout, [72]);
+        }
 
-            let with_vec_err_output = String::from_utf8(with_vec_run_res.stderr).unwrap();
-            assert!(with_vec_err_output.find("Exited successfully").is_some());
+        let context = Context::create();
+        let module = compile_to_llvm(&context, &mut lex(&("+".repeat(200) + ".")), OptimizeOptions::default(), true, 255).0;
+        compile_llvm_to_exe(&module, exe_path.to_str().unwrap(), false, false).unwrap();
 
-            assert_eq!(with_vec_res, no_vec_res);
-        }
+        let output = Command::new(&exe_path).output().unwrap();
+        assert_eq!(output.stdout, [72]);
+
+        // Renamed so the runner can call it.
+        let modules = compile_many_to_llvm(&context, &[lex(&("+".repeat(200) + "."))], OptimizeOptions::default(), true, 255);
+        modules[0].get_function("bf_main_0").unwrap().as_global_value().set_name("bf_main");
+        compile_llvm_to_exe(&modules[0], exe_path.to_str().unwrap(), false, false).unwrap();
+
+        let output = Command::new(&exe_path).output().unwrap();
+        assert_eq!(output.stdout, [72]);
     }
 
     #[test]
-    fn test_scan_loop_non_power_2() {
-        let mut prog = lex("[>>>]");
-        vectorize_scans(&mut prog);
-
-        assert_eq!(prog, [
-            Instruction::Scan(3),
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-            Instruction::Nop,
-        ]);
+    fn test_execute_link_two_scan_programs() {
+        let output_dir = tempfile::Builder::new()
+            .keep(false)
+            .tempdir().unwrap();
+
+        let runner_path = output_dir.path().join("bf_main.c");
+        let mut runner_file = File::create(runner_path.clone()).unwrap();
+        write!(runner_file, "{}", TEST_RUNNER).unwrap();
+
+        // Both programs use the same scan strides, so both define the same index tables.
+        let asm_1 = compile_to_asm(&mut lex("+>++>>+<<<[>]>[<<]."), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        let asm_2 = compile_to_asm(&mut lex("+>+>>+<<[>]<[<<]>."), OptimizeOptions::default(), AsmOptions::default(), &HashSet::new(), &mut Vec::new(), &mut CompileTimes::default()).unwrap().0;
+        let asm_2 = asm_2.replace("bf_main", "bf_main_2");
+
+        let asm_path_1 = output_dir.path().join("bf_program_1.S");
+        let mut asm_file_1 = File::create(asm_path_1.clone()).unwrap();
+        write!(asm_file_1, "{}", asm_1).unwrap();
+
+        let asm_path_2 = output_dir.path().join("bf_program_2.S");
+        let mut asm_file_2 = File::create(asm_path_2.clone()).unwrap();
+        write!(asm_file_2, "{}", asm_2).unwrap();
+
+        let exe_path = output_dir.path().join("bf.exe");
+        run_clang(Command::new("clang")
+            .arg(runner_path)
+            .arg(asm_path_1)
+            .arg(asm_path_2)
+            .arg("-march=native")
+            .arg("-o")
+            .arg(exe_path)).unwrap();
     }
 
     #[test]
@@ -1986,6 +6212,239 @@ mod tests {
         assert!(err_output.find("Exited successfully").is_some());
     }
 
+    #[test]
+    fn test_execute_partial_eval_deep_negative_head_pos() {
+        // Cells thousands left of the start are set with SetCell at large negative offsets from r13,
+        // and the head is left at a negative SetHeadPos before the runtime loop.
+        let src = "<".repeat(3000) + "+++++" + &">".repeat(2700) + "+++" + &">".repeat(170) + "++" + &"<".repeat(70)
+            + ",[->+<]>." + &"<".repeat(101) + "." + &"<".repeat(2700) + "." + &">".repeat(2870) + ".";
+        let input = vec![4];
+
+        let mut expected = Vec::new();
+        State::new(lex(&src)).interp(&input[..], &mut expected).unwrap();
+        assert_eq!(expected, [4, 3, 5, 2]);
+
+        let run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, true, true, true).unwrap();
+        assert_eq!(run_res.stdout, expected);
+        let run_res = compile_and_run_llvm_with_input(&mut lex(&src), &input, true, true, true).unwrap();
+        assert_eq!(run_res.stdout, expected);
+    }
+
+    // Append a random program with balanced brackets to `prog`. If terminating, the program is
+    // guaranteed to terminate: every loop counts its index cell down by exactly one per iteration,
+    // and nothing inside the loop is allowed to touch the index cells of the loops enclosing it.
+    // Otherwise loop bodies are left as generated, so they may scan or never end.
+    fn gen_random_program( rng : &mut rand::rngs::StdRng, prog : &mut String, head : &mut i32, loop_cells : &mut Vec<i32>, depth : u32, len : u32, terminating : bool ) {
+        for _ in 0..len {
+            let is_loop_cell = terminating && loop_cells.contains(head);
+
+            match rng.gen_range(0..8) {
+                0 | 1 => {
+                    prog.push('>');
+                    *head += 1;
+                },
+                2 | 3 => {
+                    prog.push('<');
+                    *head -= 1;
+                },
+                4 if !is_loop_cell => prog.push('+'),
+                5 if !is_loop_cell => prog.push('-'),
+                6 => prog.push('.'),
+                7 if !is_loop_cell && rng.gen_bool(0.5) => prog.push(','),
+                7 if !is_loop_cell && depth < 2 => {
+                    let loop_cell = *head;
+                    loop_cells.push(loop_cell);
+
+                    prog.push('[');
+                    gen_random_program(rng, prog, head, loop_cells, depth + 1, len / 2, terminating);
+
+                    if terminating {
+                        // Return to the index cell and count it down.
+                        while *head < loop_cell {
+                            prog.push('>');
+                            *head += 1;
+                        }
+                        while *head > loop_cell {
+                            prog.push('<');
+                            *head -= 1;
+                        }
+                        prog.push('-');
+                    }
+                    prog.push(']');
+
+                    loop_cells.pop();
+                },
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute_random_differential() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+
+        let num_tests = 20;
+        let prog_len = 40;
+        let input_len = 64;
+
+        for _ in 0..num_tests {
+            let mut src = String::new();
+            gen_random_program(&mut rng, &mut src, &mut 0, &mut Vec::new(), 0, prog_len, true);
+
+            let input : Vec<u8> = (0..input_len).map(|_| rng.gen()).collect();
+
+            // Interpret
+            let mut interp_output = Vec::new();
+            let mut state = State::new(lex(&src));
+            state.interp(&input[..], interp_output.by_ref()).unwrap();
+
+            // Compile to asm with every optimization enabled
+            let asm_run_res = compile_and_run_asm_with_input(&mut lex(&src), &input, true, true, true).unwrap();
+            assert!(asm_run_res.status.success(), "{src}");
+            assert_eq!(asm_run_res.stdout, interp_output, "{src}");
+
+            // Compile with LLVM, running the same optimization pipeline
+            let llvm_run_res = compile_and_run_llvm_with_input(&mut lex(&src), &input, true, true, true).unwrap();
+            assert!(llvm_run_res.status.success(), "{src}");
+            assert_eq!(llvm_run_res.stdout, interp_output, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_add_sub_from_loops() {
+        let mut prog = lex(",>,<[->+<]");
+        simplify_loops(&mut prog);
+        assert!(prog.contains(&Instruction::Add(1)));
+
+        let mut prog = lex(",>,<[->-<]");
+        simplify_loops(&mut prog);
+        assert!(prog.contains(&Instruction::Sub(1)));
+
+        let mut prog = lex(",<,>[-<-<+>>]");
+        simplify_loops(&mut prog);
+        assert!(prog.contains(&Instruction::Sub(-1)));
+        assert!(prog.contains(&Instruction::Add(-2)));
+    }
+
+    #[test]
+    fn test_execute_add_sub_wrap() {
+        // Each program reads the index cell n and then a target cell v, lets simplify_loops turn the
+        // loop into Add or Sub, and prints the target. Sub is target minus index, not the reverse.
+        let programs : [(&str, fn(u8, u8) -> u8); 3] = [
+            (",>,<[->+<]>.", |n, v| v.wrapping_add(n)),
+            (",>,<[->-<]>.", |n, v| v.wrapping_sub(n)),
+            (",<,>[-<-<+>>]<.", |n, v| v.wrapping_sub(n)),
+        ];
+
+        for (src, expected) in programs {
+            for (n, v) in [(1, 255), (1, 0), (2, 1), (255, 255), (255, 0), (200, 100)] {
+                let input = vec![n, v];
+
+                let mut interp_output = Vec::new();
+                let mut state = State::new(lex(src));
+                state.interp(&input[..], interp_output.by_ref()).unwrap();
+                assert_eq!(interp_output, [expected(n, v)], "{} n={} v={}", src, n, v);
+
+                let asm_run_res = compile_and_run_asm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+                assert!(asm_run_res.status.success());
+                assert_eq!(asm_run_res.stdout, interp_output, "{} n={} v={}", src, n, v);
+
+                let llvm_run_res = compile_and_run_llvm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+                assert!(llvm_run_res.status.success());
+                assert_eq!(llvm_run_res.stdout, interp_output, "{} n={} v={}", src, n, v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute_eof_consistent() {
+        // `,+[-.,+]` echoes input until a read returns 255, so every backend has to agree on EOF
+        // for it to terminate. `,.` checks the value itself.
+        for src in [",+[-.,+]", ",.", ",.,."] {
+            for input in [&b""[..], &b"ab"[..]] {
+                let input = input.to_vec();
+
+                let mut interp_output = Vec::new();
+                let mut state = State::new(lex(src));
+                state.interp(&input[..], interp_output.by_ref()).unwrap();
+
+                let asm_run_res = compile_and_run_asm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+                assert!(asm_run_res.status.success());
+                assert_eq!(asm_run_res.stdout, interp_output);
+
+                let llvm_run_res = compile_and_run_llvm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+                assert!(llvm_run_res.status.success());
+                assert_eq!(llvm_run_res.stdout, interp_output);
+            }
+        }
+    }
+
+    // Canonical programs with known output, so the backends can be checked end to end without an
+    // external test suite. Each entry is (name, program, input, expected output).
+    const CONFORMANCE_PROGRAMS : &[(&str, &str, &[u8], &[u8])] = &[
+        ("hello world",
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+            b"",
+            b"Hello World!\n"),
+        ("cat",
+            ",+[-.,+]",
+            b"cat\x00input",
+            b"cat\x00input"),
+        ("multiply",
+            // 6 * 7 = 42, which is '*'.
+            "++++++[>+++++++<-]>.",
+            b"",
+            b"*"),
+        ("nested multiply",
+            // 4 * 4 * 4 = 64, then add one for 'A'.
+            "++++[>++++[>++++<-]<-]>>+.",
+            b"",
+            b"A"),
+        ("reverse",
+            // Read until EOF, then print the input backwards.
+            ">,+[->,+]<[.<]",
+            b"stressed",
+            b"desserts"),
+        ("clear and scan",
+            "+++>++>+>>+++++[<]>[[-]>]++++++++[<++++++++>-]<+.",
+            b"",
+            b"A"),
+    ];
+
+    #[test]
+    fn test_interp_conformance() {
+        for (name, src, input, expected) in CONFORMANCE_PROGRAMS {
+            let mut output = Vec::new();
+            let mut state = State::new(lex(src));
+            state.interp(&input[..], output.by_ref()).unwrap();
+
+            assert_eq!(&output[..], *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_execute_conformance() {
+        for (name, src, input, expected) in CONFORMANCE_PROGRAMS {
+            let input = input.to_vec();
+
+            let asm_run_res = compile_and_run_asm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+            assert!(asm_run_res.status.success(), "{}", name);
+            assert_eq!(&asm_run_res.stdout[..], *expected, "{}", name);
+
+            let partial_run_res = compile_and_run_asm_with_input(&mut lex(src), &input, true, true, true).unwrap();
+            assert!(partial_run_res.status.success(), "{}", name);
+            assert_eq!(&partial_run_res.stdout[..], *expected, "{}", name);
+
+            let llvm_run_res = compile_and_run_llvm_with_input(&mut lex(src), &input, true, true, false).unwrap();
+            assert!(llvm_run_res.status.success(), "{}", name);
+            assert_eq!(&llvm_run_res.stdout[..], *expected, "{}", name);
+
+            let llvm_partial_run_res = compile_and_run_llvm_with_input(&mut lex(src), &input, true, true, true).unwrap();
+            assert!(llvm_partial_run_res.status.success(), "{}", name);
+            assert_eq!(&llvm_partial_run_res.stdout[..], *expected, "{}", name);
+        }
+    }
+
     #[test]
     #[ignore]
     fn test_bfcheck() {
@@ -2033,7 +6492,7 @@ mod tests {
             let input_prog = std::fs::read_to_string(prog_path.clone()).expect("unable to read file");
             let mut input = input.clone();
 
-            let run_res = compile_and_run_llvm_with_input(&mut lex(&input_prog), &input, true, false).unwrap();
+            let run_res = compile_and_run_llvm_with_input(&mut lex(&input_prog), &input, true, true, false).unwrap();
             assert!(run_res.status.success());
 
             let mut orig_output = Vec::new();
@@ -1,5 +1,5 @@
 use core::panic;
-use std::{collections::{HashMap, VecDeque}, io::{self, ErrorKind, Read, Write}, usize};
+use std::{collections::{BTreeSet, HashMap, HashSet, VecDeque}, error, fmt, io::{self, ErrorKind, Read, Write}, usize};
 
 use crate::common::*;
 
@@ -9,6 +9,307 @@ enum Cell {
     Val(u8)
 }
 
+// How cell values behave when incremented past 255 or decremented past 0.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Arithmetic {
+    Wrapping,
+    Saturating,
+}
+
+// What happens when the head moves past the end of the tape.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TapeMode {
+    // Add a zero cell on that side
+    Growable,
+
+    // The tape is a ring of the given number of cells and the head comes out the other side
+    Wrapping { size: usize },
+}
+
+// What happens to each byte the program writes before it's output.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OutputFilter {
+    // Write bytes unchanged
+    Raw,
+
+    // Clear the high bit, for 7-bit terminals
+    SevenBit,
+}
+
+// How FormattedOutput shows each byte the program writes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Raw,
+
+    // Two hex digits per byte, separated by spaces
+    Hex,
+
+    // A decimal number per byte, separated by spaces
+    Dec,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "raw" => Some(OutputFormat::Raw),
+            "hex" => Some(OutputFormat::Hex),
+            "dec" => Some(OutputFormat::Dec),
+            _ => None,
+        }
+    }
+}
+
+// Reads from the inner reader and copies every byte read into log, so the input a run consumed can
+// be fed back to it later. State reads a byte at a time, so the log holds exactly the bytes the
+// program read.
+pub struct RecordingReader<R: Read, L: Write> {
+    inner: R,
+    log: L,
+}
+
+impl<R: Read, L: Write> RecordingReader<R, L> {
+    pub fn new(inner: R, log: L) -> Self {
+        RecordingReader { inner, log }
+    }
+
+    pub fn into_inner(self) -> (R, L) {
+        (self.inner, self.log)
+    }
+}
+
+impl<R: Read, L: Write> Read for RecordingReader<R, L> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.log.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+// Writes bytes to the inner writer as text in the given format, for programs whose output isn't
+// meant to be printed.
+pub struct FormattedOutput<W: Write> {
+    inner: W,
+    format: OutputFormat,
+    bytes_written: usize,
+}
+
+impl<W: Write> FormattedOutput<W> {
+    pub fn new(inner: W, format: OutputFormat) -> Self {
+        FormattedOutput { inner, format, bytes_written: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for FormattedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.format == OutputFormat::Raw {
+            return self.inner.write(buf);
+        }
+
+        let mut text = String::new();
+        for (i, x) in buf.iter().enumerate() {
+            if self.bytes_written + i > 0 {
+                text.push(' ');
+            }
+            match self.format {
+                OutputFormat::Hex => text += &format!("{:02x}", x),
+                _ => text += &x.to_string(),
+            }
+        }
+
+        // All of the text is written so the separators stay in step with bytes_written.
+        self.inner.write_all(text.as_bytes())?;
+        self.bytes_written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Returned when a program tries to write more bytes than its output limit allows.
+#[derive(Debug, Clone)]
+pub struct OutputLimitExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for OutputLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "program exceeded the output limit of {} bytes", self.limit)
+    }
+}
+
+impl error::Error for OutputLimitExceeded {}
+
+// Returned when a program runs more instructions than its step limit allows.
+#[derive(Debug, Clone)]
+pub struct StepLimitExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for StepLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "program exceeded the step limit of {} instructions", self.limit)
+    }
+}
+
+impl error::Error for StepLimitExceeded {}
+
+// Returned when the program's output can't be written, e.g. because stdout was closed.
+#[derive(Debug)]
+pub struct WriteFailed {
+    pub source: io::Error,
+}
+
+impl fmt::Display for WriteFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to write output: {}", self.source)
+    }
+}
+
+impl error::Error for WriteFailed {}
+
+// What partial evaluation managed to do at compile time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialEvalReport {
+    // Instructions before bail_pc, less the instructions emitted in their place
+    pub folded_insts: usize,
+
+    // Writes turned into Output literals
+    pub outputs: usize,
+
+    // Where evaluation stopped and the rest of the program was left to run, or None if it ran to
+    // the end
+    pub bail_pc: Option<usize>,
+
+    // Whether it stopped inside a loop, throwing away the work done since entering the loop
+    pub bailed_in_loop: bool,
+}
+
+impl fmt::Display for PartialEvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Instructions folded:\t{}", self.folded_insts)?;
+        writeln!(f, "Outputs folded:\t\t{}", self.outputs)?;
+        match self.bail_pc {
+            Some(pc) => write!(f, "Bailed to runtime:\tat instruction {}{}", pc, if self.bailed_in_loop { ", inside a loop" } else { "" }),
+            None => write!(f, "Bailed to runtime:\tnever, the whole program was evaluated"),
+        }
+    }
+}
+
+// Partial evaluation gives up and leaves the rest of the program to run once its tape grows past
+// this many cells, so a program walking off forever can't use up the compiler's memory. Entering a
+// loop copies the tape, so this also bounds the time spent on such programs.
+const PARTIAL_EVAL_MAX_TAPE_LEN: usize = 1 << 12;
+
+// A cell of the tape partial evaluation left behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellTaint {
+    // Relative to where the compiled program starts the head
+    pub pos: i32,
+
+    // None if the cell's value depends on input
+    pub value: Option<u8>,
+
+    // PC of the read, or of the loop left to run at runtime, that made the cell unknown
+    pub unknown_since: Option<usize>,
+}
+
+impl fmt::Display for CellTaint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.value, self.unknown_since) {
+            (Some(val), _) => write!(f, "cell {}: {}", self.pos, val),
+            (None, Some(pc)) => write!(f, "cell {}: unknown since instruction {}", self.pos, pc),
+            (None, None) => write!(f, "cell {}: unknown", self.pos),
+        }
+    }
+}
+
+// How many cells on each side of the head DebugCommand::DumpTape prints.
+const DUMP_TAPE_RADIUS: usize = 8;
+
+// How many cells State::dump_tape writes to a line.
+const DUMP_TAPE_ROW_LEN: usize = 16;
+
+// What a run left on the cells it touched. A growable tape only grows when the head reaches a new
+// cell, so every cell on it was touched. A wrapping tape is allocated up front, so only the cells
+// the program reached are counted.
+pub struct TapeStats {
+    pub touched_cells: usize,
+    pub min_head_pos: isize,
+    pub max_head_pos: isize,
+
+    // Number of cells holding each value.
+    pub value_histogram: [usize; 256],
+}
+
+// How many times each instruction of a program ran, saved by interp -profile-out so compile
+// -profile-in can tell which loops are hot. Pcs count from the program's first instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub counts: Vec<usize>,
+}
+
+// A loop is hot if its body ran at least 1/HOT_LOOP_RATIO as often as the hottest loop's.
+const HOT_LOOP_RATIO: usize = 10;
+
+impl Profile {
+    pub fn to_json(&self) -> String {
+        let counts : Vec<String> = self.counts.iter().map(|count| count.to_string()).collect();
+        format!("{{\"counts\": [{}]}}\n", counts.join(", "))
+    }
+
+    // Parses the JSON to_json writes. Only the counts field is read.
+    pub fn from_json(json: &str) -> Result<Profile, BadProfile> {
+        let key = json.find("\"counts\"").ok_or(BadProfile)?;
+        let rest = &json[key + "\"counts\"".len()..];
+        let rest = rest.trim_start().strip_prefix(':').ok_or(BadProfile)?;
+        let rest = rest.trim_start().strip_prefix('[').ok_or(BadProfile)?;
+        let end = rest.find(']').ok_or(BadProfile)?;
+
+        let list = rest[..end].trim();
+        if list.is_empty() {
+            return Ok(Profile { counts: Vec::new() });
+        }
+        let counts = list.split(',')
+            .map(|count| count.trim().parse().map_err(|_| BadProfile))
+            .collect::<Result<Vec<usize>, BadProfile>>()?;
+        Ok(Profile { counts })
+    }
+
+    // Pcs of the '[' of each hot loop in the program the profile was recorded for. A loop's body
+    // count is that of its first instruction, as in the -p listing.
+    pub fn hot_loops(&self, program: &[Instruction]) -> HashSet<usize> {
+        let body_count = |open: usize| self.counts.get(open + 1).copied().unwrap_or(0);
+        let loops = bracket_pairs(program);
+        let hottest = loops.iter().map(|(open, _)| body_count(*open)).max().unwrap_or(0);
+        if hottest == 0 {
+            return HashSet::new();
+        }
+
+        loops.iter()
+            .map(|(open, _)| *open)
+            .filter(|open| body_count(*open) * HOT_LOOP_RATIO >= hottest)
+            .collect()
+    }
+}
+
+// Returned when a profile file isn't in the format Profile::to_json writes.
+#[derive(Debug, Clone)]
+pub struct BadProfile;
+
+impl fmt::Display for BadProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed profile, expected {{\"counts\": [...]}}")
+    }
+}
+
+impl error::Error for BadProfile {}
+
 struct LoopEnterState {
     tape: VecDeque<Cell>,
     head_pos: usize,
@@ -16,6 +317,8 @@ struct LoopEnterState {
     tape_offset: isize, 
     program_counter: usize,
     emitted_insts: Vec<Instruction>,
+    unknown_since: HashMap<i32, usize>,
+    known_input_pos: usize,
 }
 
 pub struct State {
@@ -32,6 +335,10 @@ pub struct State {
     program: Vec<Instruction>,
     execution_counter: Vec<usize>,
 
+    // Instructions run by interp in total, for progress callbacks and the step limit
+    instructions_executed: u64,
+    step_limit: u64,
+
     // If the PC becomes unknown inside of a loop, we need to reset the execution's state to the
     // beginning of the last outermost loop, then begin execution from there.
     loop_enter_state : Option<LoopEnterState>,
@@ -40,12 +347,56 @@ pub struct State {
     loop_level : i32,
 
     jump_dests: HashMap<usize, usize>,
+
+    arithmetic: Arithmetic,
+    tape_mode: TapeMode,
+    output_filter: OutputFilter,
+
+    // Which cells of a wrapping tape the program has reached, for tape_stats. Empty for a growable
+    // tape, which only grows when a cell is reached.
+    visited: Vec<bool>,
+
+    // What a read stores once input runs out
+    eof_byte: u8,
+
+    // Positions relative to where the head started of cells the core commands treat as 32 bit little
+    // endian values spanning four cells. Instructions from the optimization passes ignore them.
+    wide_cells: HashSet<isize>,
+
+    bytes_written: usize,
+
+    // PC of the read or loop that made each unknown cell unknown during partial evaluation, keyed by
+    // the cell's position relative to where the compiled program starts the head.
+    unknown_since: HashMap<i32, usize>,
+
+    // Input partial evaluation may read at compile time, and how much of it has been read
+    known_input: Vec<u8>,
+    known_input_pos: usize,
+
+    // Whether partial evaluation leaves a loop whose condition is unknown to run at runtime and
+    // carries on after it, instead of stopping there.
+    past_unknown_loops: bool,
+
+    // Most Outputs partial evaluation may fold writes into
+    partial_eval_output_limit: usize,
+
+    // Whether the cell under the head when the program ends is observed afterwards, so partial
+    // evaluation must leave its value there.
+    final_cell_live: bool,
 }
 
 impl State {
     pub fn new(program: Vec<Instruction>) -> Self {
-        let mut t = VecDeque::new();
-        t.push_back(Cell::Val(0));
+        State::with_tape(program, &[])
+    }
+
+    // Start with the tape holding the given bytes and the head on the first of them. An empty slice
+    // gives the default tape of a single zero cell.
+    pub fn with_tape(program: Vec<Instruction>, initial: &[u8]) -> Self {
+        let mut t : VecDeque<Cell> = initial.iter().map(|val| Cell::Val(*val)).collect();
+        if t.is_empty() {
+            t.push_back(Cell::Val(0));
+        }
 
         let execution_counter = vec![0; program.len()];
         let jump_dests = compute_jump_dests(&program);
@@ -58,82 +409,276 @@ impl State {
             program_counter: 0,
             program,
             execution_counter,
+            instructions_executed: 0,
+            step_limit: u64::MAX,
             loop_enter_state: None,
             loop_level: 0,
             jump_dests,
+            arithmetic: Arithmetic::Wrapping,
+            tape_mode: TapeMode::Growable,
+            output_filter: OutputFilter::Raw,
+            visited: Vec::new(),
+            eof_byte: 255,
+            wide_cells: HashSet::new(),
+            bytes_written: 0,
+            unknown_since: HashMap::new(),
+            known_input: Vec::new(),
+            known_input_pos: 0,
+            past_unknown_loops: false,
+            partial_eval_output_limit: usize::MAX,
+            final_cell_live: false,
+        }
+    }
+
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn set_output_filter(&mut self, output_filter: OutputFilter) {
+        self.output_filter = output_filter;
+    }
+
+    pub fn set_eof_byte(&mut self, eof_byte: u8) {
+        self.eof_byte = eof_byte;
+    }
+
+    // Treat the cells at these positions, relative to where the head starts, as 32 bit values when
+    // the head is on them, as ";w32" directives ask. See common::wide_cells.
+    pub fn set_wide_cells(&mut self, cells: impl IntoIterator<Item = i32>) {
+        self.wide_cells = cells.into_iter().map(|cell| cell as isize).collect();
+    }
+
+    // Have partial_eval carry on past loops whose condition depends on input, as long as each leaves
+    // the head where it found it. The loop is left to run at runtime and the cells it may write
+    // become unknown, but other known cells keep folding after it.
+    pub fn set_partial_eval_past_unknown_loops(&mut self, enabled: bool) {
+        self.past_unknown_loops = enabled;
+    }
+
+    // Stop partial_eval at the write that would fold into more than this many Outputs, so a program
+    // printing a lot of known text doesn't compile to an Output per byte. The rest of the program is
+    // left to run, the same as when partial eval reaches a loop on an unknown cell.
+    pub fn set_partial_eval_output_limit(&mut self, limit: usize) {
+        self.partial_eval_output_limit = limit;
+    }
+
+    // Have partial_eval leave the head where the program ends with that cell's value, as a compiled
+    // program that exits with the cell under the head needs, even when nothing else reads the cell.
+    pub fn set_partial_eval_final_cell_live(&mut self, enabled: bool) {
+        self.final_cell_live = enabled;
+    }
+
+    // Stop interpreting with StepLimitExceeded once the program has run this many instructions, so
+    // programs that never halt can be run safely.
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    fn filter_output(&self, x : u8) -> u8 {
+        match self.output_filter {
+            OutputFilter::Raw => x,
+            OutputFilter::SevenBit => x & 0x7f,
         }
     }
 
+    // Must be called before running the program. A wrapping tape is allocated up front, so initial
+    // cells past its size are dropped.
+    pub fn set_tape_mode(&mut self, tape_mode: TapeMode) {
+        if let TapeMode::Wrapping { size } = tape_mode {
+            assert!(size > 0, "a wrapping tape needs at least one cell");
+            self.tape.resize(size, Cell::Val(0));
+            self.visited = vec![false; size];
+            self.visited[self.head_pos] = true;
+        }
+
+        self.tape_mode = tape_mode;
+    }
+
     fn move_right(&mut self) {
         self.head_pos += 1;
 
         if self.head_pos >= self.tape.len() {
-            self.tape.push_back(Cell::Val(0));
+            match self.tape_mode {
+                TapeMode::Growable => self.tape.push_back(Cell::Val(0)),
+                TapeMode::Wrapping { .. } => self.head_pos = 0,
+            }
         }
+        self.visit(self.head_pos);
 
         self.program_counter += 1;
     }
 
     fn move_left(&mut self) {
         if self.head_pos == 0 {
-            self.tape.push_front(Cell::Val(0));
-            self.tape_offset += 1;
+            match self.tape_mode {
+                TapeMode::Growable => {
+                    self.tape.push_front(Cell::Val(0));
+                    self.tape_offset += 1;
+                },
+                TapeMode::Wrapping { size } => self.head_pos = size - 1,
+            }
         } else {
             self.head_pos -= 1;
         }
+        self.visit(self.head_pos);
 
         self.program_counter += 1;
     }
 
+    fn visit(&mut self, index : usize) {
+        if let Some(visited) = self.visited.get_mut(index) {
+            *visited = true;
+        }
+    }
+
+    // Indices of the four cells of the wide cell under the head, lowest byte first, or None if the
+    // head isn't on one.
+    fn wide_cell_at_head(&mut self) -> Option<[usize; 4]> {
+        if self.wide_cells.is_empty() || !self.wide_cells.contains(&(self.head_pos as isize - self.tape_offset)) {
+            return None;
+        }
+
+        // The head is at the left end of the cell, so the tape only grows to the right.
+        let mut indices = [0; 4];
+        for (byte, index) in indices.iter_mut().enumerate() {
+            *index = self.tape_index(self.head_pos as isize + byte as isize);
+        }
+        return Some(indices);
+    }
+
+    fn wide_val(&self, indices : [usize; 4]) -> u32 {
+        return u32::from_le_bytes(indices.map(|index| self.cell_val(index)));
+    }
+
+    fn set_wide_val(&mut self, indices : [usize; 4], val : u32) {
+        for (index, byte) in indices.into_iter().zip(val.to_le_bytes()) {
+            self.tape[index] = Cell::Val(byte);
+        }
+    }
+
     fn increment(&mut self) {
+        if let Some(indices) = self.wide_cell_at_head() {
+            let x = self.wide_val(indices);
+            let new_val = match self.arithmetic {
+                Arithmetic::Wrapping => u32::wrapping_add(x, 1),
+                Arithmetic::Saturating => u32::saturating_add(x, 1),
+            };
+            self.set_wide_val(indices, new_val);
+            self.program_counter += 1;
+            return;
+        }
+
         match self.tape[self.head_pos] {
             Cell::Unknown => panic!("incremented unknown cell"),
-            Cell::Val(x) => self.tape[self.head_pos] = Cell::Val(u8::wrapping_add(x, 1u8))
+            Cell::Val(x) => {
+                let new_val = match self.arithmetic {
+                    Arithmetic::Wrapping => u8::wrapping_add(x, 1u8),
+                    Arithmetic::Saturating => u8::saturating_add(x, 1u8),
+                };
+                self.tape[self.head_pos] = Cell::Val(new_val);
+            }
         }
 
         self.program_counter += 1;
     }
 
     fn decrement(&mut self) {
+        if let Some(indices) = self.wide_cell_at_head() {
+            let x = self.wide_val(indices);
+            let new_val = match self.arithmetic {
+                Arithmetic::Wrapping => u32::wrapping_sub(x, 1),
+                Arithmetic::Saturating => u32::saturating_sub(x, 1),
+            };
+            self.set_wide_val(indices, new_val);
+            self.program_counter += 1;
+            return;
+        }
+
         match self.tape[self.head_pos] {
             Cell::Unknown => panic!("decremented unknown cell"),
-            Cell::Val(x) => self.tape[self.head_pos] = Cell::Val(u8::wrapping_sub(x, 1u8))
+            Cell::Val(x) => {
+                let new_val = match self.arithmetic {
+                    Arithmetic::Wrapping => u8::wrapping_sub(x, 1u8),
+                    Arithmetic::Saturating => u8::saturating_sub(x, 1u8),
+                };
+                self.tape[self.head_pos] = Cell::Val(new_val);
+            }
         }
 
         self.program_counter += 1;
     }
 
-    fn write(&mut self, mut writer : impl Write) {
+    fn write(&mut self, mut writer : impl Write) -> io::Result<()> {
         match self.tape[self.head_pos] {
             Cell::Unknown => panic!("wrote unknown cell"),
             Cell::Val(x) => {
-                let buf = [x;1];
-                writer.write_all(&buf).expect("unable to write buf");
+                let buf = [self.filter_output(x);1];
+                writer.write_all(&buf)?;
+            }
+        }
+
+        self.bytes_written += 1;
+
+        self.program_counter += 1;
+        Ok(())
+    }
+
+    // The cells within DUMP_TAPE_RADIUS of the head, with the head's cell in brackets.
+    fn format_tape_near_head(&self) -> String {
+        let start = self.head_pos.saturating_sub(DUMP_TAPE_RADIUS);
+        let end = usize::min(self.tape.len(), self.head_pos + DUMP_TAPE_RADIUS + 1);
+
+        let mut cells = Vec::new();
+        for pos in start..end {
+            let cell = match self.tape[pos] {
+                Cell::Unknown => String::from("?"),
+                Cell::Val(x) => x.to_string(),
+            };
+
+            if pos == self.head_pos {
+                cells.push(format!("[{}]", cell));
+            } else {
+                cells.push(cell);
             }
         }
 
+        return cells.join(" ");
+    }
+
+    fn debug(&mut self, cmd : DebugCommand) {
+        match cmd {
+            DebugCommand::DumpTape => {
+                eprintln!("pc {}, head {}: {}", self.program_counter, self.compiled_head_offset(), self.format_tape_near_head());
+            },
+            DebugCommand::PrintCell => {
+                match self.tape[self.head_pos] {
+                    Cell::Unknown => eprintln!("?"),
+                    Cell::Val(x) => eprintln!("{}", x),
+                }
+            },
+        }
+
         self.program_counter += 1;
     }
 
     fn read(&mut self, mut reader : impl Read) {
-        // Read a character from stdin
-        let mut buf = [0u8; 1];
-        let read_res = reader.read_exact(&mut buf);
-        match read_res {
-            Ok(_) => (),
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => buf[0] = 255,
-            Err(_) => panic!("Error while reading from stdin!")
+        if let Some(indices) = self.wide_cell_at_head() {
+            let bytes = [0; 4].map(|_| read_byte(&mut reader, self.eof_byte));
+            self.set_wide_val(indices, u32::from_le_bytes(bytes));
+            self.program_counter += 1;
+            return;
         }
 
-        self.tape[self.head_pos] = Cell::Val(buf[0]);
+        self.tape[self.head_pos] = Cell::Val(read_byte(reader, self.eof_byte));
 
         self.program_counter += 1;
     }
 
     fn jump_if_zero(&mut self) {
-        let curr_value = match self.tape[self.head_pos] {
-            Cell::Unknown => panic!("jump if 0 with unknown cell"),
-            Cell::Val(x) => x
+        let curr_value = match (self.wide_cell_at_head(), self.tape[self.head_pos]) {
+            (Some(indices), _) => self.wide_val(indices),
+            (None, Cell::Unknown) => panic!("jump if 0 with unknown cell"),
+            (None, Cell::Val(x)) => x as u32,
         };
         
         if curr_value == 0 {
@@ -144,9 +689,10 @@ impl State {
     }
 
     fn jump_unless_zero(&mut self) {
-        let curr_value = match self.tape[self.head_pos] {
-            Cell::Unknown => panic!("jump unless 0 with unknown cell"),
-            Cell::Val(x) => x
+        let curr_value = match (self.wide_cell_at_head(), self.tape[self.head_pos]) {
+            (Some(indices), _) => self.wide_val(indices),
+            (None, Cell::Unknown) => panic!("jump unless 0 with unknown cell"),
+            (None, Cell::Val(x)) => x as u32,
         };
  
         if curr_value != 0 {
@@ -156,33 +702,240 @@ impl State {
         }
     }
 
-    pub fn interp(&mut self, mut reader : impl Read, mut writer : impl Write)
+    // Index of the tape cell `index` cells from the start of the current tape, growing or wrapping
+    // the tape if it's out of range the same way moving the head there would.
+    fn tape_index(&mut self, index : isize) -> usize {
+        match self.tape_mode {
+            TapeMode::Growable => {
+                let mut index = index;
+                while index < 0 {
+                    self.tape.push_front(Cell::Val(0));
+                    self.tape_offset += 1;
+                    self.head_pos += 1;
+                    index += 1;
+                }
+
+                let index = index as usize;
+                if index >= self.tape.len() {
+                    self.tape.resize(index + 1, Cell::Val(0));
+                }
+                index
+            },
+            TapeMode::Wrapping { size } => {
+                let index = index.rem_euclid(size as isize) as usize;
+                self.visit(index);
+                index
+            },
+        }
+    }
+
+    // Index of the cell at the given offset from the head
+    fn offset_index(&mut self, offset : i32) -> usize {
+        self.tape_index(self.head_pos as isize + offset as isize)
+    }
+
+    // Index of the cell at the given position relative to where the compiled program starts the head
+    fn pos_index(&mut self, pos : i32) -> usize {
+        self.tape_index(self.tape_offset + pos as isize)
+    }
+
+    fn cell_val(&self, index : usize) -> u8 {
+        match self.tape[index] {
+            Cell::Unknown => panic!("used unknown cell"),
+            Cell::Val(x) => x,
+        }
+    }
+
+    fn add_to_cell(&mut self, index : usize, val : u8) {
+        let x = self.cell_val(index);
+        let new_val = match self.arithmetic {
+            Arithmetic::Wrapping => u8::wrapping_add(x, val),
+            Arithmetic::Saturating => u8::saturating_add(x, val),
+        };
+        self.tape[index] = Cell::Val(new_val);
+    }
+
+    fn sub_from_cell(&mut self, index : usize, val : u8) {
+        let x = self.cell_val(index);
+        let new_val = match self.arithmetic {
+            Arithmetic::Wrapping => u8::wrapping_sub(x, val),
+            Arithmetic::Saturating => u8::saturating_sub(x, val),
+        };
+        self.tape[index] = Cell::Val(new_val);
+    }
+
+    // Run one of the instructions the optimization passes synthesize, with the same effect the
+    // compiled backends give it.
+    fn synthesized(&mut self, mut reader : impl Read, mut writer : impl Write, max_output : usize) -> Result<(), Box<dyn error::Error>> {
+        match self.program[self.program_counter].clone() {
+            Instruction::Zero => self.tape[self.head_pos] = Cell::Val(0),
+            Instruction::SetCurrent(val) => self.tape[self.head_pos] = Cell::Val(val),
+            Instruction::ZeroAt(offset) => {
+                let idx = self.offset_index(offset);
+                self.tape[idx] = Cell::Val(0);
+            },
+            Instruction::Add(offset) => {
+                let idx = self.offset_index(offset);
+                self.add_to_cell(idx, self.cell_val(self.head_pos));
+            },
+            Instruction::Sub(offset) => {
+                let idx = self.offset_index(offset);
+                self.sub_from_cell(idx, self.cell_val(self.head_pos));
+            },
+            Instruction::AddConst(offset, val) => {
+                let idx = self.offset_index(offset);
+                self.add_to_cell(idx, val);
+            },
+            Instruction::Scan(x) | Instruction::ClearScan(x) => {
+                assert!(x != 0, "scan with a stride of 0");
+                let clears = matches!(self.program[self.program_counter], Instruction::ClearScan(_));
+                while self.cell_val(self.head_pos) != 0 {
+                    if clears {
+                        self.tape[self.head_pos] = Cell::Val(0);
+                    }
+                    self.head_pos = self.offset_index(x);
+                }
+            },
+            Instruction::SetHeadPos(pos) => self.head_pos = self.pos_index(pos),
+            Instruction::SetCell(pos, val) => {
+                let idx = self.pos_index(pos);
+                self.tape[idx] = Cell::Val(val);
+            },
+            Instruction::ReadAt(pos) => {
+                if let Err(source) = writer.flush() {
+                    return Err(Box::new(WriteFailed { source }));
+                }
+                let idx = self.pos_index(pos);
+                self.tape[idx] = Cell::Val(read_byte(&mut reader, self.eof_byte));
+            },
+            Instruction::WriteAt(pos) => {
+                let idx = self.pos_index(pos);
+                let val = self.cell_val(idx);
+                self.write_bytes(&[val], &mut writer, max_output)?;
+            },
+            Instruction::Output(val) => self.write_bytes(&[val], &mut writer, max_output)?,
+            Instruction::OutputStr(bytes) => self.write_bytes(&bytes, &mut writer, max_output)?,
+            Instruction::Nop => (),
+            inst => panic!("unhandled instruction: {}", inst),
+        }
+
+        self.program_counter += 1;
+        Ok(())
+    }
+
+    // Write as much of bytes as fits under max_output, failing if it didn't all fit
+    fn write_bytes(&mut self, bytes : &[u8], mut writer : impl Write, max_output : usize) -> Result<(), Box<dyn error::Error>> {
+        let fits = usize::min(bytes.len(), max_output.saturating_sub(self.bytes_written));
+        let filtered : Vec<u8> = bytes[..fits].iter().map(|x| self.filter_output(*x)).collect();
+        if let Err(source) = writer.write_all(&filtered) {
+            return Err(Box::new(WriteFailed { source }));
+        }
+        self.bytes_written += fits;
+
+        if fits < bytes.len() {
+            return Err(Box::new(OutputLimitExceeded { limit: max_output }));
+        }
+        Ok(())
+    }
+
+    // Interpret the program. Returns WriteFailed if the writer returned an error and
+    // StepLimitExceeded if the step limit was hit.
+    pub fn interp(&mut self, reader : impl Read, writer : impl Write) -> Result<(), Box<dyn error::Error>>
+    {
+        // Without a limit the program can't exceed it.
+        return self.interp_with_max_output(reader, writer, usize::MAX);
+    }
+
+    // Interpret the program, stopping before it writes more than max_output bytes in total. Returns
+    // OutputLimitExceeded if the limit was hit, StepLimitExceeded if the step limit was, and
+    // WriteFailed if the writer returned an error.
+    pub fn interp_with_max_output(&mut self, reader : impl Read, writer : impl Write, max_output : usize) -> Result<(), Box<dyn error::Error>>
+    {
+        self.interp_with_progress(reader, writer, max_output, 0, |_| ())
+    }
+
+    // Interpret the program, calling cb with the number of instructions run so far after every
+    // `every` of them.
+    pub fn interp_with_callback(&mut self, reader : impl Read, writer : impl Write, every : u64, cb : impl FnMut(u64)) -> Result<(), Box<dyn error::Error>>
+    {
+        self.interp_with_progress(reader, writer, usize::MAX, every, cb)
+    }
+
+    // interp_with_max_output and interp_with_callback together. An `every` of 0 never calls cb.
+    pub fn interp_with_progress(&mut self, mut reader : impl Read, mut writer : impl Write, max_output : usize, every : u64, mut cb : impl FnMut(u64)) -> Result<(), Box<dyn error::Error>>
     {
         loop {
             if self.program_counter >= self.program.len() {
                 break;
             }
 
+            if self.program[self.program_counter] == Instruction::Write && self.bytes_written >= max_output {
+                return Err(Box::new(OutputLimitExceeded { limit: max_output }));
+            }
+
+            if self.instructions_executed >= self.step_limit {
+                return Err(Box::new(StepLimitExceeded { limit: self.step_limit }));
+            }
+
             self.execution_counter[self.program_counter] += 1;
 
+            self.instructions_executed += 1;
+            if every != 0 && self.instructions_executed % every == 0 {
+                cb(self.instructions_executed);
+            }
+
             match self.program[self.program_counter] {
                 Instruction::MoveRight => self.move_right(),
                 Instruction::MoveLeft => self.move_left(),
                 Instruction::Increment => self.increment(),
                 Instruction::Decrement => self.decrement(),
-                Instruction::Write => self.write(&mut writer),
-                Instruction::Read => self.read(&mut reader),
+                Instruction::Write => {
+                    // A wide cell is written as its four bytes, lowest first.
+                    if let Some(indices) = self.wide_cell_at_head() {
+                        let bytes = self.wide_val(indices).to_le_bytes();
+                        self.write_bytes(&bytes, &mut writer, max_output)?;
+                        self.program_counter += 1;
+                    } else if let Err(source) = self.write(&mut writer) {
+                        return Err(Box::new(WriteFailed { source }));
+                    }
+                },
+                Instruction::Read => {
+                    // Like the compiled runner, show any prompt before waiting for input.
+                    if let Err(source) = writer.flush() {
+                        return Err(Box::new(WriteFailed { source }));
+                    }
+                    self.read(&mut reader)
+                },
                 Instruction::JumpIfZero => self.jump_if_zero(),
                 Instruction::JumpUnlessZero => self.jump_unless_zero(),
-                _ => panic!("unhandled instruction: {}", self.program[self.program_counter])
+                Instruction::Debug(cmd) => self.debug(cmd),
+                _ => self.synthesized(&mut reader, &mut writer, max_output)?,
             }
         }
+
+        return Ok(());
+    }
+
+    // Position of the cell at the given tape index relative to where the compiled program starts
+    // the head, or None if it's too far away for the i32 offsets compiled instructions use.
+    fn compiled_offset(&self, idx: usize) -> Option<i32> {
+        let idx : isize = idx.try_into().ok()?;
+        return idx.checked_sub(self.tape_offset)?.try_into().ok();
+    }
+
+    // Whether every cell on the tape has a compiled offset. partial_eval checks this before each
+    // instruction, so the offsets it emits always fit.
+    fn tape_addressable(&self) -> bool {
+        return self.compiled_offset(0).is_some() && self.compiled_offset(self.tape.len() - 1).is_some();
+    }
+
+    // Position of the head relative to where the compiled program starts it.
+    fn compiled_head_offset(&self) -> i32 {
+        return self.compiled_offset(self.head_pos).expect("head is outside the addressable tape");
     }
 
     fn sync_compiled_head_pos(&mut self, insts: &mut Vec<Instruction>) {
-        let head_pos : i32 = self.head_pos.try_into().unwrap();
-        let offset : i32 = self.tape_offset.try_into().unwrap();
-        let head_offset : i32 = head_pos - offset;
+        let head_offset = self.compiled_head_offset();
 
         if head_offset != self.outputted_head_pos.try_into().unwrap() {
             insts.push(Instruction::SetHeadPos(head_offset));
@@ -190,15 +943,125 @@ impl State {
         }
     }
 
-    // Evaluate all instructions not tainted by input. After all instructions are evaluated, emit
-    // instructions to setup the head and tape state when evaluation has finished.
+    // If the loop starting at the program counter only adds constants to cells around a known index
+    // cell that it steps by one each iteration, apply all of its iterations at once and skip past it.
+    // Returns false, leaving the program counter and cell values alone, if the loop isn't like that or
+    // touches an unknown cell.
+    fn fold_simple_loop(&mut self) -> bool {
+        if self.arithmetic != Arithmetic::Wrapping {
+            return false;
+        }
+
+        let end_pc = self.jump_dests[&self.program_counter];
+        let deltas = match simple_loop_deltas(&self.program[self.program_counter + 1..end_pc]) {
+            Some(deltas) => deltas,
+            None => return false,
+        };
+
+        // The index cell reaches zero after k decrements or 256 - k increments.
+        let index = self.cell_val(self.head_pos);
+        let trip_count = match deltas.get(&0) {
+            Some(1) => index.wrapping_neg(),
+            Some(255) => index,
+            _ => return false,
+        };
+
+        // Grow the tape to cover the loop first, since growing it to the left moves every index.
+        for offset in deltas.keys() {
+            self.offset_index(*offset);
+        }
+        if deltas.keys().any(|offset| {
+            let idx = self.offset_index(*offset);
+            self.tape[idx] == Cell::Unknown
+        }) {
+            return false;
+        }
+
+        for (offset, delta) in &deltas {
+            let idx = self.offset_index(*offset);
+            self.add_to_cell(idx, delta.wrapping_mul(trip_count));
+        }
+
+        self.program_counter = end_pc + 1;
+        return true;
+    }
+
+    // Emit the loop at the program counter, whose condition is unknown, to run at runtime, and move
+    // past it. The cells it uses get their known values written out first, and the ones it may
+    // write become unknown. Returns false, changing nothing but the tape's size, if the head's
+    // position after the loop can't be known or the loop would read input known at compile time.
+    fn leave_unknown_loop(&mut self, insts : &mut Vec<Instruction>) -> bool {
+        let end_pc = self.jump_dests[&self.program_counter];
+        let loop_insts = &self.program[self.program_counter..=end_pc];
+        let (used, written) = match balanced_loop_cells(loop_insts) {
+            Some(cells) => cells,
+            None => return false,
+        };
+        if loop_insts.contains(&Instruction::Read) && self.known_input_pos < self.known_input.len() {
+            return false;
+        }
+
+        // Grow the tape to cover the loop first, since growing it to the left moves every index.
+        for offset in &used {
+            self.offset_index(*offset);
+        }
+        if !self.tape_addressable() {
+            return false;
+        }
+
+        self.sync_compiled_head_pos(insts);
+        for offset in &used {
+            let idx = self.offset_index(*offset);
+            if let Cell::Val(x) = self.tape[idx] {
+                insts.push(Instruction::SetCell(self.compiled_offset(idx).unwrap(), x));
+            }
+        }
+        insts.extend_from_slice(&self.program[self.program_counter..=end_pc]);
+
+        for offset in &written {
+            let idx = self.offset_index(*offset);
+            self.tape[idx] = Cell::Unknown;
+            self.unknown_since.entry(self.compiled_offset(idx).unwrap()).or_insert(self.program_counter);
+        }
+
+        // The loop only exits once the head's cell is zero.
+        self.tape[self.head_pos] = Cell::Val(0);
+        self.program_counter = end_pc + 1;
+        return true;
+    }
+
+    // Give up on partial evaluation, leaving the whole program to run.
+    fn abandon_partial_eval(&mut self) -> Vec<Instruction> {
+        self.program_counter = 0;
+        self.loop_enter_state = None;
+        return self.program.clone();
+    }
+
+    // Evaluate all instructions not tainted by input. After all instructions are evaluated, emit
+    // instructions to setup the head and tape state when evaluation has finished. Evaluation also
+    // stops at instructions other passes synthesize, so it can run after them, just less effectively.
     pub fn partial_eval(&mut self) -> Vec<Instruction> {
         let mut insts = Vec::new();
+        let mut outputs = 0;
 
         loop {
             if self.program_counter >= self.program.len() {
                 break;
             }
+
+            // A tape the compiled program can't address can't be written out, so go back to where
+            // the outermost loop was entered, or leave the whole program to run if we aren't in one.
+            if !self.tape_addressable() {
+                if self.loop_enter_state.is_none() {
+                    return self.abandon_partial_eval();
+                }
+                break;
+            }
+
+            // Bail out the same way as when the PC becomes unknown.
+            if self.tape.len() > PARTIAL_EVAL_MAX_TAPE_LEN {
+                break;
+            }
             
             match self.program[self.program_counter] {
                 Instruction::MoveRight => self.move_right(),
@@ -226,27 +1089,58 @@ impl State {
                     }
                 }
 
+                // I/O away from the compiled head addresses the cell directly rather than moving
+                // the head there.
                 Instruction::Write => {
                     match self.tape[self.head_pos] {
                         Cell::Unknown => {
-                            self.sync_compiled_head_pos(&mut insts);
-                            insts.push(Instruction::Write);
+                            let head_offset = self.compiled_head_offset();
+                            if head_offset == self.outputted_head_pos.try_into().unwrap() {
+                                insts.push(Instruction::Write);
+                            } else {
+                                insts.push(Instruction::WriteAt(head_offset));
+                            }
+                        }
+                        Cell::Val(_) if outputs == self.partial_eval_output_limit => break,
+                        Cell::Val(x) => {
+                            insts.push(Instruction::Output(x));
+                            outputs += 1;
                         }
-                        Cell::Val(x) => insts.push(Instruction::Output(x))
                     };
                     self.program_counter += 1;
                 },
 
+                // Reads of known input are evaluated like any other instruction.
+                Instruction::Read if self.known_input_pos < self.known_input.len() => {
+                    self.tape[self.head_pos] = Cell::Val(self.known_input[self.known_input_pos]);
+                    self.known_input_pos += 1;
+                    self.program_counter += 1;
+                },
+
                 Instruction::Read => {
-                    self.sync_compiled_head_pos(&mut insts);
+                    let head_offset = self.compiled_head_offset();
+                    if head_offset == self.outputted_head_pos.try_into().unwrap() {
+                        insts.push(Instruction::Read);
+                    } else {
+                        insts.push(Instruction::ReadAt(head_offset));
+                    }
 
                     self.tape[self.head_pos] = Cell::Unknown;
-                    insts.push(Instruction::Read);
+                    self.unknown_since.entry(head_offset).or_insert(self.program_counter);
                     self.program_counter += 1;
                 },
 
                 Instruction::JumpIfZero => {
+                    if self.tape[self.head_pos] != Cell::Unknown && self.fold_simple_loop() {
+                        continue;
+                    }
+
                     match self.tape[self.head_pos] {
+                        Cell::Unknown if self.past_unknown_loops && self.loop_enter_state.is_none() => {
+                            if !self.leave_unknown_loop(&mut insts) {
+                                break;
+                            }
+                        },
                         // We no longer know the PC. Bail out and compile the rest of the
                         // instructions.
                         Cell::Unknown => break,
@@ -259,7 +1153,9 @@ impl State {
                                         outputted_head_pos: self.outputted_head_pos,
                                         tape_offset: self.tape_offset,
                                         program_counter: self.program_counter,
-                                        emitted_insts: insts.clone()
+                                        emitted_insts: insts.clone(),
+                                        unknown_since: self.unknown_since.clone(),
+                                        known_input_pos: self.known_input_pos,
                                     })
                                 }
                                 Some(_) => (),
@@ -284,7 +1180,13 @@ impl State {
                         }
                     }
                 }
-                _ => panic!("unhandled instruction: {}", self.program[self.program_counter])
+
+                // Compiled programs ignore debug commands, so there's nothing to emit
+                Instruction::Debug(_) | Instruction::Nop => self.program_counter += 1,
+
+                // Instructions other passes synthesize are left to run at runtime, the same as a
+                // loop on an unknown cell.
+                _ => break,
             }
         }
 
@@ -299,36 +1201,113 @@ impl State {
                 self.tape_offset = s.tape_offset;
                 self.program_counter = s.program_counter;
                 insts = s.emitted_insts.clone();
+                self.unknown_since = s.unknown_since.clone();
+                self.known_input_pos = s.known_input_pos;
             }
         }
 
         // We'll be emitting runtime instructions. Write out head and tape state.
         if self.program_counter < self.program.len() {
+            // A folded loop may have grown the tape before finding an unknown cell.
+            if !self.tape_addressable() {
+                return self.abandon_partial_eval();
+            }
             self.sync_compiled_head_pos(&mut insts);
 
+            // Only cells the rest of the program can observe need to be written out.
+            let live_cells = live_cell_offsets(&self.program[self.program_counter..], self.outputted_head_pos.try_into().unwrap(), self.final_cell_live);
+
             for idx in 0..self.tape.len() {
                 match self.tape[idx] {
                     Cell::Unknown => (),
                     Cell::Val(x) => {
-                        let idx : i32 = idx.try_into().unwrap();
-                        let offset : i32 = self.tape_offset.try_into().unwrap();
-                        let offset_idx : i32 = idx - offset;
+                        let offset_idx = self.compiled_offset(idx).unwrap();
 
-                        insts.push(Instruction::SetCell(offset_idx, x));
+                        match &live_cells {
+                            Some(cells) if !cells.contains(&offset_idx) => (),
+                            _ => insts.push(Instruction::SetCell(offset_idx, x)),
+                        }
                     }
                 }
             }
+        } else if self.final_cell_live {
+            // The whole program was folded, so put the head and its cell where the program left them.
+            if !self.tape_addressable() {
+                return self.abandon_partial_eval();
+            }
+            self.sync_compiled_head_pos(&mut insts);
+
+            // An unknown cell was last written by a Read the compiled program still does.
+            if let Cell::Val(x) = self.tape[self.head_pos] {
+                insts.push(Instruction::SetCell(self.compiled_head_offset(), x));
+            }
         }
 
         // If there are any instructions after this point, simply output them and let the compiler
         // handle them.
         for pc in self.program_counter..self.program.len() {
-            insts.push(self.program[pc]);
+            insts.push(self.program[pc].clone());
         }
 
         return insts;
     }
 
+    // Like partial_eval, but the program's first reads take their bytes from input rather than
+    // leaving the cell unknown. Reads after input runs out are left for the compiled program.
+    //
+    // Bailing out inside a loop gives back the input read since entering it, so the compiled
+    // program must be fed input starting from byte known_input_consumed() rather than from the end
+    // of input.
+    pub fn partial_eval_with_input(&mut self, input: &[u8]) -> Vec<Instruction> {
+        self.known_input = input.to_vec();
+        self.known_input_pos = 0;
+        return self.partial_eval();
+    }
+
+    // How much of the input given to partial_eval_with_input was evaluated at compile time.
+    pub fn known_input_consumed(&self) -> usize {
+        return self.known_input_pos;
+    }
+
+    // Run partial_eval, but report how well it did instead of returning the instructions.
+    pub fn partial_eval_report(&mut self) -> PartialEvalReport {
+        let insts = self.partial_eval();
+
+        let bail_pc = if self.program_counter < self.program.len() { Some(self.program_counter) } else { None };
+        let evaluated_insts = bail_pc.unwrap_or(self.program.len());
+
+        // partial_eval copies everything from the bail pc onwards unchanged
+        let emitted_insts = insts.len() - (self.program.len() - evaluated_insts);
+
+        return PartialEvalReport {
+            folded_insts: evaluated_insts.saturating_sub(emitted_insts),
+            outputs: insts[..emitted_insts].iter().filter(|inst| matches!(inst, Instruction::Output(_))).count(),
+            bail_pc,
+            bailed_in_loop: self.loop_enter_state.is_some(),
+        };
+    }
+
+    // Run partial_eval, also returning which cells of the tape it left known and, for the rest, the
+    // read that made them unknown.
+    pub fn partial_eval_taint(&mut self) -> (Vec<Instruction>, Vec<CellTaint>) {
+        let insts = self.partial_eval();
+
+        let mut cells = Vec::new();
+        for (idx, cell) in self.tape.iter().enumerate() {
+            // Partial eval gave up on tapes it can't address, so there's no taint to report.
+            let Some(pos) = self.compiled_offset(idx) else {
+                continue;
+            };
+
+            cells.push(match cell {
+                Cell::Val(x) => CellTaint { pos, value: Some(*x), unknown_since: None },
+                Cell::Unknown => CellTaint { pos, value: None, unknown_since: self.unknown_since.get(&pos).copied() },
+            });
+        }
+
+        return (insts, cells);
+    }
+
     fn get_loop_executions(&self) -> (Vec<LoopExecution>, Vec<LoopExecution>) {
         let mut simple_loops : Vec<LoopExecution> = Vec::new();
         let mut complex_loops : Vec<LoopExecution> = Vec::new();
@@ -338,6 +1317,11 @@ impl State {
         let mut pointer_offset : i32 = 0;
         let mut pointer_value : i32 = 0;
 
+        // Set when an optimized instruction changes the index cell by an amount that isn't known
+        // statically, or moves the head by an unknown amount.
+        let mut index_clobbered = false;
+        let mut head_unknown = false;
+
         for pc in 0..self.program.len() {
             match curr_loop {
                 Option::None => (),
@@ -347,7 +1331,7 @@ impl State {
             match self.program[pc] {
                 Instruction::MoveRight => pointer_offset += 1,
                 Instruction::MoveLeft => pointer_offset -= 1,
-                Instruction::Write | Instruction::Read => has_io = true,
+                Instruction::Write | Instruction::Read | Instruction::WriteAt(_) | Instruction::ReadAt(_) => has_io = true,
 
                 Instruction::Increment => {
                     if pointer_offset == 0 {
@@ -360,6 +1344,28 @@ impl State {
                     }
                 },
 
+                Instruction::Output(_) => has_io = true,
+
+                Instruction::AddConst(offset, val) => {
+                    if pointer_offset + offset == 0 {
+                        pointer_value += i32::from(val as i8);
+                    }
+                },
+
+                Instruction::Add(offset) | Instruction::Sub(offset) | Instruction::ZeroAt(offset) => {
+                    if pointer_offset + offset == 0 {
+                        index_clobbered = true;
+                    }
+                },
+
+                Instruction::Zero | Instruction::SetCurrent(_) => {
+                    if pointer_offset == 0 {
+                        index_clobbered = true;
+                    }
+                },
+
+                Instruction::Scan(_) | Instruction::ClearScan(_) | Instruction::SetHeadPos(_) | Instruction::SetCell(_, _) => head_unknown = true,
+
                 _ => (),
             }
 
@@ -369,6 +1375,8 @@ impl State {
                     has_io = false;
                     pointer_offset = 0;
                     pointer_value = 0;
+                    index_clobbered = false;
+                    head_unknown = false;
                 },
 
                 Instruction::JumpUnlessZero => {
@@ -380,7 +1388,7 @@ impl State {
                     match curr {
                         Option::None => (),
                         Option::Some(l) => {
-                            if !has_io && pointer_offset == 0 && index_changed_by_1 {
+                            if !has_io && !index_clobbered && !head_unknown && pointer_offset == 0 && index_changed_by_1 {
                                 simple_loops.push(l);
                             } else {
                                 complex_loops.push(l);
@@ -408,38 +1416,215 @@ impl State {
         return (simple_loops, complex_loops);
     }
 
-    pub fn print_profile_info(&mut self)
+    // Write the per-instruction and per-loop execution counts. Pass stderr to keep them apart from
+    // the program's own output.
+    pub fn print_profile_info(&mut self, out : &mut impl Write) -> io::Result<()>
     {
-        println!("PC\tOP\t# EXECUTED");
+        writeln!(out, "PC\tOP\t# EXECUTED")?;
         for pc in 0..self.program.len() {
-            println!("{}\t{}\t{}", pc, self.program[pc], self.execution_counter[pc]);
+            writeln!(out, "{}\t{}\t{}", pc, self.program[pc], self.execution_counter[pc])?;
         }
 
         let (simple_loops, complex_loops) = self.get_loop_executions();
 
-        println!("\nSIMPLE LOOPS");
-        println!("PC\t# EXECUTED\tINSTS");
+        writeln!(out, "\nSIMPLE LOOPS")?;
+        writeln!(out, "PC\t# EXECUTED\tINSTS")?;
         for l in simple_loops {
-            print!("{}\t{}\t", l.pc, l.num_times_executed);
+            write!(out, "{}\t{}\t", l.pc, l.num_times_executed)?;
             for i in l.insts {
-                print!("{}", i);
+                write!(out, "{}", i)?;
             }
-            print!("\n");
+            write!(out, "\n")?;
         }
 
-        println!("\nCOMPLEX LOOPS");
-        println!("PC\t# EXECUTED\tINSTS");
+        writeln!(out, "\nCOMPLEX LOOPS")?;
+        writeln!(out, "PC\t# EXECUTED\tINSTS")?;
         for l in complex_loops {
-            print!("{}\t{}\t", l.pc, l.num_times_executed);
+            write!(out, "{}\t{}\t", l.pc, l.num_times_executed)?;
             for i in l.insts {
-                print!("{}", i);
+                write!(out, "{}", i)?;
             }
-            print!("\n");
+            write!(out, "\n")?;
         }
+
+        Ok(())
+    }
+
+    pub fn profile(&self) -> Profile {
+        Profile { counts: self.execution_counter.clone() }
+    }
+
+    pub fn tape_stats(&self) -> TapeStats {
+        let mut stats = TapeStats { touched_cells: 0, min_head_pos: isize::MAX, max_head_pos: isize::MIN, value_histogram: [0; 256] };
+
+        for (idx, cell) in self.tape.iter().enumerate() {
+            if !self.visited.get(idx).copied().unwrap_or(true) {
+                continue;
+            }
+
+            if let Cell::Val(x) = cell {
+                stats.value_histogram[*x as usize] += 1;
+            }
+            let pos = idx as isize - self.tape_offset;
+            stats.touched_cells += 1;
+            stats.min_head_pos = isize::min(stats.min_head_pos, pos);
+            stats.max_head_pos = isize::max(stats.max_head_pos, pos);
+        }
+
+        return stats;
+    }
+
+    // The head's position relative to where the program started it.
+    pub fn relative_head_pos(&self) -> isize {
+        self.head_pos as isize - self.tape_offset
+    }
+
+    // Each nonzero cell with its position relative to where the program started the head, from left
+    // to right. Unknown cells are left out.
+    pub fn nonzero_cells(&self) -> Vec<(isize, u8)> {
+        self.tape.iter().enumerate()
+            .filter_map(|(pos, cell)| match cell {
+                Cell::Val(x) if *x != 0 => Some((pos as isize - self.tape_offset, *x)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Write the tape, or its first `limit` cells, DUMP_TAPE_ROW_LEN to a line. Each line starts with
+    // the position of its first cell relative to where the head started, and the head's cell is
+    // bracketed. Values are hex for OutputFormat::Hex and decimal otherwise.
+    pub fn dump_tape(&self, out : &mut impl Write, limit : Option<usize>, format : OutputFormat) -> io::Result<()>
+    {
+        let len = usize::min(self.tape.len(), limit.unwrap_or(usize::MAX));
+
+        for row_start in (0..len).step_by(DUMP_TAPE_ROW_LEN) {
+            let mut cells = Vec::new();
+            for pos in row_start..usize::min(len, row_start + DUMP_TAPE_ROW_LEN) {
+                let cell = match (self.tape[pos], format) {
+                    (Cell::Unknown, _) => String::from("?"),
+                    (Cell::Val(x), OutputFormat::Hex) => format!("{:02x}", x),
+                    (Cell::Val(x), _) => x.to_string(),
+                };
+
+                if pos == self.head_pos {
+                    cells.push(format!("[{}]", cell));
+                } else {
+                    cells.push(cell);
+                }
+            }
+
+            writeln!(out, "{}\t{}", row_start as isize - self.tape_offset, cells.join(" "))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn print_tape_stats(&self, out : &mut impl Write) -> io::Result<()>
+    {
+        let stats = self.tape_stats();
+
+        writeln!(out, "\nTAPE")?;
+        writeln!(out, "Cells touched:\t{}", stats.touched_cells)?;
+        writeln!(out, "Head range:\t{} to {}", stats.min_head_pos, stats.max_head_pos)?;
+
+        writeln!(out, "\nVALUE\t# CELLS")?;
+        for val in 0..256 {
+            if stats.value_histogram[val] != 0 {
+                writeln!(out, "{}\t{}", val, stats.value_histogram[val])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Run an instruction stream, including the instructions the optimization passes synthesize, and
+// return everything it wrote. Reads past the end of input give 255, as in the compiled runner.
+pub fn interp_program(program : Vec<Instruction>, input : &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    // Writing to a Vec can't fail and there's no step limit.
+    State::new(program).interp(input, &mut output).unwrap();
+    output
+}
+
+// Read one byte, giving eof_byte at EOF. The default of 255 is the low byte of the -1 getchar
+// returns, which is what the compiled runner stores unless told otherwise.
+fn read_byte(mut reader : impl Read, eof_byte : u8) -> u8 {
+    let mut buf = [0u8; 1];
+    match reader.read_exact(&mut buf) {
+        Ok(_) => buf[0],
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => eof_byte,
+        Err(_) => panic!("Error while reading from stdin!")
     }
 }
 
-fn find_matching_jump_if_zero(insts : &Vec<Instruction>, start_pc : usize) -> usize {
+// The net amount a loop body of only moves, increments and decrements adds to each cell, keyed by
+// offset from the head at the start of the body, or None if the body does anything else or doesn't
+// return the head to where it started.
+fn simple_loop_deltas(body : &[Instruction]) -> Option<HashMap<i32, u8>> {
+    let mut deltas = HashMap::new();
+    let mut offset = 0;
+
+    for inst in body {
+        match inst {
+            Instruction::MoveRight => offset += 1,
+            Instruction::MoveLeft => offset -= 1,
+            Instruction::Increment => {
+                let delta : &mut u8 = deltas.entry(offset).or_default();
+                *delta = delta.wrapping_add(1);
+            },
+            Instruction::Decrement => {
+                let delta : &mut u8 = deltas.entry(offset).or_default();
+                *delta = delta.wrapping_sub(1);
+            },
+            _ => return None,
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+    Some(deltas)
+}
+
+// The offsets from the head of the cells a loop, brackets included, may use and of those it may
+// write, or None if the loop or one nested in it doesn't bring the head back to where its iterations
+// start, or it holds anything but core commands.
+fn balanced_loop_cells(insts : &[Instruction]) -> Option<(BTreeSet<i32>, BTreeSet<i32>)> {
+    let mut used = BTreeSet::new();
+    let mut written = BTreeSet::new();
+    let mut loop_offsets = Vec::new();
+    let mut offset = 0;
+
+    for inst in insts {
+        match inst {
+            Instruction::MoveRight => offset += 1,
+            Instruction::MoveLeft => offset -= 1,
+            Instruction::Increment | Instruction::Decrement | Instruction::Read => {
+                used.insert(offset);
+                written.insert(offset);
+            },
+            Instruction::Write => {
+                used.insert(offset);
+            },
+            Instruction::JumpIfZero => {
+                loop_offsets.push(offset);
+                used.insert(offset);
+            },
+            Instruction::JumpUnlessZero => {
+                if loop_offsets.pop() != Some(offset) {
+                    return None;
+                }
+            },
+            Instruction::Debug(_) => (),
+            _ => return None,
+        }
+    }
+
+    return Some((used, written));
+}
+
+fn find_matching_jump_if_zero(insts : &[Instruction], start_pc : usize) -> usize {
     let mut pc = start_pc + 1;
     let mut brace_count = 1;
 
@@ -458,7 +1643,7 @@ fn find_matching_jump_if_zero(insts : &Vec<Instruction>, start_pc : usize) -> us
     }
 }
 
-fn find_matching_jump_unless_zero(insts : &Vec<Instruction>, start_pc : usize) -> usize {
+fn find_matching_jump_unless_zero(insts : &[Instruction], start_pc : usize) -> usize {
     let mut pc = start_pc - 1;
     let mut brace_count = 1;
 
@@ -477,17 +1662,74 @@ fn find_matching_jump_unless_zero(insts : &Vec<Instruction>, start_pc : usize) -
     }
 }
 
-fn compute_jump_dests(insts : &Vec<Instruction>) -> HashMap<usize, usize> {
+// Find the offsets of every cell whose value the given instructions can observe, with the head
+// starting at `head_pos`. A cell that is only ever overwritten by a read doesn't count. If
+// `final_cell_live`, the cell under the head once the instructions end counts too. If any loop moves
+// the head by a nonzero amount, or a scan moves it, the head can reach any cell, so None is returned.
+fn live_cell_offsets(insts : &[Instruction], head_pos : i32, final_cell_live : bool) -> Option<HashSet<i32>> {
+    let mut live_cells = HashSet::new();
+    let mut loop_head_pos = Vec::new();
+    let mut head_pos = head_pos;
+
+    for inst in insts {
+        match inst {
+            Instruction::MoveRight => head_pos += 1,
+            Instruction::MoveLeft => head_pos -= 1,
+            Instruction::Read | Instruction::ReadAt(_) => (),
+
+            Instruction::SetHeadPos(pos) => head_pos = *pos,
+            Instruction::Scan(_) | Instruction::ClearScan(_) => return None,
+
+            // Instructions that address other cells count those too
+            Instruction::Add(offset) | Instruction::Sub(offset) => {
+                live_cells.insert(head_pos);
+                live_cells.insert(head_pos + offset);
+            },
+            Instruction::ZeroAt(offset) | Instruction::AddConst(offset, _) => {
+                live_cells.insert(head_pos + offset);
+            },
+            Instruction::SetCell(pos, _) | Instruction::WriteAt(pos) => {
+                live_cells.insert(*pos);
+            },
+
+            Instruction::JumpIfZero => {
+                loop_head_pos.push(head_pos);
+                live_cells.insert(head_pos);
+            },
+
+            Instruction::JumpUnlessZero => {
+                if loop_head_pos.pop() != Some(head_pos) {
+                    return None;
+                }
+                live_cells.insert(head_pos);
+            },
+
+            _ => {
+                live_cells.insert(head_pos);
+            },
+        }
+    }
+
+    if final_cell_live {
+        live_cells.insert(head_pos);
+    }
+
+    return Some(live_cells);
+}
+
+// Map the pc of every bracket to the pc of its match. The program's brackets must be balanced, which
+// check_brackets verifies.
+pub fn compute_jump_dests(insts : &[Instruction]) -> HashMap<usize, usize> {
     let mut jump_dests = HashMap::new();
 
     for pc in 0..insts.len() {
         match insts[pc] {
             Instruction::JumpIfZero => {
-                jump_dests.insert(pc, find_matching_jump_if_zero(&insts, pc));
+                jump_dests.insert(pc, find_matching_jump_if_zero(insts, pc));
                 ()
             },
             Instruction::JumpUnlessZero => {
-                jump_dests.insert(pc, find_matching_jump_unless_zero(&insts, pc));
+                jump_dests.insert(pc, find_matching_jump_unless_zero(insts, pc));
                 ()
             }
             _ => (),
@@ -497,6 +1739,17 @@ fn compute_jump_dests(insts : &Vec<Instruction>) -> HashMap<usize, usize> {
     return jump_dests;
 }
 
+// The pcs of each '[' and its matching ']', sorted by the '['. As with compute_jump_dests, the
+// brackets must be balanced.
+pub fn bracket_pairs(program : &[Instruction]) -> Vec<(usize, usize)> {
+    let mut pairs : Vec<(usize, usize)> = compute_jump_dests(program)
+        .into_iter()
+        .filter(|(open, close)| open < close)
+        .collect();
+    pairs.sort();
+    pairs
+}
+
 #[derive(Eq)]
 struct LoopExecution {
     pc : usize,
@@ -528,63 +1781,635 @@ mod tests {
     use std::fs::*;
 
     #[test]
-    fn test_move_right() {
-        let program = lex(">");
+    fn test_move_right() {
+        let program = lex(">");
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.head_pos, 1);
+        assert_eq!(state.tape.len(), 2);
+    }
+
+    #[test]
+    fn test_move_right_resize() {
+        let move_amt = 16;
+        let program = lex(&(0..move_amt).map(|_| ">").collect::<String>());
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.head_pos, move_amt);
+        assert_eq!(state.tape.len(), (move_amt + 1).try_into().unwrap());
+    }
+
+    #[test]
+    fn test_move_left() {
+        let program = lex("><");
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.head_pos, 0);
+    }
+
+    #[test]
+    fn test_move_left_negative() {
+        let program = lex("<+");
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.head_pos, 0);
+        assert_eq!(state.tape.len(), 2);
+        assert_eq!(state.tape[0], Cell::Val(1));
+        assert_eq!(state.tape[1], Cell::Val(0));
+    }
+
+    #[test]
+    fn test_wrapping_tape() {
+        let program = lex("<+<+<+>>>");
+        let mut state = State::new(program);
+        state.set_tape_mode(TapeMode::Wrapping { size: 3 });
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        // Cells 2, 1 and 0, then all the way around back to 0.
+        assert_eq!(state.head_pos, 0);
+        assert_eq!(state.tape, [Cell::Val(1), Cell::Val(1), Cell::Val(1)]);
+    }
+
+    #[test]
+    fn test_wrapping_tape_stats() {
+        // Cells 0, 9 and 8 of 10, reached by moving, and cell 1 by an offset that wraps.
+        let mut state = State::new(vec![Instruction::Increment, Instruction::MoveLeft, Instruction::MoveLeft, Instruction::Increment, Instruction::AddConst(3, 2)]);
+        state.set_tape_mode(TapeMode::Wrapping { size: 10 });
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        let stats = state.tape_stats();
+        assert_eq!(stats.touched_cells, 4);
+        assert_eq!((stats.min_head_pos, stats.max_head_pos), (0, 9));
+        assert_eq!(stats.value_histogram[0], 1);
+        assert_eq!(stats.value_histogram[1], 2);
+        assert_eq!(stats.value_histogram[2], 1);
+    }
+
+    #[test]
+    fn test_wrapping_tape_scan() {
+        // [>] runs off the end of the tape and finds the zero cell at the start. The compiled
+        // backends use a fixed size tape that doesn't wrap, so there the scan would walk off the end
+        // of the tape instead.
+        let program = lex("<+<+>[>]");
+        let mut state = State::new(program);
+        state.set_tape_mode(TapeMode::Wrapping { size: 5 });
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.head_pos, 0);
+        assert_eq!(state.tape, [Cell::Val(0), Cell::Val(0), Cell::Val(0), Cell::Val(1), Cell::Val(1)]);
+    }
+
+    #[test]
+    fn test_increment() {
+        let program = lex("+");
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.tape[0], Cell::Val(1));
+    }
+
+    #[test]
+    fn test_decrement() {
+        let program = lex("-");
+        let mut state = State::new(program);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.tape[0], Cell::Val(u8::MAX));
+    }
+
+    #[test]
+    fn test_increment_saturating() {
+        let program = lex(&(0..300).map(|_| "+").collect::<String>());
+        let mut state = State::new(program);
+        state.set_arithmetic(Arithmetic::Saturating);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.tape[0], Cell::Val(u8::MAX));
+    }
+
+    #[test]
+    fn test_decrement_saturating() {
+        let program = lex("++-----");
+        let mut state = State::new(program);
+        state.set_arithmetic(Arithmetic::Saturating);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        assert_eq!(state.tape[0], Cell::Val(0));
+    }
+
+    #[test]
+    fn test_read_eof() {
+        let program = lex(",>,");
+        let mut state = State::new(program);
+        state.interp(&b"a"[..], std::io::stdout()).unwrap();
+
+        assert_eq!(state.tape[0], Cell::Val(b'a'));
+        assert_eq!(state.tape[1], Cell::Val(255));
+    }
+
+    #[test]
+    fn test_eof_byte() {
+        let mut state = State::new(lex(",>,>,"));
+        state.set_eof_byte(0);
+        state.interp(&b"a"[..], std::io::stdout()).unwrap();
+        assert_eq!(state.tape, [Cell::Val(b'a'), Cell::Val(0), Cell::Val(0)]);
+
+        let mut state = State::new(vec![Instruction::ReadAt(0), Instruction::ReadAt(1)]);
+        state.set_eof_byte(b'&');
+        state.interp(&b"a"[..], std::io::stdout()).unwrap();
+        assert_eq!(state.tape, [Cell::Val(b'a'), Cell::Val(b'&')]);
+    }
+
+    #[test]
+    fn test_max_output() {
+        let program = lex("+[.]");
+        let mut state = State::new(program);
+        let mut output = Vec::new();
+        let res = state.interp_with_max_output(std::io::stdin(), &mut output, 10);
+
+        assert_eq!(res.unwrap_err().downcast::<OutputLimitExceeded>().unwrap().limit, 10);
+        assert_eq!(output, vec![1; 10]);
+    }
+
+    // Always fails, like stdout after the reading end of its pipe is closed
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(ErrorKind::BrokenPipe, "broken pipe"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_commands() {
+        let opts = LexOptions::with_builtin_debug_commands();
+        let mut state = State::new(lex_with_options("+>++#>$+++<<.", &opts));
+        let mut output = Vec::new();
+        state.interp(std::io::stdin(), &mut output).unwrap();
+
+        // Debug commands don't touch the tape or the output
+        assert_eq!(state.tape, [Cell::Val(1), Cell::Val(2), Cell::Val(3)]);
+        assert_eq!(output, [1]);
+        assert_eq!(state.format_tape_near_head(), "[1] 2 3");
+
+        // They're dropped by partial evaluation
+        let mut state = State::new(lex_with_options("+#.,$.", &opts));
+        assert_eq!(state.partial_eval(), [Instruction::Output(1), Instruction::Read, Instruction::Write]);
+    }
+
+    #[test]
+    fn test_partial_eval_report() {
+        // Runs to the end
+        let mut state = State::new(lex("++[>+++<-]>."));
+        assert_eq!(state.partial_eval_report(), PartialEvalReport {
+            folded_insts: 11,
+            outputs: 1,
+            bail_pc: None,
+            bailed_in_loop: false,
+        });
+
+        // Stops at a loop on input, after emitting the Output, the Read and the head position
+        let mut state = State::new(lex("++.>,[-]"));
+        assert_eq!(state.partial_eval_report(), PartialEvalReport {
+            folded_insts: 2,
+            outputs: 1,
+            bail_pc: Some(5),
+            bailed_in_loop: false,
+        });
+
+        // Input read inside a loop throws the loop's work away
+        let mut state = State::new(lex("++>+[,]"));
+        assert_eq!(state.partial_eval_report(), PartialEvalReport {
+            folded_insts: 2,
+            outputs: 0,
+            bail_pc: Some(4),
+            bailed_in_loop: true,
+        });
+    }
+
+    #[test]
+    fn test_bracket_pairs() {
+        assert_eq!(bracket_pairs(&lex("+[>[-]<[>]]-[]")), [(1, 10), (3, 5), (7, 9), (12, 13)]);
+        assert_eq!(bracket_pairs(&lex("+-")), []);
+
+        let jump_dests = compute_jump_dests(&lex("[[]]"));
+        assert_eq!(jump_dests[&0], 3);
+        assert_eq!(jump_dests[&3], 0);
+        assert_eq!(jump_dests[&2], 1);
+    }
+
+    #[test]
+    fn test_formatted_output() {
+        let run = |format| {
+            let mut state = State::new(lex("+++++[>+++++<-]>[.>+.<-]"));
+            let mut out = FormattedOutput::new(Vec::new(), format);
+            state.interp(std::io::stdin(), &mut out).unwrap();
+            String::from_utf8(out.into_inner()).unwrap()
+        };
+
+        assert_eq!(run(OutputFormat::Hex), "19 01 18 02 17 03 16 04 15 05 14 06 13 07 12 08 11 09 10 0a 0f 0b 0e 0c 0d 0d 0c 0e 0b 0f 0a 10 09 11 08 12 07 13 06 14 05 15 04 16 03 17 02 18 01 19");
+        assert!(run(OutputFormat::Dec).starts_with("25 1 24 2 23 3 "));
+        assert_eq!(run(OutputFormat::Raw).as_bytes()[..4], [25, 1, 24, 2]);
+        assert_eq!(OutputFormat::from_name("hex"), Some(OutputFormat::Hex));
+        assert_eq!(OutputFormat::from_name("octal"), None);
+    }
+
+    #[test]
+    fn test_recording_reader() {
+        // Reads past the end of input aren't logged, and input the program never read isn't either.
+        let run = |src : &str, input : &[u8]| {
+            let mut reader = RecordingReader::new(input, Vec::new());
+            let mut output = Vec::new();
+            State::new(lex(src)).interp(&mut reader, &mut output).unwrap();
+            (output, reader.into_inner().1)
+        };
+
+        let (output, log) = run(",.,.,.,.", b"ab");
+        assert_eq!(log, b"ab");
+        assert_eq!(output, b"ab\xff\xff");
+
+        let (output, log) = run(",.,+.", b"abcdef");
+        assert_eq!(log, b"ab");
+
+        // Replaying the log gives the same run
+        assert_eq!(run(",.,+.", &log), (output, log.clone()));
+    }
+
+    #[test]
+    fn test_print_profile_info_to_writer() {
+        let mut state = State::new(lex("++[>+<-]."));
+        let mut program_output = Vec::new();
+        state.interp(std::io::stdin(), &mut program_output).unwrap();
+
+        let mut profile_output = Vec::new();
+        state.print_profile_info(&mut profile_output).unwrap();
+        state.print_tape_stats(&mut profile_output).unwrap();
+
+        let profile_output = String::from_utf8(profile_output).unwrap();
+        assert!(profile_output.starts_with("PC\tOP\t# EXECUTED\n0\t+\t1\n"));
+        assert!(profile_output.contains("SIMPLE LOOPS\nPC\t# EXECUTED\tINSTS\n2\t2\t[>+<-]\n"));
+        assert!(profile_output.contains("Cells touched:\t2\n"));
+        assert_eq!(program_output, [0]);
+    }
+
+    #[test]
+    fn test_interp_with_callback() {
+        // 2 + 2 * 6 + 1 instructions, since each iteration runs both brackets
+        let mut state = State::new(lex("++[>+<-]."));
+        let mut totals = Vec::new();
+        state.interp_with_callback(std::io::stdin(), Vec::new(), 3, |total| totals.push(total)).unwrap();
+        assert_eq!(totals, [3, 6, 9, 12, 15]);
+
+        let mut state = State::new(lex("++[>+<-]."));
+        let mut calls = 0;
+        state.interp_with_callback(std::io::stdin(), Vec::new(), 0, |_| calls += 1).unwrap();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_dump_tape() {
+        let mut state = State::new(lex("<<+>>>>+++++[>>>>+<<<<-]>>>>[->+<]>>>>>>>>>"));
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        let dump = |limit, format| {
+            let mut out = Vec::new();
+            state.dump_tape(&mut out, limit, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(dump(None, OutputFormat::Dec), "-2\t1 0 0 0 0 0 0 0 0 5 0 0 0 0 0 0\n14\t0 [0]\n");
+        assert_eq!(dump(Some(3), OutputFormat::Hex), "-2\t01 00 00\n");
+        assert_eq!(dump(Some(0), OutputFormat::Dec), "");
+    }
+
+    #[test]
+    fn test_wide_cells() {
+        // 300 increments carry into the cell's second byte, and the loop runs until all four bytes
+        // are zero, adding 300 to the byte cell after it.
+        let program = lex(&(">".to_owned() + &"+".repeat(300) + ".[->>>>+<<<<]>>>>." + "<<<<<--."));
+        let mut state = State::new(program);
+        state.set_wide_cells([1]);
+        let mut output = Vec::new();
+        state.interp(&b""[..], &mut output).unwrap();
+        assert_eq!(output, [44, 1, 0, 0, 44, 254]);
+
+        // Reads take four bytes, and a decrement borrows across them
+        let mut state = State::new(lex("<,-.>."));
+        state.set_wide_cells([-1]);
+        let mut output = Vec::new();
+        state.interp(&b"\0\0\x01\0z"[..], &mut output).unwrap();
+        assert_eq!(output, [255, 255, 0, 0, 255]);
+
+        // Saturating arithmetic saturates the whole value
+        let mut state = State::new(lex("-."));
+        state.set_wide_cells([0]);
+        state.set_arithmetic(Arithmetic::Saturating);
+        let mut output = Vec::new();
+        state.interp(&b""[..], &mut output).unwrap();
+        assert_eq!(output, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut state = State::new(lex("+[]"));
+        state.set_step_limit(1000);
+        let err = state.interp_with_max_output(std::io::stdin(), Vec::new(), usize::MAX).unwrap_err();
+        assert_eq!(err.downcast_ref::<StepLimitExceeded>().unwrap().limit, 1000);
+        assert_eq!(state.instructions_executed, 1000);
+
+        // 15 instructions
+        let mut state = State::new(lex("++[>+<-]."));
+        state.set_step_limit(15);
+        assert!(state.interp_with_max_output(std::io::stdin(), Vec::new(), usize::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_profile() {
+        // The outer loop's body runs 3 times, and the inner loops' 12 and 60 times.
+        let program = lex("+++[>++++[-]++++++++++++++++++++[-]<-]");
+        let mut state = State::new(program.clone());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        let profile = state.profile();
+        assert_eq!(profile.counts.len(), program.len());
+        assert_eq!(Profile::from_json(&profile.to_json()).unwrap(), profile);
+        assert_eq!(profile.hot_loops(&program), HashSet::from([9, 32]));
+
+        assert_eq!(Profile::from_json("{ \"counts\" : [ 1,2 , 3 ] }").unwrap().counts, [1, 2, 3]);
+        assert_eq!(Profile::from_json("{\"counts\": []}").unwrap().counts, []);
+        assert!(Profile::from_json("{\"counts\": [1, x]}").is_err());
+        assert!(Profile::from_json("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_fold_simple_loop() {
+        let mut state = State::with_tape(lex("+++[->++<<+>]"), &[3]);
+        state.program_counter = 3;
+        assert!(state.fold_simple_loop());
+        assert_eq!(state.program_counter, 13);
+        assert_eq!(state.tape, [Cell::Val(3), Cell::Val(0), Cell::Val(6)]);
+        assert_eq!(state.head_pos, 1);
+
+        // The head doesn't come back, or the index doesn't step by one
+        for program in ["+[->+<<]", "++[-->+<]", "+[->[-]<]"] {
+            let mut state = State::new(lex(program));
+            state.program_counter = program.find('[').unwrap();
+            assert!(!state.fold_simple_loop(), "{program}");
+        }
+    }
+
+    #[test]
+    fn test_partial_eval_folds_loops() {
+        let program = lex("++++++++[->+++<]>.");
+        assert_eq!(State::new(program).partial_eval(), [Instruction::Output(24)]);
+
+        // Products wrap, and a loop that counts up runs 256 - k times
+        let program = lex(&("+".repeat(200) + "[->+++<]>."));
+        assert_eq!(State::new(program).partial_eval(), [Instruction::Output(88)]);
+        let program = lex("--[+>+++<]>.");
+        assert_eq!(State::new(program).partial_eval(), [Instruction::Output(6)]);
+
+        // Loops touching a cell holding input are run an iteration at a time instead
+        for src in [">,<+++[->+<]>.", "+++>+++[-<+++>>,<]<."] {
+            let program = lex(src);
+            let folded = State::new(program.clone()).partial_eval();
+            assert_eq!(interp_program(folded, b"ab"), interp_program(program, b"ab"), "{src}");
+        }
+    }
+
+    #[test]
+    fn test_partial_eval_with_input() {
+        // Add the two input bytes and print the sum
+        let program = lex(",>,[-<+>]<.");
+
+        let mut state = State::new(program.clone());
+        assert_eq!(state.partial_eval_with_input(&[3, 4]), [Instruction::Output(7)]);
+
+        // Once input runs out the remaining reads happen at runtime
+        let mut state = State::new(program.clone());
+        assert_eq!(state.partial_eval_with_input(&[3]), [
+            Instruction::ReadAt(1),
+            Instruction::SetHeadPos(1),
+            Instruction::SetCell(0, 3),
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::MoveLeft,
+            Instruction::Increment,
+            Instruction::MoveRight,
+            Instruction::JumpUnlessZero,
+            Instruction::MoveLeft,
+            Instruction::Write,
+        ]);
+
+        // Bailing inside a loop restarts it at runtime from its last entry, which came after the
+        // known input
+        let program = lex("+[,.]");
+        let mut state = State::new(program);
+        assert_eq!(state.partial_eval_with_input(&[5, 6]), [
+            Instruction::Output(5),
+            Instruction::Output(6),
+            Instruction::SetCell(0, 6),
+            Instruction::JumpIfZero,
+            Instruction::Read,
+            Instruction::Write,
+            Instruction::JumpUnlessZero,
+        ]);
+        assert_eq!(state.known_input_consumed(), 2);
+
+        // The second iteration's read of 7 is given back
+        let program = lex("+[,.,.]");
+        let mut state = State::new(program);
+        state.partial_eval_with_input(&[5, 6, 7]);
+        assert_eq!(state.known_input_consumed(), 2);
+    }
+
+    #[test]
+    fn test_partial_eval_taint() {
+        let mut state = State::new(lex("<+>>,>+++,<,[-]"));
+        let (insts, cells) = state.partial_eval_taint();
+
+        assert_eq!(insts, [
+            Instruction::ReadAt(1),
+            Instruction::ReadAt(2),
+            Instruction::ReadAt(1),
+            Instruction::SetHeadPos(1),
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::JumpUnlessZero,
+        ]);
+
+        // A cell read twice became unknown at the first read
+        assert_eq!(cells, [
+            CellTaint { pos: -1, value: Some(1), unknown_since: None },
+            CellTaint { pos: 0, value: Some(0), unknown_since: None },
+            CellTaint { pos: 1, value: None, unknown_since: Some(4) },
+            CellTaint { pos: 2, value: None, unknown_since: Some(9) },
+        ]);
+    }
+
+    #[test]
+    fn test_format_tape_near_head() {
+        let mut state = State::new(lex(""));
+        state.tape = (0..20).map(Cell::Val).collect();
+        state.head_pos = 10;
+        assert_eq!(state.format_tape_near_head(), "2 3 4 5 6 7 8 9 [10] 11 12 13 14 15 16 17 18");
+    }
+
+    // Records how much had been written at each flush
+    struct FlushRecorder {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_at.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_before_read() {
+        let mut state = State::new(lex("+.+.,.,"));
+        let mut writer = FlushRecorder { written: Vec::new(), flushed_at: Vec::new() };
+        state.interp(&[7u8, 8][..], &mut writer).unwrap();
+
+        assert_eq!(writer.written, [1, 2, 7]);
+        assert_eq!(writer.flushed_at, [2, 3]);
+    }
+
+    #[test]
+    fn test_write_failed() {
+        let program = lex("+.+.");
+        let mut state = State::new(program);
+        let res = state.interp_with_max_output(std::io::stdin(), BrokenWriter, usize::MAX);
+
+        let err = res.unwrap_err().downcast::<WriteFailed>().unwrap();
+        assert_eq!(err.source.kind(), ErrorKind::BrokenPipe);
+
+        // Execution stops at the first write
+        assert_eq!(state.program_counter, 1);
+        assert_eq!(state.bytes_written, 0);
+    }
+
+    #[test]
+    fn test_interp_write_failed() {
+        let mut state = State::new(lex("+.+."));
+        let err = state.interp(std::io::stdin(), BrokenWriter).unwrap_err();
+        assert!(err.downcast_ref::<WriteFailed>().is_some());
+        assert_eq!(state.program_counter, 1);
+    }
+
+    #[test]
+    fn test_interp_program() {
+        // Hand-built: put 3 and 5 in cells 1 and 2, add cell 1 into cell 0, then scan back to cell 0
+        let program = vec![
+            Instruction::SetCell(1, 3),
+            Instruction::SetCell(2, 5),
+            Instruction::SetHeadPos(1),
+            Instruction::Add(-1),
+            Instruction::AddConst(-1, 60),
+            Instruction::WriteAt(0),
+            Instruction::Scan(-1),
+            Instruction::OutputStr(b"ab".to_vec()),
+            Instruction::ReadAt(-1),
+            Instruction::SetHeadPos(-1),
+            Instruction::Write,
+            Instruction::SetHeadPos(1),
+            Instruction::ClearScan(1),
+            Instruction::WriteAt(1),
+            Instruction::ZeroAt(-1),
+            Instruction::SetCurrent(b'z'),
+            Instruction::Write,
+            Instruction::Output(b'!'),
+        ];
+
+        assert_eq!(interp_program(program, b"x"), b"?abx\0z!");
+    }
+
+    #[test]
+    fn test_max_output_output_str() {
+        let program = vec![Instruction::OutputStr(b"abc".to_vec())];
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        let mut output = Vec::new();
 
-        assert_eq!(state.head_pos, 1);
-        assert_eq!(state.tape.len(), 2);
+        let err = state.interp_with_max_output(std::io::stdin(), &mut output, 2).unwrap_err();
+        assert!(err.downcast_ref::<OutputLimitExceeded>().is_some());
+        assert_eq!(output, b"ab");
     }
 
     #[test]
-    fn test_move_right_resize() {
-        let move_amt = 16;
-        let program = lex(&(0..move_amt).map(|_| ">").collect::<String>());
+    fn test_output_filter() {
+        let program = lex(&("+".repeat(200) + "."));
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.set_output_filter(OutputFilter::SevenBit);
+        let mut output = Vec::new();
+        state.interp(std::io::stdin(), &mut output).unwrap();
+        assert_eq!(output, [72]);
 
-        assert_eq!(state.head_pos, move_amt);
-        assert_eq!(state.tape.len(), (move_amt + 1).try_into().unwrap());
+        let program = vec![Instruction::Output(200), Instruction::OutputStr(vec![65, 193])];
+        let mut state = State::new(program);
+        state.set_output_filter(OutputFilter::SevenBit);
+        let mut output = Vec::new();
+        state.interp(std::io::stdin(), &mut output).unwrap();
+        assert_eq!(output, [72, 65, 65]);
     }
 
     #[test]
-    fn test_move_left() {
-        let program = lex("><");
+    fn test_max_output_not_reached() {
+        let program = lex("+...");
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        let mut output = Vec::new();
+        let res = state.interp_with_max_output(std::io::stdin(), &mut output, 3);
 
-        assert_eq!(state.head_pos, 0);
+        assert!(res.is_ok());
+        assert_eq!(output, vec![1; 3]);
     }
 
     #[test]
-    fn test_move_left_negative() {
-        let program = lex("<+");
-        let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+    fn test_with_tape() {
+        // Move the first cell's value two cells over.
+        let program = lex("[->>+<<]");
+        let mut state = State::with_tape(program, &[3, 5]);
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
+        assert_eq!(state.tape, [Cell::Val(0), Cell::Val(5), Cell::Val(3)]);
         assert_eq!(state.head_pos, 0);
-        assert_eq!(state.tape.len(), 2);
-        assert_eq!(state.tape[0], Cell::Val(1));
-        assert_eq!(state.tape[1], Cell::Val(0));
     }
 
     #[test]
-    fn test_increment() {
-        let program = lex("+");
-        let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
-
-        assert_eq!(state.tape[0], Cell::Val(1));
+    fn test_with_empty_tape() {
+        let state = State::with_tape(lex("+"), &[]);
+        assert_eq!(state.tape, [Cell::Val(0)]);
     }
 
     #[test]
-    fn test_decrement() {
-        let program = lex("-");
+    fn test_tape_stats() {
+        let program = lex("<<+>>>>++>+<");
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
-
-        assert_eq!(state.tape[0], Cell::Val(u8::MAX));
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
+
+        let stats = state.tape_stats();
+        assert_eq!(stats.touched_cells, 6);
+        assert_eq!(stats.min_head_pos, -2);
+        assert_eq!(stats.max_head_pos, 3);
+        assert_eq!(stats.value_histogram[0], 3);
+        assert_eq!(stats.value_histogram[1], 2);
+        assert_eq!(stats.value_histogram[2], 1);
+        assert_eq!(stats.value_histogram.iter().sum::<usize>(), 6);
     }
 
     #[test]
@@ -593,7 +2418,7 @@ mod tests {
         let program = lex("[+]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.tape[0], Cell::Val(0));
     }
@@ -604,7 +2429,7 @@ mod tests {
         let program = lex("+[>[>+]>>>]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.tape[0], Cell::Val(1));
         assert_eq!(state.tape[1], Cell::Val(0));
@@ -615,7 +2440,7 @@ mod tests {
         let program = lex("+[>++>]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.tape[0], Cell::Val(1));
         assert_eq!(state.tape[1], Cell::Val(2));
@@ -627,7 +2452,7 @@ mod tests {
         let program = lex("+++++[>+<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.tape[0], Cell::Val(0));
         assert_eq!(state.tape[1], Cell::Val(5));
@@ -638,7 +2463,7 @@ mod tests {
         let program = lex("+++++[>++++++++++[>+<-]<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.tape[2], Cell::Val(50));
     }
@@ -648,7 +2473,7 @@ mod tests {
         let program = lex("+++++[>+<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
 
         assert_eq!(state.execution_counter[0], 1);
         assert_eq!(state.execution_counter[1], 1);
@@ -668,7 +2493,7 @@ mod tests {
         let program = lex("+++++");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -680,7 +2505,7 @@ mod tests {
         let program = lex(">+++[>+++<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 1);
@@ -695,7 +2520,7 @@ mod tests {
         let program = lex("+++>[>+++<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 1);
@@ -710,7 +2535,7 @@ mod tests {
         let program = lex(">+++[>.+++<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -725,7 +2550,7 @@ mod tests {
         let program = lex(">+++[>]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -740,7 +2565,7 @@ mod tests {
         let program = lex(">++++[>+<--]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -755,7 +2580,7 @@ mod tests {
         let program = lex(">+++[>+++++[>++<-]<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 1);
@@ -770,7 +2595,7 @@ mod tests {
         let program = lex(">+++[>++++++[>++<--]<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -785,7 +2610,7 @@ mod tests {
         let program = lex("+++[>--<-]++[>--<-]++++[>--<-]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 3);
@@ -804,7 +2629,7 @@ mod tests {
         let program = lex("++++[>--<--]++[>--<--]++++++[>--<--]");
 
         let mut state = State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        state.interp(std::io::stdin(), std::io::stdout()).unwrap();
         
         let (simple_loops, complex_loops) = state.get_loop_executions();
         assert_eq!(simple_loops.len(), 0);
@@ -818,12 +2643,127 @@ mod tests {
         assert_eq!(complex_loops[2].num_times_executed, 1);
     }
 
+    #[test]
+    fn test_get_loop_profile_optimized_add_const() {
+        let program = vec![
+            Instruction::JumpIfZero,
+            Instruction::MoveRight,
+            Instruction::AddConst(-1, 255),
+            Instruction::Add(1),
+            Instruction::MoveLeft,
+            Instruction::JumpUnlessZero,
+        ];
+
+        let state = State::new(program);
+
+        let (simple_loops, complex_loops) = state.get_loop_executions();
+        assert_eq!(simple_loops.len(), 1);
+        assert_eq!(complex_loops.len(), 0);
+    }
+
+    #[test]
+    fn test_get_loop_profile_optimized_index_clobbered() {
+        let program = vec![
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::MoveRight,
+            Instruction::Add(-1),
+            Instruction::MoveLeft,
+            Instruction::JumpUnlessZero,
+        ];
+
+        let state = State::new(program);
+
+        let (simple_loops, complex_loops) = state.get_loop_executions();
+        assert_eq!(simple_loops.len(), 0);
+        assert_eq!(complex_loops.len(), 1);
+    }
+
+    #[test]
+    fn test_get_loop_profile_optimized_scan_and_output() {
+        let program = vec![
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::Scan(1),
+            Instruction::JumpUnlessZero,
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::Output(65),
+            Instruction::JumpUnlessZero,
+        ];
+
+        let state = State::new(program);
+
+        let (simple_loops, complex_loops) = state.get_loop_executions();
+        assert_eq!(simple_loops.len(), 0);
+        assert_eq!(complex_loops.len(), 2);
+    }
+
     // Tests to add:
     // - all insts in loop are emitted if pc becomes dirty at end of loop
     // - tape state on loop enter is emitted if pc becomes dirty at end of loop
     // - Nested loop becomes dirty on loop enter (outer loop insts + state are emitted)
     // - Nested loop becomes dirty on loop exit (outer loop insts + state are emitted)
 
+    // Check that the program partial eval folds src into writes the same bytes as src itself for each
+    // input. Asserting on the folded instructions alone would miss a wrong but plausible fold.
+    fn assert_partial_eval_matches_interp(src : &str, inputs : &[&[u8]], past_unknown_loops : bool) {
+        let program = lex(src);
+        let mut state = State::new(program.clone());
+        state.set_partial_eval_past_unknown_loops(past_unknown_loops);
+        let folded = state.partial_eval();
+
+        for input in inputs {
+            assert_eq!(interp_program(folded.clone(), input), interp_program(program.clone(), input), "{src} with input {input:?}");
+        }
+    }
+
+    #[test]
+    fn test_partial_eval_matches_interp() {
+        // Every input ends in a 0, as "+>+++[,]<." reads until it sees one. Programs that read more
+        // than that still hit the end of input.
+        let inputs : [&[u8]; 3] = [b"\0", b"\x03\x05\0", b"ab\xff\0"];
+
+        for src in [
+            "+.",
+            ",>+++.<.",
+            ",+++.",
+            ",---.",
+            "+.,.",
+            ">>,<<<,.>>>.",
+            "+++[->++<]>.",
+            "+++[->++>,.<<]>.",
+            ",[->+<]>.",
+            ">+++[->,[->+<]]>.",
+            "+>+++[,]<.",
+            "+>++<<+++>>>,[->+<]<<.",
+            "+>++<<+++>>>,[->+<]>.",
+            "+>++>,[>]<<.",
+            "++++++++[->+++<]>.",
+            "--[+>+++<]>.",
+            ">,<+++[->+<]>.",
+            "+++>+++[-<+++>>,<]<.",
+            ",>,[-<+>]<.",
+            "++++++++[>++++++++<-]>+.+.+.,.+.+.",
+            ",[>+<-]>[<+++>-]<.[-]>>,<[<]>.",
+            ",[-]>+++.",
+            ",[-]++[->+++<]>.",
+            ",[->+<]>+.<+++.",
+            ",[>+<-]>[-<<+>>]<<.>+++[->++<]>.",
+            ",[.-],.",
+            "++>,[-]<[->+<]>.",
+        ] {
+            assert_partial_eval_matches_interp(src, &inputs, false);
+            assert_partial_eval_matches_interp(src, &inputs, true);
+        }
+
+        // Head offsets after a long walk left of the start
+        let src = "<".repeat(3000) + "+++++" + &">".repeat(2700) + "+++" + &">".repeat(170) + "++" + &"<".repeat(70)
+            + ",[->+<]>." + &"<".repeat(101) + "." + &"<".repeat(2700) + "." + &">".repeat(2870) + ".";
+        assert_partial_eval_matches_interp(&src, &inputs, false);
+        assert_partial_eval_matches_interp(&src, &inputs, true);
+    }
+
     #[test]
     fn test_partial_eval_simple() {
         let program = lex("+.");
@@ -881,17 +2821,69 @@ mod tests {
         let insts = state.partial_eval();
 
         assert_eq!(insts, [
-            Instruction::SetHeadPos(2),
-            Instruction::Read,
-            Instruction::SetHeadPos(-1),
-            Instruction::Read,
-            Instruction::Write,
-            Instruction::SetHeadPos(2),
-            Instruction::Write,
+            Instruction::ReadAt(2),
+            Instruction::ReadAt(-1),
+            Instruction::WriteAt(-1),
+            Instruction::WriteAt(2),
         ]);
         assert_eq!(state.tape, [Cell::Unknown, Cell::Val(0), Cell::Val(0), Cell::Unknown]);
     }
 
+    #[test]
+    fn test_partial_eval_deep_negative_head_pos() {
+        // Offsets are relative to where the program started the head, however far left the tape grew.
+        let program = lex(&("<".repeat(3000) + "+++++" + &">".repeat(2700) + "+++" + &">".repeat(170) + "++" + &"<".repeat(70)
+            + ",[->+<]>." + &"<".repeat(101) + "." + &"<".repeat(2700) + "." + &">".repeat(2870) + "."));
+
+        let mut state = State::new(program);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts[..6], [
+            Instruction::ReadAt(-200),
+            Instruction::SetHeadPos(-200),
+            Instruction::SetCell(-3000, 5),
+            Instruction::SetCell(-300, 3),
+            Instruction::SetCell(-199, 0),
+            Instruction::SetCell(-130, 2),
+        ]);
+    }
+
+    #[test]
+    fn test_partial_eval_io_at_offsets_skips_head_syncs() {
+        // Syncing the head for every read and write would take seven SetHeadPos instructions.
+        let program = lex(">,>>,<<<<,>>.>>.<<<<.>>>>+");
+
+        let mut state = State::new(program);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::ReadAt(1),
+            Instruction::ReadAt(3),
+            Instruction::ReadAt(-1),
+            Instruction::WriteAt(1),
+            Instruction::WriteAt(3),
+            Instruction::WriteAt(-1),
+            Instruction::SetHeadPos(3),
+            Instruction::Increment,
+        ]);
+    }
+
+    #[test]
+    fn test_partial_eval_io_at_synced_head() {
+        // Once the head has been synced for an increment, I/O on that cell doesn't need an offset.
+        let program = lex(">,+.");
+
+        let mut state = State::new(program);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::ReadAt(1),
+            Instruction::SetHeadPos(1),
+            Instruction::Increment,
+            Instruction::Write,
+        ]);
+    }
+
     #[test]
     fn test_partial_eval_loop() {
         let program = lex("+++[->++<]>.");
@@ -913,13 +2905,12 @@ mod tests {
         let insts = state.partial_eval();
 
         assert_eq!(insts, [
-            Instruction::SetHeadPos(2),
-            Instruction::Read,
-            Instruction::Write,
-            Instruction::Read,
-            Instruction::Write,
-            Instruction::Read,
-            Instruction::Write,
+            Instruction::ReadAt(2),
+            Instruction::WriteAt(2),
+            Instruction::ReadAt(2),
+            Instruction::WriteAt(2),
+            Instruction::ReadAt(2),
+            Instruction::WriteAt(2),
             Instruction::Output(6),
         ]);
         assert_eq!(state.tape, [Cell::Val(0), Cell::Val(6), Cell::Unknown]);
@@ -954,6 +2945,168 @@ mod tests {
         assert_eq!(state.tape, [Cell::Unknown]);
     }
 
+    #[test]
+    fn test_partial_eval_output_limit() {
+        let program = lex("+.+.+.");
+
+        let mut state = State::new(program.clone());
+        state.set_partial_eval_output_limit(2);
+        assert_eq!(state.partial_eval(), [
+            Instruction::Output(1),
+            Instruction::Output(2),
+            Instruction::SetCell(0, 3),
+            Instruction::Write,
+        ]);
+
+        // A write over the limit inside a loop leaves the rest of the loop's iterations to run
+        let program = lex("+.>+++[-<+.>]");
+
+        let mut state = State::new(program.clone());
+        state.set_partial_eval_output_limit(2);
+        let insts = state.partial_eval();
+        assert_eq!(insts[..5], [
+            Instruction::Output(1),
+            Instruction::Output(2),
+            Instruction::SetHeadPos(1),
+            Instruction::SetCell(0, 2),
+            Instruction::SetCell(1, 2),
+        ]);
+        assert_eq!(interp_program(insts, b""), interp_program(program, b""));
+
+        let program = lex("+.+.");
+        let mut state = State::new(program);
+        state.set_partial_eval_output_limit(2);
+        assert_eq!(state.partial_eval(), [Instruction::Output(1), Instruction::Output(2)]);
+    }
+
+    #[test]
+    fn test_partial_eval_final_cell_live() {
+        let partial_eval = |src, final_cell_live| {
+            let mut state = State::new(lex(src));
+            state.set_partial_eval_final_cell_live(final_cell_live);
+            state.partial_eval()
+        };
+
+        // Fully folded programs
+        assert_eq!(partial_eval("+++++", false), []);
+        assert_eq!(partial_eval("+++++", true), [Instruction::SetCell(0, 5)]);
+        assert_eq!(partial_eval("+++>++", true), [Instruction::SetHeadPos(1), Instruction::SetCell(1, 2)]);
+        assert_eq!(partial_eval(",", true), [Instruction::Read]);
+
+        // Partial eval stops at the loop, and nothing after it reads cell 0 before the program ends
+        // there.
+        assert!(!partial_eval("+++>,[-]<", false).contains(&Instruction::SetCell(0, 3)));
+        assert!(partial_eval("+++>,[-]<", true).contains(&Instruction::SetCell(0, 3)));
+    }
+
+    #[test]
+    fn test_live_cell_offsets() {
+        let insts = [Instruction::Add(2), Instruction::AddConst(-1, 3), Instruction::WriteAt(5), Instruction::ReadAt(7)];
+        assert_eq!(live_cell_offsets(&insts, 1, false), Some(HashSet::from([1, 3, 0, 5])));
+
+        let insts = [Instruction::SetHeadPos(4), Instruction::JumpIfZero, Instruction::ZeroAt(1), Instruction::JumpUnlessZero];
+        assert_eq!(live_cell_offsets(&insts, 0, false), Some(HashSet::from([4, 5])));
+        assert_eq!(live_cell_offsets(&insts[..1], 0, true), Some(HashSet::from([4])));
+
+        assert_eq!(live_cell_offsets(&[Instruction::Scan(1), Instruction::Write], 0, false), None);
+
+        // Cells the rest of the program only reaches by position are still written out
+        let mut program = lex(">>+++<<,[-]");
+        program.push(Instruction::WriteAt(2));
+        let insts = State::new(program.clone()).partial_eval();
+        assert!(insts.contains(&Instruction::SetCell(2, 3)));
+        assert_eq!(interp_program(insts, b"\x05"), interp_program(program, b"\x05"));
+    }
+
+    // Instructions partial eval folds away from src, with past_unknown_loops off and then on.
+    fn folded_insts_past_unknown_loops(src : &str) -> (usize, usize) {
+        let folded = |past_unknown_loops| {
+            let mut state = State::new(lex(src));
+            state.set_partial_eval_past_unknown_loops(past_unknown_loops);
+            state.partial_eval_report().folded_insts
+        };
+        return (folded(false), folded(true));
+    }
+
+    #[test]
+    fn test_partial_eval_past_unknown_loops() {
+        let program = lex(",[-]>+++.<++[->+++<]>.");
+
+        let mut state = State::new(program);
+        state.set_partial_eval_past_unknown_loops(true);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::Read,
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::JumpUnlessZero,
+            Instruction::Output(3),
+            Instruction::Output(9),
+        ]);
+        assert_eq!(state.tape, [Cell::Val(0), Cell::Val(9)]);
+        let (without, with) = folded_insts_past_unknown_loops(",[-]>+++.<++[->+++<]>.");
+        assert!(with > without, "{with} <= {without}");
+
+        // Cells the loop writes stay unknown after it.
+        let program = lex(",[->+<]>+.");
+
+        let mut state = State::new(program);
+        state.set_partial_eval_past_unknown_loops(true);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts[..8], [
+            Instruction::Read,
+            Instruction::SetCell(1, 0),
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::MoveRight,
+            Instruction::Increment,
+            Instruction::MoveLeft,
+            Instruction::JumpUnlessZero,
+        ]);
+        assert_eq!(state.tape, [Cell::Val(0), Cell::Unknown]);
+    }
+
+    #[test]
+    fn test_partial_eval_past_unknown_loops_unbalanced() {
+        // Reads in the loop are fine once input has run out
+        let program = lex(",[,]>+++.");
+
+        let mut state = State::new(program);
+        state.set_partial_eval_past_unknown_loops(true);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::Read,
+            Instruction::JumpIfZero,
+            Instruction::Read,
+            Instruction::JumpUnlessZero,
+            Instruction::Output(3),
+        ]);
+        let (without, with) = folded_insts_past_unknown_loops(",[,]>+++.");
+        assert!(with > without, "{with} <= {without}");
+
+        // but where the head ends up after "[>]" isn't known, so evaluation stops there.
+        let program = lex(",[>]>+++.");
+
+        let mut state = State::new(program);
+        state.set_partial_eval_past_unknown_loops(true);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::Read,
+            Instruction::JumpIfZero,
+            Instruction::MoveRight,
+            Instruction::JumpUnlessZero,
+            Instruction::MoveRight,
+            Instruction::Increment,
+            Instruction::Increment,
+            Instruction::Increment,
+            Instruction::Write,
+        ]);
+    }
+
     #[test]
     fn test_partial_eval_unknown_pc_loop_enter_nested() {
         let program = lex(">+++[->,[->+<]]>.");
@@ -1004,32 +3157,114 @@ mod tests {
         assert_eq!(state.tape, [Cell::Val(1), Cell::Val(3)]);
     }
 
-    // TODO: It would be nice if we only wrote out cell values that are actually used
     #[test]
     fn test_partial_eval_unknown_pc_head_and_tape_state_written() {
-        let program = lex("+>++<<+++>>>,[->+<]>.");
+        let program = lex("+>++<<+++>>>,[->+<]<<.");
 
         let mut state = State::new(program);
         let insts = state.partial_eval();
 
         assert_eq!(insts, [
+            Instruction::ReadAt(2),
             Instruction::SetHeadPos(2),
-            Instruction::Read,
-            Instruction::SetCell(-1, 3),
             Instruction::SetCell(0, 1),
-            Instruction::SetCell(1, 2),
             Instruction::JumpIfZero,
             Instruction::Decrement,
             Instruction::MoveRight,
             Instruction::Increment,
             Instruction::MoveLeft,
             Instruction::JumpUnlessZero,
-            Instruction::MoveRight,
+            Instruction::MoveLeft,
+            Instruction::MoveLeft,
             Instruction::Write,
         ]);
         assert_eq!(state.tape, [Cell::Val(3), Cell::Val(1), Cell::Val(2), Cell::Unknown]);
     }
 
+    #[test]
+    fn test_partial_eval_unused_cells_not_written() {
+        let program = lex("+>++<<+++>>>,[->+<]>.");
+
+        let mut state = State::new(program);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::ReadAt(2),
+            Instruction::SetHeadPos(2),
+            Instruction::JumpIfZero,
+            Instruction::Decrement,
+            Instruction::MoveRight,
+            Instruction::Increment,
+            Instruction::MoveLeft,
+            Instruction::JumpUnlessZero,
+            Instruction::MoveRight,
+            Instruction::Write,
+        ]);
+    }
+
+    #[test]
+    fn test_partial_eval_tape_growth_capped() {
+        // Walks right forever without reading input. Evaluation restarts at the loop's last entry,
+        // with every cell it set written out.
+        let mut state = State::new(lex("+[>+]"));
+        let insts = state.partial_eval();
+
+        let head_pos : i32 = (PARTIAL_EVAL_MAX_TAPE_LEN - 1).try_into().unwrap();
+        assert_eq!(insts[0], Instruction::SetHeadPos(head_pos));
+        assert_eq!(insts.len(), PARTIAL_EVAL_MAX_TAPE_LEN + 5);
+        assert_eq!(insts[insts.len() - 4..], [
+            Instruction::JumpIfZero,
+            Instruction::MoveRight,
+            Instruction::Increment,
+            Instruction::JumpUnlessZero,
+        ]);
+
+        // Outside of a loop it stops where the tape got too long
+        let program = ">".repeat(PARTIAL_EVAL_MAX_TAPE_LEN + 10) + ".";
+        let mut state = State::new(lex(program));
+        let report = state.partial_eval_report();
+        assert_eq!(report.bail_pc, Some(PARTIAL_EVAL_MAX_TAPE_LEN));
+        assert!(!report.bailed_in_loop);
+    }
+
+    #[test]
+    fn test_partial_eval_unaddressable_tape() {
+        // The head starts further left of where the compiled program would start it than an i32
+        // offset reaches, so partial eval leaves the whole program to run.
+        let program = lex("+.,.");
+        let mut state = State::new(program.clone());
+        state.tape_offset = isize::MIN / 2;
+        assert_eq!(state.partial_eval(), program);
+        assert_eq!(state.program_counter, 0);
+
+        let mut state = State::new(program.clone());
+        state.tape_offset = isize::MIN / 2;
+        let (insts, cells) = state.partial_eval_taint();
+        assert_eq!(insts, program);
+        assert!(cells.is_empty());
+    }
+
+    #[test]
+    fn test_partial_eval_unbounded_head_writes_all_cells() {
+        let program = lex("+>++>,[>]<<.");
+
+        let mut state = State::new(program);
+        let insts = state.partial_eval();
+
+        assert_eq!(insts, [
+            Instruction::ReadAt(2),
+            Instruction::SetHeadPos(2),
+            Instruction::SetCell(0, 1),
+            Instruction::SetCell(1, 2),
+            Instruction::JumpIfZero,
+            Instruction::MoveRight,
+            Instruction::JumpUnlessZero,
+            Instruction::MoveLeft,
+            Instruction::MoveLeft,
+            Instruction::Write,
+        ]);
+    }
+
     #[test]
     fn test_partial_eval_read_dec_write() {
         let program = lex(",---.");
@@ -1057,7 +3292,6 @@ mod tests {
         assert_eq!(insts, [
             Instruction::SetHeadPos(-1),
             Instruction::SetCell(-1, 1),
-            Instruction::SetCell(0, 0),
             Instruction::JumpIfZero,
             Instruction::Read,
             Instruction::JumpUnlessZero,
@@ -1083,7 +3317,7 @@ mod tests {
             let mut input = input.clone();
 
             let mut state = State::new(input_prog);
-            state.interp(&input[..], output.by_ref());
+            state.interp(&input[..], output.by_ref()).unwrap();
 
             let mut orig_output = Vec::new();
             let mut output_file = File::open(output_path).unwrap();
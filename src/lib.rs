@@ -0,0 +1,4 @@
+// The interpreter, optimizer and backends as a library, for the brainbug binary and the fuzz targets.
+pub mod common;
+pub mod compile;
+pub mod interp;
@@ -1,42 +1,312 @@
-use std::{collections::{HashMap, VecDeque}, env, fs, io::{self, Read, Write}, process::ExitCode, time::SystemTime, usize};
+use std::{collections::{HashMap, HashSet, VecDeque}, env, fs, io::{self, Cursor, Read, Write}, process::ExitCode, time::{Duration, SystemTime}, usize};
 use std::path::Path;
 use std::fs::File;
 
 use inkwell::context::Context;
 
-mod common;
-mod compile;
-mod interp;
+use brainbug::{common, compile, interp};
 
 fn print_usage() {
     println!("Usage: brainbug interp [path to bf file] [options]");
-    println!("       brainbug compile [path to bf file] [options]");
+    println!("       brainbug compile [path to bf file]... [options]");
     println!("       brainbug compile-llvm [path to bf file] [options]");
-    println!("Options: -p                  Print profile data (interp only)");
+    println!("       brainbug compile-rust [path to bf file] [options]");
+    println!("       brainbug dump [path to bf file] [options]");
+    println!("       brainbug stats [path to bf file]  print instruction counts, loop nesting and the longest run of one command");
+    println!("       brainbug replay [path to bf file] [path to input log] [options]  interpret with input from a -record-input log");
+    println!("       brainbug run-bc [path to bc file] [options]  link bitcode written by compile-llvm -S into an exe and run it");
+    println!("       brainbug version");
+    println!("       brainbug info       print available backends and tools as JSON");
+    println!("Multiple files given to compile are concatenated in order, so loops may span files.");
+    println!("Options: -p                  Print profile data to stderr, repeat for tape statistics (interp only)");
     println!("         -t                  Print execution time");
     println!("         -r                  execute compiled binary (compile only)");
+    println!("         -run-timeout [s]    kill the compiled binary if it runs for more than s seconds (with -r)");
     println!("         -S                  compile to asm instead of exe (compile only)");
-    println!("         -no-loop-simplify   compile to asm instead of exe (compile only)");
-    println!("         -no-scan-vectorize  compile to asm instead of exe (compile only)");
+    println!("         -nasm               write NASM syntax asm to a .asm file instead of building an exe (compile only)");
+    println!("         -o [path]           write the asm from -S or -nasm to path instead, or to stdout if path is - (compile only)");
+    println!("         -c                  compile to an object file exporting bf_main, without linking (compile and compile-llvm)");
+    println!("         -emit-llvm-ir       write textual LLVM IR to a .ll file instead of building an exe (compile-llvm only, not with -keep-temps)");
+    println!("         -O0, -O1, -O2       no optimization, loop passes (default), or loop passes and partial eval; the flags below override the preset");
+    println!("         -no-loop-simplify   don't turn simple loops into multiplies and clears (compile, compile-llvm, compile-rust and dump)");
+    println!("         -no-scan-vectorize  don't turn [>] style loops into scans (compile, compile-llvm, compile-rust and dump)");
+    println!("         -partial-eval       run the program's input-independent prefix at compile time (compile, compile-llvm, compile-rust and dump)");
+    println!("         -no-partial-eval    don't partially evaluate the program (compile, compile-llvm, compile-rust and dump)");
+    println!("         -partial-eval-output-limit [n]  stop partial eval before it folds writes into more than n literal outputs (compile, compile-llvm, compile-rust and dump, needs partial eval)");
+    println!("         -partial-eval-past-loops  keep partially evaluating past loops that depend on input (compile, compile-llvm, compile-rust and dump, needs partial eval)");
+    println!("         -no-dead-loops      keep loops that can never run, like comment loops at the start (compile, compile-llvm, compile-rust and dump)");
+    println!("         -explain-opt        print whether each loop left after partial eval and unrolling was simplified, and why not (compile, compile-llvm, compile-rust and dump)");
+    println!("         -why-not-simplified [pc] print why the loop starting at pc, as numbered by dump, was or wasn't simplified (compile, compile-llvm, compile-rust and dump)");
+    println!("         -verify-opt         interpret the program with and without optimizations and warn if the results differ (compile only)");
+    println!("         -opt-stats          print how many instructions each pass left, and how many loops, scans and partial eval instructions it handled (compile, compile-llvm, compile-rust and dump)");
+    println!("         -map                write a .map file listing each asm label's pc and instruction (compile only)");
+    println!("         -annotate           annotate asm with the instruction each block came from (compile only)");
+    println!("         -unroll-limit [n]   unroll loops with a known trip count into at most n instructions (compile, compile-llvm, compile-rust and dump)");
+    println!("         -checked            abort with an error if the head leaves the tape, at some cost in speed (compile only)");
+    println!("         -freestanding       build a static Linux executable that makes syscalls instead of using libc, with scalar scans (compile only, not with -r)");
+    println!("         -no-avx             don't use AVX2 for scans, for portable binaries (compile only)");
+    println!("         -scan-lanes [n]     check n cells per scan iteration: 8 with ymm gathers (default), 4 with xmm gathers, or SSE2 with -no-avx, or 1 without gathers (compile only, 8 needs AVX)");
+    println!("         -exit-from-cell     exit with the value of the cell under the head when the program ends (compile only)");
+    println!("         -self-center        make bf_main take the tape's base and length and start the head in the middle (compile only, not with -checked)");
+    println!("         -cache-dir [path]   reuse the exe built earlier for the same asm and options from path (compile only, when building an exe)");
+    println!("         -guard-pages        put inaccessible pages around the tape so a head that runs off it crashes at once (compile only, not with -freestanding)");
+    println!("         -keep-temps         keep the intermediate files handed to clang (compile, compile-llvm and run-bc)");
+    println!("         -time-compile       print time spent in each compile phase (compile only)");
+    println!("         -pretty             indent the listing by loop nesting depth (dump only)");
+    println!("         -emit-bf            print the optimized program as Brainfuck instead of listing it (dump only, not with -pretty)");
+    println!("         -explain-partial-eval  print how much of the program partial eval would fold away (dump only)");
+    println!("         -show-taint         print the tape partial eval leaves, and which read made each unknown cell unknown (dump only)");
+    println!("         -max-output [n]     stop with an error once the program writes more than n bytes (interp only)");
+    println!("         -saturating         use saturating instead of wrapping cell arithmetic (interp only)");
+    println!("         -tape-size [n]      use a circular tape of n cells instead of a growable one (interp only)");
+    println!("         -ascii-out          clear the high bit of every byte written, for 7-bit terminals (interp, compile and compile-llvm)");
+    println!("         -eof-byte [n]       store n instead of 255 when a read finds no more input (interp, compile and compile-llvm)");
+    println!("         -profile-out [path] write how many times each instruction ran to a JSON file (interp only)");
+    println!("         -profile-in [path]  align the loops a -profile-out file shows are hot (compile only, not with -partial-eval or -unroll-limit)");
+    println!("         -record-input [path] write every byte the program reads to a log for replay (interp only)");
+    println!("         -input-string [s]   use s as the program's input instead of stdin, with \\n, \\0 and \\xHH escapes (interp only)");
+    println!("         -output-format [f]  write output as raw bytes (default), hex or dec numbers separated by spaces (interp only)");
+    println!("         -dump-tape-after    print the tape to stderr when the program ends, in hex with -output-format hex (interp only)");
+    println!("         -dump-tape-cells [n] print only the first n cells of the tape with -dump-tape-after (interp only)");
+    println!("         -progress           print how many instructions have run to stderr as the program runs (interp only)");
+    println!("         -debug-cmds         treat '#' as dump the tape and '$' as print the current cell, to stderr (interp only)");
+    println!("         -cell-directives    treat the cells named by ';w32 N' lines as 32 bit little endian values spanning cells N to N+3 (interp only)");
+    println!("         -strict             reject characters that aren't commands or whitespace instead of skipping them as comments");
+    println!("         -strict-allow [chars] also accept chars as comments with -strict");
+}
+
+// How many instructions interp -progress runs between updates.
+const PROGRESS_INTERVAL: u64 = 10_000_000;
+
+// How many instructions compile -verify-opt lets the unoptimized program run before giving up.
+const VERIFY_OPT_STEP_LIMIT: u64 = 100_000_000;
+
+// With -strict, report the first character of source that isn't a command, whitespace or an
+// allowed comment. Returns false if there is one.
+fn check_strict(path: &str, source: &[u8], opts: &common::LexOptions) -> bool {
+    if let Err(e) = common::check_strict(source, opts) {
+        eprintln!("{}: {}", path, e);
+        return false;
+    }
+    return true;
+}
+
+// Write a profile, label map or compiled output to path. Returns false once a failure has been
+// reported.
+fn write_output_file(path: &str, contents: impl AsRef<[u8]>) -> bool {
+    if let Err(source) = fs::write(path, contents) {
+        eprintln!("{}: {}", path, interp::WriteFailed { source });
+        return false;
+    }
+    return true;
+}
+
+// Write a listing or report to stdout, flushing it so a closed stdout is reported here instead of
+// panicking in println!.
+fn write_stdout(s: &str) -> Result<(), interp::WriteFailed> {
+    let mut stdout = io::stdout().lock();
+    return stdout.write_all(s.as_bytes()).and_then(|_| stdout.flush()).map_err(|source| interp::WriteFailed { source });
+}
+
+// Lex the program a compile, dump or stats mode was given and check its brackets. input is the first
+// file's contents. Any other files are appended at the instruction level so brackets may span file
+// boundaries, and a mismatched bracket is reported relative to the file it's in. Returns None once
+// a problem has been reported.
+fn lex_program(input: &[u8], file_paths: &[&str], strict: bool, lex_opts: &common::LexOptions) -> Option<Vec<common::Instruction>> {
+    let mut program = common::lex_with_options(input, lex_opts);
+    let mut file_ends = vec![program.len()];
+    for path in &file_paths[1..] {
+        let file_input = match fs::read(path) {
+            Ok(file_input) => file_input,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return None;
+            }
+        };
+        if strict && !check_strict(path, &file_input, lex_opts) {
+            return None;
+        }
+        program.extend(common::lex_with_options(&file_input, lex_opts));
+        file_ends.push(program.len());
+    }
+
+    if let Err(mut e) = common::check_brackets(&program) {
+        let file_idx = file_ends.iter().position(|end| e.pc < *end).unwrap();
+        if file_idx > 0 {
+            e.pc -= file_ends[file_idx - 1];
+        }
+        eprintln!("{}: {}", file_paths[file_idx], e);
+        return None;
+    }
+    return Some(program);
+}
+
+// Print how the loop optimizer treats each loop for -explain-opt, and its verdict on the loop
+// starting at why_not_simplified. Pcs are those dump prints. Returns false if no loop starts at
+// why_not_simplified.
+fn print_requested_explanations(program: &[common::Instruction], opts: compile::OptimizeOptions, explain_opt: bool, why_not_simplified: Option<usize>) -> bool {
+    if !explain_opt && why_not_simplified.is_none() {
+        return true;
+    }
+
+    let reports = compile::explain_optimized_loops(program, opts);
+    if explain_opt {
+        for report in &reports {
+            eprintln!("{}", report);
+        }
+    }
+    if let Some(pc) = why_not_simplified {
+        match reports.iter().find(|report| report.start_pc == pc) {
+            Some(report) => eprintln!("{}", report),
+            None => {
+                eprintln!("no loop starts at pc {}", pc);
+                return false;
+            }
+        }
+    }
+    return true;
 }
 
 fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
 
     let mut mode = "";
-    let mut file_path = "";
-    let mut profile = false;
+    let mut file_paths : Vec<&str> = Vec::new();
+    let mut profile_level = 0;
     let mut time = false;
     let mut run = false;
     let mut compile_to_asm = false;
-    let mut simplify_loops = true;
-    let mut vectorize_scans = true;
-    let mut partial_eval = false;
+    let mut nasm = false;
+    let mut opt_level = 1;
+    let mut simplify_loops = None;
+    let mut vectorize_scans = None;
+    let mut partial_eval = None;
+    let mut partial_eval_past_loops = false;
+    let mut partial_eval_output_limit = None;
+    let mut eliminate_dead_loops = None;
+    let mut saturating = false;
+    let mut ascii_out = false;
+    let mut eof_byte = None;
+    let mut annotate = false;
+    let mut use_avx = true;
+    let mut scan_lanes = None;
+    let mut checked = false;
+    let mut freestanding = false;
+    let mut self_center = false;
+    let mut guard_pages = false;
+    let mut keep_temps = false;
+    let mut time_compile = false;
+    let mut compile_to_object = false;
+    let mut emit_llvm_ir = false;
+    let mut write_label_map = false;
+    let mut exit_from_cell = false;
+    let mut pretty = false;
+    let mut emit_bf = false;
+    let mut explain_opt = false;
+    let mut opt_stats = false;
+    let mut verify_opt = false;
+    let mut why_not_simplified = None;
+    let mut explain_partial_eval = false;
+    let mut show_taint = false;
+    let mut unroll_limit = 0;
+    let mut max_output = None;
+    let mut run_timeout = None;
+    let mut tape_size = None;
+    let mut debug_cmds = false;
+    let mut cell_directives = false;
+    let mut strict = false;
+    let mut strict_allow = None;
+    let mut progress = false;
+    let mut dump_tape_after = false;
+    let mut dump_tape_cells = None;
+    let mut profile_out = None;
+    let mut profile_in = None;
+    let mut cache_dir = None;
+    let mut record_input = None;
+    let mut input_string = None;
+    let mut output_path = None;
+    let mut output_format = interp::OutputFormat::Raw;
+
+    // Flag whose value is the next argument, if any
+    let mut value_flag = "";
 
     for i in 1..args.len() {
+        // Value of a flag that takes one
+        if !value_flag.is_empty() {
+            // Flags whose value isn't a number
+            if value_flag == "-profile-out" || value_flag == "-profile-in" || value_flag == "-output-format" || value_flag == "-cache-dir" || value_flag == "-record-input" || value_flag == "-strict-allow" || value_flag == "-o" || value_flag == "-input-string" {
+                if value_flag == "-profile-out" {
+                    profile_out = Some(args[i].as_str());
+                } else if value_flag == "-profile-in" {
+                    profile_in = Some(args[i].as_str());
+                } else if value_flag == "-cache-dir" {
+                    cache_dir = Some(Path::new(&args[i]));
+                } else if value_flag == "-record-input" {
+                    record_input = Some(args[i].as_str());
+                } else if value_flag == "-strict-allow" {
+                    strict_allow = Some(args[i].as_bytes());
+                } else if value_flag == "-o" {
+                    output_path = Some(args[i].as_str());
+                } else if value_flag == "-input-string" {
+                    input_string = Some(args[i].as_str());
+                } else {
+                    output_format = match interp::OutputFormat::from_name(&args[i]) {
+                        Some(format) => format,
+                        None => {
+                            print_usage();
+                            return ExitCode::from(1);
+                        }
+                    };
+                }
+                value_flag = "";
+                continue;
+            }
+
+            let value : usize = match args[i].parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    print_usage();
+                    return ExitCode::from(1);
+                }
+            };
+
+            if value_flag == "-unroll-limit" {
+                unroll_limit = value;
+            } else if value_flag == "-partial-eval-output-limit" {
+                partial_eval_output_limit = Some(value);
+            } else if value_flag == "-max-output" {
+                max_output = Some(value);
+            } else if value_flag == "-tape-size" {
+                tape_size = Some(value);
+            } else if value_flag == "-why-not-simplified" {
+                why_not_simplified = Some(value);
+            } else if value_flag == "-dump-tape-cells" {
+                dump_tape_cells = Some(value);
+            } else if value_flag == "-scan-lanes" {
+                if value != 1 && value != 4 && value != 8 {
+                    print_usage();
+                    return ExitCode::from(1);
+                }
+                scan_lanes = Some(value as u32);
+            } else if value_flag == "-run-timeout" {
+                run_timeout = Some(Duration::from_secs(value as u64));
+            } else if value_flag == "-eof-byte" {
+                eof_byte = match u8::try_from(value) {
+                    Ok(byte) => Some(byte),
+                    Err(_) => {
+                        print_usage();
+                        return ExitCode::from(1);
+                    }
+                };
+            }
+
+            value_flag = "";
+            continue;
+        }
+
         // Flag arguments
         if args[i] == "-p" {
-            profile = true;
+            profile_level += 1;
             continue;
         } else if args[i] == "-t" {
             time = true;
@@ -47,90 +317,691 @@ fn main() -> ExitCode {
         } else if args[i] == "-S" {
             compile_to_asm = true;
             continue;
+        } else if args[i] == "-nasm" {
+            nasm = true;
+            continue;
+        } else if args[i] == "-c" {
+            compile_to_object = true;
+            continue;
+        } else if args[i] == "-emit-llvm-ir" {
+            emit_llvm_ir = true;
+            continue;
+        } else if args[i] == "-O0" || args[i] == "-O1" || args[i] == "-O2" {
+            opt_level = args[i][2..].parse().unwrap();
+            continue;
         } else if args[i] == "-no-loop-simplify" {
-            simplify_loops = false;
+            simplify_loops = Some(false);
             continue;
         } else if args[i] == "-no-scan-vectorize" {
-            vectorize_scans = false;
+            vectorize_scans = Some(false);
             continue;
         } else if args[i] == "-partial-eval" {
-            partial_eval = true;
+            partial_eval = Some(true);
+            continue;
+        } else if args[i] == "-no-partial-eval" {
+            partial_eval = Some(false);
+            continue;
+        } else if args[i] == "-partial-eval-past-loops" {
+            partial_eval_past_loops = true;
+            continue;
+        } else if args[i] == "-no-dead-loops" {
+            eliminate_dead_loops = Some(false);
+            continue;
+        } else if args[i] == "-pretty" {
+            pretty = true;
+            continue;
+        } else if args[i] == "-emit-bf" {
+            emit_bf = true;
+            continue;
+        } else if args[i] == "-exit-from-cell" {
+            exit_from_cell = true;
+            continue;
+        } else if args[i] == "-self-center" {
+            self_center = true;
+            continue;
+        } else if args[i] == "-guard-pages" {
+            guard_pages = true;
+            continue;
+        } else if args[i] == "-explain-opt" {
+            explain_opt = true;
+            continue;
+        } else if args[i] == "-opt-stats" {
+            opt_stats = true;
+            continue;
+        } else if args[i] == "-verify-opt" {
+            verify_opt = true;
+            continue;
+        } else if args[i] == "-explain-partial-eval" {
+            explain_partial_eval = true;
+            continue;
+        } else if args[i] == "-show-taint" {
+            show_taint = true;
+            continue;
+        } else if args[i] == "-map" {
+            write_label_map = true;
+            continue;
+        } else if args[i] == "-annotate" {
+            annotate = true;
+            continue;
+        } else if args[i] == "-unroll-limit" || args[i] == "-max-output" || args[i] == "-tape-size" || args[i] == "-run-timeout" || args[i] == "-dump-tape-cells" || args[i] == "-eof-byte" || args[i] == "-why-not-simplified" || args[i] == "-scan-lanes" || args[i] == "-partial-eval-output-limit"
+            || args[i] == "-profile-out" || args[i] == "-profile-in" || args[i] == "-output-format" || args[i] == "-cache-dir" || args[i] == "-record-input" || args[i] == "-strict-allow" || args[i] == "-o" || args[i] == "-input-string" {
+            value_flag = &args[i];
+            continue;
+        } else if args[i] == "-checked" {
+            checked = true;
+            continue;
+        } else if args[i] == "-freestanding" {
+            freestanding = true;
+            continue;
+        } else if args[i] == "-no-avx" {
+            use_avx = false;
+            continue;
+        } else if args[i] == "-keep-temps" {
+            keep_temps = true;
+            continue;
+        } else if args[i] == "-time-compile" {
+            time_compile = true;
+            continue;
+        } else if args[i] == "-ascii-out" {
+            ascii_out = true;
+            continue;
+        } else if args[i] == "-saturating" {
+            saturating = true;
+            continue;
+        } else if args[i] == "-debug-cmds" {
+            debug_cmds = true;
+            continue;
+        } else if args[i] == "-cell-directives" {
+            cell_directives = true;
+            continue;
+        } else if args[i] == "-strict" {
+            strict = true;
+            continue;
+        } else if args[i] == "-progress" {
+            progress = true;
+            continue;
+        } else if args[i] == "-dump-tape-after" {
+            dump_tape_after = true;
             continue;
         }
 
         // Positional arguments
         if mode.is_empty() {
             mode = &args[i];
-        } else if file_path.is_empty() {
-            file_path = &args[i];
         } else {
+            file_paths.push(&args[i]);
+        }
+    }
+
+    // These don't take a file.
+    if mode == "version" || mode == "info" {
+        if !file_paths.is_empty() {
+            print_usage();
+            return ExitCode::from(1);
+        }
+
+        if mode == "version" {
+            println!("brainbug {}", env!("CARGO_PKG_VERSION"));
+        } else {
+            println!("{}", compile::capabilities().to_json());
+        }
+        return ExitCode::from(0);
+    }
+
+    // Flags for individual passes override the preset.
+    let simplify_loops = simplify_loops.unwrap_or(opt_level >= 1);
+    let vectorize_scans = vectorize_scans.unwrap_or(opt_level >= 1);
+    let partial_eval = partial_eval.unwrap_or(opt_level >= 2);
+    let eliminate_dead_loops = eliminate_dead_loops.unwrap_or(opt_level >= 1);
+
+    // replay is interp with its input read from a log written by -record-input.
+    let mut replay_log = None;
+    if mode == "replay" {
+        if file_paths.len() != 2 {
             print_usage();
             return ExitCode::from(1);
         }
+        replay_log = file_paths.pop();
+        mode = "interp";
     }
 
-    if mode.is_empty() || file_path.is_empty() {
+    // run-bc links bitcode from an earlier compile-llvm -S and runs it, so -r is implied.
+    if mode == "run-bc" {
+        run = true;
+    }
+
+    if mode.is_empty() || file_paths.is_empty() || !value_flag.is_empty() {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if file_paths.len() > 1 && mode != "compile" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    let file_path = file_paths[0];
+    if (profile_level > 0 || saturating || max_output.is_some() || tape_size.is_some() || debug_cmds || cell_directives || progress || dump_tape_after || profile_out.is_some() || record_input.is_some() || output_format != interp::OutputFormat::Raw) && mode != "interp" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    // A replay's input is its log.
+    if input_string.is_some() && (mode != "interp" || replay_log.is_some()) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if (run || compile_to_asm || keep_temps) && (mode != "compile" && mode != "compile-llvm" && mode != "run-bc") {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if compile_to_asm && mode == "run-bc" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if dump_tape_cells.is_some() && !dump_tape_after {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if run_timeout.is_some() && !run {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if (annotate || !use_avx || time_compile || write_label_map || exit_from_cell || verify_opt) && mode != "compile" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if (unroll_limit > 0 || explain_opt || opt_stats || why_not_simplified.is_some()) && (mode == "interp" || mode == "run-bc" || mode == "stats") {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if checked && (mode != "compile" || nasm) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if nasm && (mode != "compile" || run) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if freestanding && (mode != "compile" || nasm || run) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    // Checked mode bounds checks against the runner's tape, which a self centering bf_main doesn't
+    // assume.
+    if self_center && (mode != "compile" || checked) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    // Guard pages only change how the runner allocates the tape, and the freestanding runner's tape
+    // is static.
+    if guard_pages && (mode != "compile" || compile_to_asm || nasm || compile_to_object || freestanding) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    // NASM, checked and freestanding scans go a cell at a time, so they have no lanes. Only the 8
+    // lane scan needs AVX2; 4 lanes has an SSE2 version.
+    if scan_lanes.is_some() && (mode != "compile" || (!use_avx && scan_lanes == Some(8)) || nasm || checked || freestanding) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    let scan_lanes = scan_lanes.unwrap_or(8);
+    if cache_dir.is_some() && (mode != "compile" || compile_to_asm || nasm || compile_to_object) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if (ascii_out || eof_byte.is_some()) && mode != "interp" && mode != "compile" && mode != "compile-llvm" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    let eof_byte = eof_byte.unwrap_or(255);
+    // Profile pcs are those of the lexed program, which partial eval and unrolling renumber.
+    if profile_in.is_some() && (mode != "compile" || partial_eval || unroll_limit > 0) {
         print_usage();
         return ExitCode::from(1);
     }
-    if profile && mode != "interp" {
+    if (partial_eval_past_loops || partial_eval_output_limit.is_some()) && (!partial_eval || (mode != "compile" && mode != "compile-llvm" && mode != "compile-rust" && mode != "dump")) {
         print_usage();
         return ExitCode::from(1);
     }
-    if (run || compile_to_asm) && (mode != "compile" && mode != "compile-llvm") {
+    if tape_size == Some(0) {
         print_usage();
         return ExitCode::from(1);
     }
+    if (pretty || explain_partial_eval || show_taint) && mode != "dump" {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if emit_bf && (mode != "dump" || pretty) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if compile_to_object && ((mode != "compile" && mode != "compile-llvm") || compile_to_asm || nasm || run) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if emit_llvm_ir && (mode != "compile-llvm" || compile_to_asm || compile_to_object || run || keep_temps) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    if (strict && mode == "run-bc") || (strict_allow.is_some() && !strict) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+    // -map and -time-compile print to stdout, where they'd end up mixed into the asm.
+    if output_path.is_some() && (mode != "compile" || !(compile_to_asm || nasm) || (output_path == Some("-") && (write_label_map || time_compile))) {
+        print_usage();
+        return ExitCode::from(1);
+    }
+
+    // The bitcode is already compiled, so there's no BF source to read.
+    if mode == "run-bc" {
+        let context = Context::create();
+        let module = match compile::load_llvm_bitcode(&context, Path::new(file_path)) {
+            Ok(module) => module,
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
+        };
+
+        let output_filepath = Path::new(file_path).file_stem().unwrap().to_str().unwrap().to_owned() + ".exe";
+        if let Err(e) = compile::compile_llvm_to_exe(&module, &output_filepath, false, keep_temps) {
+            eprintln!("{}", e);
+            return ExitCode::from(1);
+        }
+        println!("Result written to {}", output_filepath);
+
+        let start_time = SystemTime::now();
+
+        if let Err(e) = compile::run(&output_filepath, false, run_timeout) {
+            eprintln!("failed to run compiled BF program: {}", e);
+            return ExitCode::from(1);
+        }
+
+        if time {
+            println!("\nExecution time: {}", start_time.elapsed().unwrap().as_secs_f64());
+        }
+
+        return ExitCode::from(0);
+    }
 
-    let input = fs::read_to_string(file_path).expect("unable to read file");
+    let mut lex_opts = if debug_cmds { common::LexOptions::with_builtin_debug_commands() } else { common::LexOptions::default() };
+    lex_opts.comment_chars.extend(strict_allow.unwrap_or_default());
+    lex_opts.cell_directives = cell_directives;
+
+    let input = fs::read(file_path).expect("unable to read file");
+    // interp checks only the part before any '!', since the rest is the program's input.
+    if strict && mode != "interp" && !check_strict(file_path, &input, &lex_opts) {
+        return ExitCode::from(1);
+    }
+
+    let opts = compile::OptimizeOptions {
+        simplify_loops,
+        vectorize_scans,
+        partial_eval,
+        partial_eval_past_loops,
+        partial_eval_output_limit,
+        eliminate_dead_loops,
+        unroll_limit,
+        exit_from_cell,
+    };
 
     if mode == "interp" {
         let start_time = SystemTime::now();
 
-        let program = common::lex(&input);
+        // Everything after the first '!' is fed to the program as input instead of stdin.
+        let (source, mut program_input) = common::split_program_input(&input);
+
+        // -input-string takes the place of input after a '!' too.
+        let string_input;
+        if let Some(s) = input_string {
+            string_input = match common::unescape(s) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("-input-string: {}", e);
+                    return ExitCode::from(1);
+                }
+            };
+            program_input = Some(&string_input);
+        }
+
+        if strict && !check_strict(file_path, source, &lex_opts) {
+            return ExitCode::from(1);
+        }
+        let program = common::lex_with_options(source, &lex_opts);
         let mut state = interp::State::new(program);
-        state.interp(std::io::stdin(), std::io::stdout());
+        if cell_directives {
+            match common::wide_cells(source) {
+                Ok(cells) => state.set_wide_cells(cells),
+                Err(e) => {
+                    eprintln!("{}: {}", file_path, e);
+                    return ExitCode::from(1);
+                }
+            }
+        }
+        if ascii_out {
+            state.set_output_filter(interp::OutputFilter::SevenBit);
+        }
+        state.set_eof_byte(eof_byte);
+        if saturating {
+            state.set_arithmetic(interp::Arithmetic::Saturating);
+        }
+        if let Some(size) = tape_size {
+            state.set_tape_mode(interp::TapeMode::Wrapping { size });
+        }
+
+        let mut reader : Box<dyn Read> = match (replay_log, program_input) {
+            (Some(path), _) => Box::new(File::open(path).expect("unable to open input log")),
+            (None, Some(bytes)) => Box::new(Cursor::new(bytes)),
+            (None, None) => Box::new(std::io::stdin()),
+        };
+        if let Some(path) = record_input {
+            // The log is unbuffered so it's complete even if the program crashes the interpreter.
+            let log = File::create(path).expect("unable to create input log");
+            reader = Box::new(interp::RecordingReader::new(reader, log));
+        }
+
+        let writer = interp::FormattedOutput::new(std::io::stdout(), output_format);
+        let progress_every = if progress { PROGRESS_INTERVAL } else { 0 };
+        let mut progress_shown = false;
+        let result = state.interp_with_progress(reader, writer, max_output.unwrap_or(usize::MAX), progress_every, |total| {
+            eprint!("\r{} instructions executed", total);
+            progress_shown = true;
+        });
+        if progress_shown {
+            eprintln!();
+        }
+        if let Err(e) = result {
+            eprintln!("\n{}", e);
+            return ExitCode::from(1);
+        }
+        if output_format != interp::OutputFormat::Raw {
+            println!();
+        }
+
+        if dump_tape_after {
+            if let Err(source) = state.dump_tape(&mut io::stderr(), dump_tape_cells, output_format) {
+                eprintln!("{}", interp::WriteFailed { source });
+                return ExitCode::from(1);
+            }
+        }
 
         if time {
             println!("\nExecution time: {}", start_time.elapsed().unwrap().as_secs_f64());
         }
 
-        if profile {
-            state.print_profile_info();
+        if let Some(path) = profile_out {
+            if !write_output_file(path, state.profile().to_json()) {
+                return ExitCode::from(1);
+            }
+        }
+
+        if profile_level > 0 {
+            if let Err(source) = state.print_profile_info(&mut io::stderr()) {
+                eprintln!("{}", interp::WriteFailed { source });
+                return ExitCode::from(1);
+            }
+        }
+        if profile_level > 1 {
+            if let Err(source) = state.print_tape_stats(&mut io::stderr()) {
+                eprintln!("{}", interp::WriteFailed { source });
+                return ExitCode::from(1);
+            }
         }
     } else if mode == "compile" {
-        let mut program = common::lex(&input);
-        let compiled_asm = compile::compile_to_asm(&mut program, simplify_loops, vectorize_scans, partial_eval);
+        let Some(mut program) = lex_program(&input, &file_paths, strict, &lex_opts) else {
+            return ExitCode::from(1);
+        };
+        if program.is_empty() {
+            eprintln!("warning: program contains no instructions");
+        }
+        if !print_requested_explanations(&program, opts, explain_opt, why_not_simplified) {
+            return ExitCode::from(1);
+        }
+
+        let hot_loops = match profile_in {
+            Some(path) => {
+                let json = fs::read_to_string(path).expect("unable to read profile");
+                let profile = match interp::Profile::from_json(&json) {
+                    Ok(profile) => profile,
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        return ExitCode::from(1);
+                    }
+                };
+                if profile.counts.len() != program.len() {
+                    eprintln!("{}: profile was recorded for a different program", path);
+                    return ExitCode::from(1);
+                }
+                profile.hot_loops(&program)
+            },
+            None => HashSet::new(),
+        };
+
+        let mut compile_times = compile::CompileTimes::default();
+        let mut labels = Vec::new();
+        let dialect = if nasm { compile::AsmDialect::Nasm } else { compile::AsmDialect::Att };
+        if verify_opt {
+            match compile::verify_optimization(&program, opts, eof_byte, VERIFY_OPT_STEP_LIMIT) {
+                Ok(true) => (),
+                Ok(false) => eprintln!("warning: the program ran more than {} instructions, so its optimizations weren't verified", VERIFY_OPT_STEP_LIMIT),
+                Err(e) => eprintln!("warning: {}", e),
+            }
+        }
+        let asm_opts = compile::AsmOptions {
+            dialect,
+            annotate,
+            use_avx,
+            scan_lanes,
+            checked,
+            freestanding,
+            ascii_out,
+            eof_byte,
+            self_center,
+        };
+        let compiled_asm = match compile::compile_to_asm(&mut program, opts, asm_opts, &hot_loops, &mut labels, &mut compile_times) {
+            Ok((asm, stats)) => {
+                if opt_stats {
+                    eprintln!("{}", stats);
+                }
+                asm
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
+        };
 
         let input_filepath = Path::new(file_path);
 
-        if compile_to_asm {
-            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".S";
-            let mut file = File::create(output_filepath.clone()).expect("Unable to open output file");
-            write!(file, "{}", compiled_asm).unwrap();
+        if write_label_map {
+            let map_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".map";
+            let mut map = "# label\tpc\tinstruction\n".to_owned();
+            for label in &labels {
+                map += &format!("{}\n", label);
+            }
+            if !write_output_file(&map_filepath, map) {
+                return ExitCode::from(1);
+            }
+
+            println!("Label map written to {}", map_filepath);
+        }
+
+        if (compile_to_asm || nasm) && output_path == Some("-") {
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout.write_all(compiled_asm.as_bytes()).and_then(|_| stdout.flush()) {
+                eprintln!("unable to write asm to stdout: {}", e);
+                return ExitCode::from(1);
+            }
+        } else if compile_to_asm || nasm {
+            let extension = if nasm { ".asm" } else { ".S" };
+            let output_filepath = match output_path {
+                Some(path) => path.to_owned(),
+                None => input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + extension,
+            };
+            if !write_output_file(&output_filepath, &compiled_asm) {
+                return ExitCode::from(1);
+            }
 
             println!("Result written to {}", output_filepath);
-        } else {
-            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".exe";
-            compile::compile_asm_to_exe(&compiled_asm, &output_filepath).expect("failed to assemble and link compiled asm");
+
+            if time_compile {
+                compile_times.print();
+            }
+        } else if compile_to_object {
+            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".o";
+            if let Err(e) = compile::compile_asm_to_object(&compiled_asm, Path::new(&output_filepath), freestanding, keep_temps, &mut compile_times) {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
             println!("Result written to {}", output_filepath);
 
+            if time_compile {
+                compile_times.print();
+            }
+        } else {
+            // Freestanding programs are Linux executables, so they don't get an .exe extension.
+            let extension = if freestanding { "" } else { ".exe" };
+            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + extension;
+            let exe_opts = compile::ExeOptions {
+                use_avx,
+                exit_from_cell,
+                self_center,
+                guard_pages,
+                freestanding,
+                keep_temps,
+            };
+            let built = match cache_dir {
+                Some(cache_dir) => compile::compile_asm_to_exe_cached(&compiled_asm, &output_filepath, exe_opts, cache_dir, &mut compile_times),
+                None => compile::compile_asm_to_exe(&compiled_asm, &output_filepath, exe_opts, &mut compile_times).map(|_| false),
+            };
+            match built {
+                Ok(true) => println!("Result copied from cache to {}", output_filepath),
+                Ok(false) => println!("Result written to {}", output_filepath),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::from(1);
+                }
+            }
+
+            if time_compile {
+                compile_times.print();
+            }
+
             if run {
                 let start_time = SystemTime::now();
 
-                compile::run(&output_filepath).expect("failed to run compiled BF program");
+                let exit_code = match compile::run(&output_filepath, exit_from_cell, run_timeout) {
+                    Ok(exit_code) => exit_code,
+                    Err(e) => {
+                        eprintln!("failed to run compiled BF program: {}", e);
+                        return ExitCode::from(1);
+                    }
+                };
 
                 if time {
                     println!("\nExecution time: {}", start_time.elapsed().unwrap().as_secs_f64());
                 }
+
+                if exit_from_cell {
+                    return ExitCode::from(exit_code);
+                }
             }
 
         }
+    } else if mode == "dump" {
+        let Some(mut program) = lex_program(&input, &file_paths, strict, &lex_opts) else {
+            return ExitCode::from(1);
+        };
+        if !print_requested_explanations(&program, opts, explain_opt, why_not_simplified) {
+            return ExitCode::from(1);
+        }
+        // Partial eval as the passes would run it.
+        let partial_eval_state = || {
+            let mut state = interp::State::new(program.clone());
+            state.set_partial_eval_past_unknown_loops(partial_eval_past_loops);
+            state.set_partial_eval_output_limit(partial_eval_output_limit.unwrap_or(usize::MAX));
+            state
+        };
+        if explain_partial_eval {
+            eprintln!("{}", partial_eval_state().partial_eval_report());
+        }
+        if show_taint {
+            let (_, cells) = partial_eval_state().partial_eval_taint();
+            for cell in cells {
+                eprintln!("{}", cell);
+            }
+        }
+
+        let stats = compile::optimize(&mut program, opts);
+        if opt_stats {
+            eprintln!("{}", stats);
+        }
+
+        let listing = if emit_bf {
+            common::emit_bf(&program)
+        } else if pretty {
+            common::pretty_print(&program)
+        } else {
+            program.iter().enumerate().map(|(pc, inst)| format!("{}\t{}\n", pc, inst)).collect()
+        };
+        if let Err(e) = write_stdout(&listing) {
+            eprintln!("{}", e);
+            return ExitCode::from(1);
+        }
+    } else if mode == "stats" {
+        let Some(program) = lex_program(&input, &file_paths, strict, &lex_opts) else {
+            return ExitCode::from(1);
+        };
+
+        if let Err(e) = write_stdout(&format!("{}\n", common::program_stats(&program))) {
+            eprintln!("{}", e);
+            return ExitCode::from(1);
+        }
+    } else if mode == "compile-rust" {
+        let Some(mut program) = lex_program(&input, &file_paths, strict, &lex_opts) else {
+            return ExitCode::from(1);
+        };
+        if program.is_empty() {
+            eprintln!("warning: program contains no instructions");
+        }
+        if !print_requested_explanations(&program, opts, explain_opt, why_not_simplified) {
+            return ExitCode::from(1);
+        }
+
+        let source = match compile::compile_to_rust(&mut program, opts) {
+            Ok((source, stats)) => {
+                if opt_stats {
+                    eprintln!("{}", stats);
+                }
+                source
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
+        };
+
+        let output_filepath = Path::new(file_path).file_stem().unwrap().to_str().unwrap().to_owned() + ".rs";
+        if !write_output_file(&output_filepath, source) {
+            return ExitCode::from(1);
+        }
+
+        println!("Result written to {}", output_filepath);
     } else if mode == "compile-llvm" {
-        let mut program = common::lex(&input);
+        let Some(mut program) = lex_program(&input, &file_paths, strict, &lex_opts) else {
+            return ExitCode::from(1);
+        };
+        if program.is_empty() {
+            eprintln!("warning: program contains no instructions");
+        }
+        if !print_requested_explanations(&program, opts, explain_opt, why_not_simplified) {
+            return ExitCode::from(1);
+        }
 
         let context = Context::create();
-        let module = compile::compile_to_llvm(&context, &mut program, simplify_loops);
+        let (module, stats) = compile::compile_to_llvm(&context, &mut program, opts, ascii_out, eof_byte);
+        if opt_stats {
+            eprintln!("{}", stats);
+        }
 
         let input_filepath = Path::new(file_path);
 
@@ -138,16 +1009,36 @@ fn main() -> ExitCode {
             let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".bc";
             module.write_bitcode_to_path(Path::new(&output_filepath));
 
+            println!("Result written to {}", output_filepath);
+        } else if emit_llvm_ir {
+            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".ll";
+            if let Err(e) = module.print_to_file(Path::new(&output_filepath)) {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
+            println!("Result written to {}", output_filepath);
+        } else if compile_to_object {
+            let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".o";
+            if let Err(e) = compile::compile_llvm_to_object(&module, Path::new(&output_filepath)) {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
             println!("Result written to {}", output_filepath);
         } else {
             let output_filepath = input_filepath.file_stem().unwrap().to_str().unwrap().to_owned() + ".exe";
-            compile::compile_llvm_to_exe(&module, &output_filepath, false).expect("failed to assemble and link compiled asm");
+            if let Err(e) = compile::compile_llvm_to_exe(&module, &output_filepath, false, keep_temps) {
+                eprintln!("{}", e);
+                return ExitCode::from(1);
+            }
             println!("Result written to {}", output_filepath);
 
             if run {
                 let start_time = SystemTime::now();
 
-                compile::run(&output_filepath).expect("failed to run compiled BF program");
+                if let Err(e) = compile::run(&output_filepath, false, run_timeout) {
+                    eprintln!("failed to run compiled BF program: {}", e);
+                    return ExitCode::from(1);
+                }
 
                 if time {
                     println!("\nExecution time: {}", start_time.elapsed().unwrap().as_secs_f64());